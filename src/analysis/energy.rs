@@ -0,0 +1,92 @@
+use super::Audio;
+
+/// Length of the stretches of audio whose loudness is measured, in seconds
+const WINDOW: f64 = 0.05;
+
+/// Stretches quieter than this (in dBFS) are silence, such as gaps and fade-outs, and left out of
+/// the loudness
+const SILENCE_DB: f64 = -60.0;
+
+/// Loudness (RMS, in dBFS) at the bottom and top of the energy scale: a quiet acoustic recording
+/// and a loud club master
+const QUIET_DB: f64 = -30.0;
+const LOUD_DB: f64 = -6.0;
+
+/// Rise in loudness from one stretch to the next, in dB, that counts as a hit (a drum, a stab)
+const HIT_DB: f64 = 6.0;
+
+/// Hits a second at which a track is as busy as it gets, as eighth notes at 120 BPM are
+const BUSIEST: f64 = 4.0;
+
+/// Rates how intense `audio` sounds, from 1 (calm) to 10 (intense), from how loud it is and how
+/// often it hits, the loudness counting for more. Silence rates 1.
+pub fn detect_energy(audio: &Audio) -> u8 {
+    let len = (WINDOW * audio.sample_rate as f64).round() as usize;
+    if len == 0 {
+        return 1;
+    }
+
+    let levels: Vec<f64> = audio
+        .samples
+        .chunks_exact(len)
+        .map(|stretch| {
+            let power = stretch.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / len as f64;
+            10.0 * power.max(1e-12).log10()
+        })
+        .collect();
+    let sounding: Vec<f64> = levels
+        .iter()
+        .copied()
+        .filter(|db| *db > SILENCE_DB)
+        .collect();
+    if sounding.is_empty() {
+        return 1;
+    }
+
+    let power =
+        sounding.iter().map(|db| 10f64.powf(db / 10.0)).sum::<f64>() / sounding.len() as f64;
+    let loudness = ((10.0 * power.log10() - QUIET_DB) / (LOUD_DB - QUIET_DB)).clamp(0.0, 1.0);
+
+    let hits = levels
+        .windows(2)
+        .filter(|pair| pair[1] > SILENCE_DB && pair[1] - pair[0] >= HIT_DB)
+        .count();
+    let busyness = (hits as f64 / (levels.len() as f64 * WINDOW) / BUSIEST).min(1.0);
+
+    1 + (9.0 * (0.6 * loudness + 0.4 * busyness)).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::tests::kicks;
+    use std::f32::consts::TAU;
+
+    #[test]
+    fn test_detect_energy() {
+        let silence = Audio {
+            sample_rate: 22050,
+            samples: vec![0.0; 22050 * 5],
+        };
+        assert_eq!(detect_energy(&silence), 1);
+
+        // a soft, steady tone is as calm as it gets
+        let tone = Audio {
+            sample_rate: 22050,
+            samples: (0..22050 * 5)
+                .map(|i| 0.02 * (TAU * 220.0 * i as f32 / 22050.0).sin())
+                .collect(),
+        };
+        assert_eq!(detect_energy(&tone), 1);
+
+        // the same kicks rate higher played louder and faster
+        let loud = kicks(128.0, 0.0, 0, 10.0);
+        let energy = detect_energy(&loud);
+        assert!((4..=8).contains(&energy), "{}", energy);
+
+        let mut soft = loud.clone();
+        soft.samples.iter_mut().for_each(|s| *s *= 0.1);
+        assert!(detect_energy(&soft) < energy);
+        assert!(detect_energy(&kicks(64.0, 0.0, 0, 10.0)) < energy);
+    }
+}
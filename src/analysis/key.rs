@@ -0,0 +1,321 @@
+use clap::ValueEnum;
+use std::fmt;
+use std::str::FromStr;
+
+use super::Audio;
+use crate::error::Error;
+
+/// Lowest and highest notes (as MIDI numbers) whose loudness counts towards the key: C2 (65 Hz)
+/// to B6 (1976 Hz), below which the bass is too muddy and above which harmonics take over
+const LOWEST_NOTE: u8 = 36;
+const HIGHEST_NOTE: u8 = 95;
+
+/// Cycles of a note each stretch of audio it is measured over lasts, enough to tell it from the
+/// notes a semitone either side
+const CYCLES: f64 = 17.0;
+
+/// How strongly each note of the scale, from the tonic up, says a key is major or minor (the
+/// probe-tone ratings of Krumhansl and Kessler)
+const MAJOR_PROFILE: [f64; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f64; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// Names of the major and minor keys by tonic, from C up, as DJ software spells them
+const MAJOR_NAMES: [&str; 12] = [
+    "C", "Db", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B",
+];
+const MINOR_NAMES: [&str; 12] = [
+    "Cm", "C#m", "Dm", "Ebm", "Em", "Fm", "F#m", "Gm", "G#m", "Am", "Bbm", "Bm",
+];
+
+/// A musical key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Key {
+    /// Pitch class of the tonic, from 0 (C) to 11 (B)
+    pub tonic: u8,
+    pub minor: bool,
+}
+
+/// How keys are written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Notation {
+    /// `C`, `Am`, `F#m`, as the `TKEY` frame has them
+    #[default]
+    Standard,
+    /// The Camelot wheel of Mixed In Key: `8B`, `8A`, `11A`
+    Camelot,
+    /// The Open Key notation of Traktor: `1d`, `1m`, `4m`
+    OpenKey,
+}
+
+impl Key {
+    /// Hour of the key on the Camelot wheel, from 1 to 12: neighbouring hours are a fifth apart,
+    /// and a minor key shares its hour with its relative major
+    pub fn camelot(self) -> u8 {
+        let major = match self.minor {
+            true => (self.tonic + 3) % 12,
+            false => self.tonic,
+        };
+        (7 * major + 7) % 12 + 1
+    }
+
+    /// The key written in `notation`
+    pub fn name(self, notation: Notation) -> String {
+        let mode = |major: &str, minor: &str| match self.minor {
+            true => minor.to_string(),
+            false => major.to_string(),
+        };
+
+        match notation {
+            Notation::Standard => match self.minor {
+                true => MINOR_NAMES[self.tonic as usize].to_string(),
+                false => MAJOR_NAMES[self.tonic as usize].to_string(),
+            },
+            Notation::Camelot => format!("{}{}", self.camelot(), mode("B", "A")),
+            // Open Key starts the wheel at C major rather than at B major
+            Notation::OpenKey => format!("{}{}", (self.camelot() + 4) % 12 + 1, mode("d", "m")),
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name(Notation::Standard))
+    }
+}
+
+impl FromStr for Key {
+    type Err = Error;
+
+    /// Reads a key written in any `Notation`, sharps and flats alike in the standard one
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || Error::from(format!("`{}` is not a key", s));
+
+        // Camelot and Open Key: an hour of the wheel and a letter
+        if let Some(split) = s.find(|c: char| !c.is_ascii_digit()).filter(|i| *i > 0) {
+            let (hour, letter) = s.split_at(split);
+            let hour: u8 = hour.parse().map_err(|_| bad())?;
+            if !(1..=12).contains(&hour) {
+                return Err(bad());
+            }
+            let (camelot, minor) = match letter {
+                "B" | "b" => (hour, false),
+                "A" | "a" => (hour, true),
+                "d" => ((hour + 6) % 12 + 1, false),
+                "m" => ((hour + 6) % 12 + 1, true),
+                _ => return Err(bad()),
+            };
+
+            // the tonic of the major key at that hour, a fifth (7 semitones) for every hour
+            let major = (7 * (camelot as u32 + 4)) % 12;
+            let tonic = match minor {
+                true => (major + 9) % 12,
+                false => major,
+            };
+            return Ok(Key {
+                tonic: tonic as u8,
+                minor,
+            });
+        }
+
+        let mut chars = s.chars();
+        let natural = match chars.next() {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => return Err(bad()),
+        };
+        let rest = chars.as_str();
+        let (tonic, rest) = match rest.strip_prefix(['#', '♯']) {
+            Some(rest) => (natural + 1, rest),
+            None => match rest.strip_prefix(['b', '♭']) {
+                Some(rest) => (natural + 11, rest),
+                None => (natural, rest),
+            },
+        };
+        let minor = match rest {
+            "" | "maj" => false,
+            "m" | "min" => true,
+            _ => return Err(bad()),
+        };
+
+        Ok(Key {
+            tonic: tonic % 12,
+            minor,
+        })
+    }
+}
+
+/// How loud each pitch class (C first) is in `audio` over the notes between `LOWEST_NOTE` and
+/// `HIGHEST_NOTE`, each measured with the Goertzel algorithm over stretches of `CYCLES` cycles
+fn chroma(audio: &Audio) -> [f64; 12] {
+    let mut chroma = [0.0; 12];
+    let rate = audio.sample_rate as f64;
+
+    for note in LOWEST_NOTE..=HIGHEST_NOTE {
+        let frequency = 440.0 * 2f64.powf((note as f64 - 69.0) / 12.0);
+        let len = (CYCLES * rate / frequency).round() as usize;
+        if frequency >= rate / 2.0 || len == 0 {
+            continue;
+        }
+
+        let coefficient = 2.0 * (std::f64::consts::TAU * frequency / rate).cos();
+        let (mut sum, mut stretches) = (0.0, 0);
+        for stretch in audio.samples.chunks_exact(len) {
+            let (mut s1, mut s2) = (0.0, 0.0);
+            for sample in stretch {
+                let s = *sample as f64 + coefficient * s1 - s2;
+                (s1, s2) = (s, s1);
+            }
+            let power = (s1 * s1 + s2 * s2 - coefficient * s1 * s2).max(0.0);
+            sum += power.sqrt() / len as f64;
+            stretches += 1;
+        }
+
+        if stretches > 0 {
+            chroma[note as usize % 12] += sum / stretches as f64;
+        }
+    }
+
+    chroma
+}
+
+/// Correlation of `a` and `b`, from -1 to 1
+fn correlation(a: &[f64; 12], b: &[f64; 12]) -> f64 {
+    let mean = |x: &[f64; 12]| x.iter().sum::<f64>() / 12.0;
+    let (mean_a, mean_b) = (mean(a), mean(b));
+
+    let (mut ab, mut aa, mut bb) = (0.0, 0.0, 0.0);
+    for (a, b) in a.iter().zip(b) {
+        let (a, b) = (a - mean_a, b - mean_b);
+        ab += a * b;
+        aa += a * a;
+        bb += b * b;
+    }
+
+    match aa * bb > 0.0 {
+        true => ab / (aa * bb).sqrt(),
+        false => 0.0,
+    }
+}
+
+/// Estimates the key of `audio` as the major or minor key whose profile the loudness of its
+/// pitch classes fits best, assuming it does not change. `None` if it has no notes to speak of,
+/// such as silence.
+pub fn detect_key(audio: &Audio) -> Option<Key> {
+    let chroma = chroma(audio);
+    if chroma.iter().sum::<f64>() < 1e-6 {
+        return None;
+    }
+
+    (0..12u8)
+        .flat_map(|tonic| [(tonic, false), (tonic, true)])
+        .map(|(tonic, minor)| {
+            let profile = match minor {
+                true => &MINOR_PROFILE,
+                false => &MAJOR_PROFILE,
+            };
+            // the chroma from the tonic up
+            let rotated: [f64; 12] = std::array::from_fn(|i| chroma[(i + tonic as usize) % 12]);
+            (Key { tonic, minor }, correlation(&rotated, profile))
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(key, _)| key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    /// `seconds` of the chords I, IV, V and I of `key` (i, iv, V and i if minor) over and over,
+    /// half a second each, played by a tone with a few harmonics over the root in the bass
+    fn chords(key: Key, seconds: f32) -> Audio {
+        let sample_rate = 11025;
+        let third = if key.minor { 3 } else { 4 };
+        let sixth = if key.minor { 8 } else { 9 };
+        let progression = [[0, third, 7], [5, sixth, 12], [7, 11, 14], [0, third, 7]];
+
+        let chord_len = sample_rate / 2;
+        let len = (seconds * sample_rate as f32) as usize;
+        let samples = (0..len)
+            .map(|i| {
+                let chord = progression[i / chord_len % progression.len()];
+                let t = (i % chord_len) as f32 / sample_rate as f32;
+                let notes = chord
+                    .iter()
+                    .map(|degree| 60 + key.tonic as i32 + degree)
+                    .chain([36 + key.tonic as i32 + chord[0]]);
+
+                let tone: f32 = notes
+                    .map(|note| {
+                        let frequency = 440.0 * 2f32.powf((note as f32 - 69.0) / 12.0);
+                        (1..=3)
+                            .map(|h| (TAU * frequency * h as f32 * t).sin() / h as f32)
+                            .sum::<f32>()
+                    })
+                    .sum();
+                0.1 * tone * (-t * 3.0).exp()
+            })
+            .collect();
+
+        Audio {
+            sample_rate: sample_rate as u32,
+            samples,
+        }
+    }
+
+    #[test]
+    fn test_key_names() {
+        for (key, standard, camelot, open_key) in [
+            ("C", "C", "8B", "1d"),
+            ("Am", "Am", "8A", "1m"),
+            ("F#m", "F#m", "11A", "4m"),
+            ("C#", "Db", "3B", "8d"),
+            ("Abm", "G#m", "1A", "6m"),
+            ("E", "E", "12B", "5d"),
+        ] {
+            let key: Key = key.parse().unwrap();
+            assert_eq!(key.name(Notation::Standard), standard);
+            assert_eq!(key.name(Notation::Camelot), camelot);
+            assert_eq!(key.name(Notation::OpenKey), open_key);
+        }
+
+        // every key reads back from each notation
+        for tonic in 0..12 {
+            for minor in [false, true] {
+                let key = Key { tonic, minor };
+                for notation in [Notation::Standard, Notation::Camelot, Notation::OpenKey] {
+                    assert_eq!(key.name(notation).parse::<Key>().unwrap(), key);
+                }
+            }
+        }
+
+        for bad in ["", "H", "Cx", "13A", "0B", "8C", "8", "am"] {
+            assert!(bad.parse::<Key>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_detect_key() {
+        for tonic in 0..12 {
+            for minor in [false, true] {
+                let key = Key { tonic, minor };
+                assert_eq!(detect_key(&chords(key, 6.0)), Some(key), "{}", key);
+            }
+        }
+
+        let silence = Audio {
+            sample_rate: 11025,
+            samples: vec![0.0; 11025 * 5],
+        };
+        assert_eq!(detect_key(&silence), None);
+    }
+}
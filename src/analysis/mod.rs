@@ -0,0 +1,184 @@
+use std::io::{Cursor, ErrorKind as IoErrorKind};
+use std::path::Path;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::error::{Error, ErrorKind};
+
+mod energy;
+mod key;
+mod tempo;
+pub use energy::detect_energy;
+pub use key::{detect_key, Key, Notation};
+pub use tempo::{detect_tempo, BeatGrid, Tempo};
+
+/// Rate audio is decoded at, about: tempo, key and energy need nothing above a few kHz, and a
+/// quarter of the samples of a 44.1 kHz track is what lets hours of a mix fit in memory
+const ANALYSIS_RATE: u32 = 11025;
+
+/// Decoded audio, mixed down to one channel and down to about `ANALYSIS_RATE` samples a second
+#[derive(Clone, Debug, Default)]
+pub struct Audio {
+    pub sample_rate: u32,
+    /// Samples between -1 and 1
+    pub samples: Vec<f32>,
+}
+
+impl Audio {
+    /// Reads and decodes the MP3 file at `path`, a little at a time
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        decode_source(Box::new(std::fs::File::open(path)?))
+    }
+
+    pub fn duration_ms(&self) -> u64 {
+        match self.sample_rate {
+            0 => 0,
+            rate => self.samples.len() as u64 * 1000 / rate as u64,
+        }
+    }
+}
+
+/// Decodes `mp3` (ID3 tags and all) into audio. Frames that fail to decode are skipped, as
+/// players do.
+pub fn decode(mp3: Vec<u8>) -> Result<Audio, Error> {
+    decode_source(Box::new(Cursor::new(mp3)))
+}
+
+/// `decode` for an MP3 file read from `source`. Every run of as many samples as take the rate
+/// down to about `ANALYSIS_RATE` is averaged into one as it is decoded.
+fn decode_source(source: Box<dyn MediaSource>) -> Result<Audio, Error> {
+    let source = MediaSourceStream::new(source, Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension("mp3");
+
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+    let track = format.default_track().ok_or(Error {
+        kind: ErrorKind::DecodeError,
+        value: String::from("no audio track in the file"),
+    })?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let step = |rate: u32| (rate / ANALYSIS_RATE).max(1);
+    let rate = track
+        .codec_params
+        .sample_rate
+        .filter(|&rate| rate > 0)
+        .ok_or(Error {
+            kind: ErrorKind::DecodeError,
+            value: String::from("no sample rate for the audio track"),
+        })?;
+    let mut audio = Audio {
+        sample_rate: rate / step(rate),
+        samples: Vec::new(),
+    };
+    if let Some(frames) = track.codec_params.n_frames {
+        audio
+            .samples
+            .reserve(frames as usize / step(rate) as usize + 1);
+    }
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    // the samples of the run being averaged so far, and their sum
+    let (mut run, mut sum) = (0, 0.0);
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == IoErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count().max(1);
+        let step = step(spec.rate);
+        audio.sample_rate = spec.rate / step;
+
+        let buffer = match &mut buffer {
+            Some(buffer) if buffer.capacity() >= decoded.capacity() * channels => buffer,
+            buffer => buffer.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+        for frame in buffer.samples().chunks(channels) {
+            sum += frame.iter().sum::<f32>() / channels as f32;
+            run += 1;
+            if run >= step {
+                audio.samples.push(sum / run as f32);
+                (run, sum) = (0, 0.0);
+            }
+        }
+    }
+
+    Ok(audio)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    /// `seconds` of a kick drum at `bpm`, the first one at `first_ms`, with every fourth beat from
+    /// `downbeat` (0 to 3) on louder
+    pub(crate) fn kicks(bpm: f64, first_ms: f64, downbeat: usize, seconds: f64) -> Audio {
+        let sample_rate = 22050;
+        let mut samples = vec![0.0; (seconds * sample_rate as f64) as usize];
+        let period = 60.0 / bpm;
+
+        let mut beat = 0;
+        loop {
+            let start = ((first_ms / 1000.0 + beat as f64 * period) * sample_rate as f64) as usize;
+            if start >= samples.len() {
+                return Audio {
+                    sample_rate,
+                    samples,
+                };
+            }
+
+            let gain = if beat % 4 == downbeat { 0.9 } else { 0.5 };
+            for (i, sample) in samples[start..].iter_mut().take(4000).enumerate() {
+                let t = i as f32 / sample_rate as f32;
+                let body = (TAU * 60.0 * t).sin() * (-t * 30.0).exp();
+                let click = (TAU * 2000.0 * t).sin() * (-t * 400.0).exp();
+                *sample += gain * (0.8 * body + 0.2 * click);
+            }
+            beat += 1;
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        // 1152 samples a frame, though the decoder holds back the first one to prime itself, and
+        // a quarter of them kept
+        let audio = decode(crate::mock::silent_mp3(100)).unwrap();
+        assert_eq!(audio.sample_rate, 11025);
+        assert!((99 * 288..=100 * 288).contains(&audio.samples.len()));
+        assert!(audio.samples.iter().all(|s| *s == 0.0));
+        assert!((2560..=2613).contains(&audio.duration_ms()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("silence.mp3");
+        std::fs::write(&path, crate::mock::silent_mp3(100)).unwrap();
+        assert_eq!(Audio::read(&path).unwrap().samples, audio.samples);
+
+        let err = decode(b"<html>rate limited</html>".to_vec()).unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::DecodeError));
+    }
+}
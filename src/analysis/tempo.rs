@@ -0,0 +1,461 @@
+use serde::{Deserialize, Serialize};
+
+use super::Audio;
+
+/// Frames a second of the onset envelope tempo is detected on (as near as a whole number of
+/// samples a frame allows)
+const ENVELOPE_RATE: f64 = 200.0;
+
+/// Tempos looked for
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+
+/// Tempo favored when the beats fit several, as the beats of a half-time groove also fit double
+/// time
+const PREFERRED_BPM: f64 = 130.0;
+
+/// How loud the off-beats have to be, next to the beats, for double the tempo to fit too; and
+/// how soft every other beat, next to the others, for half
+const ALTERNATIVE_RATIO: f64 = 0.8;
+
+/// The beats of a track at a constant tempo
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BeatGrid {
+    pub bpm: f64,
+    /// Time of the first downbeat (the first beat of a bar), within the first bar of the track
+    pub downbeat_ms: f64,
+}
+
+impl BeatGrid {
+    /// Length of a beat in milliseconds
+    pub fn beat_ms(&self) -> f64 {
+        60_000.0 / self.bpm
+    }
+
+    /// Every beat of the first `duration_ms` of the track, as its time and its number in the bar
+    /// (1 to 4, 1 being the downbeat)
+    pub fn beats(&self, duration_ms: u64) -> Vec<(f64, u8)> {
+        let period = self.beat_ms();
+        let first = -(self.downbeat_ms / period).floor() as i64;
+
+        (first..)
+            .map(|i| {
+                (
+                    self.downbeat_ms + i as f64 * period,
+                    i.rem_euclid(4) as u8 + 1,
+                )
+            })
+            .take_while(|(time, _)| *time < duration_ms as f64)
+            .collect()
+    }
+}
+
+/// The tempo of a track, as `detect_tempo` found it
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Tempo {
+    #[serde(flatten)]
+    pub grid: BeatGrid,
+    /// How sure the detection is, from 0 to 1: how much stronger the onsets on the beats are than
+    /// those between them
+    pub confidence: f64,
+    /// Half or double `bpm`, when the beats fit it too (such as a half-time groove)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alternative_bpm: Option<f64>,
+}
+
+/// How `audio` gets louder and softer, `rate` frames a second
+struct Envelope {
+    rate: f64,
+    /// Loudness of each frame, over the whole band plus in the bass where kick drums are
+    loudness: Vec<f64>,
+    /// How much louder each frame is than the one before, less what is usual for the quarter of
+    /// a second around it
+    onsets: Vec<f64>,
+}
+
+impl Envelope {
+    fn new(audio: &Audio) -> Self {
+        let hop = (audio.sample_rate as f64 / ENVELOPE_RATE).round().max(1.0) as usize;
+        let rate = audio.sample_rate as f64 / hop as f64;
+
+        // a one-pole low-pass at about 150 Hz
+        let alpha = 1.0 - (-std::f64::consts::TAU * 150.0 / audio.sample_rate as f64).exp();
+        let mut low = 0.0;
+
+        let loudness = |energy: f64, len: usize| (1.0 + 1000.0 * (energy / len as f64).sqrt()).ln();
+        let bands: Vec<(f64, f64)> = audio
+            .samples
+            .chunks(hop)
+            .map(|chunk| {
+                let (mut full, mut bass) = (0.0, 0.0);
+                for sample in chunk {
+                    let sample = *sample as f64;
+                    low += alpha * (sample - low);
+                    full += sample * sample;
+                    bass += low * low;
+                }
+                (loudness(full, chunk.len()), loudness(bass, chunk.len()))
+            })
+            .collect();
+
+        let flux: Vec<f64> = bands
+            .iter()
+            .scan((0.0, 0.0), |before, &(full, bass)| {
+                let rise = (full - before.0).max(0.0) + (bass - before.1).max(0.0);
+                *before = (full, bass);
+                Some(rise)
+            })
+            .collect();
+        let radius = (rate / 8.0) as usize;
+        let onsets = (0..flux.len())
+            .map(|i| {
+                let window = &flux[i.saturating_sub(radius)..(i + radius + 1).min(flux.len())];
+                let mean = window.iter().sum::<f64>() / window.len() as f64;
+                (flux[i] - mean).max(0.0)
+            })
+            .collect();
+
+        Envelope {
+            rate,
+            loudness: bands.into_iter().map(|(full, bass)| full + bass).collect(),
+            onsets,
+        }
+    }
+
+    /// Average onset strength at the beats `phase + k * period` (in frames), counting the frames
+    /// either side of each beat too, as an onset between frames is split over both
+    fn comb(&self, period: f64, phase: f64) -> f64 {
+        let (mut sum, mut beats) = (0.0, 0);
+        let mut at = phase;
+        while at < self.onsets.len() as f64 {
+            let i = at.round().max(0.0) as usize;
+            sum += self.onsets[i.saturating_sub(1)..(i + 2).min(self.onsets.len())]
+                .iter()
+                .sum::<f64>();
+            beats += 1;
+            at += period;
+        }
+
+        match beats {
+            0 => 0.0,
+            beats => sum / beats as f64,
+        }
+    }
+
+    /// Median loudness of the 50 ms after the beats `phase + k * period` (in frames); the median,
+    /// so that a beat in a break or before the track starts does not count
+    fn accent(&self, period: f64, phase: f64) -> f64 {
+        let len = (self.rate / 20.0).ceil() as usize;
+        let mut beats = Vec::new();
+        let mut at = phase;
+        while at < self.loudness.len() as f64 {
+            let frames = &self.loudness[at.round().max(0.0) as usize..];
+            let frames = &frames[..frames.len().min(len)];
+            if !frames.is_empty() {
+                beats.push(frames.iter().sum::<f64>() / frames.len() as f64);
+            }
+            at += period;
+        }
+
+        beats.sort_by(f64::total_cmp);
+        beats.get(beats.len() / 2).copied().unwrap_or_default()
+    }
+
+    /// The period and phase of the line best fitting the onsets nearest the first `beats` beats
+    /// of `period` and `phase` (in frames), the stronger onsets counting for more, or the same if
+    /// there are too few onsets to tell
+    fn fit(&self, period: f64, phase: f64, beats: usize) -> (f64, f64) {
+        let onsets = &self.onsets;
+        let radius = period / 8.0;
+        let (mut found, mut sum_w, mut sum_k, mut sum_t, mut sum_kk, mut sum_kt) =
+            (0, 0.0, 0.0, 0.0, 0.0, 0.0);
+
+        for k in 0..beats {
+            let predicted = phase + k as f64 * period;
+            let start = (predicted - radius).round().max(0.0) as usize;
+            let end = ((predicted + radius).round() as usize).min(onsets.len());
+            if start >= end {
+                break;
+            }
+
+            let peak = (start..end).fold(start, |peak, i| match onsets[i] > onsets[peak] {
+                true => i,
+                false => peak,
+            });
+            let w = onsets[peak];
+            if w <= 0.0 {
+                continue;
+            }
+
+            // where the peak would be between frames, from a parabola through it and its
+            // neighbours
+            let before = peak.checked_sub(1).map_or(0.0, |i| onsets[i]);
+            let after = onsets.get(peak + 1).copied().unwrap_or_default();
+            let curvature = before - 2.0 * w + after;
+            let shift = match curvature < 0.0 {
+                true => (0.5 * (before - after) / curvature).clamp(-0.5, 0.5),
+                false => 0.0,
+            };
+
+            let (k, t) = (k as f64, peak as f64 + shift);
+            found += 1;
+            sum_w += w;
+            sum_k += w * k;
+            sum_t += w * t;
+            sum_kk += w * k * k;
+            sum_kt += w * k * t;
+        }
+
+        let denominator = sum_w * sum_kk - sum_k * sum_k;
+        if found < 8 || denominator <= 0.0 {
+            return (period, phase);
+        }
+        let slope = (sum_w * sum_kt - sum_k * sum_t) / denominator;
+        (slope, (sum_t - slope * sum_k) / sum_w)
+    }
+}
+
+/// Estimates the tempo of `audio` and where its first downbeat is, assuming the tempo does not
+/// change (as in most dance music). `None` if it has no beats to speak of, such as silence or less
+/// than a few seconds of audio.
+pub fn detect_tempo(audio: &Audio) -> Option<Tempo> {
+    let envelope = Envelope::new(audio);
+    let rate = envelope.rate;
+    let min_lag = (rate * 60.0 / MAX_BPM).floor() as usize;
+    let max_lag = (rate * 60.0 / MIN_BPM).ceil() as usize;
+    // too low a sample rate (or none) to tell one beat from the next
+    if min_lag == 0 || envelope.onsets.len() < 4 * max_lag {
+        return None;
+    }
+
+    // the period the onsets repeat at most, weighted towards the preferred tempo; onsets are
+    // smeared over a few frames first, so that a period between two whole numbers of frames
+    // scores as well as one on a whole number
+    let smoothed: Vec<f64> = (0..envelope.onsets.len())
+        .map(|i| {
+            (0..5)
+                .filter_map(|j| (i + j).checked_sub(2).and_then(|i| envelope.onsets.get(i)))
+                .zip([1.0, 2.0, 3.0, 2.0, 1.0])
+                .map(|(onset, weight)| onset * weight)
+                .sum()
+        })
+        .collect();
+    let autocorrelation: Vec<f64> = (0..=max_lag + 1)
+        .map(|lag| {
+            let pairs = smoothed.len() - lag;
+            smoothed[lag..]
+                .iter()
+                .zip(&smoothed)
+                .map(|(a, b)| a * b)
+                .sum::<f64>()
+                / pairs as f64
+        })
+        .collect();
+    let weight = |lag: f64| {
+        let octaves = (rate * 60.0 / lag / PREFERRED_BPM).log2();
+        (-0.5 * octaves * octaves).exp()
+    };
+    let lag = (min_lag..=max_lag)
+        .filter(|&lag| {
+            autocorrelation[lag] >= autocorrelation[lag - 1]
+                && autocorrelation[lag] >= autocorrelation[lag + 1]
+        })
+        .max_by(|&a, &b| {
+            let a = autocorrelation[a] * weight(a as f64);
+            let b = autocorrelation[b] * weight(b as f64);
+            a.total_cmp(&b)
+        })?;
+    if autocorrelation[lag] <= 0.0 {
+        return None;
+    }
+
+    // a whole number of frames is too coarse for a grid that has to hold for minutes, so the
+    // period and phase are refined against every beat of the track
+    let (mut period, mut phase, mut best) = (lag as f64, 0.0, 0.0);
+    for step in -15..=15 {
+        let candidate = lag as f64 + step as f64 / 10.0;
+        for offset in 0..candidate.ceil() as usize {
+            let score = envelope.comb(candidate, offset as f64);
+            if score > best {
+                (period, phase, best) = (candidate, offset as f64, score);
+            }
+        }
+    }
+    if best <= 0.0 {
+        return None;
+    }
+    for beats in [16, 64, usize::MAX] {
+        (period, phase) = envelope.fit(period, phase, beats);
+    }
+
+    // the grid starts at the first beat of the track (a beat a hair before the start is on it,
+    // as tracks often start on the beat)
+    let mut first = phase.rem_euclid(period);
+    if period - first < 2.0 {
+        first = 0.0;
+    }
+
+    // the downbeat is the beat of the bar that is loudest
+    let downbeat = (0..4)
+        .max_by(|&a, &b| {
+            let a = envelope.accent(4.0 * period, first + a as f64 * period);
+            let b = envelope.accent(4.0 * period, first + b as f64 * period);
+            a.total_cmp(&b)
+        })
+        .unwrap_or(0);
+
+    // what is on the beats, next to the middle of what is between them (hi-hats on the off-beats
+    // or sixteenths being a few of the many places in between); beats only twice as strong as
+    // the rest are no beats at all, as the loudest of the tempos of noise is about that
+    let on = envelope.comb(period, first);
+    let mut between: Vec<f64> = (1..16)
+        .map(|i| envelope.comb(period, first + i as f64 * period / 16.0))
+        .collect();
+    between.sort_by(f64::total_cmp);
+    let confidence = match on > 0.0 {
+        true => (1.0 - 2.0 * between[between.len() / 2] / on).clamp(0.0, 1.0),
+        false => 0.0,
+    };
+
+    // off-beats nearly as loud as the beats could be beats themselves, and so could every other
+    // beat if the ones between them are much softer
+    let bpm = rate * 60.0 / period;
+    let loud = |phase: f64| envelope.accent(2.0 * period, phase);
+    let similar = |a: f64, b: f64| a.min(b) >= ALTERNATIVE_RATIO * a.max(b);
+    let (beat, off_beat) = (
+        envelope.accent(period, first),
+        envelope.accent(period, first + period / 2.0),
+    );
+    let alternative_bpm = if similar(beat, off_beat) {
+        Some(bpm * 2.0)
+    } else if !similar(loud(first), loud(first + period)) {
+        Some(bpm / 2.0)
+    } else {
+        None
+    };
+
+    Some(Tempo {
+        grid: BeatGrid {
+            bpm,
+            downbeat_ms: (first + downbeat as f64 * period) * 1000.0 / rate,
+        },
+        confidence,
+        alternative_bpm: alternative_bpm.filter(|bpm| (MIN_BPM..=MAX_BPM).contains(bpm)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::tests::kicks;
+
+    /// `a` with `b` at `gain` over it
+    fn mix(a: &Audio, b: &Audio, gain: f32) -> Audio {
+        Audio {
+            sample_rate: a.sample_rate,
+            samples: a
+                .samples
+                .iter()
+                .zip(&b.samples)
+                .map(|(a, b)| a + gain * b)
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_detect_tempo() {
+        for (bpm, first_ms, downbeat) in [
+            (128.0, 0.0, 0),
+            (122.0, 250.0, 1),
+            (140.0, 90.0, 3),
+            (95.5, 1200.0, 2),
+            (174.0, 40.0, 0),
+        ] {
+            let audio = kicks(bpm, first_ms, downbeat, 20.0);
+            let tempo = detect_tempo(&audio).unwrap();
+            assert!(tempo.confidence > 0.9, "{} bpm: {:?}", bpm, tempo);
+            assert_eq!(tempo.alternative_bpm, None);
+
+            let grid = tempo.grid;
+            assert!(
+                (grid.bpm - bpm).abs() < 0.05,
+                "{} detected as {}",
+                bpm,
+                grid.bpm
+            );
+
+            let expected = first_ms + downbeat as f64 * 60_000.0 / bpm;
+            assert!(
+                (grid.downbeat_ms - expected).abs() < 10.0,
+                "{} bpm: downbeat at {} rather than {}",
+                bpm,
+                grid.downbeat_ms,
+                expected
+            );
+        }
+
+        let silence = Audio {
+            sample_rate: 22050,
+            samples: vec![0.0; 22050 * 10],
+        };
+        assert_eq!(detect_tempo(&silence), None);
+        assert_eq!(detect_tempo(&kicks(128.0, 0.0, 0, 2.0)), None);
+        let no_rate = Audio {
+            sample_rate: 0,
+            ..kicks(128.0, 0.0, 0, 20.0)
+        };
+        assert_eq!(detect_tempo(&no_rate), None);
+
+        // noise has a tempo of sorts, but no confidence in it
+        let mut state = 1u32;
+        let noise = Audio {
+            sample_rate: 22050,
+            samples: (0..22050 * 20)
+                .map(|_| {
+                    state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+                })
+                .collect(),
+        };
+        assert!(detect_tempo(&noise).unwrap().confidence < 0.1);
+        let noisy = mix(&kicks(128.0, 0.0, 0, 20.0), &noise, 1.0);
+        let tempo = detect_tempo(&noisy).unwrap();
+        assert!((tempo.grid.bpm - 128.0).abs() < 0.05);
+        assert!((0.5..0.95).contains(&tempo.confidence), "{:?}", tempo);
+
+        // off-beats about as loud as the beats fit double time too
+        let off_beats = mix(
+            &kicks(95.0, 100.0, 0, 20.0),
+            &kicks(95.0, 100.0 + 30_000.0 / 95.0, 0, 20.0),
+            0.7,
+        );
+        let tempo = detect_tempo(&off_beats).unwrap();
+        assert!((tempo.grid.bpm - 95.0).abs() < 0.05);
+        assert!((tempo.alternative_bpm.unwrap() - 190.0).abs() < 0.1);
+
+        // and every other beat much softer fits half time
+        let half_time = mix(
+            &kicks(70.0, 100.0, 0, 20.0),
+            &kicks(70.0, 100.0 + 30_000.0 / 70.0, 0, 20.0),
+            0.2,
+        );
+        let tempo = detect_tempo(&half_time).unwrap();
+        assert!((tempo.grid.bpm - 140.0).abs() < 0.05);
+        assert!((tempo.alternative_bpm.unwrap() - 70.0).abs() < 0.1);
+
+        let grid = BeatGrid {
+            bpm: 120.0,
+            downbeat_ms: 1200.0,
+        };
+        assert_eq!(
+            grid.beats(2500),
+            [
+                (200.0, 3),
+                (700.0, 4),
+                (1200.0, 1),
+                (1700.0, 2),
+                (2200.0, 3)
+            ]
+        );
+    }
+}
@@ -7,7 +7,7 @@ use serde::{Deserialize, Serialize};
 /// * `Kbps96`  => 96 kb/s
 ///
 /// *NOTE*: value of each variant is assigned as seen in cnvmp3.com source code
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[repr(usize)]
 pub enum BitRate {
     Kbps320 = 0,
@@ -42,3 +42,40 @@ impl<T: Into<u64> + Copy> FromNumber<T> for BitRate {
         }
     }
 }
+
+impl BitRate {
+    /// The bit rate in kb/s
+    pub fn kbps(&self) -> u32 {
+        match self {
+            BitRate::Kbps320 => 320,
+            BitRate::Kbps256 => 256,
+            BitRate::Kbps128 => 128,
+            BitRate::Kbps96 => 96,
+        }
+    }
+
+    /// The supported bit rate closest to `kbps` (e.g., for a file of some other bit rate)
+    pub fn nearest(kbps: u32) -> Self {
+        [
+            BitRate::Kbps96,
+            BitRate::Kbps128,
+            BitRate::Kbps256,
+            BitRate::Kbps320,
+        ]
+        .into_iter()
+        .min_by_key(|bitrate| bitrate.kbps().abs_diff(kbps))
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nearest() {
+        assert_eq!(BitRate::nearest(128), BitRate::Kbps128);
+        assert_eq!(BitRate::nearest(192), BitRate::Kbps128);
+        assert_eq!(BitRate::nearest(245), BitRate::Kbps256);
+    }
+}
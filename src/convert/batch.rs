@@ -0,0 +1,262 @@
+use futures_util::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+use url::Url;
+
+use super::transport::Transport;
+use super::{convert, CNVClient, Outcome};
+use crate::bitrate::BitRate;
+use crate::error::Error;
+use crate::youtube_url::YouTubeURL;
+
+/// What became of one line of a batch
+#[derive(Debug)]
+pub enum Status {
+    /// The MP3 file was downloaded
+    Succeeded,
+    /// The MP3 file had already been saved locally
+    Cached,
+    /// The line was not converted, for the given reason
+    Skipped(String),
+    /// The conversion failed
+    Failed(Error),
+}
+
+/// One line of a batch and what became of it
+#[derive(Debug)]
+pub struct Entry {
+    /// Line number in the input, starting at 1
+    pub line: usize,
+    /// The line as given, without surrounding whitespace
+    pub input: String,
+    /// YouTube ID the line refers to, if it is a valid YouTube URL
+    pub id: Option<String>,
+    pub status: Status,
+}
+
+/// Results of a batch, in input order
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub entries: Vec<Entry>,
+}
+
+/// Reads the list of URLs from `path`, or from standard input if `path` is `-`
+pub fn read_input(path: &Path) -> Result<String, Error> {
+    if path == Path::new("-") {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+
+        return Ok(input);
+    }
+
+    Ok(std::fs::read_to_string(path)?)
+}
+
+/// A line of the input, before it has been converted
+struct Line {
+    line: usize,
+    input: String,
+    id: Option<String>,
+    /// Known right away for lines that are skipped
+    status: Option<Status>,
+}
+
+/// Turns every non-empty line of `input` (`#` starts a comment) into a `Line`; lines that are
+/// to be converted are also returned by index together with their URL
+fn parse(input: &str) -> (Vec<Line>, Vec<(usize, YouTubeURL)>) {
+    let mut entries = Vec::new();
+    let mut todo = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    let lines = input
+        .lines()
+        .enumerate()
+        .map(|(n, l)| (n + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+
+    for (line, input) in lines {
+        let parsed = Url::parse(input)
+            .map_err(|e| format!("not a url: {}", e))
+            .and_then(|url| YouTubeURL::new(url).map_err(|e| e.value));
+
+        let (id, status) = match parsed {
+            Err(reason) => (None, Some(Status::Skipped(reason))),
+            Ok(youtube_url) => match seen.get(&youtube_url.id) {
+                Some(first) => (
+                    Some(youtube_url.id),
+                    Some(Status::Skipped(format!("duplicate of line {}", first))),
+                ),
+                None => {
+                    seen.insert(youtube_url.id.clone(), line);
+                    todo.push((entries.len(), youtube_url.clone()));
+                    (Some(youtube_url.id), None)
+                }
+            },
+        };
+
+        if let Some(Status::Skipped(reason)) = &status {
+            eprintln!("warning: skipping line {} ({}): {}", line, input, reason);
+        }
+
+        entries.push(Line {
+            line,
+            input: input.to_string(),
+            id,
+            status,
+        });
+    }
+
+    (entries, todo)
+}
+
+/// Converts every URL listed in `input`, running up to `jobs` conversions at once through `c`
+pub(super) async fn run<T: Transport>(
+    c: &CNVClient<T>,
+    input: &str,
+    quality: BitRate,
+    jobs: usize,
+) -> Summary {
+    let (mut lines, todo) = parse(input);
+
+    let mut results = stream::iter(todo)
+        .map(|(i, youtube_url)| async move {
+            let status = match convert(c, youtube_url.url, quality).await {
+                Ok(conversion) if conversion.outcome == Outcome::Cached => Status::Cached,
+                Ok(_) => Status::Succeeded,
+                Err(e) => Status::Failed(e),
+            };
+
+            (i, status)
+        })
+        .buffer_unordered(jobs.max(1));
+
+    while let Some((i, status)) = results.next().await {
+        lines[i].status = Some(status);
+    }
+
+    let entries = lines
+        .into_iter()
+        .map(|l| Entry {
+            line: l.line,
+            input: l.input,
+            id: l.id,
+            status: l.status.expect("every line is either skipped or converted"),
+        })
+        .collect();
+
+    Summary { entries }
+}
+
+impl Summary {
+    fn count(&self, f: impl Fn(&Status) -> bool) -> usize {
+        self.entries.iter().filter(|e| f(&e.status)).count()
+    }
+
+    /// Number of entries that failed to convert
+    pub fn failed(&self) -> usize {
+        self.count(|s| matches!(s, Status::Failed(_)))
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Succeeded => write!(f, "succeeded"),
+            Status::Cached => write!(f, "cached"),
+            Status::Skipped(_) => write!(f, "skipped"),
+            Status::Failed(_) => write!(f, "failed"),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows: Vec<[String; 4]> = self
+            .entries
+            .iter()
+            .map(|e| {
+                let reason = match &e.status {
+                    Status::Skipped(reason) => reason.clone(),
+                    Status::Failed(error) => error.value.clone(),
+                    _ => String::new(),
+                };
+
+                [
+                    e.line.to_string(),
+                    e.id.clone().unwrap_or_else(|| e.input.clone()),
+                    e.status.to_string(),
+                    reason,
+                ]
+            })
+            .collect();
+
+        let header = ["LINE", "ID", "STATUS", "REASON"].map(String::from);
+        let widths: Vec<usize> = (0..3)
+            .map(|i| {
+                rows.iter()
+                    .chain([&header])
+                    .map(|r| r[i].len())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        for row in [&header].into_iter().chain(&rows) {
+            let line = format!(
+                "{:>w0$}  {:<w1$}  {:<w2$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+            );
+            writeln!(f, "{}", line.trim_end())?;
+        }
+
+        write!(
+            f,
+            "\n{} succeeded, {} cached, {} skipped, {} failed",
+            self.count(|s| matches!(s, Status::Succeeded)),
+            self.count(|s| matches!(s, Status::Cached)),
+            self.count(|s| matches!(s, Status::Skipped(_))),
+            self.failed(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let input = "\
+            https://www.youtube.com/watch?v=yPvoKz6tyJs\n\
+            \n\
+            # comment\n\
+            not a url\n\
+            https://www.youtube.com/watch?v=yPvoKz6tyJs&t=42\n\
+            https://www.youtube.com/shorts/dQw4w9WgXcQ\n\
+            https://www.youtube.com/invalid/invalid\n";
+
+        let (entries, todo) = parse(input);
+
+        let lines: Vec<usize> = entries.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![1, 4, 5, 6, 7]);
+        assert!(entries[0].status.is_none());
+        assert!(
+            matches!(&entries[1].status, Some(Status::Skipped(r)) if r.starts_with("not a url"))
+        );
+        assert!(
+            matches!(&entries[2].status, Some(Status::Skipped(r)) if r == "duplicate of line 1")
+        );
+        assert!(matches!(entries[4].status, Some(Status::Skipped(_))));
+
+        let ids: Vec<(usize, &str)> = todo.iter().map(|(i, u)| (*i, u.id.as_str())).collect();
+        assert_eq!(ids, vec![(0, "yPvoKz6tyJs"), (3, "dQw4w9WgXcQ")]);
+    }
+}
@@ -0,0 +1,236 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use url::Url;
+
+use super::transport::{
+    BodyStream, HttpRequest, HttpResponse, Method, StreamingResponse, Transport,
+};
+use crate::error::{Error, ErrorKind};
+
+/// Request half of a recorded exchange
+#[derive(Debug, Deserialize, Serialize)]
+struct RecordedRequest {
+    method: Method,
+    url: Url,
+    headers: Vec<(String, String)>,
+    /// The payload (one of the `Payload*` structs in `schema.rs`) as sent, if any
+    body: Option<serde_json::Value>,
+}
+
+/// Response half of a recorded exchange; the body lives next to it in a `.body` file so that the
+/// exact bytes cnvmp3 returned are kept
+#[derive(Debug, Deserialize, Serialize)]
+struct RecordedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+/// One request/response pair, stored as `<dir>/NNN-<endpoint>.json`
+#[derive(Debug, Deserialize, Serialize)]
+struct Exchange {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+/// File stem of the `n`th exchange, e.g. `003-download_video`
+fn exchange_name(n: usize, url: &Url) -> String {
+    let endpoint = url
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .unwrap_or("")
+        .trim_end_matches(".php");
+
+    format!("{:03}-{}", n, endpoint)
+}
+
+fn cassette_error(value: String) -> Error {
+    Error {
+        kind: ErrorKind::CassetteError,
+        value,
+    }
+}
+
+/// Request bodies are JSON payloads; anything else is kept as a string
+fn body_value(body: &Option<Vec<u8>>) -> Option<serde_json::Value> {
+    body.as_ref().map(|b| {
+        serde_json::from_slice(b)
+            .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(b).to_string()))
+    })
+}
+
+/// `Transport` that passes requests through to `inner` and saves every exchange into `dir`
+pub struct RecordingTransport<T: Transport> {
+    inner: T,
+    dir: PathBuf,
+    count: AtomicUsize,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    pub fn new(inner: T, dir: impl AsRef<Path>) -> Result<Self, Error> {
+        fs::create_dir_all(dir.as_ref())?;
+
+        Ok(RecordingTransport {
+            inner,
+            dir: dir.as_ref().to_path_buf(),
+            count: AtomicUsize::new(0),
+        })
+    }
+
+    /// Saves the request and response head of the next exchange, returning where its body goes
+    fn record(
+        &self,
+        request: RecordedRequest,
+        status: u16,
+        headers: &[(String, String)],
+    ) -> Result<PathBuf, Error> {
+        let n = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+        let name = exchange_name(n, &request.url);
+        let exchange = Exchange {
+            request,
+            response: RecordedResponse {
+                status,
+                headers: headers.to_vec(),
+            },
+        };
+
+        fs::write(
+            self.dir.join(format!("{}.json", name)),
+            serde_json::to_vec_pretty(&exchange)?,
+        )?;
+
+        Ok(self.dir.join(format!("{}.body", name)))
+    }
+}
+
+fn recorded_request(request: &HttpRequest) -> RecordedRequest {
+    RecordedRequest {
+        method: request.method,
+        url: request.url.clone(),
+        headers: request.headers.clone(),
+        body: body_value(&request.body),
+    }
+}
+
+/// Body that is copied into the cassette as it is read
+struct TeeBody {
+    inner: Box<dyn BodyStream>,
+    file: fs::File,
+}
+
+#[async_trait]
+impl BodyStream for TeeBody {
+    async fn chunk(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let chunk = self.inner.chunk().await?;
+
+        if let Some(chunk) = &chunk {
+            self.file.write_all(chunk)?;
+        }
+
+        Ok(chunk)
+    }
+}
+
+#[async_trait]
+impl<T: Transport> Transport for RecordingTransport<T> {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, Error> {
+        let recorded = recorded_request(&request);
+
+        let response = self.inner.send(request).await?;
+
+        let body_path = self.record(recorded, response.status, &response.headers)?;
+        fs::write(body_path, &response.body)?;
+
+        Ok(response)
+    }
+
+    async fn stream(&self, request: HttpRequest) -> Result<StreamingResponse, Error> {
+        let recorded = recorded_request(&request);
+
+        let response = self.inner.stream(request).await?;
+
+        let body_path = self.record(recorded, response.status, &response.headers)?;
+        let file = fs::File::create(body_path)?;
+
+        Ok(StreamingResponse {
+            status: response.status,
+            headers: response.headers,
+            body: Box::new(TeeBody {
+                inner: response.body,
+                file,
+            }),
+        })
+    }
+}
+
+/// `Transport` that answers requests from a directory written by `RecordingTransport`, in the
+/// order they were recorded, without touching the network
+pub struct ReplayTransport {
+    exchanges: Mutex<std::vec::IntoIter<(Exchange, Vec<u8>)>>,
+}
+
+impl ReplayTransport {
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let mut names: Vec<PathBuf> = fs::read_dir(dir.as_ref())?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        names.sort();
+
+        let mut exchanges = Vec::with_capacity(names.len());
+        for name in names {
+            let exchange: Exchange = serde_json::from_slice(&fs::read(&name)?)?;
+            let body = fs::read(name.with_extension("body"))?;
+
+            exchanges.push((exchange, body));
+        }
+
+        Ok(ReplayTransport {
+            exchanges: Mutex::new(exchanges.into_iter()),
+        })
+    }
+}
+
+#[async_trait]
+impl Transport for ReplayTransport {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, Error> {
+        let (exchange, body) = self.exchanges.lock().unwrap().next().ok_or_else(|| {
+            cassette_error(format!(
+                "no more recorded exchanges for {:?} {}",
+                request.method, request.url
+            ))
+        })?;
+
+        let recorded = &exchange.request;
+
+        // the host is not compared so that a cassette can be replayed against any `--cnv-url`
+        if recorded.method != request.method
+            || recorded.url.path() != request.url.path()
+            || recorded.url.query() != request.url.query()
+        {
+            return Err(cassette_error(format!(
+                "expected {:?} {}, got {:?} {}",
+                recorded.method, recorded.url, request.method, request.url
+            )));
+        }
+
+        if recorded.body != body_value(&request.body) {
+            return Err(cassette_error(format!(
+                "payload for {} differs from recording: expected {:?}, got {:?}",
+                request.url,
+                recorded.body,
+                body_value(&request.body)
+            )));
+        }
+
+        Ok(HttpResponse {
+            status: exchange.response.status,
+            headers: exchange.response.headers,
+            body,
+        })
+    }
+}
@@ -0,0 +1,464 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use url::Url;
+
+use super::batch;
+use super::cassette::{RecordingTransport, ReplayTransport};
+use super::transport::{ReqwestTransport, Transport};
+use super::{
+    convert, CNVClient, Cassette, Outcome, Progress, RetryConfig, Summary, CNV_BASE_URL,
+    CONNECT_TIMEOUT, DEFAULT_MAX_SIZE, DEFAULT_SEGMENTS, READ_TIMEOUT,
+};
+use crate::analysis::Notation;
+use crate::bitrate::BitRate;
+use crate::error::Error;
+use crate::id3::Version;
+use crate::library::{Library, INDEX_NAME};
+use crate::storage::Storage;
+use crate::template::Template;
+use crate::titles::TitleRules;
+
+/// What a successful conversion produced
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conversion {
+    /// YouTube ID of the video
+    pub id: String,
+    /// Where the MP3 file was saved (its location in the storage, e.g. `s3://bucket/<id>.mp3`,
+    /// for a remote one)
+    pub path: PathBuf,
+    /// Title of the video, when cnvmp3 told or the library index remembers it
+    pub title: Option<String>,
+    /// Bitrate the MP3 file was asked for at
+    pub bitrate: BitRate,
+    /// Size of the MP3 file in bytes
+    pub size: u64,
+    /// Whether cnvmp3 had converted the video before, so that only the download was left
+    pub from_database: bool,
+    pub outcome: Outcome,
+}
+
+/// Converts YouTube videos to MP3 files through cnvmp3; built with `Converter::builder()`.
+///
+/// A `Converter` keeps one HTTP client for all its conversions and can be shared between tasks.
+pub struct Converter {
+    client: CNVClient<Box<dyn Transport>>,
+    bitrate: BitRate,
+}
+
+/// Settings for a `Converter`; every setting has a sensible default
+pub struct ConverterBuilder {
+    cnv_url: Url,
+    cassette: Option<Cassette>,
+    retry: RetryConfig,
+    max_size: Option<u64>,
+    segments: usize,
+    progress: Progress,
+    out_dir: PathBuf,
+    bitrate: BitRate,
+    storage: Option<Arc<dyn Storage>>,
+    library: Option<PathBuf>,
+    template: Template,
+    id3: Option<Version>,
+    title_rules: TitleRules,
+    analyze: bool,
+    key_notation: Notation,
+}
+
+impl Default for ConverterBuilder {
+    fn default() -> Self {
+        ConverterBuilder {
+            cnv_url: Url::parse(CNV_BASE_URL).expect("CNV_BASE_URL should be a valid url"),
+            cassette: None,
+            retry: RetryConfig::default(),
+            max_size: Some(DEFAULT_MAX_SIZE),
+            segments: DEFAULT_SEGMENTS,
+            progress: Progress::default(),
+            out_dir: PathBuf::from("mp3"),
+            bitrate: BitRate::Kbps96,
+            storage: None,
+            library: None,
+            template: Template::default(),
+            id3: Some(Version::default()),
+            title_rules: TitleRules::default(),
+            analyze: false,
+            key_notation: Notation::default(),
+        }
+    }
+}
+
+impl ConverterBuilder {
+    /// Talks to a different cnvmp3 server (e.g., a mock) instead of `CNV_BASE_URL`
+    pub fn with_cnv_url(mut self, cnv_url: Url) -> Self {
+        self.cnv_url = cnv_url;
+        self
+    }
+
+    /// Records the HTTP exchanges to disk, or replays them from disk
+    pub fn with_cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// Changes how failed protocol steps are retried
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Changes the largest MP3 file (in bytes) that will be downloaded; `None` means no limit
+    pub fn with_max_size(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Downloads MP3 files over up to `segments` connections at once when the CDN allows it
+    pub fn with_segments(mut self, segments: usize) -> Self {
+        self.segments = segments;
+        self
+    }
+
+    /// Reports the progress of every conversion to `progress`
+    pub fn with_progress(mut self, progress: Progress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Saves MP3 files into `out_dir` instead of `mp3/`, creating it when needed
+    pub fn with_out_dir(mut self, out_dir: impl Into<PathBuf>) -> Self {
+        self.out_dir = out_dir.into();
+        self
+    }
+
+    /// Asks cnvmp3 for MP3 files of the given bitrate (96 kb/s by default)
+    pub fn with_bitrate(mut self, bitrate: BitRate) -> Self {
+        self.bitrate = bitrate;
+        self
+    }
+
+    /// Saves MP3 files into `storage` instead of the output directory
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Names MP3 files after `template` instead of `<id>.mp3`
+    pub fn with_name_template(mut self, template: Template) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Keeps the library index at `path` instead of `library.json` in the output directory
+    pub fn with_library(mut self, path: impl Into<PathBuf>) -> Self {
+        self.library = Some(path.into());
+        self
+    }
+
+    /// Writes ID3v2 tags of `version` (ID3v2.4 by default) into the MP3 files; `None` leaves the
+    /// files as cnvmp3 serves them
+    pub fn with_id3(mut self, version: Option<Version>) -> Self {
+        self.id3 = version;
+        self
+    }
+
+    /// Splits video titles into artist, title, remix and so on (for file names and tags) with
+    /// `title_rules` instead of `DEFAULT_RULES`
+    pub fn with_title_rules(mut self, title_rules: TitleRules) -> Self {
+        self.title_rules = title_rules;
+        self
+    }
+
+    /// Detects the tempo and key of every MP3 file saved locally (see `analyze_track`), for the
+    /// index and the `TBPM` and `TKEY` frames of its tags
+    pub fn with_analysis(mut self, analyze: bool) -> Self {
+        self.analyze = analyze;
+        self
+    }
+
+    /// Writes detected keys in `notation` instead of the standard one (`Am`)
+    pub fn with_key_notation(mut self, notation: Notation) -> Self {
+        self.key_notation = notation;
+        self
+    }
+
+    pub fn build(self) -> Result<Converter, Error> {
+        let library = Library::open(
+            self.library
+                .unwrap_or_else(|| self.out_dir.join(INDEX_NAME)),
+        )?;
+
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .read_timeout(READ_TIMEOUT)
+            .build()?;
+        let network = ReqwestTransport::new(client);
+
+        let transport: Box<dyn Transport> = match self.cassette {
+            None => Box::new(network),
+            Some(Cassette::Record(dir)) => Box::new(RecordingTransport::new(network, dir)?),
+            Some(Cassette::Replay(dir)) => Box::new(ReplayTransport::new(dir)?),
+        };
+
+        let mut client = CNVClient::new(transport)
+            .with_base_url(self.cnv_url)
+            .with_retry(self.retry)
+            .with_max_size(self.max_size)
+            .with_segments(self.segments)
+            .with_progress(self.progress)
+            .with_out_dir(self.out_dir)
+            .with_library(library)
+            .with_template(self.template)
+            .with_id3(self.id3)
+            .with_title_rules(self.title_rules)
+            .with_analysis(self.analyze)
+            .with_key_notation(self.key_notation);
+        if let Some(storage) = self.storage {
+            client = client.with_storage(storage);
+        }
+
+        Ok(Converter {
+            client,
+            bitrate: self.bitrate,
+        })
+    }
+}
+
+impl Converter {
+    pub fn builder() -> ConverterBuilder {
+        ConverterBuilder::default()
+    }
+
+    /// Converts the YouTube video at `url` to an MP3 file and downloads it, unless it has been
+    /// saved before
+    pub async fn convert(&self, url: Url) -> Result<Conversion, Error> {
+        convert(&self.client, url, self.bitrate).await
+    }
+
+    /// Converts every YouTube URL listed in `input` (one per line), running up to `jobs`
+    /// conversions at once. Lines that are not valid YouTube URLs, or repeat an earlier one, are
+    /// skipped; a failed conversion does not stop the others.
+    pub async fn convert_all(&self, input: &str, jobs: usize) -> Summary {
+        batch::run(&self.client, input, self.bitrate, jobs).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id3::{Tag, TrackTags};
+    use crate::mock::{spawn, MockConfig};
+
+    #[tokio::test]
+    async fn test_converter() {
+        let base_url = spawn(MockConfig::default()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let converter = std::sync::Arc::new(
+            Converter::builder()
+                .with_cnv_url(base_url)
+                .with_out_dir(dir.path())
+                .with_bitrate(BitRate::Kbps128)
+                .build()
+                .unwrap(),
+        );
+        let url = Url::parse("https://www.youtube.com/watch?v=yPvoKz6tyJs").unwrap();
+
+        // conversions can run on any task of an existing runtime
+        let conversion = tokio::spawn({
+            let (converter, url) = (converter.clone(), url.clone());
+            async move { converter.convert(url).await }
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(conversion.path, dir.path().join("yPvoKz6tyJs.mp3"));
+        assert_eq!(
+            conversion.title.as_deref(),
+            Some("Mock Artist - Mock Track yPvoKz6tyJs")
+        );
+        assert_eq!(conversion.bitrate, BitRate::Kbps128);
+        assert_eq!(
+            conversion.size,
+            std::fs::metadata(&conversion.path).unwrap().len()
+        );
+        assert!(!conversion.from_database);
+        assert_eq!(conversion.outcome, Outcome::Saved);
+
+        std::fs::remove_file(&conversion.path).unwrap();
+        let conversion = converter.convert(url.clone()).await.unwrap();
+        assert!(conversion.from_database);
+        assert_eq!(
+            conversion.title.as_deref(),
+            Some("Mock Artist - Mock Track yPvoKz6tyJs")
+        );
+
+        let conversion = converter.convert(url).await.unwrap();
+        assert_eq!(conversion.outcome, Outcome::Cached);
+        assert_eq!(
+            conversion.title.as_deref(),
+            Some("Mock Artist - Mock Track yPvoKz6tyJs")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_converter_library() {
+        let base_url = spawn(MockConfig::default()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let index = dir.path().join("index.json");
+        std::fs::create_dir(dir.path().join("mp3")).unwrap();
+        let converter = Converter::builder()
+            .with_cnv_url(base_url)
+            .with_out_dir(dir.path().join("mp3"))
+            .with_library(&index)
+            .with_bitrate(BitRate::Kbps128)
+            .build()
+            .unwrap();
+        let url = Url::parse("https://www.youtube.com/watch?v=yPvoKz6tyJs").unwrap();
+
+        let conversion = converter.convert(url.clone()).await.unwrap();
+        let library = Library::open(&index).unwrap();
+        let track = library.get("yPvoKz6tyJs").unwrap();
+        assert_eq!(track.title, conversion.title);
+        assert_eq!(track.url, "https://www.youtube.com/watch?v=yPvoKz6tyJs");
+        assert_eq!(track.bitrate, BitRate::Kbps128);
+        assert!(track.server_path.is_some());
+        assert_eq!(track.path, conversion.path);
+        assert_eq!(track.size, conversion.size);
+        let storage = crate::storage::LocalStorage::new(dir.path().join("mp3"));
+        let (_, sha256) = crate::migrate::hash(&storage, "yPvoKz6tyJs.mp3")
+            .await
+            .unwrap();
+        assert_eq!(track.sha256, sha256);
+
+        // the tags of the file agree with the index
+        let tag = Tag::read(&std::fs::read(&conversion.path).unwrap()).unwrap();
+        assert_eq!(TrackTags::read(&tag), Some(TrackTags::from(track)));
+        assert_eq!(tag.artist(), Some("Mock Artist"));
+
+        // an indexed file that went missing is converted again
+        std::fs::remove_file(&conversion.path).unwrap();
+        let conversion = converter.convert(url).await.unwrap();
+        assert_eq!(conversion.outcome, Outcome::Saved);
+        assert_eq!(Library::open(&index).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_converter_analysis() {
+        let base_url = spawn(MockConfig::default()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let converter = Converter::builder()
+            .with_cnv_url(base_url)
+            .with_out_dir(dir.path())
+            .with_analysis(true)
+            .build()
+            .unwrap();
+        let url = Url::parse("https://www.youtube.com/watch?v=yPvoKz6tyJs").unwrap();
+
+        // the mock serves silence, which has a length but no beats or notes
+        let conversion = converter.convert(url).await.unwrap();
+        let library = Library::open(dir.path().join(INDEX_NAME)).unwrap();
+        let track = library.get("yPvoKz6tyJs").unwrap();
+        assert!(track.duration_ms.unwrap() > 0);
+        assert_eq!(track.tempo, None);
+        assert_eq!(track.key, None);
+        assert_eq!(track.size, conversion.size);
+        assert_eq!(
+            track.size,
+            std::fs::metadata(&conversion.path).unwrap().len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_converter_name_template() {
+        let base_url = spawn(MockConfig::default()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path().join("usb").join("music");
+        let converter = Converter::builder()
+            .with_cnv_url(base_url)
+            .with_out_dir(&out_dir)
+            .with_name_template("{artist} - {title} {kbps}k.mp3".parse().unwrap())
+            .with_bitrate(BitRate::Kbps320)
+            .build()
+            .unwrap();
+        let url = Url::parse("https://www.youtube.com/watch?v=yPvoKz6tyJs").unwrap();
+
+        // the output directory is created, and the file named after the title
+        let conversion = converter.convert(url.clone()).await.unwrap();
+        assert_eq!(
+            conversion.path,
+            out_dir.join("Mock Artist - Mock Track yPvoKz6tyJs 320k.mp3")
+        );
+        assert!(conversion.path.exists());
+
+        // the index knows the name, so the file is found without asking cnvmp3
+        let conversion = converter.convert(url.clone()).await.unwrap();
+        assert_eq!(conversion.outcome, Outcome::Cached);
+
+        // without the index, the tag of the file tells it is the same video
+        let converter = Converter::builder()
+            .with_cnv_url(spawn(MockConfig::default()).await)
+            .with_out_dir(&out_dir)
+            .with_library(dir.path().join("new.json"))
+            .with_name_template("{artist} - {title} {kbps}k.mp3".parse().unwrap())
+            .with_bitrate(BitRate::Kbps320)
+            .build()
+            .unwrap();
+        let again = converter.convert(url.clone()).await.unwrap();
+        assert_eq!(again.outcome, Outcome::Cached);
+        assert_eq!(again.path, conversion.path);
+        assert_eq!(std::fs::read_dir(&out_dir).unwrap().count(), 2);
+
+        // a file of another video by the same name is left alone
+        std::fs::remove_file(&conversion.path).unwrap();
+        std::fs::write(&conversion.path, b"another video").unwrap();
+        let library = dir.path().join("other.json");
+        let converter = Converter::builder()
+            .with_cnv_url(spawn(MockConfig::default()).await)
+            .with_out_dir(&out_dir)
+            .with_library(&library)
+            .with_name_template("{artist} - {title} {kbps}k.mp3".parse().unwrap())
+            .with_bitrate(BitRate::Kbps320)
+            .build()
+            .unwrap();
+        let conversion = converter.convert(url).await.unwrap();
+        assert_eq!(conversion.outcome, Outcome::Saved);
+        assert_eq!(
+            conversion.path,
+            out_dir.join("Mock Artist - Mock Track yPvoKz6tyJs 320k [yPvoKz6tyJs].mp3")
+        );
+        assert_eq!(
+            std::fs::read(out_dir.join("Mock Artist - Mock Track yPvoKz6tyJs 320k.mp3")).unwrap(),
+            b"another video"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_converter_title_rules() {
+        let base_url = spawn(MockConfig::default()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let rules = "(?i)\\btrack\\b => Tune\nyPvoKz6tyJs artist = Someone Else"
+            .parse()
+            .unwrap();
+        let converter = Converter::builder()
+            .with_cnv_url(base_url)
+            .with_out_dir(dir.path())
+            .with_name_template("{artist} - {title}.mp3".parse().unwrap())
+            .with_title_rules(rules)
+            .build()
+            .unwrap();
+        let url = Url::parse("https://www.youtube.com/watch?v=yPvoKz6tyJs").unwrap();
+
+        // the name and the tags follow the rules, the index keeps the title as cnvmp3 told it
+        let conversion = converter.convert(url).await.unwrap();
+        assert_eq!(
+            conversion.path,
+            dir.path().join("Someone Else - Mock Tune yPvoKz6tyJs.mp3")
+        );
+        let tag = Tag::read(&std::fs::read(&conversion.path).unwrap()).unwrap();
+        assert_eq!(tag.artist(), Some("Someone Else"));
+        assert_eq!(tag.title(), Some("Mock Tune yPvoKz6tyJs"));
+        assert_eq!(
+            conversion.title.as_deref(),
+            Some("Mock Artist - Mock Track yPvoKz6tyJs")
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+
+use super::retry::Failed;
+use super::transport::{HttpRequest, Transport};
+use super::CNVClient;
+use crate::error::Error;
+use crate::id3::replace_tag_in_stream;
+use crate::migrate::{digest, hashing, tagged_id};
+use crate::template::{disambiguate, Metadata};
+
+impl<T: Transport> CNVClient<T> {
+    /// Whether MP3 files are saved into the local output directory, where downloads can be split
+    /// into segments and resumed, rather than to a remote storage
+    pub(super) fn saves_locally(&self) -> bool {
+        self.storage.local_dir().is_some()
+    }
+
+    /// Where the MP3 file `name` is saved: a local path, or its location in the storage
+    pub(super) fn saved_path(&self, name: &str) -> PathBuf {
+        match self.saves_locally() {
+            true => self.out_dir.join(name),
+            false => PathBuf::from(self.storage.location(name)),
+        }
+    }
+
+    /// Size of the MP3 file `name` if it has been saved before
+    pub(super) async fn saved_size(&self, name: &str) -> Result<Option<u64>, Error> {
+        let stat = self.storage.stat(name).await?;
+
+        Ok(stat.map(|stat| stat.size))
+    }
+
+    /// Whether the saved MP3 file `name` is that of `youtube_id`, as the library index or else
+    /// the tag of the file tells
+    async fn saved_as(&self, name: &str, youtube_id: &str) -> Result<bool, Error> {
+        let indexed = self.library.as_ref().and_then(|library| {
+            let library = library.lock().unwrap();
+            let track = library.tracks().find(|track| track.file_name() == name)?;
+            Some(track.id == youtube_id)
+        });
+        if let Some(indexed) = indexed {
+            return Ok(indexed);
+        }
+
+        Ok(tagged_id(self.storage.as_ref(), name).await?.as_deref() == Some(youtube_id))
+    }
+
+    /// Name for the MP3 file of the video `metadata` describes, from the file name template.
+    /// Unless the template holds the YouTube ID, a name already used by a saved file of another
+    /// video, or by another conversion of this client, gets the ID added to it, so that a video
+    /// never overwrites another with the same title.
+    pub(super) async fn choose_name(&self, metadata: &Metadata<'_>) -> Result<String, Error> {
+        let name = self
+            .template
+            .render(metadata)
+            .expect("the title is known once cnvmp3 has been asked");
+        let exists = !self.template.has_id()
+            && self.saved_size(&name).await?.is_some()
+            && !self.saved_as(&name, metadata.id).await?;
+
+        let mut claimed = self.claimed.lock().unwrap();
+        let taken = claimed.get(&name).is_some_and(|id| id != metadata.id);
+        let name = match exists || taken {
+            true => disambiguate(&name, metadata.id),
+            false => name,
+        };
+        claimed.insert(name.clone(), metadata.id.to_string());
+
+        Ok(name)
+    }
+
+    /// Streams the body of `request` into the storage as `name`, checking it the same way as
+    /// `stream_to_file`. An interrupted upload is started over rather than resumed. Returns the
+    /// size and SHA-256 of the file.
+    ///
+    /// With `tag`, the ID3v2 tag of the file is replaced by it on the way.
+    pub(super) async fn stream_to_storage(
+        &self,
+        request: &HttpRequest,
+        youtube_id: &str,
+        name: &str,
+        tag: Option<&[u8]>,
+    ) -> Result<(u64, String), Failed> {
+        let res = self.request_from(request, 0, None).await?;
+        let length = self.full_length(&res)?;
+        let body = self.checked_body(res, youtube_id, 0, length);
+        let (body, length) = match tag {
+            Some(tag) => replace_tag_in_stream(body, tag.to_vec(), length).await?,
+            None => (body, length),
+        };
+        let (body, hashed) = hashing(body);
+
+        self.storage.put(name, body, length).await?;
+
+        Ok(digest(&hashed))
+    }
+}
@@ -0,0 +1,387 @@
+use futures_util::stream::{self, StreamExt};
+use infer::audio::is_mp3;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::retry::{check_status, Failed};
+use super::transport::{BodyStream, HttpRequest, StreamingResponse, Transport};
+use super::{CNVClient, Event, Progress};
+use crate::error::{Error, ErrorKind};
+use crate::storage::ByteStream;
+
+/// Number of leading bytes `is_mp3` needs to recognize an MP3 file
+pub(super) const MAGIC_LEN: usize = 3;
+
+/// What is remembered about an interrupted download, stored as `<id>.mp3.part.json` next to the
+/// `<id>.mp3.part` file holding the bytes received so far
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PartInfo {
+    /// Where the file was being downloaded from
+    pub server_path: String,
+    /// `ETag` of the remote file, used to make sure it has not changed in the meantime
+    pub etag: Option<String>,
+    /// Total size of the remote file, if the CDN said so
+    pub length: Option<u64>,
+    /// Title of the video, as cnvmp3 told it before the download was interrupted
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+pub(super) fn not_mp3() -> Error {
+    Error {
+        kind: ErrorKind::UnexpectedContent,
+        value: String::from("downloaded content is not an mp3 file"),
+    }
+}
+
+pub(super) fn too_large(size: u64, max_size: u64) -> Error {
+    Error {
+        kind: ErrorKind::TooLarge,
+        value: format!(
+            "download is at least {} bytes, more than the maximum of {} bytes",
+            size, max_size
+        ),
+    }
+}
+
+/// Parses a `Content-Range: bytes <start>-<end>/<total>` header into `(start, total)`
+pub(super) fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let range = value.trim().strip_prefix("bytes ")?;
+    let (span, total) = range.split_once('/')?;
+    let (start, _) = span.split_once('-')?;
+
+    Some((start.trim().parse().ok()?, total.trim().parse().ok()))
+}
+
+impl<T: Transport> CNVClient<T> {
+    /// Local path an MP3 file is written to while it is still being downloaded
+    pub(super) fn part_path(&self, youtube_id: &str) -> PathBuf {
+        self.out_dir.join(format!("{}.mp3.part", youtube_id))
+    }
+
+    /// Local path of the `PartInfo` describing `part_path`
+    pub(super) fn part_info_path(&self, youtube_id: &str) -> PathBuf {
+        self.out_dir.join(format!("{}.mp3.part.json", youtube_id))
+    }
+
+    /// The interrupted download of `youtube_id`, if there is one to resume
+    pub(super) fn interrupted(&self, youtube_id: &str) -> Option<PartInfo> {
+        if !self.part_path(youtube_id).exists() {
+            return None;
+        }
+
+        let info = std::fs::read(self.part_info_path(youtube_id)).ok()?;
+
+        serde_json::from_slice(&info).ok()
+    }
+
+    /// Number of bytes already received into the `.part` file of `youtube_id`
+    pub(super) async fn part_len(&self, youtube_id: &str) -> u64 {
+        tokio::fs::metadata(self.part_path(youtube_id))
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0)
+    }
+
+    /// Whether an interrupted download of `youtube_id` with bytes worth keeping exists
+    pub(super) async fn resumable(&self, youtube_id: &str) -> bool {
+        self.interrupted(youtube_id).is_some() && self.part_len(youtube_id).await > 0
+    }
+
+    /// Removes what is left of an interrupted download of `youtube_id`
+    pub(super) async fn discard_part(&self, youtube_id: &str) {
+        let _ = tokio::fs::remove_file(self.part_path(youtube_id)).await;
+        let _ = tokio::fs::remove_file(self.part_info_path(youtube_id)).await;
+    }
+
+    /// Sends `request`, asking only for the bytes after `offset` when there are some already
+    pub(super) async fn request_from(
+        &self,
+        request: &HttpRequest,
+        offset: u64,
+        etag: Option<&str>,
+    ) -> Result<StreamingResponse, Failed> {
+        let mut request = request.clone();
+
+        if offset > 0 {
+            request
+                .headers
+                .push(("Range".to_string(), format!("bytes={}-", offset)));
+
+            // a changed file is sent in full instead of the requested range
+            if let Some(etag) = etag {
+                request
+                    .headers
+                    .push(("If-Range".to_string(), etag.to_string()));
+            }
+        }
+
+        let res = self.transport.stream(request.clone()).await?;
+
+        if !(offset > 0 && res.status == 416) {
+            check_status(&request.url, res.status, res.header("Retry-After"))?;
+        }
+
+        Ok(res)
+    }
+
+    /// Streams the body of `request` into the `.part` file of `youtube_id` chunk by chunk,
+    /// resuming an earlier interrupted download of the same `server_path` when the CDN supports
+    /// `Range` requests and the remote file is unchanged. The content is checked to be an MP3 file
+    /// as soon as its first bytes arrive, and `max_size` is enforced throughout, so bad downloads
+    /// are abandoned early. `title` is kept with the `.part` file for when it is resumed. Returns
+    /// the size of the complete file.
+    pub(super) async fn stream_to_file(
+        &self,
+        request: &HttpRequest,
+        youtube_id: &str,
+        title: Option<&str>,
+    ) -> Result<u64, Failed> {
+        let part = self.part_path(youtube_id);
+        let server_path = request.url.to_string();
+
+        let previous = self
+            .interrupted(youtube_id)
+            .filter(|info| info.server_path == server_path);
+        let mut offset = match &previous {
+            Some(_) => tokio::fs::metadata(&part)
+                .await
+                .map(|m| m.len())
+                .unwrap_or(0),
+            None => 0,
+        };
+        let etag = previous.as_ref().and_then(|p| p.etag.as_deref());
+
+        let mut res = self.request_from(request, offset, etag).await?;
+
+        if offset > 0 {
+            let resumed = res.status == 206
+                && res
+                    .header("Content-Range")
+                    .and_then(parse_content_range)
+                    .is_some_and(|(start, total)| {
+                        let expected = previous.as_ref().and_then(|p| p.length);
+                        start == offset
+                            && (total.is_none() || expected.is_none() || total == expected)
+                    })
+                && (etag.is_none() || res.header("ETag") == etag);
+
+            if !resumed {
+                eprintln!("info: cannot resume {}, starting over", youtube_id);
+                offset = 0;
+
+                if res.status != 200 {
+                    res = self.request_from(request, 0, None).await?;
+                }
+            }
+        }
+
+        let length = self.full_length(&res)?;
+
+        let info = PartInfo {
+            server_path,
+            etag: res.header("ETag").map(str::to_string),
+            length,
+            title: title.map(String::from),
+        };
+        tokio::fs::write(
+            self.part_info_path(youtube_id),
+            serde_json::to_vec(&info).map_err(Error::from)?,
+        )
+        .await?;
+
+        let mut file = if offset > 0 {
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&part)
+                .await?
+        } else {
+            tokio::fs::File::create(&part).await?
+        };
+
+        let written = self
+            .write_body(res, &mut file, youtube_id, offset, length)
+            .await?;
+        file.sync_all().await?;
+
+        Ok(written)
+    }
+
+    /// Size of the whole file `res` is (part of), if the CDN said so, making sure it is not over
+    /// `max_size`
+    pub(super) fn full_length(&self, res: &StreamingResponse) -> Result<Option<u64>, Error> {
+        let length = match res.status {
+            206 => res
+                .header("Content-Range")
+                .and_then(parse_content_range)
+                .and_then(|(_, total)| total),
+            _ => res
+                .header("Content-Length")
+                .and_then(|l| l.trim().parse::<u64>().ok()),
+        };
+
+        if let (Some(max_size), Some(len)) = (self.max_size, length) {
+            if len > max_size {
+                return Err(too_large(len, max_size));
+            }
+        }
+
+        Ok(length)
+    }
+
+    /// The rest of the body of `res`, part of a file of `length` bytes (if known) whose first
+    /// `offset` bytes were already received. The content is checked to be an MP3 file as soon as
+    /// its first bytes arrive, and `max_size` is enforced throughout; the stream ends with an
+    /// error if either check fails, or if it is shorter than `length`.
+    pub(super) fn checked_body(
+        &self,
+        res: StreamingResponse,
+        youtube_id: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> ByteStream {
+        let body = CheckedBody {
+            body: Some(res.body),
+            head: Vec::with_capacity(MAGIC_LEN),
+            // bytes before `offset` were checked when they were first received
+            checked: offset >= MAGIC_LEN as u64,
+            received: offset,
+            length,
+            max_size: self.max_size,
+            id: youtube_id.to_string(),
+            progress: self.progress.clone(),
+        };
+
+        stream::unfold(body, |mut body| async move {
+            let chunk = body.next().await.transpose()?;
+            if chunk.is_err() {
+                body.body = None;
+            }
+            Some((chunk, body))
+        })
+        .boxed()
+    }
+
+    /// Writes the rest of the body of `res` into `out`, which already holds the first `offset`
+    /// bytes of a file of `length` bytes (if known), checking it as `checked_body` does. Returns
+    /// the number of bytes `out` holds afterwards.
+    pub(super) async fn write_body(
+        &self,
+        res: StreamingResponse,
+        out: &mut (impl AsyncWrite + Unpin),
+        youtube_id: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<u64, Failed> {
+        let mut body = self.checked_body(res, youtube_id, offset, length);
+        let mut written = offset;
+
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    // make sure what did arrive is stored for the next attempt to resume from
+                    out.flush().await?;
+                    return Err(e.into());
+                }
+            };
+
+            out.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        out.flush().await?;
+
+        Ok(written)
+    }
+}
+
+/// State of `CNVClient::checked_body`
+struct CheckedBody {
+    /// `None` once the body has ended or failed
+    body: Option<Box<dyn BodyStream>>,
+    /// First bytes, held back until there are enough of them to check
+    head: Vec<u8>,
+    checked: bool,
+    received: u64,
+    length: Option<u64>,
+    max_size: Option<u64>,
+    id: String,
+    progress: Progress,
+}
+
+impl CheckedBody {
+    /// Next checked chunk, `Ok(None)` at the end of a good body
+    async fn next(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            let Some(body) = &mut self.body else {
+                return Ok(None);
+            };
+
+            let Some(chunk) = body.chunk().await? else {
+                self.body = None;
+
+                if !self.checked {
+                    return Err(not_mp3());
+                }
+
+                if let Some(length) = self.length {
+                    if self.received < length {
+                        return Err(Error {
+                            kind: ErrorKind::ConnectionError,
+                            value: format!(
+                                "download ended after {} of {} bytes",
+                                self.received, length
+                            ),
+                        });
+                    }
+                }
+
+                return Ok(None);
+            };
+            self.received += chunk.len() as u64;
+
+            if let Some(max_size) = self.max_size {
+                if self.received > max_size {
+                    return Err(too_large(self.received, max_size));
+                }
+            }
+
+            self.progress.emit(Event::Downloaded {
+                id: self.id.clone(),
+                bytes: self.received,
+                total: self.length,
+            });
+
+            if self.checked {
+                return Ok(Some(chunk));
+            }
+
+            self.head.extend_from_slice(&chunk);
+            if self.head.len() >= MAGIC_LEN {
+                if !is_mp3(&self.head) {
+                    return Err(not_mp3());
+                }
+
+                self.checked = true;
+                return Ok(Some(std::mem::take(&mut self.head)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 100-199/1000"),
+            Some((100, Some(1000)))
+        );
+        assert_eq!(parse_content_range("bytes 5-9/*"), Some((5, None)));
+        assert_eq!(parse_content_range("items 5-9/10"), None);
+        assert_eq!(parse_content_range("bytes */1000"), None);
+    }
+}
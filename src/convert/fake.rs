@@ -0,0 +1,232 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use super::transport::{BodyStream, HttpRequest, HttpResponse, StreamingResponse, Transport};
+use crate::error::{Error, ErrorKind};
+
+/// A tiny MP3 file for tests: an empty ID3v2.4 header followed by the header of one MPEG-1 Layer
+/// III frame (128 kb/s, 44.1 kHz)
+pub const MP3_SAMPLE: &[u8] = &[
+    b'I', b'D', b'3', 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFB, 0x90, 0x00,
+];
+
+/// Size of the chunks `FakeTransport::stream` hands bodies over in
+const CHUNK_LEN: usize = 5;
+
+/// In-memory `Transport` that answers each endpoint (keyed by URL path, e.g.
+/// `/check_database.php`) with responses scripted ahead of time, in order, falling back to files
+/// served like a CDN would
+#[derive(Default)]
+pub struct FakeTransport {
+    scripts: Mutex<HashMap<String, VecDeque<Scripted>>>,
+    files: Mutex<HashMap<String, Vec<u8>>>,
+    requests: Mutex<Vec<HttpRequest>>,
+}
+
+/// `ETag` of every file served by `FakeTransport::serve_file`
+pub const FILE_ETAG: &str = "\"fake\"";
+
+/// A scripted outcome, optionally with the connection dropping after `cut_at` body bytes
+struct Scripted {
+    res: Result<HttpResponse, Error>,
+    cut_at: Option<usize>,
+}
+
+fn connection_reset() -> Error {
+    Error {
+        kind: ErrorKind::ConnectionError,
+        value: String::from("connection reset by peer"),
+    }
+}
+
+impl FakeTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a `200 OK` response with the given body for `path`
+    pub fn respond(self, path: &str, body: impl Into<Vec<u8>>) -> Self {
+        self.respond_with(
+            path,
+            Ok(HttpResponse {
+                status: 200,
+                headers: Vec::new(),
+                body: body.into(),
+            }),
+        )
+    }
+
+    /// Queue an arbitrary outcome (including transport errors) for `path`
+    pub fn respond_with(self, path: &str, res: Result<HttpResponse, Error>) -> Self {
+        self.script(path, Scripted { res, cut_at: None })
+    }
+
+    /// Queue a response for `path` whose connection drops after `cut_at` bytes of the body
+    pub fn respond_cut(self, path: &str, res: HttpResponse, cut_at: usize) -> Self {
+        self.script(
+            path,
+            Scripted {
+                res: Ok(res),
+                cut_at: Some(cut_at),
+            },
+        )
+    }
+
+    /// Serve `body` at `path` for as long as no scripted response is queued, honoring `Range`
+    /// and `If-Range` headers
+    pub fn serve_file(self, path: &str, body: impl Into<Vec<u8>>) -> Self {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), body.into());
+        self
+    }
+
+    fn script(self, path: &str, scripted: Scripted) -> Self {
+        self.scripts
+            .lock()
+            .unwrap()
+            .entry(path.to_string())
+            .or_default()
+            .push_back(scripted);
+        self
+    }
+
+    /// Records `request` and pops the next outcome scripted for its path
+    fn next(&self, request: HttpRequest) -> Scripted {
+        let path = request.url.path().to_string();
+        let headers = request.headers.clone();
+        self.requests.lock().unwrap().push(request);
+
+        let scripted = self
+            .scripts
+            .lock()
+            .unwrap()
+            .get_mut(&path)
+            .and_then(|q| q.pop_front());
+        let served = || {
+            let files = self.files.lock().unwrap();
+
+            files.get(&path).map(|body| Scripted {
+                res: Ok(serve(&headers, body)),
+                cut_at: None,
+            })
+        };
+
+        scripted.or_else(served).unwrap_or_else(|| Scripted {
+            res: Err(Error {
+                kind: ErrorKind::ReqwestError,
+                value: format!("no scripted response for {}", path),
+            }),
+            cut_at: None,
+        })
+    }
+
+    /// URL paths of every request seen so far, in order
+    pub fn paths(&self) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.url.path().to_string())
+            .collect()
+    }
+
+    /// Every request seen so far, in order
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Answers a request with `headers` for a file containing `body`
+fn serve(headers: &[(String, String)], body: &[u8]) -> HttpResponse {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+
+    let range = header("Range")
+        .filter(|_| header("If-Range").is_none_or(|etag| etag == FILE_ETAG))
+        .and_then(|r| r.strip_prefix("bytes="))
+        .and_then(|r| r.split_once('-'))
+        .and_then(|(start, end)| {
+            let start: usize = start.parse().ok()?;
+            let end = match end {
+                "" => body.len() - 1,
+                end => end.parse::<usize>().ok()?.min(body.len() - 1),
+            };
+            Some((start, end))
+        });
+
+    let mut res = HttpResponse {
+        status: 200,
+        headers: vec![
+            ("ETag".to_string(), FILE_ETAG.to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ],
+        body: body.to_vec(),
+    };
+
+    if let Some((start, end)) = range {
+        res.status = 206;
+        res.headers[1].1 = (end + 1 - start).to_string();
+        res.headers.push((
+            "Content-Range".to_string(),
+            format!("bytes {}-{}/{}", start, end, body.len()),
+        ));
+        res.body = body[start..=end].to_vec();
+    }
+
+    res
+}
+
+#[async_trait]
+impl Transport for FakeTransport {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, Error> {
+        let scripted = self.next(request);
+
+        match scripted.cut_at {
+            Some(_) => Err(connection_reset()),
+            None => scripted.res,
+        }
+    }
+
+    /// Hands the body over in `CHUNK_LEN` byte pieces, like a slow network would
+    async fn stream(&self, request: HttpRequest) -> Result<StreamingResponse, Error> {
+        let scripted = self.next(request);
+        let res = scripted.res?;
+
+        let body = match scripted.cut_at {
+            Some(cut_at) => &res.body[..cut_at.min(res.body.len())],
+            None => &res.body[..],
+        };
+
+        Ok(StreamingResponse {
+            status: res.status,
+            headers: res.headers,
+            body: Box::new(FakeBody {
+                chunks: body.chunks(CHUNK_LEN).map(|c| c.to_vec()).collect(),
+                cut: scripted.cut_at.is_some(),
+            }),
+        })
+    }
+}
+
+struct FakeBody {
+    chunks: VecDeque<Vec<u8>>,
+    cut: bool,
+}
+
+#[async_trait]
+impl BodyStream for FakeBody {
+    async fn chunk(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match self.chunks.pop_front() {
+            Some(chunk) => Ok(Some(chunk)),
+            None if self.cut => Err(connection_reset()),
+            None => Ok(None),
+        }
+    }
+}
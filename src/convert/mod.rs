@@ -1,21 +1,88 @@
-use infer::audio::is_mp3;
+use clap::ValueEnum;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::Write;
-use std::path::Path;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use url::Url;
 
+use crate::analysis::Notation;
 use crate::bitrate::BitRate;
 use crate::error::{Error, ErrorKind};
+use crate::id3::{replace_tag_in_file, Tag, TrackTags, Version};
+use crate::library::{self, analyze_track, Library, Track};
+use crate::migrate::hash;
+use crate::storage::{LocalStorage, Storage};
+use crate::template::{Metadata, Template};
+use crate::titles::TitleRules;
 use crate::youtube_url::YouTubeURL;
 
-mod schema;
+mod batch;
+mod cassette;
+mod converter;
+mod destination;
+mod download;
+#[cfg(test)]
+mod fake;
+mod progress;
+mod retry;
+pub mod schema;
+mod segment;
+mod transport;
+pub use batch::{read_input, Entry, Status, Summary};
+pub use converter::{Conversion, Converter, ConverterBuilder};
+pub use progress::{Event, Progress, Source};
+use retry::{check_status, retrying};
+pub use retry::{RetryConfig, RetryPolicy};
 use schema::{
     CheckDatabaseFail, CheckDatabaseSuccess, DownloadVideoFail, DownloadVideoSuccess,
     GetVideoDataFail, GetVideoDataSuccess, InsertToDatabaseFail, InsertToDatabaseSuccess,
     PayloadCheckDatabase, PayloadDownloadVideo, PayloadGetVideoData, PayloadInsertToDatabase,
     ResponseCheckDatabase, ResponseDownloadVideo, ResponseGetVideoData, ResponseInsertToDatabase,
 };
+pub use segment::DEFAULT_SEGMENTS;
+use transport::{HttpRequest, HttpResponse, Method, Transport};
+
+/// Where the cnvmp3 web server lives unless told otherwise
+pub const CNV_BASE_URL: &str = "https://cnvmp3.com/";
+
+/// How long to wait for a connection to cnvmp3 (or its CDN) before considering it failed
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for each read from an established connection
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The five steps of the cnvmp3 protocol (see `steps.csv`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Step {
+    CheckDatabase,
+    GetVideoData,
+    DownloadVideo,
+    InsertToDatabase,
+    Download,
+}
+
+/// Largest MP3 file downloaded unless told otherwise (1 GiB)
+pub const DEFAULT_MAX_SIZE: u64 = 1 << 30;
+
+/// Whether (and where) to save or replay the HTTP exchanges of a conversion
+pub enum Cassette {
+    /// Talk to cnvmp3 as usual, saving every exchange into the given directory
+    Record(PathBuf),
+    /// Answer every request from a directory previously written by `Record`
+    Replay(PathBuf),
+}
+
+/// How a successful conversion came about
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// The MP3 file was downloaded
+    Saved,
+    /// The MP3 file had already been saved locally
+    Cached,
+}
 
 /// Enumerated list of supported formats to download youtube videos as
 /// * MP3 for audio
@@ -27,15 +94,301 @@ enum DLFormat {
     MP3 = 1,
 }
 
-/// Custom wrapper for `reqwest::Client`
-#[allow(dead_code)]
-struct CNVClient {
-    client: reqwest::Client,
-    dest_type: String,
+/// Client for the cnvmp3 protocol, generic over how requests are carried (see `Transport`)
+struct CNVClient<T: Transport> {
+    transport: T,
+    base_url: Url,
+    /// Where MP3 files are saved
+    storage: Arc<dyn Storage>,
+    /// Local directory for MP3 files and interrupted downloads
+    out_dir: PathBuf,
+    retry: RetryConfig,
+    max_size: Option<u64>,
+    segments: usize,
+    min_segment_len: u64,
+    progress: Progress,
+    /// Index of converted videos, kept up to date when there is one
+    library: Option<Mutex<Library>>,
+    /// What MP3 files are named
+    template: Template,
+    /// The YouTube ID each file name handed out by `choose_name` went to
+    claimed: Mutex<HashMap<String, String>>,
+    /// Version of the ID3v2 tags written into MP3 files, if any
+    id3: Option<Version>,
+    /// How video titles are split into artist, title and the rest for names and tags
+    title_rules: TitleRules,
+    /// Whether the tempo and key of MP3 files are detected once they are saved
+    analyze: bool,
+    /// How detected keys are written
+    key_notation: Notation,
 }
 
 /// Implementation of the responsibilities of my custom client
-impl CNVClient {
+impl<T: Transport> CNVClient<T> {
+    /// Creates a client talking to `https://cnvmp3.com` through `transport` and saving MP3 files
+    /// into `mp3/`
+    fn new(transport: T) -> Self {
+        CNVClient {
+            transport,
+            base_url: Url::parse(CNV_BASE_URL).expect("CNV_BASE_URL should be a valid url"),
+            storage: Arc::new(LocalStorage::new(PathBuf::from("mp3"))),
+            out_dir: PathBuf::from("mp3"),
+            retry: RetryConfig::default(),
+            max_size: Some(DEFAULT_MAX_SIZE),
+            segments: 1,
+            min_segment_len: segment::MIN_SEGMENT_LEN,
+            progress: Progress::default(),
+            library: None,
+            template: Template::default(),
+            claimed: Mutex::new(HashMap::new()),
+            id3: None,
+            title_rules: TitleRules::default(),
+            analyze: false,
+            key_notation: Notation::default(),
+        }
+    }
+
+    /// Points the client at a different cnvmp3 server (e.g., a mock)
+    fn with_base_url(mut self, base_url: Url) -> Self {
+        self.base_url = base_url;
+        self
+    }
+
+    /// Changes how failed protocol steps are retried
+    fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Changes the largest MP3 file (in bytes) that will be downloaded; `None` means no limit
+    fn with_max_size(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Downloads MP3 files over up to `segments` connections at once when the CDN allows it
+    fn with_segments(mut self, segments: usize) -> Self {
+        self.segments = segments;
+        self
+    }
+
+    /// Reports the progress of every conversion to `progress`
+    fn with_progress(mut self, progress: Progress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Changes the size below which files are not split into segments
+    #[cfg(test)]
+    fn with_min_segment_len(mut self, min_segment_len: u64) -> Self {
+        self.min_segment_len = min_segment_len;
+        self
+    }
+
+    /// Changes the directory MP3 files are saved into
+    fn with_out_dir(mut self, out_dir: PathBuf) -> Self {
+        self.storage = Arc::new(LocalStorage::new(out_dir.clone()));
+        self.out_dir = out_dir;
+        self
+    }
+
+    /// Saves MP3 files to `storage` instead of the output directory
+    fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        if let Some(dir) = storage.local_dir() {
+            self.out_dir = dir.to_path_buf();
+        }
+        self.storage = storage;
+        self
+    }
+
+    /// Records every conversion in `library`, and trusts it to know which videos were converted
+    fn with_library(mut self, library: Library) -> Self {
+        self.library = Some(Mutex::new(library));
+        self
+    }
+
+    /// Names MP3 files after `template` instead of `<id>.mp3`
+    fn with_template(mut self, template: Template) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Writes ID3v2 tags of `version` into every MP3 file it saves (see `TrackTags`)
+    fn with_id3(mut self, version: Option<Version>) -> Self {
+        self.id3 = version;
+        self
+    }
+
+    /// Parses video titles with `title_rules` instead of the default rules
+    fn with_title_rules(mut self, title_rules: TitleRules) -> Self {
+        self.title_rules = title_rules;
+        self
+    }
+
+    /// Detects the tempo and key of every MP3 file saved locally (see `analyze`)
+    fn with_analysis(mut self, analyze: bool) -> Self {
+        self.analyze = analyze;
+        self
+    }
+
+    /// Writes detected keys in `notation`
+    fn with_key_notation(mut self, notation: Notation) -> Self {
+        self.key_notation = notation;
+        self
+    }
+
+    /// The ID3v2 tag to write into an MP3 file described by `tags`, unless tags are not written
+    fn id3_tag(&self, tags: TrackTags) -> Option<Vec<u8>> {
+        let mut tag = Tag::default();
+        tags.apply(&mut tag, &self.title_rules);
+
+        Some(tag.to_bytes(self.id3?))
+    }
+
+    /// `track` with the tempo and key of its MP3 file when asked for them (see `with_analysis`),
+    /// written into its tags too if they are written. The MP3 file is already saved by then, so
+    /// failing to analyze it is only a warning.
+    async fn analyze(&self, track: Track) -> Track {
+        if !self.analyze || !self.saves_locally() {
+            return track;
+        }
+
+        let mut analyzed = track.clone();
+        match analyze_track(&mut analyzed, self.id3, self.key_notation).await {
+            Ok(()) => {
+                if analyzed.tempo.is_none() {
+                    eprintln!("warning: no beats found in {}", track.path.display());
+                }
+                if analyzed.key.is_none() {
+                    eprintln!("warning: no notes found in {}", track.path.display());
+                }
+                analyzed
+            }
+            Err(e) => {
+                eprintln!("warning: could not analyze {}: {}", track.path.display(), e);
+                track
+            }
+        }
+    }
+
+    /// What the library index knows about `youtube_id`
+    fn indexed(&self, youtube_id: &str) -> Option<Track> {
+        let library = self.library.as_ref()?.lock().unwrap();
+        library.get(youtube_id).cloned()
+    }
+
+    /// Records `track` in the library index (or forgets `youtube_id` when `track` is `None`).
+    /// The MP3 file is already saved by then, so failing to write the index is only a warning.
+    fn index(&self, youtube_id: &str, track: Option<Track>) {
+        let Some(library) = &self.library else {
+            return;
+        };
+        let mut library = library.lock().unwrap();
+
+        match track {
+            Some(track) => library.insert(track),
+            None => {
+                library.remove(youtube_id);
+            }
+        }
+        if let Err(e) = library.save() {
+            eprintln!(
+                "warning: could not update the library index {}: {}",
+                library.path().display(),
+                e
+            );
+        }
+    }
+
+    /// Reports where the MP3 file for `youtube_id` is going to come from
+    fn source(&self, youtube_id: &str, source: Source) {
+        self.progress.emit(Event::Source {
+            id: youtube_id.to_string(),
+            source,
+        });
+    }
+
+    /// Runs `step` of the conversion of `youtube_id`, reporting when it starts and finishes
+    async fn step<R>(
+        &self,
+        youtube_id: &str,
+        step: Step,
+        run: impl Future<Output = Result<R, Error>>,
+    ) -> Result<R, Error> {
+        let id = youtube_id.to_string();
+        self.progress.emit(Event::StepStarted {
+            id: id.clone(),
+            step,
+        });
+
+        let res = run.await;
+
+        self.progress.emit(Event::StepFinished {
+            id,
+            step,
+            ok: res.is_ok(),
+        });
+
+        res
+    }
+
+    /// Local path of the MP3 file for `youtube_id` under the default file name template
+    #[cfg(test)]
+    fn mp3_path(&self, youtube_id: &str) -> PathBuf {
+        self.out_dir.join(format!("{}.mp3", youtube_id))
+    }
+
+    /// Sends `request` until `parse` accepts the response, the error is permanent, or the retry
+    /// policy for `step` runs out of attempts
+    async fn send<R>(
+        &self,
+        step: Step,
+        request: HttpRequest,
+        parse: impl Fn(HttpResponse) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        let request = &request;
+        let parse = &parse;
+
+        retrying(self.retry.policy(step), step, move || async move {
+            let res = self.transport.send(request.clone()).await?;
+            check_status(&request.url, res.status, res.header("Retry-After"))?;
+
+            Ok(parse(res)?)
+        })
+        .await
+    }
+
+    /// POSTs `payload` as JSON to `endpoint` (relative to `base_url`) and parses the response
+    async fn post_json<P: Serialize, R: DeserializeOwned>(
+        &self,
+        step: Step,
+        endpoint: &str,
+        payload: &P,
+    ) -> Result<R, Error> {
+        let url = self.base_url.join(endpoint).map_err(|e| Error {
+            kind: ErrorKind::InvalidURL,
+            value: format!("bad endpoint {}: {}", endpoint, e),
+        })?;
+
+        let request = HttpRequest {
+            method: Method::Post,
+            url,
+            headers: vec![
+                ("Content-Type".to_string(), "application/json".to_string()),
+                ("Accept".to_string(), "application/json".to_string()),
+            ],
+            body: Some(serde_json::to_vec(payload)?),
+        };
+
+        self.send(step, request, |res| {
+            serde_json::from_slice(res.body.as_ref()).map_err(|e| Error {
+                kind: ErrorKind::MalformedResponse,
+                value: format!("{} did not answer with the expected JSON: {}", endpoint, e),
+            })
+        })
+        .await
+    }
+
     /// Sends a payload to the `/check_database.php` endpoint to determine whether
     /// the metadata for an MP3 file is available. If found, the metadata includes
     /// the remote location for downloading via the custom client (`cdn_download`).
@@ -43,8 +396,8 @@ impl CNVClient {
     /// # Arguments
     ///
     /// * `youtube_id` - A `String` representing the unique identifier of the YouTube video.
-    ///                  This ID is used to query the database for metadata associated
-    ///                  with the corresponding MP3 file.
+    ///   This ID is used to query the database for metadata associated
+    ///   with the corresponding MP3 file.
     ///
     /// # Returns
     ///
@@ -76,28 +429,8 @@ impl CNVClient {
             youtube_id,
         };
 
-        let checkdb_res = self
-            .client
-            .post("https://cnvmp3.com/check_database.php")
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .json(&pcd)
-            .send()
+        self.post_json(Step::CheckDatabase, "check_database.php", &pcd)
             .await
-            .map_err(|e| Error {
-                kind: ErrorKind::ReqwestError,
-                value: format!("HTTP request failed: {}", e),
-            })?
-            .bytes()
-            .await
-            .map_err(|e| Error {
-                kind: ErrorKind::ReqwestError,
-                value: format!("Failed to read response as bytes: {}", e),
-            })?;
-
-        let checkdb_parsed: ResponseCheckDatabase = serde_json::from_slice(checkdb_res.as_ref())?;
-
-        Ok(checkdb_parsed)
     }
 
     /// Sends a request to `cnvmp3` to retrieve the YouTube video ID associated with the provided URL.
@@ -105,7 +438,7 @@ impl CNVClient {
     /// # Arguments
     ///
     /// * `url` - A `String` containing the URL of the YouTube video. This URL is used to query the
-    ///           `cnvmp3` service to obtain the corresponding YouTube video ID.
+    ///   `cnvmp3` service to obtain the corresponding YouTube video ID.
     ///
     /// # Returns
     ///
@@ -115,28 +448,8 @@ impl CNVClient {
     async fn cdn_fetch(&self, url: Url) -> Result<ResponseGetVideoData, Error> {
         let pgvd = PayloadGetVideoData { url };
 
-        let gvd_res = self
-            .client
-            .post("https://cnvmp3.com/get_video_data.php")
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .json(&pgvd)
-            .send()
+        self.post_json(Step::GetVideoData, "get_video_data.php", &pgvd)
             .await
-            .map_err(|e| Error {
-                kind: ErrorKind::ReqwestError,
-                value: format!("HTTP request failed: {}", e),
-            })?
-            .bytes()
-            .await
-            .map_err(|e| Error {
-                kind: ErrorKind::ReqwestError,
-                value: format!("Failed to read response as bytes: {}", e),
-            })?;
-
-        let gvd_parsed: ResponseGetVideoData = serde_json::from_slice(gvd_res.as_ref())?;
-
-        Ok(gvd_parsed)
     }
 
     /// Sends a request for the cnvmp3 web server to find where the MP3 file is in the Content
@@ -145,9 +458,9 @@ impl CNVClient {
     /// # Arguments
     ///
     /// * `url` - A `String` containing the URL of the YouTube video. This URL is used to identify
-    ///           the video and locate the corresponding MP3 file in the CDN.
+    ///   the video and locate the corresponding MP3 file in the CDN.
     /// * `title` - A `String` representing the title of the YouTube video. This may be used for
-    ///             additional metadata or as part of the request to the `cnvmp3` web server.
+    ///   additional metadata or as part of the request to the `cnvmp3` web server.
     ///
     /// # Returns
     ///
@@ -169,28 +482,8 @@ impl CNVClient {
             url,
         };
 
-        let dv_res = self
-            .client
-            .post("https://cnvmp3.com/download_video.php")
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .json(&pdv)
-            .send()
+        self.post_json(Step::DownloadVideo, "download_video.php", &pdv)
             .await
-            .map_err(|e| Error {
-                kind: ErrorKind::ReqwestError,
-                value: format!("HTTP request failed: {}", e),
-            })?
-            .bytes()
-            .await
-            .map_err(|e| Error {
-                kind: ErrorKind::ReqwestError,
-                value: format!("Failed to read response as bytes: {}", e),
-            })?;
-
-        let dv_parsed: ResponseDownloadVideo = serde_json::from_slice(dv_res.as_ref())?;
-
-        Ok(dv_parsed)
     }
 
     /// Inserts video metadata into a local database to enable faster file retrieval in future requests.
@@ -198,11 +491,11 @@ impl CNVClient {
     /// # Arguments
     ///
     /// * `server_path` - A `String` representing the path to the MP3 file on the server. This is used
-    ///                   to locate the file when retrieving it from the local database.
+    ///   to locate the file when retrieving it from the local database.
     /// * `title` - A `String` containing the title of the YouTube video. This metadata is stored in
-    ///             the local database for reference and identification purposes.
+    ///   the local database for reference and identification purposes.
     /// * `youtube_id` - A `String` representing the unique identifier of the YouTube video. This ID
-    ///                  is stored to associate the video metadata with the specific video.
+    ///   is stored to associate the video metadata with the specific video.
     ///
     /// # Returns
     ///
@@ -225,204 +518,1418 @@ impl CNVClient {
             youtube_id,
         };
 
-        let ins_res = self
-            .client
-            .post("https://cnvmp3.com/insert_to_database.php")
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .json(&pid)
-            .send()
-            .await
-            .map_err(|e| Error {
-                kind: ErrorKind::ReqwestError,
-                value: format!("HTTP request failed: {}", e),
-            })?
-            .bytes()
+        self.post_json(Step::InsertToDatabase, "insert_to_database.php", &pid)
             .await
-            .map_err(|e| Error {
-                kind: ErrorKind::ReqwestError,
-                value: format!("Failed to read response as bytes: {}", e),
-            })?;
-
-        let ins_parsed: ResponseInsertToDatabase = serde_json::from_slice(ins_res.as_ref())?;
-
-        Ok(ins_parsed)
     }
 
     /// Downloads the MP3 file from the specified remote location (`server_path`) and saves it locally.
+    /// Large files are fetched over several connections at once (see `with_segments`) when the
+    /// CDN supports `Range` requests, and as a single stream otherwise.
+    /// With a remote storage, the file is streamed into the storage instead.
     ///
     /// # Arguments
     ///
     /// * `server_path` - A `String` representing the remote path to the MP3 file on the server.
-    ///                   This path is used to fetch the file for download.
+    ///   This path is used to fetch the file for download.
     /// * `youtube_id` - A `String` containing the unique identifier of the YouTube video. This ID
-    ///                  is used to associate the downloaded file with its source video.
+    ///   is used to associate the downloaded file with its source video.
+    /// * `name` - The name the MP3 file is saved as (see `choose_name`).
+    /// * `title` - The title of the video, kept for resuming an interrupted download.
     ///
     /// # Returns
     ///
-    /// Returns a `Result` with an empty tuple (`()`) on success, indicating the MP3 file was
-    /// successfully downloaded and saved locally. On failure, returns an error (`Box<dyn std::error::Error>`).
+    /// Returns a `Result` with the size of the MP3 file in bytes and its SHA-256 on success,
+    /// indicating the MP3 file was successfully downloaded and saved. On failure, returns an
+    /// `Error`.
     async fn cdn_download(
         &self,
         server_path: String,
         youtube_id: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let download = self
-            .client
-            .get(server_path)
-            .header("Referer", "https://cnvmp3.com")
-            .send()
-            .await?
-            .bytes()
-            .await?;
+        name: &str,
+        title: Option<&str>,
+        tag: Option<&[u8]>,
+    ) -> Result<(u64, String), Error> {
+        let url = Url::parse(&server_path).map_err(|e| Error {
+            kind: ErrorKind::InvalidURL,
+            value: format!("bad server path {}: {}", server_path, e),
+        })?;
+        let referer = self.base_url.as_str().trim_end_matches('/').to_string();
 
-        if is_mp3(&download) {
-            let mut outfile = File::create(format!("mp3/{}.mp3", youtube_id))
-                .expect("file creation should succeed");
+        let request = HttpRequest {
+            method: Method::Get,
+            url,
+            headers: vec![("Referer".to_string(), referer)],
+            body: None,
+        };
 
-            if let Err(e) = outfile.write_all(&download) {
-                return Err(format!("{:?}", e).into());
-            }
+        let (request, id) = (&request, youtube_id.as_str());
+        let policy = self.retry.policy(Step::Download);
+
+        if !self.saves_locally() {
+            return retrying(policy, Step::Download, move || {
+                self.stream_to_storage(request, id, name, tag)
+            })
+            .await;
+        }
+
+        tokio::fs::create_dir_all(&self.out_dir).await?;
+
+        // an interrupted download is resumed as a single stream rather than started over
+        let probe = if self.segments > 1 && self.interrupted(id).is_none() {
+            retrying(policy, Step::Download, move || self.probe(request))
+                .await?
+                .filter(|p| p.length >= 2 * self.min_segment_len)
         } else {
-            return Err("downloaded content is not an mp3 file".into());
+            None
+        };
+
+        let res = match probe {
+            Some(probe) => self.download_segments(request, id, probe).await,
+            None => {
+                retrying(policy, Step::Download, move || {
+                    self.stream_to_file(request, id, title)
+                })
+                .await
+            }
+        };
+
+        match res {
+            Ok(_) => {}
+            Err(e) if e.is_transient() && self.resumable(id).await => {
+                eprintln!(
+                    "info: keeping partial download {} to resume later",
+                    self.part_path(&youtube_id).display()
+                );
+                return Err(e);
+            }
+            Err(e) => {
+                self.discard_part(&youtube_id).await;
+                return Err(e);
+            }
+        }
+
+        let part = self.part_path(&youtube_id);
+        match tag {
+            Some(tag) => {
+                // tagged next to the download, so that the file only appears once it is whole
+                let tagged = part.with_extension("tagged");
+                replace_tag_in_file(&part, &tagged, tag).await?;
+                tokio::fs::rename(&tagged, self.out_dir.join(name)).await?;
+                tokio::fs::remove_file(&part).await?;
+            }
+            None => tokio::fs::rename(&part, self.out_dir.join(name)).await?,
         }
+        let _ = tokio::fs::remove_file(self.part_info_path(&youtube_id)).await;
 
-        Ok(())
+        hash(self.storage.as_ref(), name).await
     }
 }
 
-/// Converts a YouTube video to an MP3 file and downloads it.
-///
-/// # Arguments
-///
-/// * `youtube_url` - The URL of the YouTube video to convert.
-/// * `dest_type` - The destination type for the MP3 file download.
-///
-/// # Returns
-///
-/// * `Ok(())` - If the MP3 file is downloaded successfully.
-/// * `Err` - If an error occurs during conversion or download.
-///
-/// # Example
-///
-/// ```rust
-/// use url::Url;
-///
-/// #[tokio::main]
-/// async fn main() {
-///     let youtube_url = Url::parse("https://www.youtube.com/watch?v=dQw4w9WgXcQ").unwrap();
-///     let dest_type = String::from("local");
-///     if let Err(e) = download(youtube_url, dest_type).await {
-///         eprintln!("Error: {}", e);
-///     } else {
-///         println!("Download successful!");
-///     }
-/// }
-/// ```
-///
-/// # Notes
-///
-/// This function uses a custom CDN service to perform the conversion and
-/// downloading process. It handles checking whether the video is already
-/// saved as an MP3, fetching video data, inserting into the database, and
-/// downloading the MP3 file.
-#[tokio::main]
-pub async fn y2mp3(url: Url, dest_type: String, quality: BitRate) -> Result<(), Error> {
+/// Runs the five-step cnvmp3 protocol (see `steps.csv`) for `url` using client `c`
+async fn convert<T: Transport>(
+    c: &CNVClient<T>,
+    url: Url,
+    quality: BitRate,
+) -> Result<Conversion, Error> {
     eprintln!("info: using bitrate = {quality:?}");
 
-    let youtube_url = YouTubeURL::new(url).unwrap();
+    let youtube_url = YouTubeURL::new(url)?;
+    let id = youtube_url.id.clone();
+
+    let result = run_steps(c, youtube_url, quality).await;
+
+    c.progress.emit(Event::Finished {
+        id,
+        result: match &result {
+            Ok(conversion) => Ok(conversion.outcome.clone()),
+            Err(e) => Err(e.value.clone()),
+        },
+    });
+
+    result
+}
+
+async fn run_steps<T: Transport>(
+    c: &CNVClient<T>,
+    youtube_url: YouTubeURL,
+    quality: BitRate,
+) -> Result<Conversion, Error> {
+    let id = youtube_url.id.as_str();
+    let metadata = |title| Metadata {
+        id,
+        title,
+        bitrate: quality,
+    };
+    let conversion = |name: &str, outcome, size, title, from_database| Conversion {
+        id: id.to_string(),
+        path: c.saved_path(name),
+        title,
+        bitrate: quality,
+        size,
+        from_database,
+        outcome,
+    };
+    // the same time goes into the tags and the index, so that they agree
+    let downloaded_at = library::now();
+    let id3_tag = |title: Option<&str>| {
+        c.id3_tag(TrackTags {
+            id: id.to_string(),
+            url: youtube_url.url.to_string(),
+            title: title.map(String::from),
+            bitrate: quality,
+            downloaded_at,
+        })
+    };
+    let track = |name: &str, server_path, (size, sha256), title| Track {
+        id: id.to_string(),
+        title,
+        url: youtube_url.url.to_string(),
+        bitrate: quality,
+        server_path,
+        path: c.saved_path(name),
+        name: Some(name.to_string()),
+        size,
+        sha256,
+        downloaded_at,
+        duration_ms: None,
+        tempo: None,
+        key: None,
+        energy: None,
+    };
+    let saved = |track: Track| {
+        let (size, title) = (track.size, track.title.clone());
+        c.index(id, Some(track));
+
+        (size, title)
+    };
+    let cached = |name: &str, size, title| {
+        println!("info: the requested video has already been saved as mp3");
+        c.source(id, Source::Local);
+        conversion(name, Outcome::Cached, size, title, false)
+    };
 
-    if Path::new(format!("mp3/{}.mp3", youtube_url.id).as_str()).exists() {
-        println!("info: the requested video has already been saved locally as mp3");
-        return Ok(());
+    // the name of the file is known before asking cnvmp3 if the index has it, or if the template
+    // does not need the title
+    let indexed = c.indexed(id);
+    let known_name = match &indexed {
+        Some(track) => Some(track.file_name()),
+        None => c.template.render(&metadata(None)),
+    };
+    if let Some(name) = &known_name {
+        if let Some(size) = c.saved_size(name).await? {
+            let title = indexed.and_then(|track| track.title);
+            return Ok(cached(name, size, title));
+        }
+    }
+    if let Some(track) = indexed {
+        eprintln!(
+            "info: {} is in the library index but missing, converting again",
+            track.path.display()
+        );
+        c.index(id, None);
     }
 
-    let client = reqwest::Client::new();
+    // uploads to a remote storage are not resumed; downloads whose name needs the title are
+    // resumed once cnvmp3 has told it
+    let interrupted = match (c.saves_locally(), c.template.render(&metadata(None))) {
+        (true, Some(name)) => c.interrupted(id).map(|part| (part, name)),
+        _ => None,
+    };
 
-    let c = CNVClient { client, dest_type };
+    if let Some((part, name)) = interrupted {
+        eprintln!("info: resuming interrupted download of {}", id);
+        c.source(id, Source::Database);
 
-    let checkdb_res = c.check_database(youtube_url.id.clone(), quality).await?;
+        let tag = id3_tag(part.title.as_deref());
+        let res = c
+            .step(
+                id,
+                Step::Download,
+                c.cdn_download(
+                    part.server_path.clone(),
+                    id.to_string(),
+                    &name,
+                    part.title.as_deref(),
+                    tag.as_deref(),
+                ),
+            )
+            .await;
 
-    match checkdb_res {
-        ResponseCheckDatabase::Exist(CheckDatabaseSuccess { data, _success }) => {
-            if let Err(e) = c.cdn_download(data.server_path, youtube_url.id).await {
-                return Err(format!("error: {}", e).into());
+        match res {
+            Ok(file) => {
+                let track = track(&name, Some(part.server_path), file, part.title);
+                let (size, title) = saved(c.analyze(track).await);
+                return Ok(conversion(&name, Outcome::Saved, size, title, true));
             }
+            Err(e) if e.is_transient() => return Err(e),
+            Err(e) => eprintln!("info: could not resume ({}), converting again", e.value),
+        }
+    }
+
+    let checkdb_res = c
+        .step(
+            id,
+            Step::CheckDatabase,
+            c.check_database(id.to_string(), quality),
+        )
+        .await?;
+
+    let (server_path, title, from_database) = match checkdb_res {
+        ResponseCheckDatabase::Exist(CheckDatabaseSuccess { data, _success }) => {
+            c.source(id, Source::Database);
+            (data.server_path, data._title, true)
         }
         ResponseCheckDatabase::NoExist(CheckDatabaseFail { _success, error }) => {
             eprintln!("info: {}", error);
-            let gvd_res = c.cdn_fetch(youtube_url.url.clone()).await?;
-
-            let title = match gvd_res {
-                ResponseGetVideoData::Success(GetVideoDataSuccess { title, _success }) => title,
-                ResponseGetVideoData::Fail(GetVideoDataFail { error, _success }) => {
-                    return Err(Error {
-                        kind: ErrorKind::CNVResponseError,
-                        value: format!("get_video_data.php failed: {}", error),
-                    });
-                }
-            };
+            c.source(id, Source::Conversion);
 
-            let dv_res = c
-                .srv_download(youtube_url.url, title.clone(), quality)
+            let title = c
+                .step(id, Step::GetVideoData, async {
+                    match c.cdn_fetch(youtube_url.url.clone()).await? {
+                        ResponseGetVideoData::Success(GetVideoDataSuccess { title, _success }) => {
+                            Ok(title)
+                        }
+                        ResponseGetVideoData::Fail(GetVideoDataFail { error, _success }) => {
+                            Err(Error {
+                                kind: ErrorKind::CNVResponseError,
+                                value: format!("get_video_data.php failed: {}", error),
+                            })
+                        }
+                    }
+                })
                 .await?;
 
-            let dl_link = match dv_res {
-                ResponseDownloadVideo::Success(DownloadVideoSuccess {
-                    download_link,
-                    _success,
-                }) => download_link,
-                ResponseDownloadVideo::Fail(DownloadVideoFail {
-                    error,
-                    error_type,
-                    _success,
-                }) => {
-                    return Err(Error {
-                        kind: ErrorKind::CNVResponseError,
-                        value: format!("download_video.php failed: {} {}", error_type, error),
-                    });
-                }
-            };
+            let dl_link = c
+                .step(id, Step::DownloadVideo, async {
+                    let dv_res = c
+                        .srv_download(youtube_url.url.clone(), title.clone(), quality)
+                        .await?;
 
-            let dl_res = c
-                .cdn_insert(dl_link.clone(), title, youtube_url.id.clone(), quality)
+                    match dv_res {
+                        ResponseDownloadVideo::Success(DownloadVideoSuccess {
+                            download_link,
+                            _success,
+                        }) => Ok(download_link),
+                        ResponseDownloadVideo::Fail(DownloadVideoFail {
+                            error,
+                            error_type,
+                            _success,
+                        }) => Err(Error {
+                            kind: ErrorKind::CNVResponseError,
+                            value: format!("download_video.php failed: {} {}", error_type, error),
+                        }),
+                    }
+                })
                 .await?;
 
-            match dl_res {
-                ResponseInsertToDatabase::Success(InsertToDatabaseSuccess {
-                    message,
-                    _success,
-                }) => {
-                    eprintln!("info: {}", message);
-                }
-                ResponseInsertToDatabase::Fail(InsertToDatabaseFail { error, _success }) => {
-                    return Err(Error {
-                        kind: ErrorKind::CNVResponseError,
-                        value: format!("insert_to_database.php failed: {}", error),
-                    });
+            c.step(id, Step::InsertToDatabase, async {
+                let dl_res = c
+                    .cdn_insert(dl_link.clone(), title.clone(), id.to_string(), quality)
+                    .await?;
+
+                match dl_res {
+                    ResponseInsertToDatabase::Success(InsertToDatabaseSuccess {
+                        message,
+                        _success,
+                    }) => {
+                        eprintln!("info: {}", message);
+                        Ok(())
+                    }
+                    ResponseInsertToDatabase::Fail(InsertToDatabaseFail { error, _success }) => {
+                        Err(Error {
+                            kind: ErrorKind::CNVResponseError,
+                            value: format!("insert_to_database.php failed: {}", error),
+                        })
+                    }
                 }
-            }
+            })
+            .await?;
 
-            if let Err(e) = c.cdn_download(dl_link, youtube_url.id).await {
-                return Err(format!("error: {}", e).into());
-            }
+            (dl_link, title, false)
         }
     };
 
-    Ok(())
+    let parsed = c.title_rules.parse(Some(id), &title);
+    let name = c.choose_name(&metadata(Some(&parsed))).await?;
+    if let Some(size) = c.saved_size(&name).await? {
+        return Ok(cached(&name, size, Some(title)));
+    }
+
+    let tag = id3_tag(Some(&title));
+    let file = c
+        .step(
+            id,
+            Step::Download,
+            c.cdn_download(
+                server_path.clone(),
+                id.to_string(),
+                &name,
+                Some(&title),
+                tag.as_deref(),
+            ),
+        )
+        .await?;
+    let track = track(&name, Some(server_path), file, Some(title));
+    let (size, title) = saved(c.analyze(track).await);
+
+    Ok(conversion(
+        &name,
+        Outcome::Saved,
+        size,
+        title,
+        from_database,
+    ))
 }
 
 #[cfg(test)]
 mod tests {
+    use super::cassette::{RecordingTransport, ReplayTransport};
+    use super::fake::{FakeTransport, MP3_SAMPLE};
+    use super::transport::ReqwestTransport;
     use super::*;
+    use crate::id3::replace_tag;
+    use crate::storage::SftpStorage;
+    use infer::audio::is_mp3;
+    use sha2::{Digest, Sha256};
+
+    const VIDEO: &str = "https://www.youtube.com/watch?v=yPvoKz6tyJs";
+    const DOWNLOAD_LINK: &str = "https://cdn.example.com/download.php?file=yPvoKz6tyJs";
+
+    fn client<T: Transport>(transport: T, out_dir: &tempfile::TempDir) -> CNVClient<T> {
+        CNVClient::new(transport)
+            .with_base_url(Url::parse("https://cnvmp3.test/").unwrap())
+            .with_out_dir(out_dir.path().to_path_buf())
+            .with_retry(RetryConfig::never())
+    }
+
+    /// Retries every step up to three times without waiting in between
+    fn fast_retry() -> RetryConfig {
+        RetryConfig {
+            default: RetryPolicy {
+                attempts: 3,
+                base_delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+                jitter: 0.0,
+            },
+            steps: Default::default(),
+        }
+    }
+
+    fn status(status: u16, headers: &[(&str, &str)]) -> Result<HttpResponse, Error> {
+        Ok(HttpResponse {
+            status,
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            body: b"<html>oops</html>".to_vec(),
+        })
+    }
+
+    fn no_exist() -> &'static str {
+        r#"{"success":false,"error":"No entry found"}"#
+    }
+
+    fn video_data() -> &'static str {
+        r#"{"success":true,"title":"Some Artist - Some Track"}"#
+    }
+
+    fn download_video() -> String {
+        format!(r#"{{"success":true,"download_link":"{}"}}"#, DOWNLOAD_LINK)
+    }
+
+    fn inserted() -> &'static str {
+        r#"{"success":true,"message":"Inserted"}"#
+    }
+
+    async fn run<T: Transport>(c: &CNVClient<T>) -> Result<Conversion, Error> {
+        convert(c, Url::parse(VIDEO).unwrap(), BitRate::Kbps96).await
+    }
+
+    #[tokio::test]
+    async fn test_y2mp3() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new()
+            .respond("/check_database.php", no_exist())
+            .respond("/get_video_data.php", video_data())
+            .respond("/download_video.php", download_video())
+            .respond("/insert_to_database.php", inserted())
+            .respond("/download.php", MP3_SAMPLE);
+        let c = client(transport, &dir);
+
+        let result = run(&c).await;
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(
+            c.transport.paths(),
+            vec![
+                "/check_database.php",
+                "/get_video_data.php",
+                "/download_video.php",
+                "/insert_to_database.php",
+                "/download.php",
+            ]
+        );
+        assert_eq!(
+            std::fs::read(c.mp3_path("yPvoKz6tyJs")).unwrap(),
+            MP3_SAMPLE
+        );
+
+        let requests = c.transport.requests();
+        let payload: serde_json::Value =
+            serde_json::from_slice(requests[0].body.as_ref().unwrap()).unwrap();
+        assert_eq!(payload["youtube_id"], "yPvoKz6tyJs");
+        assert_eq!(requests[4].url.as_str(), DOWNLOAD_LINK);
+    }
+
+    #[tokio::test]
+    async fn test_y2mp3_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        let exist = format!(
+            r#"{{"success":true,"data":{{"id":1,"youtube_id":"yPvoKz6tyJs","server_path":"{}","quality":"5","title":"Some Artist - Some Track"}}}}"#,
+            DOWNLOAD_LINK
+        );
+        let transport = FakeTransport::new()
+            .respond("/check_database.php", exist)
+            .respond("/download.php", MP3_SAMPLE);
+        let c = client(transport, &dir);
+
+        assert!(run(&c).await.is_ok());
+        assert_eq!(
+            c.transport.paths(),
+            vec!["/check_database.php", "/download.php"]
+        );
+        assert!(c.mp3_path("yPvoKz6tyJs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_y2mp3_saved_locally() {
+        let dir = tempfile::tempdir().unwrap();
+        let c = client(FakeTransport::new(), &dir);
+        std::fs::write(c.mp3_path("yPvoKz6tyJs"), MP3_SAMPLE).unwrap();
+
+        assert!(run(&c).await.is_ok());
+        assert!(c.transport.paths().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_y2mp3_get_video_data_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new()
+            .respond("/check_database.php", no_exist())
+            .respond(
+                "/get_video_data.php",
+                r#"{"success":false,"error":"Video unavailable"}"#,
+            );
+        let c = client(transport, &dir);
+
+        let err = run(&c).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::CNVResponseError));
+        assert!(err.value.contains("Video unavailable"));
+        assert!(!c.mp3_path("yPvoKz6tyJs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_y2mp3_download_video_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new()
+            .respond("/check_database.php", no_exist())
+            .respond("/get_video_data.php", video_data())
+            .respond(
+                "/download_video.php",
+                r#"{"success":false,"errorType":3,"error":"Video too long"}"#,
+            );
+        let c = client(transport, &dir);
+
+        let err = run(&c).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::CNVResponseError));
+        assert!(err.value.contains("3 Video too long"));
+        assert!(!c.mp3_path("yPvoKz6tyJs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_y2mp3_insert_to_database_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new()
+            .respond("/check_database.php", no_exist())
+            .respond("/get_video_data.php", video_data())
+            .respond("/download_video.php", download_video())
+            .respond(
+                "/insert_to_database.php",
+                r#"{"success":false,"error":"Duplicate entry"}"#,
+            );
+        let c = client(transport, &dir);
+
+        let err = run(&c).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::CNVResponseError));
+        assert!(err.value.contains("Duplicate entry"));
+        assert!(!c.transport.paths().contains(&"/download.php".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_y2mp3_not_mp3() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new()
+            .respond("/check_database.php", no_exist())
+            .respond("/get_video_data.php", video_data())
+            .respond("/download_video.php", download_video())
+            .respond("/insert_to_database.php", inserted())
+            .respond("/download.php", "<html>rate limited</html>");
+        let c = client(transport, &dir);
+
+        let err = run(&c).await.unwrap_err();
+        assert!(err.value.contains("not an mp3 file"));
+        assert!(!c.mp3_path("yPvoKz6tyJs").exists());
+        assert!(!c.part_path("yPvoKz6tyJs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_too_large() {
+        let dir = tempfile::tempdir().unwrap();
+        let advertised = Ok(HttpResponse {
+            status: 200,
+            headers: vec![("Content-Length".to_string(), "1000000".to_string())],
+            body: MP3_SAMPLE.to_vec(),
+        });
+        let transport = FakeTransport::new()
+            .respond_with("/download.php", advertised)
+            .respond("/download.php", MP3_SAMPLE);
+        let c = client(transport, &dir)
+            .with_retry(fast_retry())
+            .with_max_size(Some(10));
+
+        // refused up front because of `Content-Length`, and again once 10 bytes have streamed in
+        for _ in 0..2 {
+            let err = c
+                .cdn_download(
+                    DOWNLOAD_LINK.to_string(),
+                    String::from("yPvoKz6tyJs"),
+                    "yPvoKz6tyJs.mp3",
+                    None,
+                    None,
+                )
+                .await
+                .unwrap_err();
+            assert!(matches!(err.kind, ErrorKind::TooLarge));
+            assert!(!c.mp3_path("yPvoKz6tyJs").exists());
+            assert!(!c.part_path("yPvoKz6tyJs").exists());
+        }
+        assert_eq!(c.transport.paths().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_y2mp3_transport_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let c = client(FakeTransport::new(), &dir);
+
+        let err = run(&c).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::ReqwestError));
+    }
+
+    #[tokio::test]
+    async fn test_y2mp3_malformed_response() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new().respond("/check_database.php", "<html></html>");
+        let c = client(transport, &dir);
+
+        let err = run(&c).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::MalformedResponse));
+    }
+
+    #[tokio::test]
+    async fn test_y2mp3_mock_server() {
+        let base_url = crate::mock::spawn(crate::mock::MockConfig::default()).await;
+        let transport = ReqwestTransport::new(reqwest::Client::new());
+        let first = tempfile::tempdir().unwrap();
+        let c = CNVClient::new(transport)
+            .with_base_url(base_url.clone())
+            .with_out_dir(first.path().to_path_buf());
+
+        assert!(run(&c).await.is_ok());
+        assert!(is_mp3(&std::fs::read(c.mp3_path("yPvoKz6tyJs")).unwrap()));
+
+        // the insert step should have made the video available straight from the database
+        let second = tempfile::tempdir().unwrap();
+        let c = c.with_out_dir(second.path().to_path_buf());
+        let res = c
+            .check_database(String::from("yPvoKz6tyJs"), BitRate::Kbps96)
+            .await
+            .unwrap();
+        assert!(matches!(res, ResponseCheckDatabase::Exist(_)));
+        assert!(run(&c).await.is_ok());
+        assert!(c.mp3_path("yPvoKz6tyJs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_y2mp3_mock_server_truncated() {
+        let base_url = crate::mock::spawn(crate::mock::MockConfig {
+            truncate: vec![Step::Download],
+            ..Default::default()
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let c = CNVClient::new(ReqwestTransport::new(reqwest::Client::new()))
+            .with_base_url(base_url)
+            .with_out_dir(dir.path().to_path_buf())
+            .with_retry(RetryConfig::never());
+
+        assert!(run(&c).await.is_err());
+        assert!(!c.mp3_path("yPvoKz6tyJs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_cassette_round_trip() {
+        let cassette = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new()
+            .respond("/check_database.php", no_exist())
+            .respond("/get_video_data.php", video_data())
+            .respond("/download_video.php", download_video())
+            .respond("/insert_to_database.php", inserted())
+            .respond("/download.php", MP3_SAMPLE);
+
+        let recorded = tempfile::tempdir().unwrap();
+        let recorder = RecordingTransport::new(transport, cassette.path()).unwrap();
+        assert!(run(&client(recorder, &recorded)).await.is_ok());
+
+        let replayed = tempfile::tempdir().unwrap();
+        let c = client(ReplayTransport::new(cassette.path()).unwrap(), &replayed);
+        assert!(run(&c).await.is_ok());
+        assert_eq!(
+            std::fs::read(c.mp3_path("yPvoKz6tyJs")).unwrap(),
+            std::fs::read(recorded.path().join("yPvoKz6tyJs.mp3")).unwrap()
+        );
+
+        // everything recorded has been replayed
+        let err = c
+            .check_database(String::from("yPvoKz6tyJs"), BitRate::Kbps96)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::CassetteError));
+    }
+
+    #[tokio::test]
+    async fn test_cassette_download_video_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = ReplayTransport::new("tests/cassettes/mock_download_video_fail").unwrap();
+        let c = client(transport, &dir);
+
+        let err = run(&c).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::CNVResponseError));
+        assert!(err.value.contains("1 Conversion failed"));
+    }
+
+    #[tokio::test]
+    async fn test_cassette_payload_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = ReplayTransport::new("tests/cassettes/mock_download_video_fail").unwrap();
+        let c = client(transport, &dir);
+
+        let err = convert(&c, Url::parse(VIDEO).unwrap(), BitRate::Kbps320)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::CassetteError));
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new()
+            .respond_with("/check_database.php", status(503, &[("Retry-After", "0")]))
+            .respond_with(
+                "/check_database.php",
+                Err(Error {
+                    kind: ErrorKind::ConnectionError,
+                    value: String::from("connection reset by peer"),
+                }),
+            )
+            .respond("/check_database.php", no_exist())
+            .respond("/get_video_data.php", "<html>502 Bad Gateway</html>")
+            .respond("/get_video_data.php", video_data())
+            .respond("/download_video.php", download_video())
+            .respond("/insert_to_database.php", inserted())
+            .respond_with("/download.php", status(500, &[]))
+            .respond("/download.php", MP3_SAMPLE);
+        let c = client(transport, &dir).with_retry(fast_retry());
+
+        let result = run(&c).await;
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(c.transport.paths().len(), 9);
+        assert!(c.mp3_path("yPvoKz6tyJs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new()
+            .respond_with("/check_database.php", status(429, &[]))
+            .respond_with("/check_database.php", status(429, &[]))
+            .respond_with("/check_database.php", status(429, &[]));
+        let c = client(transport, &dir).with_retry(fast_retry());
+
+        let err = run(&c).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::ServerError));
+        assert!(err.is_transient());
+        assert_eq!(c.transport.paths().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_per_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new()
+            .respond_with("/check_database.php", status(500, &[]))
+            .respond_with("/check_database.php", status(500, &[]));
+        let mut retry = fast_retry();
+        retry.steps.insert(
+            Step::CheckDatabase,
+            RetryPolicy {
+                attempts: 2,
+                ..retry.default
+            },
+        );
+        let c = client(transport, &dir).with_retry(retry);
+
+        assert!(run(&c).await.is_err());
+        assert_eq!(c.transport.paths().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_permanent() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new()
+            .respond("/check_database.php", no_exist())
+            .respond(
+                "/get_video_data.php",
+                r#"{"success":false,"error":"Private video"}"#,
+            );
+        let c = client(transport, &dir).with_retry(fast_retry());
+
+        let err = run(&c).await.unwrap_err();
+        assert!(!err.is_transient());
+        assert_eq!(c.transport.paths().len(), 2);
+
+        let transport = FakeTransport::new()
+            .respond(
+                "/check_database.php",
+                format!(
+                    r#"{{"success":true,"data":{{"id":1,"youtube_id":"yPvoKz6tyJs","server_path":"{}","quality":"5","title":"t"}}}}"#,
+                    DOWNLOAD_LINK
+                ),
+            )
+            .respond_with("/download.php", status(404, &[]));
+        let c = client(transport, &dir).with_retry(fast_retry());
+
+        let err = run(&c).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::ClientError));
+        assert_eq!(c.transport.paths().len(), 2);
+
+        // a page rather than an mp3 file will not become one
+        let transport = converted()
+            .respond("/download.php", "<html>not found</html>")
+            .respond("/download.php", MP3_SAMPLE);
+        let c = client(transport, &dir).with_retry(fast_retry());
+
+        let err = run(&c).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::UnexpectedContent));
+        assert_eq!(c.transport.paths().len(), 5);
+
+        let url = Url::parse("https://www.youtube.com/invalid/invalid").unwrap();
+        let err = convert(&c, url, BitRate::Kbps96).await.unwrap_err();
+        assert!(!err.is_transient());
+    }
+
+    fn mp3(status: u16, headers: &[(&str, &str)], body: &[u8]) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            body: body.to_vec(),
+        }
+    }
+
+    /// Runs the whole protocol with the download dropping after 7 bytes, leaving a `.part` behind
+    async fn interrupt(dir: &tempfile::TempDir) {
+        let transport = FakeTransport::new()
+            .respond("/check_database.php", no_exist())
+            .respond("/get_video_data.php", video_data())
+            .respond("/download_video.php", download_video())
+            .respond("/insert_to_database.php", inserted())
+            .respond_cut(
+                "/download.php",
+                mp3(
+                    200,
+                    &[("ETag", "\"v1\""), ("Content-Length", "14")],
+                    MP3_SAMPLE,
+                ),
+                7,
+            );
+        let c = client(transport, dir);
+
+        let err = run(&c).await.unwrap_err();
+        assert!(err.is_transient());
+        assert!(!c.mp3_path("yPvoKz6tyJs").exists());
+        assert_eq!(
+            std::fs::read(c.part_path("yPvoKz6tyJs")).unwrap(),
+            &MP3_SAMPLE[..7]
+        );
+        assert!(c.interrupted("yPvoKz6tyJs").is_some());
+    }
+
+    fn header<'a>(request: &'a HttpRequest, name: &str) -> Option<&'a str> {
+        request
+            .headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[tokio::test]
+    async fn test_resume_interrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        interrupt(&dir).await;
+
+        let transport = FakeTransport::new().respond_with(
+            "/download.php",
+            Ok(mp3(
+                206,
+                &[("ETag", "\"v1\""), ("Content-Range", "bytes 7-13/14")],
+                &MP3_SAMPLE[7..],
+            )),
+        );
+        let library = Library::open(dir.path().join(library::INDEX_NAME)).unwrap();
+        let c = client(transport, &dir)
+            .with_library(library)
+            .with_id3(Some(Version::V2_4));
+
+        let result = run(&c).await;
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(c.transport.paths(), vec!["/download.php"]);
+
+        // the title cnvmp3 told before the interruption is not lost
+        let title = Some(String::from("Some Artist - Some Track"));
+        assert_eq!(result.unwrap().title, title);
+        assert_eq!(c.indexed("yPvoKz6tyJs").unwrap().title, title);
+        let mp3 = std::fs::read(c.mp3_path("yPvoKz6tyJs")).unwrap();
+        assert_eq!(Tag::read(&mp3).unwrap().title(), Some("Some Track"));
+
+        let request = &c.transport.requests()[0];
+        assert_eq!(request.url.as_str(), DOWNLOAD_LINK);
+        assert_eq!(header(request, "Range"), Some("bytes=7-"));
+        assert_eq!(header(request, "If-Range"), Some("\"v1\""));
+
+        assert_eq!(replace_tag(&mp3, &[]), replace_tag(MP3_SAMPLE, &[]));
+        assert!(!c.part_path("yPvoKz6tyJs").exists());
+        assert!(!c.part_info_path("yPvoKz6tyJs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_resume_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        interrupt(&dir).await;
+
+        // the file changed on the CDN, so it is sent in full
+        let transport = FakeTransport::new().respond_with(
+            "/download.php",
+            Ok(mp3(200, &[("ETag", "\"v2\"")], MP3_SAMPLE)),
+        );
+        let c = client(transport, &dir);
+
+        assert!(run(&c).await.is_ok());
+        assert_eq!(c.transport.paths(), vec!["/download.php"]);
+        assert_eq!(
+            std::fs::read(c.mp3_path("yPvoKz6tyJs")).unwrap(),
+            MP3_SAMPLE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resume_bad_range() {
+        let dir = tempfile::tempdir().unwrap();
+        interrupt(&dir).await;
+
+        let transport = FakeTransport::new()
+            .respond_with(
+                "/download.php",
+                Ok(mp3(
+                    206,
+                    &[("ETag", "\"v1\""), ("Content-Range", "bytes 0-13/14")],
+                    MP3_SAMPLE,
+                )),
+            )
+            .respond("/download.php", MP3_SAMPLE);
+        let c = client(transport, &dir);
+
+        assert!(run(&c).await.is_ok());
+
+        let requests = c.transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(header(&requests[1], "Range"), None);
+        assert_eq!(
+            std::fs::read(c.mp3_path("yPvoKz6tyJs")).unwrap(),
+            MP3_SAMPLE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resume_within_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new()
+            .respond("/check_database.php", no_exist())
+            .respond("/get_video_data.php", video_data())
+            .respond("/download_video.php", download_video())
+            .respond("/insert_to_database.php", inserted())
+            .respond_cut(
+                "/download.php",
+                mp3(200, &[("Content-Length", "14")], MP3_SAMPLE),
+                10,
+            )
+            .respond_with(
+                "/download.php",
+                Ok(mp3(
+                    206,
+                    &[("Content-Range", "bytes 10-13/14")],
+                    &MP3_SAMPLE[10..],
+                )),
+            );
+        let c = client(transport, &dir).with_retry(fast_retry());
+
+        let result = run(&c).await;
+        assert!(result.is_ok(), "{:?}", result);
+
+        let requests = c.transport.requests();
+        assert_eq!(requests.len(), 6);
+        assert_eq!(header(&requests[5], "Range"), Some("bytes=10-"));
+        assert_eq!(header(&requests[5], "If-Range"), None);
+        assert_eq!(
+            std::fs::read(c.mp3_path("yPvoKz6tyJs")).unwrap(),
+            MP3_SAMPLE
+        );
+    }
+
+    /// Transport running the protocol up to the point where `/download.php` is asked for
+    fn converted() -> FakeTransport {
+        FakeTransport::new()
+            .respond("/check_database.php", no_exist())
+            .respond("/get_video_data.php", video_data())
+            .respond("/download_video.php", download_video())
+            .respond("/insert_to_database.php", inserted())
+    }
+
+    #[tokio::test]
+    async fn test_segmented_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = converted().serve_file("/download.php", MP3_SAMPLE);
+        let c = client(transport, &dir)
+            .with_segments(3)
+            .with_min_segment_len(4);
+
+        let result = run(&c).await;
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(
+            std::fs::read(c.mp3_path("yPvoKz6tyJs")).unwrap(),
+            MP3_SAMPLE
+        );
+        assert!(!c.part_path("yPvoKz6tyJs").exists());
+
+        let mut ranges: Vec<_> = c.transport.requests()[4..]
+            .iter()
+            .map(|r| header(r, "Range").unwrap().to_string())
+            .collect();
+        ranges.sort();
+        assert_eq!(
+            ranges,
+            vec!["bytes=0-0", "bytes=0-4", "bytes=10-13", "bytes=5-9"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_segmented_download_small_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = converted().serve_file("/download.php", MP3_SAMPLE);
+        let c = client(transport, &dir).with_segments(4);
+
+        assert!(run(&c).await.is_ok());
+
+        // probed, then fetched in one piece
+        let requests = c.transport.requests();
+        assert_eq!(requests.len(), 6);
+        assert_eq!(header(&requests[5], "Range"), None);
+    }
+
+    #[tokio::test]
+    async fn test_segmented_download_fallback() {
+        // the CDN ignores `Range`, or does not know how large the file is
+        for probe in [
+            mp3(200, &[], MP3_SAMPLE),
+            mp3(206, &[("Content-Range", "bytes 0-0/*")], &MP3_SAMPLE[..1]),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let transport = converted()
+                .respond_with("/download.php", Ok(probe))
+                .respond("/download.php", MP3_SAMPLE);
+            let c = client(transport, &dir)
+                .with_segments(3)
+                .with_min_segment_len(4);
+
+            let result = run(&c).await;
+            assert!(result.is_ok(), "{:?}", result);
+            assert_eq!(c.transport.paths().len(), 6);
+            assert_eq!(
+                std::fs::read(c.mp3_path("yPvoKz6tyJs")).unwrap(),
+                MP3_SAMPLE
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_segmented_download_not_mp3() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = converted().serve_file("/download.php", "<html>rate limited</html>");
+        let c = client(transport, &dir)
+            .with_segments(3)
+            .with_min_segment_len(4);
+
+        let err = run(&c).await.unwrap_err();
+        assert!(err.value.contains("not an mp3 file"));
+        assert!(!c.mp3_path("yPvoKz6tyJs").exists());
+        assert!(!c.part_path("yPvoKz6tyJs").exists());
+    }
+
+    #[tokio::test]
+    async fn test_segmented_download_mock_server() {
+        let base_url = crate::mock::spawn(crate::mock::MockConfig::default()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let c = CNVClient::new(ReqwestTransport::new(reqwest::Client::new()))
+            .with_base_url(base_url)
+            .with_out_dir(dir.path().to_path_buf())
+            .with_retry(RetryConfig::never())
+            .with_segments(4)
+            .with_min_segment_len(1024);
+
+        let result = run(&c).await;
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(
+            std::fs::read(c.mp3_path("yPvoKz6tyJs")).unwrap(),
+            crate::mock::silent_mp3(38)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch_mock_server() {
+        let base_url = crate::mock::spawn(crate::mock::MockConfig::default()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let c = CNVClient::new(ReqwestTransport::new(reqwest::Client::new()))
+            .with_base_url(base_url)
+            .with_out_dir(dir.path().to_path_buf())
+            .with_retry(RetryConfig::never());
+        std::fs::write(c.mp3_path("dQw4w9WgXcQ"), MP3_SAMPLE).unwrap();
+
+        let input = "\
+            https://www.youtube.com/watch?v=yPvoKz6tyJs
+            https://www.youtube.com/watch?v=dQw4w9WgXcQ
+            https://www.youtube.com/nonsense
+            https://www.youtube.com/shorts/9bZkp7q19f0
+            https://www.youtube.com/watch?v=yPvoKz6tyJs&t=42
+            https://www.youtube.com/embed/kJQP7kiw5Fk
+        ";
+        let summary = batch::run(&c, input, BitRate::Kbps96, 3).await;
+
+        let statuses: Vec<String> = summary
+            .entries
+            .iter()
+            .map(|e| e.status.to_string())
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                "succeeded",
+                "cached",
+                "skipped",
+                "succeeded",
+                "skipped",
+                "succeeded"
+            ]
+        );
+        for id in ["yPvoKz6tyJs", "9bZkp7q19f0", "kJQP7kiw5Fk"] {
+            assert!(is_mp3(&std::fs::read(c.mp3_path(id)).unwrap()));
+        }
+
+        let table = summary.to_string();
+        assert!(table.contains("duplicate of line 1"), "{}", table);
+        assert!(table.ends_with("3 succeeded, 1 cached, 2 skipped, 0 failed"));
+    }
+
+    #[tokio::test]
+    async fn test_batch_failures() {
+        let base_url = crate::mock::spawn(crate::mock::MockConfig {
+            fail: vec![Step::GetVideoData],
+            ..Default::default()
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let c = CNVClient::new(ReqwestTransport::new(reqwest::Client::new()))
+            .with_base_url(base_url)
+            .with_out_dir(dir.path().to_path_buf())
+            .with_retry(RetryConfig::never());
+
+        let input = "https://www.youtube.com/watch?v=yPvoKz6tyJs\nhttps://www.youtube.com/watch?v=dQw4w9WgXcQ\n";
+        let summary = batch::run(&c, input, BitRate::Kbps96, 2).await;
+
+        assert_eq!(summary.failed(), 2);
+        assert!(summary
+            .to_string()
+            .contains("get_video_data.php failed: Video unavailable"));
+    }
+
+    #[tokio::test]
+    async fn test_progress_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let (progress, mut events) = Progress::channel();
+        let transport = converted().serve_file("/download.php", MP3_SAMPLE);
+        let c = client(transport, &dir).with_progress(progress);
+
+        assert!(run(&c).await.is_ok());
+        assert_eq!(run(&c).await.unwrap().outcome, Outcome::Cached);
+        drop(c);
+
+        let mut received = Vec::new();
+        while let Some(event) = events.recv().await {
+            received.push(event);
+        }
+
+        let id = || String::from("yPvoKz6tyJs");
+        let steps: Vec<_> = received
+            .iter()
+            .filter_map(|e| match e {
+                Event::StepStarted { step, .. } => Some((*step, None)),
+                Event::StepFinished { step, ok, .. } => Some((*step, Some(*ok))),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (Step::CheckDatabase, None),
+                (Step::CheckDatabase, Some(true)),
+                (Step::GetVideoData, None),
+                (Step::GetVideoData, Some(true)),
+                (Step::DownloadVideo, None),
+                (Step::DownloadVideo, Some(true)),
+                (Step::InsertToDatabase, None),
+                (Step::InsertToDatabase, Some(true)),
+                (Step::Download, None),
+                (Step::Download, Some(true)),
+            ]
+        );
+        assert_eq!(
+            received[2],
+            Event::Source {
+                id: id(),
+                source: Source::Conversion
+            }
+        );
+        assert!(received.contains(&Event::Downloaded {
+            id: id(),
+            bytes: MP3_SAMPLE.len() as u64,
+            total: Some(MP3_SAMPLE.len() as u64),
+        }));
+
+        let tail = &received[received.len() - 3..];
+        assert_eq!(
+            tail,
+            &[
+                Event::Finished {
+                    id: id(),
+                    result: Ok(Outcome::Saved)
+                },
+                Event::Source {
+                    id: id(),
+                    source: Source::Local
+                },
+                Event::Finished {
+                    id: id(),
+                    result: Ok(Outcome::Cached)
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_progress_events_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = FakeTransport::new()
+            .respond("/check_database.php", no_exist())
+            .respond(
+                "/get_video_data.php",
+                r#"{"success":false,"error":"Video unavailable"}"#,
+            );
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let c = client(transport, &dir)
+            .with_progress(Progress::callback(move |e| sink.lock().unwrap().push(e)));
+
+        assert!(run(&c).await.is_err());
+
+        let events = events.lock().unwrap();
+        assert!(events.contains(&Event::StepFinished {
+            id: String::from("yPvoKz6tyJs"),
+            step: Step::GetVideoData,
+            ok: false,
+        }));
+        assert!(matches!(
+            events.last(),
+            Some(Event::Finished { result: Err(e), .. }) if e.contains("Video unavailable")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_progress_events_segmented() {
+        let dir = tempfile::tempdir().unwrap();
+        let (progress, mut events) = Progress::channel();
+        let transport = converted().serve_file("/download.php", MP3_SAMPLE);
+        let c = client(transport, &dir)
+            .with_segments(3)
+            .with_min_segment_len(4)
+            .with_progress(progress);
+
+        assert!(run(&c).await.is_ok());
+        drop(c);
+
+        let mut downloaded = Vec::new();
+        while let Some(event) = events.recv().await {
+            if let Event::Downloaded { bytes, total, .. } = event {
+                assert_eq!(total, Some(MP3_SAMPLE.len() as u64));
+                downloaded.push(bytes);
+            }
+        }
+        assert!(downloaded.is_sorted());
+        assert_eq!(downloaded.last(), Some(&(MP3_SAMPLE.len() as u64)));
+    }
+
+    #[tokio::test]
+    async fn test_ssh_destination() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        std::fs::create_dir(remote.path().join("incoming")).unwrap();
+        let server = crate::ssh::server::spawn(remote.path(), false).await;
+        let storage: Arc<dyn Storage> = Arc::new(SftpStorage::new(
+            server.remote("/incoming"),
+            server.config.clone(),
+        ));
+
+        // segments are not used for remote storages
+        let transport = converted().serve_file("/download.php", MP3_SAMPLE);
+        let c = client(transport, &local)
+            .with_storage(storage.clone())
+            .with_segments(3)
+            .with_min_segment_len(4);
+
+        let conversion = run(&c).await.unwrap();
+        assert_eq!(conversion.outcome, Outcome::Saved);
+        assert_eq!(conversion.size, MP3_SAMPLE.len() as u64);
+        assert_eq!(
+            conversion.path,
+            PathBuf::from(format!(
+                "ssh://dj@127.0.0.1:{}/incoming/yPvoKz6tyJs.mp3",
+                server.port
+            ))
+        );
+        assert_eq!(
+            std::fs::read(remote.path().join("incoming/yPvoKz6tyJs.mp3")).unwrap(),
+            MP3_SAMPLE
+        );
+        assert_eq!(
+            std::fs::read_dir(remote.path().join("incoming"))
+                .unwrap()
+                .count(),
+            1
+        );
+        assert_eq!(std::fs::read_dir(local.path()).unwrap().count(), 0);
+        assert_eq!(c.transport.paths().len(), 5);
+
+        // the remote copy is found without asking cnvmp3 again
+        let c = client(FakeTransport::new(), &local).with_storage(storage);
+        let conversion = run(&c).await.unwrap();
+        assert_eq!(conversion.outcome, Outcome::Cached);
+        assert_eq!(conversion.size, MP3_SAMPLE.len() as u64);
+        assert!(c.transport.paths().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_id3_tags() {
+        let tags = TrackTags {
+            id: String::from("yPvoKz6tyJs"),
+            url: String::from(VIDEO),
+            title: Some(String::from("Artist - Title")),
+            bitrate: BitRate::Kbps128,
+            downloaded_at: 1_700_000_000,
+        };
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let server = crate::ssh::server::spawn(remote.path(), false).await;
+        let storage: Arc<dyn Storage> =
+            Arc::new(SftpStorage::new(server.remote("/"), server.config.clone()));
+
+        // no tags are written unless asked for
+        assert_eq!(
+            client(FakeTransport::new(), &local).id3_tag(tags.clone()),
+            None
+        );
+
+        // tagged on disk, and on the way to a remote storage
+        for remote_storage in [false, true] {
+            let transport = FakeTransport::new().serve_file("/download.php", MP3_SAMPLE);
+            let mut c = client(transport, &local).with_id3(Some(Version::V2_3));
+            if remote_storage {
+                c = c.with_storage(storage.clone());
+            }
+            let tag = c.id3_tag(tags.clone()).unwrap();
+
+            let (size, sha256) = c
+                .cdn_download(
+                    DOWNLOAD_LINK.to_string(),
+                    String::from("yPvoKz6tyJs"),
+                    "yPvoKz6tyJs.mp3",
+                    None,
+                    Some(&tag),
+                )
+                .await
+                .unwrap();
+
+            let dir = if remote_storage { &remote } else { &local };
+            let mp3 = std::fs::read(dir.path().join("yPvoKz6tyJs.mp3")).unwrap();
+            assert_eq!(mp3, replace_tag(MP3_SAMPLE, &tag));
+            assert_eq!(size, mp3.len() as u64);
+            assert_eq!(sha256, crate::storage::hex(&Sha256::digest(&mp3)));
+            assert_eq!(
+                TrackTags::read(&Tag::read(&mp3).unwrap()),
+                Some(tags.clone())
+            );
+        }
+        assert_eq!(std::fs::read_dir(local.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_ssh_destination_not_mp3() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let server = crate::ssh::server::spawn(remote.path(), false).await;
+        let transport = converted().serve_file("/download.php", "<html>rate limited</html>");
+        let c = client(transport, &local).with_storage(Arc::new(SftpStorage::new(
+            server.remote("/"),
+            server.config.clone(),
+        )));
+
+        let err = run(&c).await.unwrap_err();
+        assert!(err.value.contains("not an mp3 file"));
+        assert_eq!(std::fs::read_dir(remote.path()).unwrap().count(), 0);
+    }
 
-    #[test]
-    fn test_y2mp3() {
-        let youtube_url = Url::parse("https://www.youtube.com/watch?v=yPvoKz6tyJs")
-            .expect("Url::parse should work");
-        let dest_type = String::from("local");
+    #[tokio::test]
+    async fn test_y2mp3_invalid_url() {
+        let dir = tempfile::tempdir().unwrap();
+        let c = client(FakeTransport::new(), &dir);
 
-        let result = y2mp3(youtube_url.clone(), dest_type.clone(), BitRate::Kbps96);
-        assert!(result.is_ok());
+        let url = Url::parse("https://www.youtube.com/invalid/invalid").unwrap();
+        let err = convert(&c, url, BitRate::Kbps96).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidURL));
+        assert!(c.transport.paths().is_empty());
     }
 }
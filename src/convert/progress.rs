@@ -0,0 +1,68 @@
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use super::{Outcome, Step};
+
+/// Where the MP3 file of a conversion comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    /// It had already been saved (locally or to the remote destination), nothing is downloaded
+    Local,
+    /// cnvmp3 had converted it before and only the download is left
+    Database,
+    /// cnvmp3 has to convert it first
+    Conversion,
+}
+
+/// Something that happened during a conversion; every event names the YouTube ID it is about, so
+/// that concurrent conversions can be told apart
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Where the MP3 file is going to come from
+    Source { id: String, source: Source },
+    /// A protocol step is about to be sent (retries included)
+    StepStarted { id: String, step: Step },
+    /// A protocol step is over; `ok` is false if it failed even after retrying
+    StepFinished { id: String, step: Step, ok: bool },
+    /// `bytes` of the MP3 file have been downloaded so far, out of `total` if known
+    Downloaded {
+        id: String,
+        bytes: u64,
+        total: Option<u64>,
+    },
+    /// The conversion is over, either with its outcome or with why it failed
+    Finished {
+        id: String,
+        result: Result<Outcome, String>,
+    },
+}
+
+/// Where progress events of a conversion are sent; by default, nowhere
+#[derive(Clone, Default)]
+pub struct Progress(Option<Arc<dyn Fn(Event) + Send + Sync>>);
+
+impl Progress {
+    /// Calls `f` with every event, from whichever task the event happened on
+    pub fn callback(f: impl Fn(Event) + Send + Sync + 'static) -> Self {
+        Progress(Some(Arc::new(f)))
+    }
+
+    /// Sends every event into the returned channel; events are discarded once the receiver is
+    /// dropped
+    pub fn channel() -> (Self, mpsc::UnboundedReceiver<Event>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        (
+            Progress::callback(move |event| {
+                let _ = tx.send(event);
+            }),
+            rx,
+        )
+    }
+
+    pub(super) fn emit(&self, event: Event) {
+        if let Some(f) = &self.0 {
+            f(event);
+        }
+    }
+}
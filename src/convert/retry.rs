@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+use super::Step;
+use crate::error::{Error, ErrorKind};
+
+/// How often, and how patiently, a single protocol step is retried after a transient failure
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub attempts: u32,
+    /// Delay before the second attempt; doubled for every attempt after that
+    pub base_delay: Duration,
+    /// Upper bound for the delay, whether exponential or asked for by `Retry-After`
+    pub max_delay: Duration,
+    /// Fraction (0.0 to 1.0) by which each delay is randomly shortened or lengthened
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay to wait after failed attempt number `attempt` (starting at 1)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return exp;
+        }
+
+        let factor = 1.0 + jitter * (2.0 * rand::random::<f64>() - 1.0);
+
+        exp.mul_f64(factor)
+    }
+
+    /// Delay to wait after failed attempt number `attempt`: what the server asked for with
+    /// `Retry-After`, if it did, but no more than `max_delay`
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(retry_after) => retry_after.min(self.max_delay),
+            None => self.backoff(attempt),
+        }
+    }
+}
+
+/// Retry policies for every protocol step: a default plus per-step overrides
+#[derive(Clone, Debug, Default)]
+pub struct RetryConfig {
+    pub default: RetryPolicy,
+    pub steps: HashMap<Step, RetryPolicy>,
+}
+
+impl RetryConfig {
+    /// Configuration that never retries anything
+    #[cfg(test)]
+    pub fn never() -> Self {
+        RetryConfig {
+            default: RetryPolicy {
+                attempts: 1,
+                ..Default::default()
+            },
+            steps: HashMap::new(),
+        }
+    }
+
+    /// Policy in effect for `step`
+    pub fn policy(&self, step: Step) -> &RetryPolicy {
+        self.steps.get(&step).unwrap_or(&self.default)
+    }
+}
+
+/// Why a single attempt failed, and how long the server asked to wait before the next one
+pub struct Failed {
+    pub error: Error,
+    pub retry_after: Option<Duration>,
+}
+
+impl From<Error> for Failed {
+    fn from(error: Error) -> Self {
+        Failed {
+            error,
+            retry_after: None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Failed {
+    fn from(error: std::io::Error) -> Self {
+        Error::from(error).into()
+    }
+}
+
+/// Turns a failing HTTP `status` for `url` into an error; `429` and `503` carry the delay asked
+/// for by their `Retry-After` header
+pub fn check_status(url: &Url, status: u16, retry_after: Option<&str>) -> Result<(), Failed> {
+    let Some(kind) = ErrorKind::from_status(status) else {
+        return Ok(());
+    };
+
+    let retry_after = match status {
+        429 | 503 => retry_after.and_then(parse_retry_after),
+        _ => None,
+    };
+
+    Err(Failed {
+        error: Error {
+            kind,
+            value: format!("{} answered HTTP {}", url, status),
+        },
+        retry_after,
+    })
+}
+
+/// Runs `attempt` until it succeeds, fails permanently, or `policy` runs out of attempts
+pub async fn retrying<R, F, Fut>(policy: &RetryPolicy, step: Step, attempt: F) -> Result<R, Error>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<R, Failed>>,
+{
+    let mut n = 1;
+
+    loop {
+        let Failed { error, retry_after } = match attempt().await {
+            Ok(r) => return Ok(r),
+            Err(failed) => failed,
+        };
+
+        if !error.is_transient() || n >= policy.attempts {
+            return Err(error);
+        }
+
+        let delay = policy.delay(n, retry_after);
+        eprintln!(
+            "warning: {:?} attempt {}/{} failed ({}), retrying in {:?}",
+            step, n, policy.attempts, error.value, delay
+        );
+
+        tokio::time::sleep(delay).await;
+        n += 1;
+    }
+}
+
+/// Parses a `Retry-After` header value, which is either a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let at = httpdate::parse_http_date(value).ok()?;
+
+    Some(
+        at.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(350),
+            jitter: 0.0,
+        };
+
+        let delays: Vec<u128> = (1..=4).map(|a| policy.backoff(a).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 350, 350]);
+    }
+
+    #[test]
+    fn test_backoff_jitter() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..Default::default()
+        };
+
+        for _ in 0..100 {
+            let delay = policy.backoff(1);
+            assert!(delay >= Duration::from_millis(250) && delay <= Duration::from_millis(750));
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.0,
+        };
+
+        assert_eq!(policy.delay(2, None), Duration::from_millis(200));
+        assert_eq!(
+            policy.delay(2, Some(Duration::from_secs(5))),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(86_400))),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert!(parse_retry_after(&httpdate::fmt_http_date(
+            SystemTime::now() + Duration::from_secs(60)
+        ))
+        .is_some_and(|d| d > Duration::from_secs(50)));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+}
@@ -5,7 +5,7 @@ use crate::bitrate::BitRate;
 
 /// Payload to send to `check_database.php` endpoint
 /// Used to retrieve video metadata as described by `CheckDatabaseVideoData`
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct PayloadCheckDatabase {
     #[serde(rename = "formatValue")]
     pub format_value: usize,
@@ -14,21 +14,21 @@ pub struct PayloadCheckDatabase {
 }
 
 /// Metadata of a YouTube video as defined by cnvmp3
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct VideoData {
     #[serde(rename = "id")]
-    _id: i64,
+    pub _id: i64,
     #[serde(rename = "quality")]
-    _quality: String, // NOTE: this is a String in the response, but number in the payload
+    pub _quality: String, // NOTE: this is a String in the response, but number in the payload
     pub server_path: String,
     #[serde(rename = "title")]
-    _title: String,
+    pub _title: String,
     #[serde(rename = "youtube_id")]
-    _youtube_id: String,
+    pub _youtube_id: String,
 }
 
 /// Response schema of successfully fulfilled request to `/check_database.php`
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct CheckDatabaseSuccess {
     #[serde(rename = "success")]
     pub _success: bool,
@@ -36,7 +36,7 @@ pub struct CheckDatabaseSuccess {
 }
 
 /// Response schema of a failed request to `/check_database.php`
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct CheckDatabaseFail {
     #[serde(rename = "success")]
     pub _success: bool,
@@ -48,7 +48,7 @@ pub struct CheckDatabaseFail {
 /// When a video is not found in the cnvmp3 database
 /// `error` will describe what happened on cnvmp3's side
 /// (NoExist)
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum ResponseCheckDatabase {
     Exist(CheckDatabaseSuccess),
@@ -57,13 +57,13 @@ pub enum ResponseCheckDatabase {
 
 /// Payload to send to `get_video_data.php` endpoint
 /// Used to retrieve the title of the YouTube video
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct PayloadGetVideoData {
     pub url: Url,
 }
 
 /// Response schema upon successfully fulfilled request to `/get_video_data.php`
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct GetVideoDataSuccess {
     #[serde(rename = "success")]
     pub _success: bool,
@@ -71,7 +71,7 @@ pub struct GetVideoDataSuccess {
 }
 
 /// Response schema upon failed request to `/get_video_data.php`
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct GetVideoDataFail {
     #[serde(rename = "success")]
     pub _success: bool,
@@ -79,7 +79,7 @@ pub struct GetVideoDataFail {
 }
 
 /// Possibilities of responses to requests made to `/get_video_data.php`
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum ResponseGetVideoData {
     Success(GetVideoDataSuccess),
@@ -89,7 +89,7 @@ pub enum ResponseGetVideoData {
 /// Payload to send to `download_video.php` endpoint
 /// Used to retrieve the remote location in cnvmp3's cdn where the MP3 file
 /// is hosted
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct PayloadDownloadVideo {
     #[serde(rename = "formatValue")]
     pub format_value: usize,
@@ -99,7 +99,7 @@ pub struct PayloadDownloadVideo {
 }
 
 /// Response schema upon successfully fulfilled request to `/download_video.php`
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct DownloadVideoSuccess {
     pub download_link: String,
     #[serde(rename = "success")]
@@ -107,16 +107,17 @@ pub struct DownloadVideoSuccess {
 }
 
 /// Response schema upon failed request to `/download_video.php`
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct DownloadVideoFail {
     pub error: String,
     #[serde(rename = "errorType")]
     pub error_type: i64,
+    #[serde(rename = "success")]
     pub _success: bool,
 }
 
 /// Possibilities of responses to requests made to `/download_video.php`
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum ResponseDownloadVideo {
     Success(DownloadVideoSuccess),
@@ -125,7 +126,7 @@ pub enum ResponseDownloadVideo {
 
 /// Payload to send to `insert_to_database.php` endpoint
 /// Used as an entry into the cnvmp3 database
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct PayloadInsertToDatabase {
     #[serde(rename = "formatValue")]
     pub format_value: usize,
@@ -136,7 +137,7 @@ pub struct PayloadInsertToDatabase {
 }
 
 /// Response schema upon successfully fulfilled request to `/insert_to_database.php`
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct InsertToDatabaseSuccess {
     #[serde(rename = "success")]
     pub _success: bool,
@@ -144,7 +145,7 @@ pub struct InsertToDatabaseSuccess {
 }
 
 /// Response schema upon failed request to `/insert_to_database.php`
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct InsertToDatabaseFail {
     #[serde(rename = "success")]
     pub _success: bool,
@@ -152,7 +153,7 @@ pub struct InsertToDatabaseFail {
 }
 
 /// Possibilities of responses to requests made to `/insert_to_database.php`
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum ResponseInsertToDatabase {
     Success(InsertToDatabaseSuccess),
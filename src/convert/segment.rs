@@ -0,0 +1,247 @@
+use futures_util::future::try_join_all;
+use infer::audio::is_mp3;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use super::download::{not_mp3, parse_content_range, too_large, MAGIC_LEN};
+use super::retry::{check_status, retrying, Failed};
+use super::transport::{HttpRequest, Transport};
+use super::{CNVClient, Event, Step};
+use crate::error::{Error, ErrorKind};
+
+/// Number of connections a download is split over unless told otherwise
+pub const DEFAULT_SEGMENTS: usize = 4;
+
+/// Files are not split into pieces smaller than this (1 MiB), as the extra connections would cost
+/// more than they bring
+pub const MIN_SEGMENT_LEN: u64 = 1 << 20;
+
+/// What the CDN said about a file when asked for its first byte
+pub(super) struct Probe {
+    pub length: u64,
+    pub etag: Option<String>,
+}
+
+fn range_ignored(url: &url::Url, start: u64) -> Error {
+    Error {
+        kind: ErrorKind::UnexpectedContent,
+        value: format!("{} did not send the range starting at byte {}", url, start),
+    }
+}
+
+/// Splits `length` bytes into at most `segments` contiguous inclusive ranges of at least
+/// `min_len` bytes each
+pub(super) fn split(length: u64, segments: usize, min_len: u64) -> Vec<(u64, u64)> {
+    let n = (length / min_len.max(1)).clamp(1, segments.max(1) as u64);
+    let len = length.div_ceil(n);
+
+    (0..n)
+        .map(|i| (i * len, ((i + 1) * len).min(length) - 1))
+        .filter(|(start, end)| start <= end)
+        .collect()
+}
+
+/// Running total of the bytes downloaded by all segments of a file
+struct Downloaded<'a> {
+    id: &'a str,
+    bytes: AtomicU64,
+    total: u64,
+}
+
+impl Downloaded<'_> {
+    /// Counts `n` more bytes, returning the event reporting the new total
+    fn add(&self, n: u64) -> Event {
+        Event::Downloaded {
+            id: self.id.to_string(),
+            bytes: self.bytes.fetch_add(n, Ordering::SeqCst) + n,
+            total: Some(self.total),
+        }
+    }
+
+    /// Stops counting `n` bytes that will be downloaded again
+    fn sub(&self, n: u64) {
+        self.bytes.fetch_sub(n, Ordering::SeqCst);
+    }
+}
+
+impl<T: Transport> CNVClient<T> {
+    /// Asks for the first byte of `request` to find out whether the CDN supports `Range` requests
+    /// and how large the file is. Returns `None` when the file cannot be downloaded in segments.
+    pub(super) async fn probe(&self, request: &HttpRequest) -> Result<Option<Probe>, Failed> {
+        let mut request = request.clone();
+        request
+            .headers
+            .push(("Range".to_string(), "bytes=0-0".to_string()));
+
+        let res = self.transport.stream(request.clone()).await?;
+        check_status(&request.url, res.status, res.header("Retry-After"))?;
+
+        let length = match res.status {
+            206 => res
+                .header("Content-Range")
+                .and_then(parse_content_range)
+                .and_then(|(_, total)| total),
+            _ => None,
+        };
+
+        Ok(length.map(|length| Probe {
+            length,
+            etag: res.header("ETag").map(str::to_string),
+        }))
+    }
+
+    /// Downloads the file described by `probe` into the `.part` file of `youtube_id` over up to
+    /// `segments` connections at once, each writing its own byte range in place. Every segment is
+    /// retried on its own; the first segment is checked to be the start of an MP3 file.
+    pub(super) async fn download_segments(
+        &self,
+        request: &HttpRequest,
+        youtube_id: &str,
+        probe: Probe,
+    ) -> Result<u64, Error> {
+        if let Some(max_size) = self.max_size {
+            if probe.length > max_size {
+                return Err(too_large(probe.length, max_size));
+            }
+        }
+
+        let part = self.part_path(youtube_id);
+        let file = tokio::fs::File::create(&part).await?;
+        file.set_len(probe.length).await?;
+
+        let ranges = split(probe.length, self.segments, self.min_segment_len);
+        eprintln!(
+            "info: downloading {} bytes in {} segments",
+            probe.length,
+            ranges.len()
+        );
+
+        let (part, etag) = (part.as_path(), probe.etag.as_deref());
+        let policy = self.retry.policy(Step::Download);
+        let downloaded = &Downloaded {
+            id: youtube_id,
+            bytes: AtomicU64::new(0),
+            total: probe.length,
+        };
+
+        try_join_all(ranges.into_iter().map(|(start, end)| {
+            retrying(policy, Step::Download, move || async move {
+                let mut written = 0;
+                let res = self
+                    .fetch_segment(request, part, (start, end), etag, |n| {
+                        written += n;
+                        self.progress.emit(downloaded.add(n));
+                    })
+                    .await;
+
+                // a retried segment starts over
+                if res.is_err() {
+                    downloaded.sub(written);
+                }
+
+                res
+            })
+        }))
+        .await?;
+
+        Ok(probe.length)
+    }
+
+    /// Fetches bytes `start..=end` of `request` and writes them at the same offset into `part`,
+    /// calling `written_chunk` with the size of every chunk written
+    async fn fetch_segment(
+        &self,
+        request: &HttpRequest,
+        part: &Path,
+        (start, end): (u64, u64),
+        etag: Option<&str>,
+        mut written_chunk: impl FnMut(u64),
+    ) -> Result<(), Failed> {
+        let mut request = request.clone();
+        request
+            .headers
+            .push(("Range".to_string(), format!("bytes={}-{}", start, end)));
+
+        // a changed file is sent in full instead, which is then refused below
+        if let Some(etag) = etag {
+            request
+                .headers
+                .push(("If-Range".to_string(), etag.to_string()));
+        }
+
+        let mut res = self.transport.stream(request.clone()).await?;
+        check_status(&request.url, res.status, res.header("Retry-After"))?;
+
+        let in_place = res.status == 206
+            && res
+                .header("Content-Range")
+                .and_then(parse_content_range)
+                .is_some_and(|(s, _)| s == start);
+        if !in_place {
+            return Err(range_ignored(&request.url, start).into());
+        }
+
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(part).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+
+        let len = end - start + 1;
+        let mut head = Vec::with_capacity(MAGIC_LEN);
+        let mut written = 0;
+
+        loop {
+            let chunk = match res.body.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    file.flush().await?;
+                    return Err(e.into());
+                }
+            };
+
+            written += chunk.len() as u64;
+            if written > len {
+                return Err(range_ignored(&request.url, start).into());
+            }
+
+            if start == 0 && head.len() < MAGIC_LEN {
+                head.extend_from_slice(&chunk);
+                if head.len() >= MAGIC_LEN && !is_mp3(&head) {
+                    return Err(not_mp3().into());
+                }
+            }
+
+            file.write_all(&chunk).await?;
+            written_chunk(chunk.len() as u64);
+        }
+
+        file.sync_all().await?;
+
+        if written < len {
+            return Err(Error {
+                kind: ErrorKind::ConnectionError,
+                value: format!(
+                    "segment at byte {} ended after {} of {} bytes",
+                    start, written, len
+                ),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split(10, 4, 1), vec![(0, 2), (3, 5), (6, 8), (9, 9)]);
+        assert_eq!(split(10, 4, 4), vec![(0, 4), (5, 9)]);
+        assert_eq!(split(10, 4, 100), vec![(0, 9)]);
+        assert_eq!(split(10, 1, 1), vec![(0, 9)]);
+    }
+}
@@ -0,0 +1,187 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::error::{Error, ErrorKind};
+
+/// HTTP methods used by the cnvmp3 protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Method {
+    Get,
+    Post,
+}
+
+/// A single request as issued by `CNVClient`
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: Url,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+}
+
+/// A fully read response to an `HttpRequest`
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Value of the first header called `name` (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+/// The body of a `StreamingResponse`, handed over as it arrives
+#[async_trait]
+pub trait BodyStream: Send {
+    /// Next chunk of the body, or `None` once the body has been read completely
+    async fn chunk(&mut self) -> Result<Option<Vec<u8>>, Error>;
+}
+
+/// A response whose body has not been read yet
+pub struct StreamingResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Box<dyn BodyStream>,
+}
+
+impl StreamingResponse {
+    /// Value of the first header called `name` (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// A body that was read in full before being handed over
+struct BufferedBody(Option<Vec<u8>>);
+
+#[async_trait]
+impl BodyStream for BufferedBody {
+    async fn chunk(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.0.take().filter(|b| !b.is_empty()))
+    }
+}
+
+/// Anything capable of carrying `CNVClient` requests to cnvmp3 (or something pretending to be
+/// cnvmp3)
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, Error>;
+
+    /// Like `send`, but hands the body over as it arrives instead of reading all of it first.
+    /// Transports that cannot do better read the whole body and hand it over in one chunk.
+    async fn stream(&self, request: HttpRequest) -> Result<StreamingResponse, Error> {
+        let res = self.send(request).await?;
+
+        Ok(StreamingResponse {
+            status: res.status,
+            headers: res.headers,
+            body: Box::new(BufferedBody(Some(res.body))),
+        })
+    }
+}
+
+#[async_trait]
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, Error> {
+        (**self).send(request).await
+    }
+
+    async fn stream(&self, request: HttpRequest) -> Result<StreamingResponse, Error> {
+        (**self).stream(request).await
+    }
+}
+
+/// Default transport, backed by `reqwest::Client`
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(client: reqwest::Client) -> Self {
+        ReqwestTransport { client }
+    }
+}
+
+/// Body of a `reqwest::Response`, read chunk by chunk
+struct ReqwestBody(reqwest::Response);
+
+#[async_trait]
+impl BodyStream for ReqwestBody {
+    async fn chunk(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let chunk = self.0.chunk().await.map_err(|e| Error {
+            kind: ErrorKind::from_reqwest(&e),
+            value: format!("Failed to read response as bytes: {}", e),
+        })?;
+
+        Ok(chunk.map(|c| c.to_vec()))
+    }
+}
+
+#[async_trait]
+impl Transport for ReqwestTransport {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, Error> {
+        let mut res = self.stream(request).await?;
+
+        let mut body = Vec::new();
+        while let Some(chunk) = res.body.chunk().await? {
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(HttpResponse {
+            status: res.status,
+            headers: res.headers,
+            body,
+        })
+    }
+
+    async fn stream(&self, request: HttpRequest) -> Result<StreamingResponse, Error> {
+        let mut builder = match request.method {
+            Method::Get => self.client.get(request.url),
+            Method::Post => self.client.post(request.url),
+        };
+
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+
+        if let Some(body) = request.body {
+            builder = builder.body(body);
+        }
+
+        let res = builder.send().await.map_err(|e| Error {
+            kind: ErrorKind::from_reqwest(&e),
+            value: format!("HTTP request failed: {}", e),
+        })?;
+
+        let status = res.status().as_u16();
+        let headers = res
+            .headers()
+            .iter()
+            .map(|(k, v)| {
+                (
+                    k.to_string(),
+                    String::from_utf8_lossy(v.as_bytes()).to_string(),
+                )
+            })
+            .collect();
+
+        Ok(StreamingResponse {
+            status,
+            headers,
+            body: Box::new(ReqwestBody(res)),
+        })
+    }
+}
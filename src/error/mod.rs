@@ -10,7 +10,33 @@ pub enum ErrorKind {
     InvalidURLType,
     CNVResponseError,
     ReqwestError,
+    /// The request or response took too long (transient)
+    Timeout,
+    /// The connection could not be made or broke off midway (transient)
+    ConnectionError,
+    /// The server answered 5xx or 429 (transient)
+    ServerError,
+    /// The server answered with any other 4xx (permanent)
+    ClientError,
+    /// The server answered, but not with what the protocol expects, e.g. an HTML error page
+    /// instead of JSON (transient)
+    MalformedResponse,
+    /// A download exceeded the configured maximum size (permanent)
+    TooLarge,
+    /// A download is not what was asked for, and will not become it by asking again: a page
+    /// rather than an MP3 file, or the whole file rather than the range asked for (permanent)
+    UnexpectedContent,
     SerdeError,
+    IoError,
+    CassetteError,
+    /// A remote directory was not written as `user@host:/path` or `ssh://user@host:port/path`
+    InvalidRemote,
+    /// The SSH connection, login or SFTP session failed
+    SshError,
+    /// A copied file does not hash to the same SHA-256 as its source
+    ChecksumMismatch,
+    /// An MP3 file could not be decoded into audio
+    DecodeError,
     BoxError,
     Error,
 }
@@ -22,13 +48,63 @@ impl std::fmt::Display for ErrorKind {
             Self::InvalidURLType => writeln!(f, "InvalidURLType"),
             Self::CNVResponseError => writeln!(f, "JSONParseError"),
             Self::ReqwestError => writeln!(f, "ReqwestError"),
+            Self::Timeout => writeln!(f, "Timeout"),
+            Self::ConnectionError => writeln!(f, "ConnectionError"),
+            Self::ServerError => writeln!(f, "ServerError"),
+            Self::ClientError => writeln!(f, "ClientError"),
+            Self::MalformedResponse => writeln!(f, "MalformedResponse"),
+            Self::TooLarge => writeln!(f, "TooLarge"),
+            Self::UnexpectedContent => writeln!(f, "UnexpectedContent"),
             Self::SerdeError => writeln!(f, "SerdeError"),
+            Self::IoError => writeln!(f, "IoError"),
+            Self::CassetteError => writeln!(f, "CassetteError"),
+            Self::InvalidRemote => writeln!(f, "InvalidRemote"),
+            Self::SshError => writeln!(f, "SshError"),
+            Self::ChecksumMismatch => writeln!(f, "ChecksumMismatch"),
+            Self::DecodeError => writeln!(f, "DecodeError"),
             Self::BoxError => writeln!(f, "BoxError"),
             Self::Error => writeln!(f, "Error"),
         }
     }
 }
 
+impl ErrorKind {
+    /// Whether an operation that failed with this kind of error may succeed if simply tried again
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::Timeout | Self::ConnectionError | Self::ServerError | Self::MalformedResponse
+        )
+    }
+
+    /// Classifies a `reqwest` failure
+    pub fn from_reqwest(e: &reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Self::Timeout
+        } else if e.is_connect() || e.is_request() || e.is_body() || e.is_decode() {
+            Self::ConnectionError
+        } else {
+            Self::ReqwestError
+        }
+    }
+
+    /// Classifies an HTTP status code, if it indicates failure
+    pub fn from_status(status: u16) -> Option<Self> {
+        match status {
+            429 | 500..=599 => Some(Self::ServerError),
+            400..=499 => Some(Self::ClientError),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// See `ErrorKind::is_transient`
+    pub fn is_transient(&self) -> bool {
+        self.kind.is_transient()
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "Kind: {}, Message: {}", self.kind, self.value)
@@ -38,7 +114,7 @@ impl std::fmt::Display for Error {
 impl From<reqwest::Error> for Error {
     fn from(value: reqwest::Error) -> Self {
         Error {
-            kind: ErrorKind::ReqwestError,
+            kind: ErrorKind::from_reqwest(&value),
             value: format!("error: reqwest ({})", value),
         }
     }
@@ -53,6 +129,42 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error {
+            kind: ErrorKind::IoError,
+            value: format!("error: io ({})", value),
+        }
+    }
+}
+
+impl From<russh::Error> for Error {
+    fn from(value: russh::Error) -> Self {
+        Error {
+            kind: ErrorKind::SshError,
+            value: format!("error: ssh ({})", value),
+        }
+    }
+}
+
+impl From<russh_sftp::client::error::Error> for Error {
+    fn from(value: russh_sftp::client::error::Error) -> Self {
+        Error {
+            kind: ErrorKind::SshError,
+            value: format!("error: sftp ({})", value),
+        }
+    }
+}
+
+impl From<symphonia::core::errors::Error> for Error {
+    fn from(value: symphonia::core::errors::Error) -> Self {
+        Error {
+            kind: ErrorKind::DecodeError,
+            value: format!("error: decode ({})", value),
+        }
+    }
+}
+
 impl From<Box<dyn std::error::Error>> for Error {
     fn from(value: Box<dyn std::error::Error>) -> Self {
         Error {
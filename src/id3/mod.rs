@@ -0,0 +1,356 @@
+mod track;
+mod write;
+pub use track::TrackTags;
+pub use write::{replace_tag, replace_tag_in_file, replace_tag_in_stream, Version};
+
+/// A frame of an ID3v2 tag
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    /// A text frame (`T???`), such as `TIT2` (title) or `TPE1` (artist)
+    Text { id: String, value: String },
+    /// A user-defined text frame (`TXXX`)
+    UserText { description: String, value: String },
+    /// A URL frame (`W???`), such as `WOAS` (source)
+    Url { id: String, url: String },
+    /// A user-defined URL frame (`WXXX`)
+    UserUrl { description: String, url: String },
+    /// Any other frame, kept as it is
+    Other { id: String, data: Vec<u8> },
+}
+
+/// The tags of an MP3 file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tag {
+    pub frames: Vec<Frame>,
+}
+
+impl Tag {
+    /// The tags of the MP3 file `mp3`: its ID3v2 tag, or else its ID3v1 tag. `None` if it has
+    /// neither.
+    pub fn read(mp3: &[u8]) -> Option<Tag> {
+        Tag::read_ends(mp3, mp3)
+    }
+
+    /// Like `read`, from the ends of an MP3 file only: `head`, its first bytes holding any ID3v2
+    /// tag whole (see `id3v2_len`), and `tail`, its last 128 bytes
+    pub fn read_ends(head: &[u8], tail: &[u8]) -> Option<Tag> {
+        read_v2(head).or_else(|| read_v1(tail))
+    }
+
+    /// The value of text frame `id`
+    pub fn text(&self, id: &str) -> Option<&str> {
+        self.frames.iter().find_map(|frame| match frame {
+            Frame::Text { id: i, value } if i == id => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The value of the user-defined text frame described as `description`
+    pub fn user_text(&self, description: &str) -> Option<&str> {
+        self.frames.iter().find_map(|frame| match frame {
+            Frame::UserText {
+                description: d,
+                value,
+            } if d == description => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.text("TIT2").filter(|t| !t.is_empty())
+    }
+
+    pub fn artist(&self) -> Option<&str> {
+        self.text("TPE1").filter(|t| !t.is_empty())
+    }
+}
+
+/// Sizes written as four 7-bit bytes
+fn synchsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |size, byte| (size << 7) | (byte & 0x7F) as usize)
+}
+
+/// Size of the ID3v2 tag at the start of `head` (header included), or 0 if there is none
+pub fn id3v2_len(head: &[u8]) -> usize {
+    match head {
+        // the size leaves out the header and the footer
+        [b'I', b'D', b'3', _, _, flags, ..] if head.len() >= 10 => {
+            let footer = if flags & 0x10 != 0 { 10 } else { 0 };
+            10 + synchsafe(&head[6..10]) + footer
+        }
+        _ => 0,
+    }
+}
+
+/// Undoes the unsynchronisation scheme, which puts a zero after every `FF`
+fn resynchronise(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, byte) in data.iter().enumerate() {
+        if *byte == 0 && i > 0 && data[i - 1] == 0xFF {
+            continue;
+        }
+        out.push(*byte);
+    }
+
+    out
+}
+
+/// Decodes a string in ID3 text encoding `encoding`, dropping the terminating null if any
+fn decode(encoding: u8, bytes: &[u8]) -> String {
+    let text = match encoding {
+        // ISO-8859-1, whose code points are the first 256 of Unicode
+        0 => bytes.iter().map(|b| *b as char).collect(),
+        // UTF-16 with a byte order mark, or big-endian without one
+        1 | 2 => {
+            let (big_endian, bytes) = match bytes {
+                [0xFF, 0xFE, rest @ ..] => (false, rest),
+                [0xFE, 0xFF, rest @ ..] => (true, rest),
+                _ => (true, bytes),
+            };
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|c| match big_endian {
+                    true => u16::from_be_bytes([c[0], c[1]]),
+                    false => u16::from_le_bytes([c[0], c[1]]),
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    };
+
+    text.trim_end_matches('\0').to_string()
+}
+
+/// Splits `bytes` after the first string terminator of `encoding` (two zero bytes, aligned, for
+/// UTF-16)
+fn split_terminated(encoding: u8, bytes: &[u8]) -> (&[u8], &[u8]) {
+    match encoding {
+        1 | 2 => match bytes.chunks_exact(2).position(|c| c == [0, 0]) {
+            Some(i) => (&bytes[..2 * i], &bytes[2 * i + 2..]),
+            None => (bytes, &[]),
+        },
+        _ => match bytes.iter().position(|b| *b == 0) {
+            Some(i) => (&bytes[..i], &bytes[i + 1..]),
+            None => (bytes, &[]),
+        },
+    }
+}
+
+fn parse_frame(id: &str, data: &[u8]) -> Frame {
+    let other = || Frame::Other {
+        id: id.to_string(),
+        data: data.to_vec(),
+    };
+
+    match (id, data) {
+        ("TXXX", [encoding, rest @ ..]) => {
+            let (description, value) = split_terminated(*encoding, rest);
+            Frame::UserText {
+                description: decode(*encoding, description),
+                value: decode(*encoding, value),
+            }
+        }
+        ("WXXX", [encoding, rest @ ..]) => {
+            let (description, url) = split_terminated(*encoding, rest);
+            Frame::UserUrl {
+                description: decode(*encoding, description),
+                url: decode(0, url),
+            }
+        }
+        (id, [encoding, rest @ ..]) if id.starts_with('T') => Frame::Text {
+            id: id.to_string(),
+            // ID3v2.4 separates multiple values with nulls
+            value: decode(*encoding, rest).replace('\0', "/"),
+        },
+        (id, url) if id.starts_with('W') => Frame::Url {
+            id: id.to_string(),
+            url: decode(0, url),
+        },
+        _ => other(),
+    }
+}
+
+/// The ID3v2.3 or ID3v2.4 tag at the start of `mp3`
+fn read_v2(mp3: &[u8]) -> Option<Tag> {
+    let [b'I', b'D', b'3', version @ (3 | 4), _, flags, ..] = *mp3 else {
+        return None;
+    };
+    let body = mp3.get(10..id3v2_len(mp3).min(mp3.len()))?;
+
+    // ID3v2.3 unsynchronises the whole tag, ID3v2.4 frame by frame
+    let body = match version == 3 && flags & 0x80 != 0 {
+        true => resynchronise(body),
+        false => body.to_vec(),
+    };
+
+    let mut pos = 0;
+    if flags & 0x40 != 0 {
+        // extended header, whose size counts itself in ID3v2.4 but not in ID3v2.3
+        let size = body.get(..4)?;
+        pos = match version {
+            4 => synchsafe(size),
+            _ => 4 + u32::from_be_bytes(size.try_into().ok()?) as usize,
+        };
+    }
+
+    let mut frames = Vec::new();
+    while let Some(header) = body.get(pos..pos + 10) {
+        // padding
+        if header[0] == 0 {
+            break;
+        }
+
+        let id = String::from_utf8_lossy(&header[..4]).into_owned();
+        let size = match version {
+            4 => synchsafe(&header[4..8]),
+            _ => u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize,
+        };
+        let format = header[9];
+        let Some(data) = body.get(pos + 10..pos + 10 + size) else {
+            break;
+        };
+        pos += 10 + size;
+
+        // compressed or encrypted frames are kept as they are
+        let (compressed, encrypted, unsynchronised, data_length) = match version {
+            4 => (
+                format & 0x08 != 0,
+                format & 0x04 != 0,
+                format & 0x02 != 0,
+                format & 0x01 != 0,
+            ),
+            _ => (format & 0x80 != 0, format & 0x40 != 0, false, false),
+        };
+        if compressed || encrypted {
+            frames.push(Frame::Other {
+                id,
+                data: data.to_vec(),
+            });
+            continue;
+        }
+
+        let data = match unsynchronised {
+            true => resynchronise(data),
+            false => data.to_vec(),
+        };
+        let data = match data_length {
+            true => data.get(4..).unwrap_or_default().to_vec(),
+            false => data,
+        };
+
+        frames.push(parse_frame(&id, &data));
+    }
+
+    Some(Tag { frames })
+}
+
+/// The ID3v1 tag at the end of `mp3`, as ID3v2 frames
+fn read_v1(mp3: &[u8]) -> Option<Tag> {
+    let tag = mp3.get(mp3.len().checked_sub(128)?..)?;
+    if !tag.starts_with(b"TAG") {
+        return None;
+    }
+
+    let field = |range: std::ops::Range<usize>| decode(0, &tag[range]).trim().to_string();
+    let frames = [
+        ("TIT2", field(3..33)),
+        ("TPE1", field(33..63)),
+        ("TALB", field(63..93)),
+        ("TDRC", field(93..97)),
+    ]
+    .into_iter()
+    .filter(|(_, value)| !value.is_empty())
+    .map(|(id, value)| Frame::Text {
+        id: id.to_string(),
+        value,
+    })
+    .collect();
+
+    Some(Tag { frames })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ID3v2 tag of `version` holding `frames` (ID and body), sizes written for that version
+    fn v2(version: u8, frames: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (id, data) in frames {
+            body.extend_from_slice(id.as_bytes());
+            let size = data.len() as u32;
+            match version {
+                4 => body.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7F) as u8)),
+                _ => body.extend_from_slice(&size.to_be_bytes()),
+            }
+            body.extend_from_slice(&[0, 0]);
+            body.extend_from_slice(data);
+        }
+        // padding
+        body.extend_from_slice(&[0; 16]);
+
+        let size = body.len() as u32;
+        let mut tag = vec![b'I', b'D', b'3', version, 0, 0];
+        tag.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7F) as u8));
+        tag.extend_from_slice(&body);
+        tag.extend_from_slice(&crate::mock::silent_mp3(2));
+        tag
+    }
+
+    #[test]
+    fn test_read_v2() {
+        let mut utf16 = vec![1, 0xFF, 0xFE];
+        utf16.extend("Daft Punk".encode_utf16().flat_map(|u| u.to_le_bytes()));
+        let frames = [
+            ("TIT2", b"\x03One More Time \xE2\x80\x93 Edit\x00".to_vec()),
+            ("TPE1", utf16),
+            ("TXXX", b"\x00YOUTUBE_ID\x00yPvoKz6tyJs".to_vec()),
+            (
+                "WOAS",
+                b"https://www.youtube.com/watch?v=yPvoKz6tyJs".to_vec(),
+            ),
+            ("APIC", vec![0, 1, 2]),
+        ];
+
+        for version in [3, 4] {
+            let tag = Tag::read(&v2(version, &frames)).unwrap();
+            assert_eq!(tag.title(), Some("One More Time – Edit"));
+            assert_eq!(tag.artist(), Some("Daft Punk"));
+            assert_eq!(tag.user_text("YOUTUBE_ID"), Some("yPvoKz6tyJs"));
+            assert_eq!(
+                tag.frames[3],
+                Frame::Url {
+                    id: String::from("WOAS"),
+                    url: String::from("https://www.youtube.com/watch?v=yPvoKz6tyJs"),
+                }
+            );
+            assert_eq!(
+                tag.frames[4],
+                Frame::Other {
+                    id: String::from("APIC"),
+                    data: vec![0, 1, 2],
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_v1() {
+        let mut mp3 = crate::mock::silent_mp3(2);
+        let mut tag = [0u8; 128];
+        tag[..3].copy_from_slice(b"TAG");
+        tag[3..16].copy_from_slice(b"One More Time");
+        tag[33..42].copy_from_slice(b"Daft Punk");
+        mp3.extend_from_slice(&tag);
+
+        let tag = Tag::read(&mp3).unwrap();
+        assert_eq!(tag.title(), Some("One More Time"));
+        assert_eq!(tag.artist(), Some("Daft Punk"));
+        assert_eq!(tag.text("TALB"), None);
+
+        assert_eq!(Tag::read(&crate::mock::silent_mp3(2)), None);
+    }
+}
@@ -0,0 +1,163 @@
+use super::{Frame, Tag};
+use crate::bitrate::{BitRate, FromNumber};
+use crate::library::date::{format_datetime, parse_datetime};
+use crate::library::Track;
+use crate::titles::TitleRules;
+
+/// Descriptions of the user-defined text frames photon writes
+const YOUTUBE_ID: &str = "YOUTUBE_ID";
+const YOUTUBE_TITLE: &str = "YOUTUBE_TITLE";
+const BITRATE: &str = "PHOTON_BITRATE";
+const DOWNLOADED: &str = "PHOTON_DOWNLOADED";
+
+/// What photon writes into the tags of an MP3 file it saves, enough to tell which video it came
+/// from and to rebuild its entry in the library index
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackTags {
+    /// YouTube ID of the video
+    pub id: String,
+    /// YouTube URL of the video
+    pub url: String,
+    /// Title of the video, as cnvmp3 told it
+    pub title: Option<String>,
+    /// Bitrate the MP3 file was asked for at
+    pub bitrate: BitRate,
+    /// When the MP3 file was saved, in seconds since the Unix epoch
+    pub downloaded_at: u64,
+}
+
+impl From<&Track> for TrackTags {
+    fn from(track: &Track) -> Self {
+        TrackTags {
+            id: track.id.clone(),
+            url: track.url.clone(),
+            title: track.title.clone(),
+            bitrate: track.bitrate,
+            downloaded_at: track.downloaded_at,
+        }
+    }
+}
+
+impl Tag {
+    /// The YouTube ID photon wrote into the tag
+    pub fn youtube_id(&self) -> Option<&str> {
+        self.user_text(YOUTUBE_ID)
+    }
+}
+
+impl TrackTags {
+    /// Writes the title, artist and label (parsed from the title of the video with `rules`), the
+    /// URL (`WOAS`), and the YouTube ID, bitrate and download time (`TXXX`) into `tag`, keeping
+    /// its other frames
+    pub fn apply(&self, tag: &mut Tag, rules: &TitleRules) {
+        let user_text = |description: &str, value: String| Frame::UserText {
+            description: description.to_string(),
+            value,
+        };
+
+        if let Some(video_title) = &self.title {
+            let parsed = rules.parse(Some(&self.id), video_title);
+            let text = |id: &str, value: String| Frame::Text {
+                id: id.to_string(),
+                value,
+            };
+
+            tag.set(text("TIT2", parsed.full_title()));
+            if let Some(artist) = parsed.artist_credit() {
+                tag.set(text("TPE1", artist));
+            }
+            if let Some(label) = parsed.label {
+                tag.set(text("TPUB", label));
+            }
+            tag.set(user_text(YOUTUBE_TITLE, video_title.clone()));
+        }
+
+        tag.set(Frame::Url {
+            id: String::from("WOAS"),
+            url: self.url.clone(),
+        });
+        tag.set(user_text(YOUTUBE_ID, self.id.clone()));
+        tag.set(user_text(BITRATE, self.bitrate.kbps().to_string()));
+        tag.set(user_text(DOWNLOADED, format_datetime(self.downloaded_at)));
+    }
+
+    /// What photon wrote into `tag`, or `None` if it lacks the YouTube ID, bitrate or download
+    /// time. Without photon's frames, the URL and title come from the others.
+    pub fn read(tag: &Tag) -> Option<TrackTags> {
+        let id = tag.youtube_id()?.to_string();
+        let url = tag
+            .frames
+            .iter()
+            .find_map(|frame| match frame {
+                Frame::Url { id, url } if id == "WOAS" => Some(url.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| format!("https://www.youtube.com/watch?v={}", id));
+        let title = tag.user_text(YOUTUBE_TITLE).map(String::from).or_else(|| {
+            match (tag.artist(), tag.title()) {
+                (Some(artist), Some(title)) => Some(format!("{} - {}", artist, title)),
+                (_, title) => title.map(String::from),
+            }
+        });
+
+        Some(TrackTags {
+            id,
+            url,
+            title,
+            bitrate: tag
+                .user_text(BITRATE)
+                .and_then(|kbps| kbps.parse::<u32>().ok())
+                .and_then(|kbps| BitRate::from_number(kbps).ok())?,
+            downloaded_at: tag
+                .user_text(DOWNLOADED)
+                .and_then(|time| parse_datetime(time).ok())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id3::{replace_tag, Version};
+    use crate::library::tests::track;
+
+    #[test]
+    fn test_track_tags() {
+        let mut track = track("yPvoKz6tyJs");
+        track.title = Some(String::from("Daft Punk - One More Time (Radio Edit)"));
+        track.bitrate = BitRate::Kbps320;
+        let tags = TrackTags::from(&track);
+
+        // other frames are kept, photon's replaced
+        let mut tag = Tag::default();
+        tag.set(Frame::Text {
+            id: String::from("TALB"),
+            value: String::from("Discovery"),
+        });
+        tag.set(Frame::UserText {
+            description: String::from(BITRATE),
+            value: String::from("96"),
+        });
+        tags.apply(&mut tag, &TitleRules::default());
+        assert_eq!(tag.frames.len(), 8);
+
+        for version in [Version::V2_3, Version::V2_4] {
+            let mp3 = replace_tag(&crate::mock::silent_mp3(2), &tag.to_bytes(version));
+            let read = Tag::read(&mp3).unwrap();
+            assert_eq!(read.title(), Some("One More Time (Radio Edit)"));
+            assert_eq!(read.artist(), Some("Daft Punk"));
+            assert_eq!(read.text("TALB"), Some("Discovery"));
+            assert_eq!(read.user_text(DOWNLOADED), Some("2023-11-14T22:13:20Z"));
+            assert_eq!(TrackTags::read(&read), Some(tags.clone()));
+        }
+
+        // a YouTube ID alone is not enough to rebuild an index entry from
+        let mut tag = Tag::default();
+        tag.set(Frame::UserText {
+            description: String::from(YOUTUBE_ID),
+            value: String::from("yPvoKz6tyJs"),
+        });
+        assert_eq!(TrackTags::read(&tag), None);
+        assert_eq!(TrackTags::read(&Tag::default()), None);
+    }
+}
@@ -0,0 +1,334 @@
+use clap::ValueEnum;
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use super::{id3v2_len, Frame, Tag};
+use crate::error::Error;
+use crate::storage::ByteStream;
+
+/// Version of the ID3v2 tags photon writes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Version {
+    /// ID3v2.3, for older players
+    #[value(name = "2.3")]
+    V2_3,
+    /// ID3v2.4, with UTF-8 text
+    #[default]
+    #[value(name = "2.4")]
+    V2_4,
+}
+
+/// Sizes as four 7-bit bytes
+fn synchsafe(size: usize) -> [u8; 4] {
+    [21, 14, 7, 0].map(|shift| ((size >> shift) & 0x7F) as u8)
+}
+
+/// The encoding byte to use for `texts` in `version`: UTF-8 in ID3v2.4; in ID3v2.3,
+/// ISO-8859-1 when it is enough and UTF-16 otherwise
+fn encoding(version: Version, texts: &[&str]) -> u8 {
+    match version {
+        Version::V2_4 => 3,
+        Version::V2_3 if texts.iter().all(|t| t.chars().all(|c| (c as u32) < 256)) => 0,
+        Version::V2_3 => 1,
+    }
+}
+
+/// `text` in ID3 text encoding `encoding`, terminated if `terminated`
+fn encode(encoding: u8, text: &str, terminated: bool) -> Vec<u8> {
+    let mut bytes = match encoding {
+        0 => text.chars().map(|c| c as u8).collect(),
+        1 => {
+            let mut bytes = vec![0xFF, 0xFE];
+            bytes.extend(text.encode_utf16().flat_map(|u| u.to_le_bytes()));
+            bytes
+        }
+        _ => text.as_bytes().to_vec(),
+    };
+
+    if terminated {
+        match encoding {
+            1 => bytes.extend_from_slice(&[0, 0]),
+            _ => bytes.push(0),
+        }
+    }
+
+    bytes
+}
+
+/// ID and body of `frame` as written in `version`
+fn frame_data(frame: &Frame, version: Version) -> (&str, Vec<u8>) {
+    match frame {
+        Frame::Text { id, value } => {
+            let encoding = encoding(version, &[value]);
+            let mut data = vec![encoding];
+            data.extend(encode(encoding, value, false));
+            (id, data)
+        }
+        Frame::UserText { description, value } => {
+            let encoding = encoding(version, &[description, value]);
+            let mut data = vec![encoding];
+            data.extend(encode(encoding, description, true));
+            data.extend(encode(encoding, value, false));
+            (Frame::USER_TEXT, data)
+        }
+        Frame::Url { id, url } => (id, encode(0, url, false)),
+        Frame::UserUrl { description, url } => {
+            let encoding = encoding(version, &[description]);
+            let mut data = vec![encoding];
+            data.extend(encode(encoding, description, true));
+            data.extend(encode(0, url, false));
+            (Frame::USER_URL, data)
+        }
+        Frame::Other { id, data } => (id, data.clone()),
+    }
+}
+
+impl Frame {
+    const USER_TEXT: &'static str = "TXXX";
+    const USER_URL: &'static str = "WXXX";
+
+    /// Whether `self` and `other` hold the same thing, so that a tag needs only one of them
+    fn same_slot(&self, other: &Frame) -> bool {
+        match (self, other) {
+            (Frame::Text { id: a, .. }, Frame::Text { id: b, .. }) => a == b,
+            (Frame::Url { id: a, .. }, Frame::Url { id: b, .. }) => a == b,
+            (Frame::UserText { description: a, .. }, Frame::UserText { description: b, .. }) => {
+                a == b
+            }
+            (Frame::UserUrl { description: a, .. }, Frame::UserUrl { description: b, .. }) => {
+                a == b
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Tag {
+    /// Puts `frame` in the tag, in place of the frame holding the same thing if there is one
+    pub fn set(&mut self, frame: Frame) {
+        match self.frames.iter_mut().find(|f| f.same_slot(&frame)) {
+            Some(old) => *old = frame,
+            None => self.frames.push(frame),
+        }
+    }
+
+    /// Removes the frames `keep` returns `false` for
+    pub fn retain(&mut self, keep: impl FnMut(&Frame) -> bool) {
+        self.frames.retain(keep);
+    }
+
+    /// The tag as written at the start of an MP3 file, in ID3v2 `version`
+    pub fn to_bytes(&self, version: Version) -> Vec<u8> {
+        let mut body = Vec::new();
+        for frame in &self.frames {
+            let (id, data) = frame_data(frame, version);
+            body.extend_from_slice(id.as_bytes());
+            match version {
+                Version::V2_4 => body.extend_from_slice(&synchsafe(data.len())),
+                Version::V2_3 => body.extend_from_slice(&(data.len() as u32).to_be_bytes()),
+            }
+            body.extend_from_slice(&[0, 0]);
+            body.extend_from_slice(&data);
+        }
+
+        let major = match version {
+            Version::V2_3 => 3,
+            Version::V2_4 => 4,
+        };
+        let mut tag = vec![b'I', b'D', b'3', major, 0, 0];
+        tag.extend_from_slice(&synchsafe(body.len()));
+        tag.extend_from_slice(&body);
+        tag
+    }
+}
+
+/// `mp3` with its ID3v2 tag (if any) replaced by `tag`, already turned into bytes
+pub fn replace_tag(mp3: &[u8], tag: &[u8]) -> Vec<u8> {
+    let audio = &mp3[id3v2_len(mp3).min(mp3.len())..];
+
+    let mut out = Vec::with_capacity(tag.len() + audio.len());
+    out.extend_from_slice(tag);
+    out.extend_from_slice(audio);
+    out
+}
+
+/// `replace_tag` for an MP3 file of `length` bytes (if known) on its way somewhere. The start of
+/// `body` is read right away to find how long its old tag is; returns the file with the new tag
+/// and its length.
+pub async fn replace_tag_in_stream(
+    mut body: ByteStream,
+    tag: Vec<u8>,
+    length: Option<u64>,
+) -> Result<(ByteStream, Option<u64>), Error> {
+    // an ID3v2 header is 10 bytes
+    let mut head = Vec::new();
+    while head.len() < 10 {
+        match body.next().await {
+            Some(chunk) => head.extend_from_slice(&chunk?),
+            None => break,
+        }
+    }
+
+    let old = id3v2_len(&head);
+    let length = length.map(|len| tag.len() as u64 + len.saturating_sub(old as u64));
+    let skipped = old.min(head.len());
+    let mut first = tag;
+    first.extend_from_slice(&head[skipped..]);
+
+    // what is left of the old tag after the head
+    let rest = body
+        .scan(old - skipped, |skip, chunk| {
+            let chunk = chunk.map(|mut chunk| {
+                let skipped = (*skip).min(chunk.len());
+                *skip -= skipped;
+                chunk.drain(..skipped);
+                chunk
+            });
+            future::ready(Some(chunk))
+        })
+        .filter(|chunk| future::ready(!matches!(chunk, Ok(chunk) if chunk.is_empty())));
+
+    Ok((
+        stream::once(future::ready(Ok(first))).chain(rest).boxed(),
+        length,
+    ))
+}
+
+/// `replace_tag` from the MP3 file at `from` into a new file at `to`, copying the audio over
+/// rather than reading it all into memory. Returns the size of the new file.
+pub async fn replace_tag_in_file(from: &Path, to: &Path, tag: &[u8]) -> Result<u64, Error> {
+    let mut mp3 = tokio::fs::File::open(from).await?;
+    let mut head = Vec::with_capacity(10);
+    (&mut mp3).take(10).read_to_end(&mut head).await?;
+    mp3.seek(SeekFrom::Start(id3v2_len(&head) as u64)).await?;
+
+    let mut out = tokio::fs::File::create(to).await?;
+    out.write_all(tag).await?;
+    let audio = tokio::io::copy(&mut mp3, &mut out).await?;
+    out.sync_all().await?;
+
+    Ok(tag.len() as u64 + audio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag() -> Tag {
+        let mut tag = Tag::default();
+        tag.set(Frame::Text {
+            id: String::from("TIT2"),
+            value: String::from("Één – Twee"),
+        });
+        tag.set(Frame::Text {
+            id: String::from("TPE1"),
+            value: String::from("Daft Punk"),
+        });
+        tag.set(Frame::UserText {
+            description: String::from("YOUTUBE_ID"),
+            value: String::from("yPvoKz6tyJs"),
+        });
+        tag.set(Frame::Url {
+            id: String::from("WOAS"),
+            url: String::from("https://www.youtube.com/watch?v=yPvoKz6tyJs"),
+        });
+        tag.set(Frame::UserUrl {
+            description: String::from("YouTube"),
+            url: String::from("https://youtu.be/yPvoKz6tyJs"),
+        });
+        tag.set(Frame::Other {
+            id: String::from("APIC"),
+            data: vec![0, 1, 2],
+        });
+        tag
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mp3 = crate::mock::silent_mp3(4);
+
+        for version in [Version::V2_3, Version::V2_4] {
+            let tagged = replace_tag(&mp3, &tag().to_bytes(version));
+            assert_eq!(Tag::read(&tagged), Some(tag()));
+            assert_eq!(&tagged[id3v2_len(&tagged)..], mp3);
+
+            // replacing a tag keeps the audio as it is
+            let mut other = Tag::default();
+            other.set(Frame::Text {
+                id: String::from("TIT2"),
+                value: String::from("Other"),
+            });
+            let retagged = replace_tag(&tagged, &other.to_bytes(version));
+            assert_eq!(Tag::read(&retagged), Some(other));
+            assert_eq!(&retagged[id3v2_len(&retagged)..], mp3);
+        }
+
+        // a frame for the same thing is replaced rather than repeated
+        let mut tag = tag();
+        tag.set(Frame::UserText {
+            description: String::from("YOUTUBE_ID"),
+            value: String::from("dQw4w9WgXcQ"),
+        });
+        assert_eq!(tag.frames.len(), 6);
+        assert_eq!(tag.user_text("YOUTUBE_ID"), Some("dQw4w9WgXcQ"));
+    }
+
+    #[tokio::test]
+    async fn test_replace_tag_in_stream() {
+        let mp3 = crate::mock::silent_mp3(4);
+        let old = replace_tag(&mp3, &tag().to_bytes(Version::V2_4));
+        let new = Tag::default().to_bytes(Version::V2_3);
+
+        // whatever the chunks, the old tag is dropped and the new one put first
+        for file in [&mp3, &old] {
+            for chunk_len in [1, 7, 64, file.len()] {
+                let chunks: Vec<Result<Vec<u8>, Error>> =
+                    file.chunks(chunk_len).map(|c| Ok(c.to_vec())).collect();
+                let body = stream::iter(chunks).boxed();
+
+                let (body, length) =
+                    replace_tag_in_stream(body, new.clone(), Some(file.len() as u64))
+                        .await
+                        .unwrap();
+                let out: Vec<u8> = body.map(|chunk| chunk.unwrap()).concat().await;
+                assert_eq!(out, replace_tag(file, &new));
+                assert_eq!(length, Some(out.len() as u64));
+            }
+        }
+
+        let body = stream::iter(vec![Ok(b"ID3".to_vec())]).boxed();
+        let (body, length) = replace_tag_in_stream(body, new.clone(), None)
+            .await
+            .unwrap();
+        let out: Vec<_> = body.collect().await;
+        assert_eq!(out.len(), 1);
+        assert_eq!(length, None);
+
+        let failing: Vec<Result<Vec<u8>, Error>> = vec![Err(Error::from("connection reset"))];
+        let res = replace_tag_in_stream(stream::iter(failing).boxed(), new, None).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replace_tag_in_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mp3 = crate::mock::silent_mp3(4);
+        let new = Tag::default().to_bytes(Version::V2_3);
+
+        for file in [
+            mp3.clone(),
+            replace_tag(&mp3, &tag().to_bytes(Version::V2_4)),
+        ] {
+            let (from, to) = (dir.path().join("from.mp3"), dir.path().join("to.mp3"));
+            std::fs::write(&from, &file).unwrap();
+
+            let size = replace_tag_in_file(&from, &to, &new).await.unwrap();
+            let out = std::fs::read(&to).unwrap();
+            assert_eq!(out, replace_tag(&file, &new));
+            assert_eq!(size, out.len() as u64);
+        }
+    }
+}
@@ -0,0 +1,32 @@
+//! Frontend to downloading music from cnvmp3.com
+//!
+//! ```no_run
+//! use photon::convert::Converter;
+//! use url::Url;
+//!
+//! # async fn run() -> Result<(), photon::error::Error> {
+//! let converter = Converter::builder().with_out_dir("music").build()?;
+//! let url = Url::parse("https://www.youtube.com/watch?v=yPvoKz6tyJs").unwrap();
+//!
+//! let conversion = converter.convert(url).await?;
+//! println!("saved {:?} as {}", conversion.title, conversion.path.display());
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod analysis;
+pub mod bitrate;
+pub mod convert;
+pub mod error;
+pub mod id3;
+pub mod library;
+pub mod migrate;
+pub mod mock;
+pub mod mp3;
+pub mod rekordbox;
+pub mod ssh;
+pub mod storage;
+pub mod sync;
+pub mod template;
+pub mod titles;
+pub mod youtube_url;
@@ -0,0 +1,252 @@
+use futures_util::StreamExt;
+use tokio::io::AsyncReadExt;
+
+use super::{Library, Track, Unmatched};
+use crate::analysis::{detect_energy, detect_key, detect_tempo, Audio, Notation};
+use crate::error::Error;
+use crate::id3::{id3v2_len, replace_tag_in_file, Frame, Tag, Version};
+use crate::migrate::{digest, hashing};
+use crate::storage::read_stream;
+
+/// Confidence below which a detected tempo is worth checking by ear unless told otherwise
+pub const DEFAULT_MIN_CONFIDENCE: f64 = 0.5;
+
+/// What `analyze` did
+#[derive(Debug, Default)]
+pub struct Analyzed {
+    /// YouTube IDs of the tracks whose tempo was detected
+    pub analyzed: Vec<String>,
+    /// Tracks in which no beats were found, so that their tempo is still unknown
+    pub without_beats: Vec<String>,
+    /// Tracks in which no notes were found, so that their key is still unknown
+    pub without_key: Vec<String>,
+    /// Tracks whose tempo, key and energy were already known
+    pub known: usize,
+    /// Files that could not be analyzed, such as missing or remote ones
+    pub failed: Vec<Unmatched>,
+}
+
+/// Decodes the local MP3 file of `track` and records its tempo (see `detect_tempo`), key (see
+/// `detect_key`, written in `notation`), energy (see `detect_energy`) and length in `track`. With
+/// `version`, the tempo and key also go into the ID3v2 tag of the file (see `write_tags`).
+pub async fn analyze_track(
+    track: &mut Track,
+    version: Option<Version>,
+    notation: Notation,
+) -> Result<(), Error> {
+    // decoding takes a while, so it is kept off the threads running async tasks
+    let path = track.path.clone();
+    let (tempo, key, energy, duration_ms) = tokio::task::spawn_blocking(move || {
+        let audio = Audio::read(&path)?;
+        Ok::<_, Error>((
+            detect_tempo(&audio),
+            detect_key(&audio).map(|key| key.name(notation)),
+            detect_energy(&audio),
+            audio.duration_ms(),
+        ))
+    })
+    .await
+    .unwrap_or_else(|e| Err(Error::from(e.to_string())))?;
+
+    track.tempo = tempo;
+    track.key = key;
+    track.energy = Some(energy);
+    track.duration_ms = Some(duration_ms);
+
+    match version {
+        Some(version) => write_tags(track, version).await,
+        None => Ok(()),
+    }
+}
+
+/// Writes the tempo of `track` into the `TBPM` frame (a whole number, as the frame holds) and its
+/// key into the `TKEY` frame of the ID3v2 tag of its file, keeping the other frames, and updates
+/// the size and SHA-256 of the file. The file is written next to it first (see
+/// `replace_tag_in_file`), so that it is never left half written.
+async fn write_tags(track: &mut Track, version: Version) -> Result<(), Error> {
+    let mut frames = Vec::new();
+    if let Some(tempo) = track.tempo {
+        frames.push(("TBPM", tempo.grid.bpm.round().to_string()));
+    }
+    if let Some(key) = &track.key {
+        frames.push(("TKEY", key.clone()));
+    }
+    if frames.is_empty() {
+        return Ok(());
+    }
+
+    let mut mp3 = tokio::fs::File::open(&track.path).await?;
+    let mut head = Vec::with_capacity(10);
+    (&mut mp3).take(10).read_to_end(&mut head).await?;
+    let old_len = id3v2_len(&head);
+    (&mut mp3)
+        .take(old_len.saturating_sub(head.len()) as u64)
+        .read_to_end(&mut head)
+        .await?;
+    drop(mp3);
+
+    let mut tag = Tag::read_ends(&head, &[]).unwrap_or_default();
+    for (id, value) in frames {
+        tag.set(Frame::Text {
+            id: id.to_string(),
+            value,
+        });
+    }
+    let tag = tag.to_bytes(version);
+    if head.get(..old_len) == Some(&tag[..]) {
+        return Ok(());
+    }
+
+    let tmp = track.path.with_extension("mp3.tagged");
+    let res = match replace_tag_in_file(&track.path, &tmp, &tag).await {
+        Ok(_) => tokio::fs::rename(&tmp, &track.path)
+            .await
+            .map_err(Error::from),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(e);
+    }
+
+    let (mut body, hashed) = hashing(read_stream(tokio::fs::File::open(&track.path).await?));
+    while let Some(chunk) = body.next().await {
+        chunk?;
+    }
+    (track.size, track.sha256) = digest(&hashed);
+
+    Ok(())
+}
+
+/// Detects the tempo, key and energy of every local MP3 file of `library` for which one of them is
+/// not known yet (or of all of them if `again`), see `analyze_track`. Call `Library::save` to keep
+/// what was found.
+pub async fn analyze(
+    library: &mut Library,
+    version: Option<Version>,
+    notation: Notation,
+    again: bool,
+) -> Analyzed {
+    let mut analyzed = Analyzed::default();
+    let tracks: Vec<_> = library.tracks().cloned().collect();
+
+    for mut track in tracks {
+        if track.tempo.is_some() && track.key.is_some() && track.energy.is_some() && !again {
+            analyzed.known += 1;
+            continue;
+        }
+
+        if let Err(e) = analyze_track(&mut track, version, notation).await {
+            analyzed.failed.push(Unmatched {
+                path: track.path.clone(),
+                reason: e.to_string(),
+            });
+            continue;
+        }
+
+        match track.tempo {
+            Some(_) => analyzed.analyzed.push(track.id.clone()),
+            None => analyzed.without_beats.push(track.id.clone()),
+        }
+        if track.key.is_none() {
+            analyzed.without_key.push(track.id.clone());
+        }
+        library.insert(track);
+    }
+
+    analyzed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{BeatGrid, Tempo};
+    use crate::id3::replace_tag;
+    use crate::library::tests::track;
+    use crate::library::INDEX_NAME;
+    use crate::storage::hex;
+    use sha2::{Digest, Sha256};
+
+    fn tempo(bpm: f64) -> Tempo {
+        Tempo {
+            grid: BeatGrid {
+                bpm,
+                downbeat_ms: 0.0,
+            },
+            confidence: 0.9,
+            alternative_bpm: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_analyze() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut library = Library::open(dir.path().join(INDEX_NAME)).unwrap();
+
+        let mut silent = track("yPvoKz6tyJs");
+        silent.path = dir.path().join("yPvoKz6tyJs.mp3");
+        std::fs::write(&silent.path, crate::mock::silent_mp3(200)).unwrap();
+        library.insert(silent.clone());
+        let missing = track("dQw4w9WgXcQ");
+        library.insert(missing.clone());
+
+        let analyzed = analyze(&mut library, Some(Version::V2_4), Notation::Standard, false).await;
+        assert!(analyzed.analyzed.is_empty());
+        assert_eq!(analyzed.without_beats, ["yPvoKz6tyJs"]);
+        assert_eq!(analyzed.without_key, ["yPvoKz6tyJs"]);
+        assert_eq!(analyzed.failed.len(), 1);
+        assert_eq!(analyzed.failed[0].path, missing.path);
+
+        // silence has a length but no tempo or key, so nothing is written into it
+        let indexed = library.get("yPvoKz6tyJs").unwrap();
+        assert!((5200..=5225).contains(&indexed.duration_ms.unwrap()));
+        assert_eq!(indexed.tempo, None);
+        assert_eq!(indexed.key, None);
+        assert_eq!(indexed.energy, Some(1));
+        assert_eq!(indexed.sha256, silent.sha256);
+        assert_eq!(Tag::read(&std::fs::read(&silent.path).unwrap()), None);
+
+        // a known tempo, key and energy are left alone unless asked again
+        let mut known = library.get("yPvoKz6tyJs").unwrap().clone();
+        known.tempo = Some(tempo(127.6));
+        known.key = Some(String::from("8A"));
+        known.energy = Some(6);
+        library.insert(known);
+        library.remove("dQw4w9WgXcQ");
+        let analyzed = analyze(&mut library, None, Notation::Camelot, false).await;
+        assert_eq!(analyzed.known, 1);
+        assert!(library.get("yPvoKz6tyJs").unwrap().tempo.is_some());
+        let analyzed = analyze(&mut library, None, Notation::Camelot, true).await;
+        assert_eq!(analyzed.without_beats, ["yPvoKz6tyJs"]);
+        assert!(library.get("yPvoKz6tyJs").unwrap().tempo.is_none());
+        assert!(library.get("yPvoKz6tyJs").unwrap().key.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tag = Tag::default();
+        tag.set(Frame::Text {
+            id: String::from("TALB"),
+            value: String::from("Discovery"),
+        });
+        let mp3 = replace_tag(&crate::mock::silent_mp3(10), &tag.to_bytes(Version::V2_3));
+
+        let mut track = track("yPvoKz6tyJs");
+        track.path = dir.path().join("yPvoKz6tyJs.mp3");
+        std::fs::write(&track.path, &mp3).unwrap();
+        track.tempo = Some(tempo(127.6));
+        track.key = Some(String::from("8A"));
+        write_tags(&mut track, Version::V2_3).await.unwrap();
+
+        let file = std::fs::read(&track.path).unwrap();
+        assert_eq!(track.size, file.len() as u64);
+        assert_eq!(track.sha256, hex(&Sha256::digest(&file)));
+        let tag = Tag::read(&file).unwrap();
+        assert_eq!(tag.text("TBPM"), Some("128"));
+        assert_eq!(tag.text("TKEY"), Some("8A"));
+        assert_eq!(tag.text("TALB"), Some("Discovery"));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert_eq!(&file[id3v2_len(&file)..], crate::mock::silent_mp3(10));
+    }
+}
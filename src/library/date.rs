@@ -0,0 +1,118 @@
+use crate::error::{Error, ErrorKind};
+
+/// Year, month and day of the day `days` after 1970-01-01, after Howard Hinnant
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month as u32, day as u32)
+}
+
+/// Days from 1970-01-01 to the given day, the inverse of `civil_from_days`
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+/// `secs` since the Unix epoch as `YYYY-MM-DD`
+pub fn format_date(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// `secs` since the Unix epoch as `YYYY-MM-DDTHH:MM:SSZ`
+pub fn format_datetime(secs: u64) -> String {
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        format_date(secs),
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Seconds since the Unix epoch at a time given as `YYYY-MM-DDTHH:MM:SSZ`
+pub fn parse_datetime(s: &str) -> Result<u64, Error> {
+    let invalid = || Error {
+        kind: ErrorKind::Error,
+        value: format!("`{}` is not a time of the form YYYY-MM-DDTHH:MM:SSZ", s),
+    };
+
+    let (date, time) = s.split_once('T').ok_or_else(invalid)?;
+    let time = time.strip_suffix('Z').ok_or_else(invalid)?;
+    let fields: Vec<u64> = time
+        .split(':')
+        .map(|f| f.parse().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()?;
+    let [hours @ 0..=23, minutes @ 0..=59, seconds @ 0..=59] = fields[..] else {
+        return Err(invalid());
+    };
+
+    Ok(parse_date(date)? + hours * 3600 + minutes * 60 + seconds)
+}
+
+/// Seconds since the Unix epoch at the start (UTC) of the day given as `YYYY-MM-DD`
+pub fn parse_date(s: &str) -> Result<u64, Error> {
+    let invalid = || Error {
+        kind: ErrorKind::Error,
+        value: format!("`{}` is not a date of the form YYYY-MM-DD", s),
+    };
+
+    let mut parts = s.splitn(3, '-');
+    let mut part = || parts.next().ok_or_else(invalid);
+    let (year, month, day) = (part()?, part()?, part()?);
+    let year: i64 = year.parse().map_err(|_| invalid())?;
+    let month: u32 = month.parse().map_err(|_| invalid())?;
+    let day: u32 = day.parse().map_err(|_| invalid())?;
+
+    let days = days_from_civil(year, month, day);
+    // catches days past the end of the month, like 2023-02-29
+    if !(1..=12).contains(&month) || civil_from_days(days) != (year, month, day) || days < 0 {
+        return Err(invalid());
+    }
+
+    Ok(days as u64 * 86400)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(1_709_251_198), "2024-02-29");
+        assert_eq!(parse_date("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_date("2024-02-29").unwrap(), 1_709_164_800);
+        for days in [0, 59, 365, 11_016, 19_782, 30_000] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+
+        assert!(parse_date("2023-02-29").is_err());
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("2024-1").is_err());
+        assert!(parse_date("yesterday").is_err());
+
+        assert_eq!(format_datetime(1_709_251_198), "2024-02-29T23:59:58Z");
+        assert_eq!(
+            parse_datetime("2024-02-29T23:59:58Z").unwrap(),
+            1_709_251_198
+        );
+        assert!(parse_datetime("2024-02-29T24:00:00Z").is_err());
+        assert!(parse_datetime("2024-02-29 23:59:58").is_err());
+    }
+}
@@ -0,0 +1,331 @@
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::{now, Library, Track};
+use crate::bitrate::BitRate;
+use crate::error::Error;
+use crate::id3::{id3v2_len, Tag, TrackTags};
+use crate::mp3::{Frames, Mp3Info};
+use crate::storage::hex;
+use crate::youtube_url::is_youtube_id;
+
+/// An MP3 file that could not be added to the library (or retagged)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unmatched {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// What `import` found
+#[derive(Debug, Default)]
+pub struct Imported {
+    /// YouTube IDs added to the library
+    pub added: Vec<String>,
+    /// Files whose YouTube ID the library already knew, left alone
+    pub known: usize,
+    pub unmatched: Vec<Unmatched>,
+}
+
+/// The YouTube ID in `name`, the name of an MP3 file: either all of it (`<id>.mp3`, as photon
+/// saves files) or the last part, in square brackets (`Title [<id>].mp3`, as yt-dlp does)
+pub fn youtube_id_in(name: &str) -> Option<&str> {
+    let stem = name.strip_suffix(".mp3").or(name.strip_suffix(".MP3"))?;
+    let id = match stem.strip_suffix(']') {
+        Some(rest) => &rest[rest.rfind('[')? + 1..],
+        None => stem,
+    };
+
+    is_youtube_id(id).then_some(id)
+}
+
+/// Every `.mp3` file under `dir`, in order
+fn mp3_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            mp3_files(&path, files)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("mp3"))
+        {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// What reading an MP3 file through once tells
+struct Scanned {
+    tag: Option<Tag>,
+    size: u64,
+    sha256: String,
+    info: Option<Mp3Info>,
+}
+
+/// Reads the MP3 file at `path` chunk by chunk, keeping only its ID3v2 tag and last 128 bytes
+fn scan(path: &Path) -> Result<Scanned, std::io::Error> {
+    let mut file = std::fs::File::open(path)?;
+
+    let mut head = Vec::new();
+    (&mut file).take(10).read_to_end(&mut head)?;
+    let tag_len = id3v2_len(&head);
+    (&mut file)
+        .take(tag_len.saturating_sub(head.len()) as u64)
+        .read_to_end(&mut head)?;
+
+    let (mut size, mut sha256, mut frames) = (0, Sha256::new(), Frames::new(tag_len));
+    let mut tail = Vec::new();
+    let mut chunk = head.clone();
+    while !chunk.is_empty() {
+        size += chunk.len() as u64;
+        sha256.update(&chunk);
+        frames.push(&chunk);
+        tail.extend_from_slice(&chunk);
+        tail.drain(..tail.len().saturating_sub(128));
+
+        chunk.resize(64 * 1024, 0);
+        let n = file.read(&mut chunk)?;
+        chunk.truncate(n);
+    }
+
+    Ok(Scanned {
+        tag: Tag::read_ends(&head, &tail),
+        size,
+        sha256: hex(&sha256.finalize()),
+        info: frames.finish(),
+    })
+}
+
+/// What can be learned about the MP3 file at `path` (`scanned`) of `youtube_id`, `name` in the
+/// directory being imported. What photon wrote into its tags is trusted over the file.
+fn track(path: &Path, name: &str, youtube_id: &str, scanned: &Scanned) -> Result<Track, String> {
+    let info = scanned.info.ok_or("no MPEG audio frames in the file")?;
+    let tag = scanned.tag.as_ref();
+
+    let title = tag.and_then(|tag| match (tag.artist(), tag.title()) {
+        (Some(artist), Some(title)) => Some(format!("{} - {}", artist, title)),
+        (_, title) => title.map(String::from),
+    });
+
+    let downloaded_at = std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_else(now);
+
+    let mut track = Track {
+        id: youtube_id.to_string(),
+        title,
+        url: format!("https://www.youtube.com/watch?v={}", youtube_id),
+        bitrate: BitRate::nearest(info.kbps),
+        server_path: None,
+        path: path.to_path_buf(),
+        name: (name != format!("{}.mp3", youtube_id)).then(|| name.to_string()),
+        size: scanned.size,
+        sha256: scanned.sha256.clone(),
+        downloaded_at,
+        duration_ms: Some(info.duration_ms),
+        tempo: None,
+        key: None,
+        energy: None,
+    };
+
+    if let Some(tags) = tag.and_then(TrackTags::read).filter(|t| t.id == youtube_id) {
+        track.url = tags.url;
+        track.title = tags.title.or(track.title);
+        track.bitrate = tags.bitrate;
+        track.downloaded_at = tags.downloaded_at;
+    }
+
+    Ok(track)
+}
+
+/// Adds every MP3 file under `dir` whose name holds a YouTube ID (see `youtube_id_in`), or whose
+/// tags do (as photon writes them), to `library`, with what its ID3 tags and MPEG frames tell.
+/// IDs the library already knows are left alone; call `Library::save` to keep the rest.
+pub fn import(library: &mut Library, dir: &Path) -> Result<Imported, Error> {
+    let mut files = Vec::new();
+    mp3_files(dir, &mut files)?;
+
+    let mut imported = Imported::default();
+    for path in files {
+        let unmatched = |reason: String| Unmatched {
+            path: path.clone(),
+            reason,
+        };
+
+        let scanned = match scan(&path) {
+            Ok(scanned) => scanned,
+            Err(e) => {
+                imported.unmatched.push(unmatched(e.to_string()));
+                continue;
+            }
+        };
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let Some(id) = youtube_id_in(&name).or_else(|| {
+            scanned
+                .tag
+                .as_ref()?
+                .youtube_id()
+                .filter(|id| is_youtube_id(id))
+        }) else {
+            imported.unmatched.push(unmatched(String::from(
+                "no YouTube ID in the file name or tags",
+            )));
+            continue;
+        };
+
+        if let Some(known) = library.get(id) {
+            match imported.added.iter().any(|added| added == id) {
+                true => imported.unmatched.push(unmatched(format!(
+                    "{} has the same YouTube ID",
+                    known.path.display()
+                ))),
+                false => imported.known += 1,
+            }
+            continue;
+        }
+
+        let name = path.strip_prefix(dir).unwrap_or(&path).to_string_lossy();
+        match track(&path, &name, id, &scanned) {
+            Ok(track) => {
+                imported.added.push(track.id.clone());
+                library.insert(track);
+            }
+            Err(reason) => imported.unmatched.push(unmatched(reason)),
+        }
+    }
+
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id3::{replace_tag, Version};
+    use crate::library::INDEX_NAME;
+    use crate::titles::TitleRules;
+
+    #[test]
+    fn test_youtube_id_in() {
+        assert_eq!(youtube_id_in("yPvoKz6tyJs.mp3"), Some("yPvoKz6tyJs"));
+        assert_eq!(
+            youtube_id_in("Daft Punk - One More Time [FGBhQbmPwH8].mp3"),
+            Some("FGBhQbmPwH8")
+        );
+        assert_eq!(youtube_id_in("Daft Punk - One More Time.mp3"), None);
+        assert_eq!(youtube_id_in("yPvoKz6tyJs.mp3.part"), None);
+        assert_eq!(youtube_id_in("yPvoKz6tyJ$.mp3"), None);
+        assert_eq!(youtube_id_in("[yPvoKz6tyJsX].mp3"), None);
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let mp3 = crate::mock::silent_mp3(100);
+
+        let mut tagged = vec![b'I', b'D', b'3', 3, 0, 0, 0, 0, 0, 44];
+        for (id, text) in [("TIT2", "One More Time"), ("TPE1", "Daft Punk")] {
+            tagged.extend_from_slice(id.as_bytes());
+            tagged.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+            tagged.extend_from_slice(&[0, 0, 0]);
+            tagged.extend_from_slice(text.as_bytes());
+        }
+        tagged.extend_from_slice(&mp3);
+
+        std::fs::create_dir(dir.path().join("old")).unwrap();
+        std::fs::write(dir.path().join("yPvoKz6tyJs.mp3"), &mp3).unwrap();
+        std::fs::write(
+            dir.path().join("old/One More Time [FGBhQbmPwH8].mp3"),
+            &tagged,
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("old/FGBhQbmPwH8.mp3"), &mp3).unwrap();
+        std::fs::write(dir.path().join("old/mixtape.mp3"), &mp3).unwrap();
+        std::fs::write(dir.path().join("dQw4w9WgXcQ.mp3"), b"<html>").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"").unwrap();
+
+        // longer than a chunk read at once, with an ID3v1 tag at the end
+        let mut long = crate::mock::silent_mp3(400);
+        long.extend_from_slice(b"TAGDespacito");
+        long.resize(long.len() + 116, 0);
+        std::fs::write(dir.path().join("Despacito [kJQP7kiw5Fk].mp3"), &long).unwrap();
+
+        let tags = TrackTags {
+            id: String::from("9bZkp7q5f0w"),
+            url: String::from("https://www.youtube.com/watch?v=9bZkp7q5f0w"),
+            title: Some(String::from("PSY - GANGNAM STYLE")),
+            bitrate: BitRate::Kbps320,
+            downloaded_at: 1_700_000_000,
+        };
+        let mut tag = Tag::default();
+        tags.apply(&mut tag, &TitleRules::default());
+        std::fs::write(
+            dir.path().join("Gangnam Style.mp3"),
+            replace_tag(&mp3, &tag.to_bytes(Version::V2_4)),
+        )
+        .unwrap();
+
+        let mut library = Library::open(dir.path().join(INDEX_NAME)).unwrap();
+        let imported = import(&mut library, dir.path()).unwrap();
+
+        assert_eq!(
+            imported.added,
+            ["kJQP7kiw5Fk", "9bZkp7q5f0w", "FGBhQbmPwH8", "yPvoKz6tyJs"]
+        );
+        assert_eq!(
+            imported
+                .unmatched
+                .iter()
+                .map(|u| u.path.strip_prefix(dir.path()).unwrap().to_str().unwrap())
+                .collect::<Vec<_>>(),
+            [
+                "dQw4w9WgXcQ.mp3",
+                "old/One More Time [FGBhQbmPwH8].mp3",
+                "old/mixtape.mp3"
+            ]
+        );
+
+        let track = library.get("yPvoKz6tyJs").unwrap();
+        assert_eq!(track.title, None);
+        assert_eq!(track.bitrate, BitRate::Kbps128);
+        assert_eq!(track.duration_ms, Some(2612));
+        assert_eq!(track.size, mp3.len() as u64);
+        assert_eq!(track.sha256, hex(&Sha256::digest(&mp3)));
+        assert_eq!(track.path, dir.path().join("yPvoKz6tyJs.mp3"));
+
+        let track = library.get("kJQP7kiw5Fk").unwrap();
+        assert_eq!(track.title.as_deref(), Some("Despacito"));
+        assert_eq!(track.duration_ms, Some(10448));
+        assert_eq!(track.sha256, hex(&Sha256::digest(&long)));
+
+        // what photon tagged the file with wins over what the file tells
+        let track = library.get("9bZkp7q5f0w").unwrap();
+        assert_eq!(TrackTags::from(track), tags);
+        assert_eq!(track.name.as_deref(), Some("Gangnam Style.mp3"));
+
+        // known IDs are left alone, tagged ones get their title
+        library.remove("FGBhQbmPwH8");
+        std::fs::remove_file(dir.path().join("old/FGBhQbmPwH8.mp3")).unwrap();
+        let imported = import(&mut library, dir.path()).unwrap();
+        assert_eq!(imported.added, ["FGBhQbmPwH8"]);
+        assert_eq!(imported.known, 3);
+        assert_eq!(
+            library.get("FGBhQbmPwH8").unwrap().title.as_deref(),
+            Some("Daft Punk - One More Time")
+        );
+        assert_eq!(
+            library.get("FGBhQbmPwH8").unwrap().file_name(),
+            "old/One More Time [FGBhQbmPwH8].mp3"
+        );
+        assert_eq!(library.get("yPvoKz6tyJs").unwrap().name, None);
+    }
+}
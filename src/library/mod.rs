@@ -0,0 +1,243 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::analysis::Tempo;
+use crate::bitrate::BitRate;
+use crate::error::{Error, ErrorKind};
+
+mod analyze;
+pub mod date;
+mod import;
+mod query;
+mod retag;
+mod suggest;
+pub use analyze::{analyze, analyze_track, Analyzed, DEFAULT_MIN_CONFIDENCE};
+pub use import::{import, youtube_id_in, Imported, Unmatched};
+pub use query::{render, OutputFormat, Query, TitleFilter};
+pub use retag::{retag, Retagged};
+pub use suggest::{
+    render_suggestions, suggest, KeyMove, SuggestFormat, Suggestion, Suggestions, DEFAULT_BPM_RANGE,
+};
+
+/// Name of the index file, kept in the output directory unless told otherwise
+pub const INDEX_NAME: &str = "library.json";
+
+/// Version of the index format written by this version of photon
+const INDEX_VERSION: u32 = 1;
+
+/// What is known about a converted video and its MP3 file
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Track {
+    /// YouTube ID of the video
+    pub id: String,
+    /// Title of the video, as cnvmp3 told it
+    pub title: Option<String>,
+    /// YouTube URL the video was converted from
+    pub url: String,
+    /// Bitrate the MP3 file was asked for at
+    pub bitrate: BitRate,
+    /// Where cnvmp3 served the MP3 file from
+    pub server_path: Option<String>,
+    /// Where the MP3 file was saved (a location in the storage for a remote one)
+    pub path: PathBuf,
+    /// Name of the MP3 file in its storage, when it is not `<id>.mp3`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Size of the MP3 file in bytes
+    pub size: u64,
+    /// SHA-256 of the MP3 file
+    pub sha256: String,
+    /// When the MP3 file was saved, in seconds since the Unix epoch
+    pub downloaded_at: u64,
+    /// Length of the audio in milliseconds, when it has been measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Tempo of the audio, when it has been analyzed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tempo: Option<Tempo>,
+    /// Musical key of the audio, when it has been analyzed, in the notation asked for then (see
+    /// `Notation`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// How intense the audio sounds, from 1 (calm) to 10 (intense), when it has been analyzed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub energy: Option<u8>,
+}
+
+impl Track {
+    /// Name of the MP3 file in its storage
+    pub fn file_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("{}.mp3", self.id),
+        }
+    }
+}
+
+/// Layout of the index file
+#[derive(Deserialize, Serialize)]
+struct Index {
+    version: u32,
+    tracks: BTreeMap<String, Track>,
+}
+
+/// The index of converted videos by YouTube ID, stored as JSON at `path`
+#[derive(Debug)]
+pub struct Library {
+    path: PathBuf,
+    tracks: BTreeMap<String, Track>,
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Library {
+    /// Reads the index at `path`, or starts an empty one if there is no such file yet
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+
+        let json = match std::fs::read(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Library {
+                    path,
+                    tracks: BTreeMap::new(),
+                })
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let index: Index = serde_json::from_slice(&json).map_err(|e| Error {
+            kind: ErrorKind::SerdeError,
+            value: format!("{} is not a library index: {}", path.display(), e),
+        })?;
+        if index.version > INDEX_VERSION {
+            return Err(Error::from(format!(
+                "{} was written by a newer photon (index version {})",
+                path.display(),
+                index.version
+            )));
+        }
+
+        Ok(Library {
+            path,
+            tracks: index.tracks,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Track> {
+        self.tracks.get(id)
+    }
+
+    /// Every track, by YouTube ID
+    pub fn tracks(&self) -> impl Iterator<Item = &Track> {
+        self.tracks.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    /// The tracks that `query` keeps, by YouTube ID
+    pub fn search(&self, query: &Query) -> Vec<&Track> {
+        self.tracks().filter(|track| query.matches(track)).collect()
+    }
+
+    /// Records `track`, replacing what was known about its ID; call `save` to keep it
+    pub fn insert(&mut self, track: Track) {
+        self.tracks.insert(track.id.clone(), track);
+    }
+
+    /// Forgets the track of `id`; call `save` to keep it forgotten
+    pub fn remove(&mut self, id: &str) -> Option<Track> {
+        self.tracks.remove(id)
+    }
+
+    /// Writes the index back to its file, all at once so that an interrupted write cannot leave a
+    /// broken index behind
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let index = Index {
+            version: INDEX_VERSION,
+            tracks: self.tracks.clone(),
+        };
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&index)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn track(id: &str) -> Track {
+        Track {
+            id: id.to_string(),
+            title: Some(String::from("Mock Artist - Mock Track")),
+            url: format!("https://www.youtube.com/watch?v={}", id),
+            bitrate: BitRate::Kbps128,
+            server_path: Some(String::from("https://cdn.example.com/download.php?id=1")),
+            path: PathBuf::from(format!("mp3/{}.mp3", id)),
+            name: None,
+            size: 417,
+            sha256: String::from("ab"),
+            downloaded_at: 1_700_000_000,
+            duration_ms: None,
+            tempo: None,
+            key: None,
+            energy: None,
+        }
+    }
+
+    #[test]
+    fn test_library() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index").join(INDEX_NAME);
+
+        let mut library = Library::open(&path).unwrap();
+        assert!(library.is_empty());
+        library.insert(track("yPvoKz6tyJs"));
+        library.insert(track("dQw4w9WgXcQ"));
+        library.save().unwrap();
+
+        let mut library = Library::open(&path).unwrap();
+        assert_eq!(library.len(), 2);
+        assert_eq!(library.get("yPvoKz6tyJs"), Some(&track("yPvoKz6tyJs")));
+        assert_eq!(
+            library.tracks().map(|t| t.id.as_str()).collect::<Vec<_>>(),
+            ["dQw4w9WgXcQ", "yPvoKz6tyJs"]
+        );
+
+        library.remove("dQw4w9WgXcQ");
+        library.save().unwrap();
+        assert_eq!(Library::open(&path).unwrap().len(), 1);
+        assert_eq!(
+            std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+            1
+        );
+
+        std::fs::write(&path, "[]").unwrap();
+        assert!(Library::open(&path).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use clap::ValueEnum;
+use regex::Regex;
+
+use super::date::format_date;
+use super::Track;
+use crate::bitrate::BitRate;
+use crate::error::Error;
+
+/// How a title is looked for
+#[derive(Clone, Debug)]
+pub enum TitleFilter {
+    /// Text found anywhere in the title, ignoring case
+    Contains(String),
+    /// A regular expression matching part of the title
+    Regex(Regex),
+}
+
+/// Which tracks to keep; tracks must pass every filter that is set
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    pub title: Option<TitleFilter>,
+    pub bitrate: Option<BitRate>,
+    /// Earliest download time, in seconds since the Unix epoch
+    pub since: Option<u64>,
+    /// Download times must be before this, in seconds since the Unix epoch
+    pub until: Option<u64>,
+    /// Start of the path (or storage location) the file was saved at, e.g. `s3://music`
+    pub location: Option<String>,
+}
+
+impl Query {
+    pub fn matches(&self, track: &Track) -> bool {
+        let title = track.title.as_deref().unwrap_or("");
+
+        let title_matches = match &self.title {
+            None => true,
+            Some(TitleFilter::Contains(text)) => {
+                title.to_lowercase().contains(&text.to_lowercase())
+            }
+            Some(TitleFilter::Regex(regex)) => regex.is_match(title),
+        };
+
+        title_matches
+            && self.bitrate.is_none_or(|bitrate| track.bitrate == bitrate)
+            && self.since.is_none_or(|since| track.downloaded_at >= since)
+            && self.until.is_none_or(|until| track.downloaded_at < until)
+            && self
+                .location
+                .as_ref()
+                .is_none_or(|location| track.path.to_string_lossy().starts_with(location.as_str()))
+    }
+}
+
+/// How `photon list` and `photon search` print tracks
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned columns for reading
+    #[default]
+    Table,
+    /// The tracks as they are kept in the index
+    Json,
+    /// One line per track, with a header
+    Csv,
+}
+
+/// `tracks` written out in `format`
+pub fn render(tracks: &[&Track], format: OutputFormat) -> Result<String, Error> {
+    match format {
+        OutputFormat::Table => Ok(table(tracks)),
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(tracks)? + "\n"),
+        OutputFormat::Csv => Ok(csv(tracks)),
+    }
+}
+
+fn table(tracks: &[&Track]) -> String {
+    let mut rows = vec![["ID", "KBPS", "SIZE", "DOWNLOADED", "TITLE", "PATH"].map(String::from)];
+    for track in tracks {
+        rows.push([
+            track.id.clone(),
+            track.bitrate.kbps().to_string(),
+            track.size.to_string(),
+            format_date(track.downloaded_at),
+            track.title.clone().unwrap_or_default(),
+            track.path.display().to_string(),
+        ]);
+    }
+
+    columns(&rows)
+}
+
+/// `rows` as columns padded to their widest cell, two spaces apart
+pub(super) fn columns<const N: usize>(rows: &[[String; N]]) -> String {
+    let mut widths = [0; N];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    for row in rows {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+
+    out
+}
+
+/// `field` quoted when it holds a comma, quote or line break (RFC 4180)
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+fn csv(tracks: &[&Track]) -> String {
+    let mut out =
+        String::from("id,title,url,kbps,server_path,path,size,sha256,downloaded_at,duration_ms\n");
+
+    for track in tracks {
+        let fields = [
+            track.id.clone(),
+            track.title.clone().unwrap_or_default(),
+            track.url.clone(),
+            track.bitrate.kbps().to_string(),
+            track.server_path.clone().unwrap_or_default(),
+            track.path.display().to_string(),
+            track.size.to_string(),
+            track.sha256.clone(),
+            track.downloaded_at.to_string(),
+            track
+                .duration_ms
+                .map(|ms| ms.to_string())
+                .unwrap_or_default(),
+        ];
+        let fields: Vec<_> = fields.iter().map(|f| csv_field(f)).collect();
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::date::parse_date;
+    use crate::library::tests::track;
+
+    #[test]
+    fn test_query() {
+        let mut old = track("yPvoKz6tyJs");
+        old.downloaded_at = parse_date("2024-01-15").unwrap() + 3600;
+        let mut new = track("dQw4w9WgXcQ");
+        new.title = Some(String::from("Rick Astley - Never Gonna Give You Up"));
+        new.bitrate = BitRate::Kbps320;
+        new.path = "s3://music/dQw4w9WgXcQ.mp3".into();
+        new.downloaded_at = parse_date("2024-03-01").unwrap();
+
+        let found = |query: Query| {
+            [&old, &new]
+                .into_iter()
+                .filter(|t| query.matches(t))
+                .map(|t| t.id.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(found(Query::default()), ["yPvoKz6tyJs", "dQw4w9WgXcQ"]);
+        assert_eq!(
+            found(Query {
+                title: Some(TitleFilter::Contains(String::from("never gonna"))),
+                ..Default::default()
+            }),
+            ["dQw4w9WgXcQ"]
+        );
+        assert_eq!(
+            found(Query {
+                title: Some(TitleFilter::Regex(Regex::new("^Mock .* Track$").unwrap())),
+                ..Default::default()
+            }),
+            ["yPvoKz6tyJs"]
+        );
+        assert_eq!(
+            found(Query {
+                bitrate: Some(BitRate::Kbps320),
+                ..Default::default()
+            }),
+            ["dQw4w9WgXcQ"]
+        );
+        assert_eq!(
+            found(Query {
+                since: Some(parse_date("2024-01-15").unwrap()),
+                until: Some(parse_date("2024-01-16").unwrap()),
+                ..Default::default()
+            }),
+            ["yPvoKz6tyJs"]
+        );
+        assert_eq!(
+            found(Query {
+                location: Some(String::from("s3://music")),
+                ..Default::default()
+            }),
+            ["dQw4w9WgXcQ"]
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let mut track = track("yPvoKz6tyJs");
+        track.title = Some(String::from("Artist - \"Track\", Remix"));
+        track.downloaded_at = parse_date("2024-02-29").unwrap();
+
+        let table = render(&[&track], OutputFormat::Table).unwrap();
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("ID           KBPS  SIZE  DOWNLOADED  TITLE"));
+        assert!(
+            lines[1].starts_with("yPvoKz6tyJs  128   417   2024-02-29  Artist - \"Track\", Remix")
+        );
+
+        let csv = render(&[&track], OutputFormat::Csv).unwrap();
+        assert_eq!(
+            csv.lines().nth(1).unwrap(),
+            format!(
+                "yPvoKz6tyJs,\"Artist - \"\"Track\"\", Remix\",{},128,{},mp3/yPvoKz6tyJs.mp3,417,ab,{},",
+                track.url,
+                track.server_path.as_deref().unwrap(),
+                track.downloaded_at
+            )
+        );
+
+        let json = render(&[&track], OutputFormat::Json).unwrap();
+        let tracks: Vec<Track> = serde_json::from_str(&json).unwrap();
+        assert_eq!(tracks, [track]);
+    }
+}
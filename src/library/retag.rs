@@ -0,0 +1,135 @@
+use sha2::{Digest, Sha256};
+
+use super::{Library, Track, Unmatched};
+use crate::id3::{replace_tag, Tag, TrackTags, Version};
+use crate::storage::hex;
+use crate::titles::TitleRules;
+
+/// What `retag` did
+#[derive(Debug, Default)]
+pub struct Retagged {
+    /// YouTube IDs whose files got new tags
+    pub retagged: Vec<String>,
+    /// Files whose tags were already up to date
+    pub unchanged: usize,
+    /// Files that could not be retagged, such as missing or remote ones
+    pub failed: Vec<Unmatched>,
+}
+
+/// Rewrites the ID3v2 tags (as `version`) of every local MP3 file in `library` from what the
+/// index knows about it (see `TrackTags`), its title parsed with `rules`, keeping the frames
+/// photon does not write. The size and SHA-256 of the files are updated in the index; call
+/// `Library::save` to keep them.
+pub fn retag(library: &mut Library, version: Version, rules: &TitleRules) -> Retagged {
+    let mut retagged = Retagged::default();
+    let tracks: Vec<_> = library.tracks().cloned().collect();
+
+    for mut track in tracks {
+        let path = track.path.clone();
+        let failed = |reason: String| Unmatched {
+            path: path.clone(),
+            reason,
+        };
+
+        let mp3 = match std::fs::read(&track.path) {
+            Ok(mp3) => mp3,
+            Err(e) => {
+                retagged.failed.push(failed(e.to_string()));
+                continue;
+            }
+        };
+
+        let mut tag = Tag::read(&mp3).unwrap_or_default();
+        TrackTags::from(&track).apply(&mut tag, rules);
+        let tagged = replace_tag(&mp3, &tag.to_bytes(version));
+        if tagged == mp3 {
+            retagged.unchanged += 1;
+            continue;
+        }
+
+        if let Err(e) = write_tagged(&mut track, &tagged) {
+            retagged.failed.push(failed(e.to_string()));
+            continue;
+        }
+
+        retagged.retagged.push(track.id.clone());
+        library.insert(track);
+    }
+
+    retagged
+}
+
+/// Replaces the MP3 file of `track` with `tagged` and updates its size and SHA-256. It is written
+/// next to the file first, so that the file is never left half written.
+fn write_tagged(track: &mut Track, tagged: &[u8]) -> Result<(), std::io::Error> {
+    let tmp = track.path.with_extension("mp3.tagged");
+    let res = std::fs::write(&tmp, tagged).and_then(|_| std::fs::rename(&tmp, &track.path));
+    if let Err(e) = res {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+
+    track.size = tagged.len() as u64;
+    track.sha256 = hex(&Sha256::digest(tagged));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id3::Frame;
+    use crate::library::tests::track;
+    use crate::library::INDEX_NAME;
+
+    #[test]
+    fn test_retag() {
+        let dir = tempfile::tempdir().unwrap();
+        let mp3 = crate::mock::silent_mp3(10);
+
+        // a file tagged by something else, and one saved before photon wrote tags
+        let mut tag = Tag::default();
+        tag.set(Frame::Text {
+            id: String::from("TALB"),
+            value: String::from("Discovery"),
+        });
+        let mut tagged = track("yPvoKz6tyJs");
+        tagged.path = dir.path().join("yPvoKz6tyJs.mp3");
+        std::fs::write(
+            &tagged.path,
+            replace_tag(&mp3, &tag.to_bytes(Version::V2_3)),
+        )
+        .unwrap();
+        let mut untagged = track("dQw4w9WgXcQ");
+        untagged.path = dir.path().join("dQw4w9WgXcQ.mp3");
+        std::fs::write(&untagged.path, &mp3).unwrap();
+        let missing = track("FGBhQbmPwH8");
+
+        let mut library = Library::open(dir.path().join(INDEX_NAME)).unwrap();
+        for track in [&tagged, &untagged, &missing] {
+            library.insert(track.clone());
+        }
+
+        let retagged = retag(&mut library, Version::V2_4, &TitleRules::default());
+        assert_eq!(retagged.retagged, ["dQw4w9WgXcQ", "yPvoKz6tyJs"]);
+        assert_eq!(retagged.failed.len(), 1);
+        assert_eq!(retagged.failed[0].path, missing.path);
+
+        for track in [&tagged, &untagged] {
+            let file = std::fs::read(&track.path).unwrap();
+            let indexed = library.get(&track.id).unwrap();
+            assert_eq!(indexed.size, file.len() as u64);
+            assert_eq!(indexed.sha256, hex(&Sha256::digest(&file)));
+
+            let tag = Tag::read(&file).unwrap();
+            assert_eq!(TrackTags::read(&tag), Some(TrackTags::from(track)));
+        }
+        let file = std::fs::read(&tagged.path).unwrap();
+        assert_eq!(Tag::read(&file).unwrap().text("TALB"), Some("Discovery"));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        // once tagged, files are left alone
+        let retagged = retag(&mut library, Version::V2_4, &TitleRules::default());
+        assert!(retagged.retagged.is_empty());
+        assert_eq!(retagged.unchanged, 2);
+    }
+}
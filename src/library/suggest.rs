@@ -0,0 +1,340 @@
+use clap::ValueEnum;
+use std::fmt;
+
+use super::query::columns;
+use super::{Library, Track};
+use crate::analysis::Key;
+use crate::error::Error;
+
+/// Difference in tempo, in percent, within which tracks are suggested unless told otherwise: as
+/// far as a pitch fader of ±6% reaches
+pub const DEFAULT_BPM_RANGE: f64 = 6.0;
+
+/// How much the key, the tempo and the energy count towards the score of a suggestion
+const KEY_WEIGHT: f64 = 0.5;
+const TEMPO_WEIGHT: f64 = 0.3;
+const ENERGY_WEIGHT: f64 = 0.2;
+
+/// How the key of a track leads on from the key of the one before, on the Camelot wheel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyMove {
+    /// The same key (`8A` to `8A`)
+    Same,
+    /// One hour up the wheel, a fifth higher (`8A` to `9A`)
+    Up,
+    /// One hour down the wheel, a fifth lower (`8A` to `7A`)
+    Down,
+    /// The relative major or minor key, on the same hour (`8A` to `8B`)
+    Relative,
+}
+
+impl KeyMove {
+    /// How `to` leads on from `from`, or `None` if the keys clash
+    pub fn between(from: Key, to: Key) -> Option<Self> {
+        let hours = (to.camelot() + 12 - from.camelot()) % 12;
+        match (hours, from.minor == to.minor) {
+            (0, true) => Some(KeyMove::Same),
+            (1, true) => Some(KeyMove::Up),
+            (11, true) => Some(KeyMove::Down),
+            (0, false) => Some(KeyMove::Relative),
+            _ => None,
+        }
+    }
+
+    /// How smoothly the move sounds, from 0 to 1
+    fn smoothness(self) -> f64 {
+        match self {
+            KeyMove::Same => 1.0,
+            KeyMove::Up | KeyMove::Down => 0.9,
+            KeyMove::Relative => 0.8,
+        }
+    }
+}
+
+impl fmt::Display for KeyMove {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            KeyMove::Same => "same",
+            KeyMove::Up => "+1",
+            KeyMove::Down => "-1",
+            KeyMove::Relative => "relative",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A track that mixes well after another one
+#[derive(Clone, Debug, PartialEq)]
+pub struct Suggestion<'a> {
+    pub track: &'a Track,
+    pub key_move: KeyMove,
+    /// What the tempo of `track` is multiplied by to meet the other one: 1, or 2 or 0.5 to mix in
+    /// double or half time
+    pub time: f64,
+    /// How much faster (or, below 0, slower) `track` is in percent, once multiplied by `time`
+    pub bpm_difference: f64,
+    /// How well `track` fits, from 0 to 1, the key counting for most, then the tempo and then the
+    /// energy
+    pub score: f64,
+}
+
+/// What `suggest` found
+#[derive(Debug, Default)]
+pub struct Suggestions<'a> {
+    /// Tracks that mix well, best first
+    pub suggestions: Vec<Suggestion<'a>>,
+    /// Tracks whose tempo or key is not known, so that they could not be weighed
+    pub unanalyzed: usize,
+}
+
+/// The tempo and key of `track`, which it needs to be weighed
+fn tempo_and_key(track: &Track) -> Option<(f64, Key)> {
+    let bpm = track.tempo?.grid.bpm;
+    let key = track.key.as_deref()?.parse().ok()?;
+    Some((bpm, key))
+}
+
+/// Ranks the tracks of `library` that mix well after `track`: those whose key is the same as, next
+/// to or relative to its key on the Camelot wheel, and whose tempo is within `bpm_range` percent of
+/// its tempo, straight or in half or double time
+pub fn suggest<'a>(
+    library: &'a Library,
+    track: &Track,
+    bpm_range: f64,
+) -> Result<Suggestions<'a>, Error> {
+    let (bpm, key) = tempo_and_key(track).ok_or(Error::from(format!(
+        "the tempo or key of {} is not known yet (see `photon analyze`)",
+        track.id
+    )))?;
+
+    let mut suggestions = Suggestions::default();
+    for candidate in library.tracks().filter(|t| t.id != track.id) {
+        let Some((candidate_bpm, candidate_key)) = tempo_and_key(candidate) else {
+            suggestions.unanalyzed += 1;
+            continue;
+        };
+        let Some(key_move) = KeyMove::between(key, candidate_key) else {
+            continue;
+        };
+
+        // the nearest of straight, double and half time
+        let (time, bpm_difference) = [1.0, 2.0, 0.5]
+            .map(|time| (time, (candidate_bpm * time / bpm - 1.0) * 100.0))
+            .into_iter()
+            .min_by(|(_, a), (_, b)| a.abs().total_cmp(&b.abs()))
+            .unwrap_or((1.0, 0.0));
+        if bpm_difference.abs() > bpm_range {
+            continue;
+        }
+
+        let tempo_fit = match bpm_range > 0.0 {
+            true => 1.0 - bpm_difference.abs() / bpm_range,
+            false => 1.0,
+        };
+        // a track whose energy is not known is neither near nor far
+        let energy_fit = match (track.energy, candidate.energy) {
+            (Some(a), Some(b)) => 1.0 - a.abs_diff(b) as f64 / 9.0,
+            _ => 0.5,
+        };
+
+        suggestions.suggestions.push(Suggestion {
+            track: candidate,
+            key_move,
+            time,
+            bpm_difference,
+            score: KEY_WEIGHT * key_move.smoothness()
+                + TEMPO_WEIGHT * tempo_fit
+                + ENERGY_WEIGHT * energy_fit,
+        });
+    }
+
+    suggestions
+        .suggestions
+        .sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(suggestions)
+}
+
+/// How `photon suggest` prints suggestions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SuggestFormat {
+    /// Aligned columns for reading
+    #[default]
+    Table,
+    /// An extended M3U playlist of the track and then the suggestions, to load into a player
+    M3u,
+}
+
+/// `suggestions` for `track` written out in `format`
+pub fn render_suggestions(
+    track: &Track,
+    suggestions: &[Suggestion],
+    format: SuggestFormat,
+) -> String {
+    match format {
+        SuggestFormat::Table => suggestion_table(suggestions),
+        SuggestFormat::M3u => {
+            m3u(std::iter::once(track).chain(suggestions.iter().map(|s| s.track)))
+        }
+    }
+}
+
+fn suggestion_table(suggestions: &[Suggestion]) -> String {
+    let mut rows = vec![[
+        "ID", "BPM", "DIFF", "KEY", "MOVE", "ENERGY", "SCORE", "TITLE",
+    ]
+    .map(String::from)];
+    for suggestion in suggestions {
+        let track = suggestion.track;
+        let time = match suggestion.time {
+            2.0 => " (x2)",
+            0.5 => " (/2)",
+            _ => "",
+        };
+        rows.push([
+            track.id.clone(),
+            track
+                .tempo
+                .map(|t| format!("{:.2}", t.grid.bpm))
+                .unwrap_or_default(),
+            format!("{:+.1}%{}", suggestion.bpm_difference, time),
+            track.key.clone().unwrap_or_default(),
+            suggestion.key_move.to_string(),
+            track.energy.map(|e| e.to_string()).unwrap_or_default(),
+            format!("{:.2}", suggestion.score),
+            track.title.clone().unwrap_or_default(),
+        ]);
+    }
+
+    columns(&rows)
+}
+
+fn m3u<'a>(tracks: impl Iterator<Item = &'a Track>) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for track in tracks {
+        // -1 for a length that is not known
+        let seconds = track.duration_ms.map(|ms| (ms / 1000) as i64).unwrap_or(-1);
+        let title = track.title.as_deref().unwrap_or(&track.id);
+        out.push_str(&format!(
+            "#EXTINF:{},{}\n{}\n",
+            seconds,
+            title.replace(['\r', '\n'], " "),
+            track.path.display()
+        ));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{BeatGrid, Tempo};
+    use crate::library::tests::track;
+    use crate::library::INDEX_NAME;
+
+    fn analyzed(id: &str, bpm: f64, key: &str, energy: u8) -> Track {
+        let mut track = track(id);
+        track.tempo = Some(Tempo {
+            grid: BeatGrid {
+                bpm,
+                downbeat_ms: 0.0,
+            },
+            confidence: 0.9,
+            alternative_bpm: None,
+        });
+        track.key = Some(key.to_string());
+        track.energy = Some(energy);
+        track
+    }
+
+    #[test]
+    fn test_key_moves() {
+        let between =
+            |from: &str, to: &str| KeyMove::between(from.parse().unwrap(), to.parse().unwrap());
+
+        assert_eq!(between("8A", "Am"), Some(KeyMove::Same));
+        assert_eq!(between("8A", "9A"), Some(KeyMove::Up));
+        assert_eq!(between("1A", "12A"), Some(KeyMove::Down));
+        assert_eq!(between("12B", "1B"), Some(KeyMove::Up));
+        assert_eq!(between("Am", "C"), Some(KeyMove::Relative));
+        assert_eq!(between("8A", "9B"), None);
+        assert_eq!(between("8A", "10A"), None);
+        assert_eq!(between("Am", "F#m"), None);
+    }
+
+    #[test]
+    fn test_suggest() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut library = Library::open(dir.path().join(INDEX_NAME)).unwrap();
+
+        let playing = analyzed("yPvoKz6tyJs", 126.0, "8A", 6);
+        library.insert(playing.clone());
+        // keys written in any notation
+        library.insert(analyzed("dQw4w9WgXcQ", 126.0, "Am", 6));
+        library.insert(analyzed("9bZkp7q19f0", 128.0, "9A", 7));
+        library.insert(analyzed("kJQP7kiw5Fk", 64.0, "C", 3));
+        library.insert(analyzed("fJ9rUzIMcZQ", 174.0, "8A", 6));
+        library.insert(analyzed("hTWKbfoikeg", 126.0, "F#m", 6));
+        library.insert(analyzed("L_jWHffIx5E", 120.0, "7A", 6));
+        library.insert(track("CevxZvSJLk8"));
+
+        let found = suggest(&library, &playing, DEFAULT_BPM_RANGE).unwrap();
+        let ids: Vec<_> = found
+            .suggestions
+            .iter()
+            .map(|s| s.track.id.as_str())
+            .collect();
+        // 174 BPM is too fast, F#m clashes with Am and the last track is not analyzed
+        assert_eq!(
+            ids,
+            ["dQw4w9WgXcQ", "9bZkp7q19f0", "kJQP7kiw5Fk", "L_jWHffIx5E"]
+        );
+        assert_eq!(found.unanalyzed, 1);
+
+        let same = &found.suggestions[0];
+        assert_eq!(same.key_move, KeyMove::Same);
+        assert!((same.score - 1.0).abs() < 1e-9);
+        let half_time = &found.suggestions[2];
+        assert_eq!(half_time.key_move, KeyMove::Relative);
+        assert_eq!(half_time.time, 2.0);
+        assert!((half_time.bpm_difference - 1.587).abs() < 0.01);
+        assert_eq!(found.suggestions[3].key_move, KeyMove::Down);
+
+        // a narrower range leaves out the tracks more than 1% off
+        let found = suggest(&library, &playing, 1.0).unwrap();
+        assert_eq!(found.suggestions.len(), 1);
+
+        assert!(suggest(&library, &track("CevxZvSJLk8"), DEFAULT_BPM_RANGE).is_err());
+    }
+
+    #[test]
+    fn test_render_suggestions() {
+        let mut playing = analyzed("yPvoKz6tyJs", 126.0, "8A", 6);
+        playing.title = Some(String::from("Mock Artist -\nMock Track"));
+        let mut next = analyzed("9bZkp7q19f0", 63.5, "9A", 7);
+        next.title = Some(String::from("Artist - Track (Remix)"));
+        next.duration_ms = Some(215_400);
+        let suggestions = [Suggestion {
+            track: &next,
+            key_move: KeyMove::Up,
+            time: 2.0,
+            bpm_difference: 0.79,
+            score: 0.92,
+        }];
+
+        let table = render_suggestions(&playing, &suggestions, SuggestFormat::Table);
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("ID           BPM    DIFF        KEY  MOVE  ENERGY  SCORE"));
+        assert!(lines[1].starts_with("9bZkp7q19f0  63.50  +0.8% (x2)  9A   +1    7       0.92"));
+
+        // the track playing comes first, on one line whatever its title
+        let m3u = render_suggestions(&playing, &suggestions, SuggestFormat::M3u);
+        assert_eq!(
+            m3u,
+            "#EXTM3U\n\
+             #EXTINF:-1,Mock Artist - Mock Track\nmp3/yPvoKz6tyJs.mp3\n\
+             #EXTINF:215,Artist - Track (Remix)\nmp3/9bZkp7q19f0.mp3\n"
+        );
+    }
+}
@@ -1,15 +1,36 @@
 // ethan stoneman 2024
 
-use clap::{command, Parser, Subcommand};
+use clap::builder::RangedU64ValueParser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use regex::Regex;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 use url::Url;
 
-mod bitrate;
-mod convert;
-mod error;
-mod youtube_url;
+mod progress_bar;
 
-use bitrate::{BitRate, FromNumber};
-use convert::y2mp3;
+use photon::analysis::Notation;
+use photon::bitrate::{BitRate, FromNumber};
+use photon::convert::{
+    read_input, Cassette, Converter, RetryConfig, RetryPolicy, Step, CNV_BASE_URL,
+    DEFAULT_MAX_SIZE, DEFAULT_SEGMENTS,
+};
+use photon::id3::Version;
+use photon::library::date::parse_date;
+use photon::library::{
+    analyze, import, render, render_suggestions, retag, suggest, Library, OutputFormat, Query,
+    SuggestFormat, TitleFilter, DEFAULT_BPM_RANGE, DEFAULT_MIN_CONFIDENCE, INDEX_NAME,
+};
+use photon::migrate::migrate;
+use photon::mock::{mock_server, MockConfig};
+use photon::rekordbox::{collection_xml, export_anlz, CrateList};
+use photon::ssh::SshConfig;
+use photon::storage::Location;
+use photon::sync::{sync, SyncOptions};
+use photon::template::{Template, DEFAULT_TEMPLATE};
+use photon::titles::{TitleRules, DEFAULT_RULES};
+use progress_bar::progress_bars;
 
 /// Top-level command-line argument specification
 #[derive(Parser)]
@@ -20,7 +41,52 @@ struct Cli {
     command: Commands,
 }
 
+/// Filters and output options shared by `list` and `search`
+#[derive(Args)]
+struct LibraryArgs {
+    /// Library index to read
+    #[arg(long, value_name = "FILE", default_value = "mp3/library.json")]
+    library: PathBuf,
+    /// Only tracks downloaded at this bitrate
+    #[arg(long, value_parser = bitrate_parser, value_name = "BITRATE")]
+    quality: Option<BitRate>,
+    /// Only tracks downloaded on or after this day (YYYY-MM-DD, UTC)
+    #[arg(long, value_parser = date_parser, value_name = "DATE")]
+    since: Option<u64>,
+    /// Only tracks downloaded on or before this day (YYYY-MM-DD, UTC)
+    #[arg(long, value_parser = date_parser, value_name = "DATE")]
+    until: Option<u64>,
+    /// Only tracks saved under this path or storage location (e.g., `s3://music`)
+    #[arg(long, value_name = "TARGET")]
+    location: Option<String>,
+    /// How to print the tracks
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+}
+
+/// Which ID3v2 tags to write into downloaded MP3 files
+#[derive(Clone, Copy, ValueEnum)]
+enum Id3Tags {
+    #[value(name = "2.4")]
+    V2_4,
+    #[value(name = "2.3")]
+    V2_3,
+    /// Leave the files as cnvmp3 serves them
+    None,
+}
+
+impl Id3Tags {
+    fn version(self) -> Option<Version> {
+        match self {
+            Id3Tags::V2_4 => Some(Version::V2_4),
+            Id3Tags::V2_3 => Some(Version::V2_3),
+            Id3Tags::None => None,
+        }
+    }
+}
+
 /// Currently supported subcommands
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Converts YouTube videos to local mp3 files
@@ -29,24 +95,295 @@ enum Commands {
         #[arg(long, value_parser = bitrate_parser, value_name = "BITRATE")]
         quality: Option<BitRate>,
         /// Where to store the returned MP3 file
-        #[arg(long, value_parser = ["local", "ssh"], value_name = "TYPE", default_value = "local")]
+        #[arg(long, value_parser = ["local", "ssh", "webdav", "s3"], value_name = "TYPE",
+              default_value = "local")]
         dest_type: Option<String>,
+        /// Storage to save MP3 files into, of the kind given by `--dest-type`: a local directory,
+        /// `user@host:/path`, `webdav://host/path` or `s3://bucket/prefix`
+        #[arg(long, value_name = "TARGET",
+              required_if_eq_any([("dest_type", "ssh"), ("dest_type", "webdav"), ("dest_type", "s3")]))]
+        dest: Option<Location>,
+        /// Private key to log in to the `--dest` host with (by default, the SSH agent and then
+        /// `~/.ssh/id_*`)
+        #[arg(long, value_name = "FILE")]
+        identity: Option<PathBuf>,
+        /// Known hosts file to check the `--dest` host key against instead of
+        /// `~/.ssh/known_hosts`
+        #[arg(long, value_name = "FILE")]
+        known_hosts: Option<PathBuf>,
+        /// Directory to save MP3 files into (created if needed)
+        #[arg(long, value_name = "DIR", default_value = "mp3")]
+        output_dir: PathBuf,
+        /// How to name MP3 files, from `{id}`, `{artist}`, `{title}` and `{kbps}` (e.g.,
+        /// `{artist} - {title} [{id}] {kbps}k.mp3`)
+        #[arg(long, value_name = "TEMPLATE", value_parser = template_parser,
+              default_value = DEFAULT_TEMPLATE)]
+        name_template: Template,
+        /// Library index to record conversions in instead of `library.json` in the output
+        /// directory
+        #[arg(long, value_name = "FILE")]
+        library: Option<PathBuf>,
+        /// Version of the ID3v2 tags (title, artist, YouTube URL and ID, bitrate, download date)
+        /// written into MP3 files
+        #[arg(long, value_enum, value_name = "VERSION", default_value = "2.4")]
+        id3: Id3Tags,
+        /// Rules file to clean and split video titles with instead of the default rules (see
+        /// `photon title --default-rules`)
+        #[arg(long, value_name = "FILE")]
+        title_rules: Option<PathBuf>,
+        /// Detect the tempo and key of every MP3 file saved (see `photon analyze`)
+        #[arg(long)]
+        analyze: bool,
+        /// How to write the keys `--analyze` detects
+        #[arg(long, value_enum, value_name = "NOTATION", default_value_t = Notation::Standard)]
+        key_notation: Notation,
         /// A valid YouTube URL
-        #[arg(long, value_name = "URL")]
-        youtube_url: Url,
+        #[arg(
+            long,
+            value_name = "URL",
+            required_unless_present = "input",
+            conflicts_with = "input"
+        )]
+        youtube_url: Option<Url>,
+        /// File listing one YouTube URL per line to convert them all (`-` reads standard input)
+        #[arg(long, value_name = "FILE")]
+        input: Option<PathBuf>,
+        /// Number of conversions to run at once with `--input`
+        #[arg(long, value_name = "N", default_value_t = 4,
+              value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+        jobs: usize,
+        /// Base URL of the cnvmp3 server to talk to (e.g., a `mock-server`)
+        #[arg(long, value_name = "URL", default_value = CNV_BASE_URL)]
+        cnv_url: Url,
+        /// Save every HTTP request and response made during the conversion into this directory
+        #[arg(long, value_name = "DIR", conflicts_with = "replay")]
+        record: Option<PathBuf>,
+        /// Answer every HTTP request from a directory written by `--record` instead of the network
+        #[arg(long, value_name = "DIR")]
+        replay: Option<PathBuf>,
+        /// How many times each protocol step is attempted before giving up on transient failures
+        #[arg(long, value_name = "N", default_value_t = 3)]
+        attempts: u32,
+        /// Milliseconds to wait before the first retry; doubled for every retry after that
+        #[arg(long, value_name = "MS", default_value_t = 500)]
+        retry_delay_ms: u64,
+        /// Upper bound in milliseconds for the delay between retries, including one asked for
+        /// by `Retry-After`
+        #[arg(long, value_name = "MS", default_value_t = 30_000)]
+        retry_max_delay_ms: u64,
+        /// Fraction (0.0 to 1.0) by which retry delays are randomly varied
+        #[arg(long, value_name = "FRACTION", default_value_t = 0.2)]
+        retry_jitter: f64,
+        /// Attempts for a single protocol step, overriding `--attempts` (e.g., `download=5`)
+        #[arg(long, value_name = "STEP=N", value_parser = step_attempts_parser)]
+        step_attempts: Vec<(Step, u32)>,
+        /// Largest MP3 file to download, in MiB (0 means no limit)
+        #[arg(long, value_name = "MIB", default_value_t = DEFAULT_MAX_SIZE >> 20)]
+        max_size_mib: u64,
+        /// Number of connections to download a large MP3 file over at once (1 disables splitting)
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_SEGMENTS,
+              value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+        segments: usize,
+        /// Show a progress bar for every conversion
+        #[arg(long)]
+        progress: bool,
     },
     /// Migrates mp3 files from a source to a destination (e.g., remote server to local or vice
     /// versa)
     Migrate {
-        /// Source from which you want to move MP3 file
+        /// Source from which you want to move MP3 file (a local directory, `user@host:/path`,
+        /// `ssh://user@host:port/path`, `webdav://host/path` or `s3://bucket/prefix`)
         #[arg(long, value_name = "FROM")]
-        from: String,
-        /// Destination to which you want to move MP3 file
+        from: Location,
+        /// Destination to which you want to move MP3 file (same forms as `--from`)
         #[arg(long, value_name = "TO")]
-        to: String,
+        to: Location,
         /// YouTube ID of MP3 file
         #[arg(long, value_name = "ID")]
         youtube_id: String,
+        /// Private key to log in with (by default, the SSH agent and then `~/.ssh/id_*`)
+        #[arg(long, value_name = "FILE")]
+        identity: Option<PathBuf>,
+        /// Known hosts file to check host keys against instead of `~/.ssh/known_hosts`
+        #[arg(long, value_name = "FILE")]
+        known_hosts: Option<PathBuf>,
+        /// Delete the source file once the copy has been verified
+        #[arg(long)]
+        delete_source: bool,
+    },
+    /// Copies the mp3 files one library has and the other does not (e.g., between a laptop and a
+    /// server), comparing them by YouTube ID and SHA-256
+    Sync {
+        /// Library to copy from (same forms as `migrate --from`)
+        #[arg(long, value_name = "FROM")]
+        from: Location,
+        /// Library to copy into (same forms as `migrate --from`)
+        #[arg(long, value_name = "TO")]
+        to: Location,
+        /// Also copy the files only `--to` has into `--from`
+        #[arg(long, conflicts_with = "mirror")]
+        both_ways: bool,
+        /// Delete the files only `--to` has, so that it ends up the same as `--from`
+        #[arg(long)]
+        mirror: bool,
+        /// Print what would be copied or deleted without doing it
+        #[arg(long)]
+        dry_run: bool,
+        /// Private key to log in with (by default, the SSH agent and then `~/.ssh/id_*`)
+        #[arg(long, value_name = "FILE")]
+        identity: Option<PathBuf>,
+        /// Known hosts file to check host keys against instead of `~/.ssh/known_hosts`
+        #[arg(long, value_name = "FILE")]
+        known_hosts: Option<PathBuf>,
+    },
+    /// Lists the mp3 files recorded in the library index
+    List {
+        #[command(flatten)]
+        args: LibraryArgs,
+    },
+    /// Searches the library index for titles containing some text
+    Search {
+        /// Text to look for in titles, ignoring case
+        #[arg(value_name = "TEXT")]
+        text: String,
+        /// Treat TEXT as a regular expression
+        #[arg(long)]
+        regex: bool,
+        #[command(flatten)]
+        args: LibraryArgs,
+    },
+    /// Adds the mp3 files of a directory (e.g., from earlier photon versions) to the library index,
+    /// recognizing YouTube IDs in their names
+    Import {
+        /// Directory to look for mp3 files in, subdirectories included
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+        /// Library index to add them to instead of `library.json` in DIR
+        #[arg(long, value_name = "FILE")]
+        library: Option<PathBuf>,
+    },
+    /// Rewrites the ID3v2 tags of the local mp3 files in the library index from what it knows
+    /// about them (e.g., files saved before photon wrote tags)
+    Retag {
+        /// Library index whose files to retag
+        #[arg(long, value_name = "FILE", default_value = "mp3/library.json")]
+        library: PathBuf,
+        /// Version of the ID3v2 tags to write
+        #[arg(long, value_enum, value_name = "VERSION", default_value = "2.4")]
+        id3: Version,
+        /// Rules file to clean and split video titles with instead of the default rules
+        #[arg(long, value_name = "FILE")]
+        title_rules: Option<PathBuf>,
+    },
+    /// Detects the tempo (BPM), first downbeat, key and energy of the local mp3 files in the
+    /// library index, writing the tempo and key into their ID3v2 tags too
+    Analyze {
+        /// Library index whose files to analyze
+        #[arg(long, value_name = "FILE", default_value = "mp3/library.json")]
+        library: PathBuf,
+        /// Version of the ID3v2 tags to write the tempo (`TBPM`) and key (`TKEY`) into
+        #[arg(long, value_enum, value_name = "VERSION", default_value = "2.4")]
+        id3: Id3Tags,
+        /// How to write keys: `Am` (standard), `8A` (camelot) or `1m` (open-key)
+        #[arg(long, value_enum, value_name = "NOTATION", default_value_t = Notation::Standard)]
+        key_notation: Notation,
+        /// Analyze the files whose tempo and key are already known again (e.g., to write their keys
+        /// in another notation)
+        #[arg(long)]
+        again: bool,
+        /// Confidence (0.0 to 1.0) below which a tempo is reported as doubtful
+        #[arg(long, value_name = "FRACTION", default_value_t = DEFAULT_MIN_CONFIDENCE)]
+        min_confidence: f64,
+    },
+    /// Suggests tracks of the library index that mix well after a track, by key (Camelot wheel),
+    /// tempo and energy, as `photon analyze` found them
+    Suggest {
+        /// YouTube ID of the track to mix out of
+        #[arg(value_name = "ID")]
+        id: String,
+        /// Library index to look for tracks in
+        #[arg(long, value_name = "FILE", default_value = "mp3/library.json")]
+        library: PathBuf,
+        /// How far (in percent) the tempo of a track may be from that of ID, in half or double
+        /// time too
+        #[arg(long, value_name = "PERCENT", default_value_t = DEFAULT_BPM_RANGE)]
+        bpm_range: f64,
+        /// Most tracks to suggest
+        #[arg(long, value_name = "COUNT", default_value_t = 20)]
+        limit: usize,
+        /// How to print the suggestions
+        #[arg(long, value_enum, value_name = "FORMAT", default_value_t = SuggestFormat::Table)]
+        format: SuggestFormat,
+    },
+    /// Exports the library index for DJ software
+    Export {
+        #[command(subcommand)]
+        format: ExportFormat,
+    },
+    /// Shows how video titles are split into artist, title, featured artists, remix and label
+    Title {
+        /// Video titles to split
+        #[arg(value_name = "TITLE", required_unless_present = "default_rules")]
+        titles: Vec<String>,
+        /// YouTube ID of the video, for the fields the rules file sets by ID
+        #[arg(long, value_name = "ID")]
+        youtube_id: Option<String>,
+        /// Rules file to clean and split video titles with instead of the default rules
+        #[arg(long, value_name = "FILE")]
+        title_rules: Option<PathBuf>,
+        /// Print the default rules file (a starting point for `--title-rules`) and exit
+        #[arg(long, conflicts_with = "title_rules")]
+        default_rules: bool,
+    },
+    /// Serves a local imitation of cnvmp3.com for demos and integration tests
+    MockServer {
+        /// Address to listen on
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+        bind: SocketAddr,
+        /// Endpoint that should answer with its failure response (repeatable)
+        #[arg(long, value_enum, value_name = "ENDPOINT")]
+        fail: Vec<Step>,
+        /// Endpoint whose response body should be cut off halfway through (repeatable)
+        #[arg(long, value_enum, value_name = "ENDPOINT")]
+        truncate: Vec<Step>,
+        /// Milliseconds to wait before answering each request
+        #[arg(long, value_name = "MS", default_value_t = 0)]
+        delay_ms: u64,
+    },
+}
+
+#[derive(Subcommand)]
+enum ExportFormat {
+    /// Writes the local mp3 files of the library index as a Rekordbox XML collection, with a
+    /// playlist for each crate list
+    Rekordbox {
+        /// File to write the collection to
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+        /// Library index to export
+        #[arg(long, value_name = "FILE", default_value = "mp3/library.json")]
+        library: PathBuf,
+        /// Crate list (a file of YouTube URLs or IDs, one per line) to export as a playlist named
+        /// after the file (repeatable)
+        #[arg(long = "crate", value_name = "FILE")]
+        crates: Vec<PathBuf>,
+        /// Rules file to clean and split video titles with instead of the default rules
+        #[arg(long, value_name = "FILE")]
+        title_rules: Option<PathBuf>,
+    },
+    /// Writes Rekordbox analysis files (beat grid and waveforms) for the local mp3 files of the
+    /// library index, one directory per YouTube ID
+    Anlz {
+        /// Directory to write them into (e.g., `PIONEER/USBANLZ` on a USB stick)
+        #[arg(long, value_name = "DIR")]
+        out: PathBuf,
+        /// Library index to export
+        #[arg(long, value_name = "FILE", default_value = "mp3/library.json")]
+        library: PathBuf,
+        /// Where the player finds the files from (e.g., where the USB stick is mounted), rather
+        /// than the root of this machine
+        #[arg(long, value_name = "DIR")]
+        root: Option<PathBuf>,
     },
 }
 
@@ -59,36 +396,583 @@ fn bitrate_parser(s: &str) -> Result<BitRate, String> {
     }
 }
 
-fn main() {
+fn step_attempts_parser(s: &str) -> Result<(Step, u32), String> {
+    let (step, attempts) = s
+        .split_once('=')
+        .ok_or_else(|| format!("`{s}` is not of the form STEP=N"))?;
+
+    let step = Step::from_str(step, true)?;
+    let attempts: u32 = attempts
+        .parse()
+        .map_err(|_| format!("`{attempts}` is not a number"))?;
+
+    Ok((step, attempts))
+}
+
+fn template_parser(s: &str) -> Result<Template, String> {
+    s.parse::<Template>().map_err(|e| e.value)
+}
+
+fn date_parser(s: &str) -> Result<u64, String> {
+    parse_date(s).map_err(|e| e.value)
+}
+
+/// The rules file at `path`, or the default rules
+fn title_rules(path: Option<&PathBuf>) -> TitleRules {
+    let Some(path) = path else {
+        return TitleRules::default();
+    };
+
+    match TitleRules::read(path) {
+        Ok(rules) => rules,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints the tracks of the library index that `args` (and `title`) select
+fn list(args: &LibraryArgs, title: Option<TitleFilter>) {
+    let library = match Library::open(&args.library) {
+        Ok(library) => library,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let query = Query {
+        title,
+        bitrate: args.quality,
+        since: args.since,
+        // through the end of that day
+        until: args.until.map(|until| until + 86400),
+        location: args.location.clone(),
+    };
+    let tracks = library.search(&query);
+
+    match render(&tracks, args.format) {
+        Ok(out) => print!("{}", out),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
+    eprintln!("info: {} of {} tracks", tracks.len(), library.len());
+}
+
+#[tokio::main]
+async fn main() {
     let cli = Cli::parse();
 
     match &cli.command {
         Commands::Y2Mp3 {
             youtube_url,
+            input,
+            jobs,
             dest_type,
+            dest,
+            identity,
+            known_hosts,
+            output_dir,
+            name_template,
+            library,
+            id3,
+            title_rules: rules,
+            analyze,
+            key_notation,
             quality,
+            cnv_url,
+            record,
+            replay,
+            attempts,
+            retry_delay_ms,
+            retry_max_delay_ms,
+            retry_jitter,
+            step_attempts,
+            max_size_mib,
+            segments,
+            progress,
         } => {
             let bitrate: BitRate = match quality {
                 Some(q) => *q,
                 None => BitRate::Kbps96,
             };
 
-            match y2mp3(
-                youtube_url.clone(),
-                dest_type.as_ref().unwrap().to_string(),
-                bitrate,
-            ) {
-                Ok(_) => eprintln!("info: conversion complete"),
-                Err(e) => eprintln!("error: {}", e),
+            let default = RetryPolicy {
+                attempts: *attempts,
+                base_delay: Duration::from_millis(*retry_delay_ms),
+                max_delay: Duration::from_millis(*retry_max_delay_ms),
+                jitter: *retry_jitter,
+            };
+            let retry = RetryConfig {
+                default,
+                steps: step_attempts
+                    .iter()
+                    .map(|(step, attempts)| {
+                        let policy = RetryPolicy {
+                            attempts: *attempts,
+                            ..default
+                        };
+                        (*step, policy)
+                    })
+                    .collect(),
+            };
+
+            let mut builder = Converter::builder()
+                .with_cnv_url(cnv_url.clone())
+                .with_retry(retry)
+                .with_max_size(Some(max_size_mib << 20).filter(|m| *m > 0))
+                .with_segments(*segments)
+                .with_bitrate(bitrate)
+                .with_out_dir(output_dir)
+                .with_name_template(name_template.clone())
+                .with_id3(id3.version())
+                .with_title_rules(title_rules(rules.as_ref()))
+                .with_analysis(*analyze)
+                .with_key_notation(*key_notation);
+
+            if let Some(dest) = dest {
+                let expected = match dest {
+                    Location::Local(_) => "local",
+                    Location::Ssh(_) => "ssh",
+                    Location::WebDav(_) => "webdav",
+                    Location::S3 { .. } => "s3",
+                };
+                if dest_type.as_deref() != Some(expected) {
+                    eprintln!(
+                        "error: --dest {} is not a {} destination",
+                        dest,
+                        dest_type.as_deref().unwrap_or("local")
+                    );
+                    std::process::exit(1);
+                }
+
+                let ssh = SshConfig {
+                    identity: identity.clone(),
+                    known_hosts: known_hosts.clone(),
+                };
+                match dest.open(&ssh) {
+                    Ok(storage) => builder = builder.with_storage(storage),
+                    Err(e) => {
+                        eprintln!("error: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            match (record, replay) {
+                (Some(dir), _) => builder = builder.with_cassette(Cassette::Record(dir.clone())),
+                (_, Some(dir)) => builder = builder.with_cassette(Cassette::Replay(dir.clone())),
+                _ => {}
+            }
+
+            if *progress {
+                builder = builder.with_progress(progress_bars());
+            }
+
+            if let Some(library) = library {
+                builder = builder.with_library(library);
+            }
+
+            let converter = match builder.build() {
+                Ok(converter) => converter,
+                Err(e) => return eprintln!("error: {}", e),
+            };
+
+            let Some(input) = input else {
+                let youtube_url = youtube_url.clone().expect("clap requires --youtube-url");
+
+                match converter.convert(youtube_url).await {
+                    Ok(_) => eprintln!("info: conversion complete"),
+                    Err(e) => eprintln!("error: {}", e),
+                }
+                return;
+            };
+
+            let urls = match read_input(input) {
+                Ok(urls) => urls,
+                Err(e) => return eprintln!("error: {}", e),
+            };
+
+            let summary = converter.convert_all(&urls, *jobs).await;
+            println!("{}", summary);
+
+            if summary.failed() > 0 {
+                std::process::exit(1);
             }
         }
         Commands::Migrate {
             from,
             to,
             youtube_id,
+            identity,
+            known_hosts,
+            delete_source,
         } => {
-            eprintln!("id: {}, from: {}, to: {}", youtube_id, from, to);
-            todo!();
+            let ssh = SshConfig {
+                identity: identity.clone(),
+                known_hosts: known_hosts.clone(),
+            };
+
+            match migrate(from, to, youtube_id, &ssh, *delete_source).await {
+                Ok(m) => eprintln!(
+                    "info: {} {} ({} bytes, sha256 {})",
+                    if m.source_deleted {
+                        "moved to"
+                    } else {
+                        "copied to"
+                    },
+                    m.to,
+                    m.size,
+                    m.sha256
+                ),
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Sync {
+            from,
+            to,
+            both_ways,
+            mirror,
+            dry_run,
+            identity,
+            known_hosts,
+        } => {
+            let ssh = SshConfig {
+                identity: identity.clone(),
+                known_hosts: known_hosts.clone(),
+            };
+            let options = SyncOptions {
+                both_ways: *both_ways,
+                mirror: *mirror,
+                dry_run: *dry_run,
+            };
+
+            let report = match sync(from, to, &ssh, options).await {
+                Ok(report) => report,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            if *dry_run {
+                for action in &report.actions {
+                    println!("{}", report.describe(action));
+                }
+            }
+            for conflict in &report.conflicts {
+                println!(
+                    "conflict {}: {} in {}, {} in {}",
+                    conflict.id, conflict.from, report.from, conflict.to, report.to
+                );
+            }
+            for (action, e) in &report.failed {
+                eprintln!("error: could not {}: {}", report.describe(action), e);
+            }
+
+            eprintln!(
+                "info: {} in sync, {} {}, {} conflicting, {} failed",
+                report.in_sync,
+                report.actions.len() - report.failed.len(),
+                if *dry_run { "to do" } else { "done" },
+                report.conflicts.len(),
+                report.failed.len()
+            );
+
+            if !report.conflicts.is_empty() || !report.failed.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Commands::List { args } => list(args, None),
+        Commands::Search { text, regex, args } => {
+            let title = match regex {
+                true => match Regex::new(text) {
+                    Ok(regex) => TitleFilter::Regex(regex),
+                    Err(e) => {
+                        eprintln!("error: bad regular expression: {}", e);
+                        std::process::exit(1);
+                    }
+                },
+                false => TitleFilter::Contains(text.clone()),
+            };
+
+            list(args, Some(title));
+        }
+        Commands::Import { dir, library } => {
+            let path = library.clone().unwrap_or_else(|| dir.join(INDEX_NAME));
+            let imported = Library::open(&path).and_then(|mut library| {
+                let imported = import(&mut library, dir)?;
+                library.save()?;
+                Ok(imported)
+            });
+            let imported = match imported {
+                Ok(imported) => imported,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            for unmatched in &imported.unmatched {
+                println!(
+                    "unmatched {}: {}",
+                    unmatched.path.display(),
+                    unmatched.reason
+                );
+            }
+            eprintln!(
+                "info: {} added to {}, {} already indexed, {} unmatched",
+                imported.added.len(),
+                path.display(),
+                imported.known,
+                imported.unmatched.len()
+            );
+
+            if !imported.unmatched.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Retag {
+            library,
+            id3,
+            title_rules: rules,
+        } => {
+            let rules = title_rules(rules.as_ref());
+            let retagged = Library::open(library).and_then(|mut library| {
+                let retagged = retag(&mut library, *id3, &rules);
+                library.save()?;
+                Ok(retagged)
+            });
+            let retagged = match retagged {
+                Ok(retagged) => retagged,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            for failed in &retagged.failed {
+                println!("failed {}: {}", failed.path.display(), failed.reason);
+            }
+            eprintln!(
+                "info: {} retagged, {} up to date, {} failed",
+                retagged.retagged.len(),
+                retagged.unchanged,
+                retagged.failed.len()
+            );
+
+            if !retagged.failed.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Analyze {
+            library,
+            id3,
+            key_notation,
+            again,
+            min_confidence,
+        } => {
+            let analyzed = match Library::open(library) {
+                Ok(mut library) => {
+                    let analyzed =
+                        analyze(&mut library, id3.version(), *key_notation, *again).await;
+                    library.save().map(|()| (library, analyzed))
+                }
+                Err(e) => Err(e),
+            };
+            let (library, analyzed) = match analyzed {
+                Ok(analyzed) => analyzed,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            for failed in &analyzed.failed {
+                println!("failed {}: {}", failed.path.display(), failed.reason);
+            }
+            for id in &analyzed.without_beats {
+                eprintln!("warning: no beats found in {}", id);
+            }
+            for id in &analyzed.without_key {
+                eprintln!("warning: no notes found in {}, so its key is unknown", id);
+            }
+            let mut doubtful = 0;
+            for track in library.tracks() {
+                let Some(tempo) = track.tempo.filter(|t| t.confidence < *min_confidence) else {
+                    continue;
+                };
+                let alternative = tempo
+                    .alternative_bpm
+                    .map(|bpm| format!(" (or {:.2})", bpm))
+                    .unwrap_or_default();
+                println!(
+                    "doubtful {}: {:.2} BPM{}, confidence {:.2}",
+                    track.id, tempo.grid.bpm, alternative, tempo.confidence
+                );
+                doubtful += 1;
+            }
+            eprintln!(
+                "info: {} analyzed, {} already known, {} without beats, {} without a key, {} doubtful, {} failed",
+                analyzed.analyzed.len(),
+                analyzed.known,
+                analyzed.without_beats.len(),
+                analyzed.without_key.len(),
+                doubtful,
+                analyzed.failed.len()
+            );
+
+            if !analyzed.failed.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Suggest {
+            id,
+            library,
+            bpm_range,
+            limit,
+            format,
+        } => {
+            let library = match Library::open(library) {
+                Ok(library) => library,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let Some(track) = library.get(id) else {
+                eprintln!("error: {} is not in {}", id, library.path().display());
+                std::process::exit(1);
+            };
+            let mut found = match suggest(&library, track, *bpm_range) {
+                Ok(found) => found,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            let total = found.suggestions.len();
+            found.suggestions.truncate(*limit);
+            print!("{}", render_suggestions(track, &found.suggestions, *format));
+            eprintln!(
+                "info: {} of {} tracks that mix after {}, {} not analyzed",
+                found.suggestions.len(),
+                total,
+                id,
+                found.unanalyzed
+            );
+        }
+        Commands::Export {
+            format:
+                ExportFormat::Rekordbox {
+                    out,
+                    library,
+                    crates,
+                    title_rules: rules,
+                },
+        } => {
+            let rules = title_rules(rules.as_ref());
+            let collection = crates
+                .iter()
+                .map(|path| CrateList::read(path))
+                .collect::<Result<Vec<_>, _>>()
+                .and_then(|crates| {
+                    let library = Library::open(library)?;
+                    let collection = collection_xml(&library, &crates, &rules)?;
+                    std::fs::write(out, &collection.xml)?;
+                    Ok(collection)
+                });
+            let collection = match collection {
+                Ok(collection) => collection,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            for skipped in &collection.skipped {
+                println!("skipped {}: {}", skipped.path.display(), skipped.reason);
+            }
+            for (name, id) in &collection.missing {
+                eprintln!(
+                    "warning: {} in crate list {} is not in the collection",
+                    id, name
+                );
+            }
+            eprintln!(
+                "info: {} tracks and {} playlists written to {}, {} skipped",
+                collection.tracks.len(),
+                crates.len(),
+                out.display(),
+                collection.skipped.len()
+            );
+        }
+        Commands::Export {
+            format: ExportFormat::Anlz { out, library, root },
+        } => {
+            let export = match Library::open(library) {
+                Ok(library) => export_anlz(&library, out, root.as_deref()),
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            for skipped in &export.skipped {
+                println!("skipped {}: {}", skipped.path.display(), skipped.reason);
+            }
+            for id in &export.without_grid {
+                eprintln!("warning: no beats found in {}, so it has no beat grid", id);
+            }
+            eprintln!(
+                "info: analysis files of {} tracks written to {}, {} without a beat grid, {} skipped",
+                export.written.len(),
+                out.display(),
+                export.without_grid.len(),
+                export.skipped.len()
+            );
+        }
+        Commands::Title {
+            titles,
+            youtube_id,
+            title_rules: rules,
+            default_rules,
+        } => {
+            if *default_rules {
+                return print!("{}", DEFAULT_RULES);
+            }
+
+            let rules = title_rules(rules.as_ref());
+            for title in titles {
+                let parsed = rules.parse(youtube_id.as_deref(), title);
+                match serde_json::to_string(&parsed) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => eprintln!("error: {}", e),
+                }
+            }
+        }
+        Commands::MockServer {
+            bind,
+            fail,
+            truncate,
+            delay_ms,
+        } => {
+            let config = MockConfig {
+                fail: fail.clone(),
+                truncate: truncate.clone(),
+                delay: Duration::from_millis(*delay_ms),
+            };
+
+            if let Err(e) = mock_server(*bind, config).await {
+                eprintln!("error: {}", e);
+            }
         }
     }
 }
@@ -0,0 +1,477 @@
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::error::{Error, ErrorKind};
+use crate::id3::{id3v2_len, Tag};
+use crate::library::youtube_id_in;
+use crate::ssh::SshConfig;
+use crate::storage::{hex, ByteStream, Location, Stat, Storage};
+use crate::template::disambiguate;
+use crate::youtube_url::is_youtube_id;
+
+/// Most bytes read from the start of a file to find the YouTube ID in its tag
+const MAX_TAG_LEN: usize = 1 << 20;
+
+/// What a successful migration did
+#[derive(Clone, Debug, PartialEq)]
+pub struct Migration {
+    /// Where the MP3 file was copied from
+    pub from: String,
+    /// Where the MP3 file is now
+    pub to: String,
+    /// Size of the MP3 file in bytes
+    pub size: u64,
+    /// SHA-256 of the MP3 file, the same on both sides
+    pub sha256: String,
+    /// Whether the destination already held the same file, so that nothing was copied
+    pub already_there: bool,
+    /// Whether the source file was deleted afterwards
+    pub source_deleted: bool,
+}
+
+/// Size and SHA-256 of what `body` yields so far, updated as it is read
+pub(crate) type Hashed = Arc<Mutex<(u64, Sha256)>>;
+
+/// Hashes `body` as it goes by
+pub(crate) fn hashing(body: ByteStream) -> (ByteStream, Hashed) {
+    let hashed = Hashed::default();
+    let hasher = hashed.clone();
+
+    let body = body
+        .map(move |chunk| {
+            if let Ok(chunk) = &chunk {
+                let mut hasher = hasher.lock().unwrap();
+                hasher.0 += chunk.len() as u64;
+                hasher.1.update(chunk);
+            }
+            chunk
+        })
+        .boxed();
+
+    (body, hashed)
+}
+
+pub(crate) fn digest(hashed: &Hashed) -> (u64, String) {
+    let hasher = hashed.lock().unwrap();
+    (hasher.0, hex(&hasher.1.clone().finalize()))
+}
+
+/// Reads the file called `name` to the end. Returns its size and SHA-256.
+pub(crate) async fn hash(storage: &dyn Storage, name: &str) -> Result<(u64, String), Error> {
+    let (mut body, hashed) = hashing(storage.get(name).await?);
+    while let Some(chunk) = body.next().await {
+        chunk?;
+    }
+
+    Ok(digest(&hashed))
+}
+
+/// The YouTube ID photon wrote into the tag of the file called `name`, reading no more of it
+/// than the tag
+pub(crate) async fn tagged_id(storage: &dyn Storage, name: &str) -> Result<Option<String>, Error> {
+    let mut body = storage.get(name).await?;
+    let mut head = Vec::new();
+
+    while let Some(chunk) = body.next().await {
+        head.extend(chunk?);
+
+        if (head.len() >= 10 && head.len() >= id3v2_len(&head)) || head.len() >= MAX_TAG_LEN {
+            break;
+        }
+    }
+
+    Ok(Tag::read(&head).and_then(|tag| tag.youtube_id().map(String::from)))
+}
+
+/// The MP3 files of `storage` by YouTube ID: named after it (`<id>.mp3` or `Title [<id>].mp3`),
+/// or else tagged with it, as the files photon names after a template are. Other files are left
+/// out, and so is any second file of the same ID.
+pub(crate) async fn library(storage: &dyn Storage) -> Result<BTreeMap<String, Stat>, Error> {
+    let mut stats = storage.list().await?;
+    stats.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut files = BTreeMap::new();
+    let mut untagged = Vec::new();
+    for stat in stats {
+        match youtube_id_in(&stat.name) {
+            Some(id) => {
+                files.entry(id.to_string()).or_insert(stat);
+            }
+            None if stat.name.to_lowercase().ends_with(".mp3") => untagged.push(stat),
+            None => {}
+        }
+    }
+    for stat in untagged {
+        if let Some(id) = tagged_id(storage, &stat.name).await? {
+            files.entry(id).or_insert(stat);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Name of the MP3 file of `youtube_id` in `storage` (see `library`), if it holds one
+pub(crate) async fn find(storage: &dyn Storage, youtube_id: &str) -> Result<Option<String>, Error> {
+    let name = format!("{}.mp3", youtube_id);
+    if storage.exists(&name).await? {
+        return Ok(Some(name));
+    }
+
+    Ok(library(storage)
+        .await?
+        .remove(youtube_id)
+        .map(|stat| stat.name))
+}
+
+/// The name to copy the file of `youtube_id` called `name` under into `dst`, which holds no file
+/// of that video (see `library`): `name`, or if some other file already has it, `name` with the
+/// ID in it (see `disambiguate`). Files are never replaced; an error if both names are taken.
+pub(crate) async fn free_name(
+    dst: &dyn Storage,
+    name: &str,
+    youtube_id: &str,
+) -> Result<String, Error> {
+    if !dst.exists(name).await? {
+        return Ok(name.to_string());
+    }
+
+    let other = disambiguate(name, youtube_id);
+    if other != name && !dst.exists(&other).await? {
+        return Ok(other);
+    }
+
+    Err(Error {
+        kind: ErrorKind::IoError,
+        value: format!(
+            "{} already exists and is not of {}",
+            dst.location(name),
+            youtube_id
+        ),
+    })
+}
+
+fn invalid_id(youtube_id: &str) -> Error {
+    Error {
+        kind: ErrorKind::InvalidURL,
+        value: format!("`{}` is not a YouTube ID", youtube_id),
+    }
+}
+
+/// Copies the MP3 file of `youtube_id` (`<youtube_id>.mp3`, or whichever file `library` finds
+/// for it) from `from` to `to` under the same name (or see `free_name`), then reads the copy back
+/// and checks it has the same SHA-256 as the source (see `copy`). With `delete_source`, the source file is deleted
+/// once the copy is known to be good, which makes this a move.
+///
+/// If the destination already holds a file of the video, nothing is copied as long as both are
+/// the same.
+pub async fn migrate(
+    from: &Location,
+    to: &Location,
+    youtube_id: &str,
+    ssh: &SshConfig,
+    delete_source: bool,
+) -> Result<Migration, Error> {
+    if !is_youtube_id(youtube_id) {
+        return Err(invalid_id(youtube_id));
+    }
+
+    if from == to {
+        return Err(Error::from(format!(
+            "{} is both the source and the destination",
+            from
+        )));
+    }
+
+    let (src, dst) = (from.open(ssh)?, to.open(ssh)?);
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+
+    let Some(name) = find(src, youtube_id).await? else {
+        let name = format!("{}.mp3", youtube_id);
+        return Err(Error {
+            kind: ErrorKind::IoError,
+            value: format!("{} does not exist", src.location(&name)),
+        });
+    };
+
+    let existing = find(dst, youtube_id).await?;
+    let already_there = existing.is_some();
+    let (size, sha256, to_name) = if let Some(existing) = existing {
+        let (size, sha256) = hash(src, &name).await?;
+        let (_, existing_sha256) = hash(dst, &existing).await?;
+
+        if existing_sha256 != sha256 {
+            return Err(Error {
+                kind: ErrorKind::IoError,
+                value: format!("{} already exists and differs", dst.location(&existing)),
+            });
+        }
+
+        eprintln!("info: {} already exists", dst.location(&existing));
+        (size, sha256, existing)
+    } else {
+        let to_name = free_name(dst, &name, youtube_id).await?;
+        eprintln!(
+            "info: copying {} to {}",
+            src.location(&name),
+            dst.location(&to_name)
+        );
+        let (size, sha256) = copy(src, dst, &name, &to_name).await?;
+        (size, sha256, to_name)
+    };
+
+    if delete_source {
+        src.delete(&name).await?;
+    }
+
+    Ok(Migration {
+        from: src.location(&name),
+        to: dst.location(&to_name),
+        size,
+        sha256,
+        already_there,
+        source_deleted: delete_source,
+    })
+}
+
+/// Copies the file called `name` from `src` to `dst` as `to_name`, replacing any file of that
+/// name, then reads the copy back and checks it has the same SHA-256 as the source; a bad copy is
+/// deleted. Returns the size and SHA-256 of the file.
+pub(crate) async fn copy(
+    src: &dyn Storage,
+    dst: &dyn Storage,
+    name: &str,
+    to_name: &str,
+) -> Result<(u64, String), Error> {
+    let size = src.stat(name).await?.map(|stat| stat.size);
+    let (body, hashed) = hashing(src.get(name).await?);
+    dst.put(to_name, body, size).await?;
+
+    let copied = digest(&hashed);
+    let written = hash(dst, to_name).await?;
+
+    if written != copied {
+        dst.delete(to_name).await?;
+
+        return Err(Error {
+            kind: ErrorKind::ChecksumMismatch,
+            value: format!(
+                "copy of {} has sha256 {} ({} bytes), expected {} ({} bytes)",
+                src.location(name),
+                written.1,
+                written.0,
+                copied.1,
+                copied.0
+            ),
+        });
+    }
+
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::server::spawn;
+
+    const ID: &str = "yPvoKz6tyJs";
+
+    fn mp3() -> Vec<u8> {
+        // large enough to take several SFTP packets
+        (0..200_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn sha256(bytes: &[u8]) -> String {
+        hex(&Sha256::digest(bytes))
+    }
+
+    #[tokio::test]
+    async fn test_migrate_upload_download() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        std::fs::create_dir(remote.path().join("music")).unwrap();
+        let server = spawn(remote.path(), false).await;
+
+        let here = Location::Local(local.path().to_path_buf());
+        let there = Location::Ssh(server.remote("/music"));
+        let file = format!("{}.mp3", ID);
+        std::fs::write(local.path().join(&file), mp3()).unwrap();
+
+        // copy up
+        let migration = migrate(&here, &there, ID, &server.config, false)
+            .await
+            .unwrap();
+        assert_eq!(migration.size, mp3().len() as u64);
+        assert_eq!(migration.sha256, sha256(&mp3()));
+        assert!(!migration.already_there);
+        assert_eq!(
+            std::fs::read(remote.path().join("music").join(&file)).unwrap(),
+            mp3()
+        );
+        assert!(local.path().join(&file).exists());
+        assert!(!remote
+            .path()
+            .join("music")
+            .join(format!("{}.part", file))
+            .exists());
+
+        // move back down, after the local copy was lost
+        std::fs::remove_file(local.path().join(&file)).unwrap();
+        let migration = migrate(&there, &here, ID, &server.config, true)
+            .await
+            .unwrap();
+        assert!(migration.source_deleted);
+        assert_eq!(std::fs::read(local.path().join(&file)).unwrap(), mp3());
+        assert!(!remote.path().join("music").join(&file).exists());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_already_there() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let server = spawn(remote.path(), false).await;
+        let (here, there) = (
+            Location::Local(local.path().to_path_buf()),
+            Location::Ssh(server.remote("/")),
+        );
+        let file = format!("{}.mp3", ID);
+        std::fs::write(local.path().join(&file), mp3()).unwrap();
+        std::fs::write(remote.path().join(&file), mp3()).unwrap();
+
+        let migration = migrate(&here, &there, ID, &server.config, true)
+            .await
+            .unwrap();
+        assert!(migration.already_there);
+        assert!(!local.path().join(&file).exists());
+
+        // a different file of the same name is left alone
+        std::fs::write(local.path().join(&file), b"something else").unwrap();
+        let e = migrate(&here, &there, ID, &server.config, true)
+            .await
+            .unwrap_err();
+        assert!(
+            e.value.ends_with("already exists and differs"),
+            "{}",
+            e.value
+        );
+        assert!(local.path().join(&file).exists());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_checksum_mismatch() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let server = spawn(remote.path(), true).await;
+        let (here, there) = (
+            Location::Local(local.path().to_path_buf()),
+            Location::Ssh(server.remote("/")),
+        );
+        let file = format!("{}.mp3", ID);
+        std::fs::write(local.path().join(&file), mp3()).unwrap();
+
+        let e = migrate(&here, &there, ID, &server.config, true)
+            .await
+            .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::ChecksumMismatch));
+
+        // neither the bad copy nor its part file are kept, and the source is still there
+        assert_eq!(std::fs::read_dir(remote.path()).unwrap().count(), 0);
+        assert!(local.path().join(&file).exists());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_untrusted_host() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let server = spawn(remote.path(), false).await;
+        std::fs::write(local.path().join(format!("{}.mp3", ID)), mp3()).unwrap();
+
+        let empty = local.path().join("known_hosts");
+        std::fs::write(&empty, "").unwrap();
+        let ssh = SshConfig {
+            known_hosts: Some(empty),
+            ..server.config.clone()
+        };
+
+        let e = migrate(
+            &Location::Local(local.path().to_path_buf()),
+            &Location::Ssh(server.remote("/")),
+            ID,
+            &ssh,
+            false,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::SshError));
+        assert!(e.value.contains("not a known host"), "{}", e.value);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_missing() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (from, to) = (
+            Location::Local(from.path().to_path_buf()),
+            Location::Local(to.path().to_path_buf()),
+        );
+        let ssh = SshConfig::default();
+
+        let e = migrate(&from, &to, ID, &ssh, false).await.unwrap_err();
+        assert!(e.value.ends_with("does not exist"), "{}", e.value);
+
+        let e = migrate(&from, &to, "../etc/passwd", &ssh, false)
+            .await
+            .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::InvalidURL));
+        assert!(migrate(&from, &from, ID, &ssh, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_templated_name() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (here, there) = (
+            Location::Local(from.path().to_path_buf()),
+            Location::Local(to.path().to_path_buf()),
+        );
+        let ssh = SshConfig::default();
+
+        let file = "Daft Punk - One More Time.mp3";
+        let mp3 = crate::sync::tests::tagged(ID, &mp3());
+        std::fs::write(from.path().join(file), &mp3).unwrap();
+        std::fs::write(from.path().join("Other.mp3"), b"not tagged").unwrap();
+
+        let migration = migrate(&here, &there, ID, &ssh, false).await.unwrap();
+        assert!(!migration.already_there);
+        assert_eq!(migration.to, there.open(&ssh).unwrap().location(file));
+        assert_eq!(std::fs::read(to.path().join(file)).unwrap(), mp3);
+
+        let migration = migrate(&here, &there, ID, &ssh, true).await.unwrap();
+        assert!(migration.already_there);
+        assert!(!from.path().join(file).exists());
+        assert_eq!(std::fs::read_dir(to.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_name_of_another_video() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (here, there) = (
+            Location::Local(from.path().to_path_buf()),
+            Location::Local(to.path().to_path_buf()),
+        );
+        let ssh = SshConfig::default();
+
+        let (ours, theirs) = (
+            crate::sync::tests::tagged(ID, &mp3()),
+            crate::sync::tests::tagged("dQw4w9WgXcQ", b"another video"),
+        );
+        std::fs::write(from.path().join("Intro.mp3"), &ours).unwrap();
+        std::fs::write(to.path().join("Intro.mp3"), &theirs).unwrap();
+
+        let migration = migrate(&here, &there, ID, &ssh, false).await.unwrap();
+        assert!(!migration.already_there);
+        let file = format!("Intro [{}].mp3", ID);
+        assert_eq!(migration.to, there.open(&ssh).unwrap().location(&file));
+        assert_eq!(std::fs::read(to.path().join(&file)).unwrap(), ours);
+        assert_eq!(std::fs::read(to.path().join("Intro.mp3")).unwrap(), theirs);
+
+        // found again under its new name
+        let migration = migrate(&here, &there, ID, &ssh, false).await.unwrap();
+        assert!(migration.already_there);
+    }
+}
@@ -0,0 +1,515 @@
+use axum::body::{Body, Bytes};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::Response;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use url::Url;
+
+use crate::bitrate::BitRate;
+use crate::convert::schema::{
+    CheckDatabaseFail, CheckDatabaseSuccess, DownloadVideoFail, DownloadVideoSuccess,
+    GetVideoDataFail, GetVideoDataSuccess, InsertToDatabaseFail, InsertToDatabaseSuccess,
+    PayloadCheckDatabase, PayloadDownloadVideo, PayloadGetVideoData, PayloadInsertToDatabase,
+    ResponseCheckDatabase, ResponseDownloadVideo, ResponseGetVideoData, ResponseInsertToDatabase,
+    VideoData,
+};
+use crate::convert::Step;
+use crate::error::{Error, ErrorKind};
+use crate::youtube_url::YouTubeURL;
+
+/// Number of silent MPEG frames in the served MP3 (~1 second of audio)
+const MP3_FRAMES: usize = 38;
+
+/// `ETag` of the served MP3, which never changes
+const MP3_ETAG: &str = "\"silent-mp3\"";
+
+/// Ways in which the mock server should misbehave on purpose
+#[derive(Clone, Debug, Default)]
+pub struct MockConfig {
+    /// Endpoints that answer with their `*Fail` response (or a 404 for `Download`)
+    pub fail: Vec<Step>,
+    /// Endpoints whose response body is cut off halfway through
+    pub truncate: Vec<Step>,
+    /// How long to wait before answering any request
+    pub delay: Duration,
+}
+
+/// A row of the in-memory "database" behind `/check_database.php`
+struct Entry {
+    id: i64,
+    server_path: String,
+    title: String,
+}
+
+struct MockState {
+    base_url: Url,
+    config: MockConfig,
+    database: Mutex<HashMap<(String, BitRate), Entry>>,
+    mp3: Bytes,
+}
+
+/// Query string accepted by the CDN `/download.php` endpoint
+#[derive(Deserialize)]
+struct DownloadQuery {
+    id: String,
+}
+
+/// Builds a valid MP3 file made of `frames` silent MPEG-1 Layer III frames (128 kb/s, 44.1 kHz,
+/// stereo)
+pub fn silent_mp3(frames: usize) -> Vec<u8> {
+    // 144 * 128000 / 44100 bytes per frame, no padding
+    const FRAME_LEN: usize = 417;
+
+    let mut mp3 = Vec::with_capacity(frames * FRAME_LEN);
+    for _ in 0..frames {
+        mp3.extend_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+        mp3.resize(mp3.len() + FRAME_LEN - 4, 0);
+    }
+
+    mp3
+}
+
+/// Builds the mock cnvmp3 application; `base_url` is where the server can be reached and is used
+/// to hand out CDN download links
+pub fn router(base_url: Url, config: MockConfig) -> Router {
+    let state = Arc::new(MockState {
+        base_url,
+        config,
+        database: Mutex::new(HashMap::new()),
+        mp3: Bytes::from(silent_mp3(MP3_FRAMES)),
+    });
+
+    Router::new()
+        .route("/check_database.php", post(check_database))
+        .route("/get_video_data.php", post(get_video_data))
+        .route("/download_video.php", post(download_video))
+        .route("/insert_to_database.php", post(insert_to_database))
+        .route("/download.php", get(download))
+        .with_state(state)
+}
+
+/// Serves the mock on an already bound `listener` until the process is stopped
+pub async fn serve(listener: TcpListener, config: MockConfig) -> Result<(), Error> {
+    let addr = listener.local_addr()?;
+    let base_url = Url::parse(&format!("http://{}/", addr)).map_err(|e| Error {
+        kind: ErrorKind::InvalidURL,
+        value: format!("bad listen address {}: {}", addr, e),
+    })?;
+
+    axum::serve(listener, router(base_url, config)).await?;
+
+    Ok(())
+}
+
+/// Runs a mock cnvmp3 server on `bind`
+pub async fn mock_server(bind: SocketAddr, config: MockConfig) -> Result<(), Error> {
+    let listener = TcpListener::bind(bind).await?;
+
+    eprintln!(
+        "info: mock cnvmp3 listening on http://{}",
+        listener.local_addr()?
+    );
+
+    serve(listener, config).await
+}
+
+/// Starts a mock server on an ephemeral port in the background and returns its base url
+#[cfg(test)]
+pub async fn spawn(config: MockConfig) -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+
+    tokio::spawn(serve(listener, config));
+
+    base_url
+}
+
+impl MockState {
+    fn fails(&self, step: Step) -> bool {
+        self.config.fail.contains(&step)
+    }
+
+    /// Sends `body` after the configured delay, truncating it if asked to
+    async fn respond(
+        &self,
+        step: Step,
+        status: StatusCode,
+        content_type: &str,
+        body: Bytes,
+    ) -> Response {
+        eprintln!("info: mock {:?} -> {}", step, status);
+
+        if !self.config.delay.is_zero() {
+            tokio::time::sleep(self.config.delay).await;
+        }
+
+        let builder = Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, body.len());
+
+        let body = if self.config.truncate.contains(&step) {
+            // advertise the full length, send half of the body, then hang up
+            let half = futures_util::stream::iter([Ok(body.slice(..body.len() / 2))]);
+            let hang_up = futures_util::stream::once(async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "body truncated by mock server",
+                ))
+            });
+
+            Body::from_stream(half.chain(hang_up))
+        } else {
+            Body::from(body)
+        };
+
+        builder.body(body).expect("mock response should be valid")
+    }
+
+    async fn json<T: Serialize>(&self, step: Step, value: &T) -> Response {
+        let body = serde_json::to_vec(value).expect("schema types should serialize");
+
+        self.respond(step, StatusCode::OK, "application/json", body.into())
+            .await
+    }
+}
+
+async fn check_database(
+    State(state): State<Arc<MockState>>,
+    Json(payload): Json<PayloadCheckDatabase>,
+) -> Response {
+    let res = {
+        let database = state.database.lock().unwrap();
+        let entry = database.get(&(payload.youtube_id.clone(), payload.quality));
+
+        match entry {
+            Some(entry) if !state.fails(Step::CheckDatabase) => {
+                ResponseCheckDatabase::Exist(CheckDatabaseSuccess {
+                    _success: true,
+                    data: VideoData {
+                        _id: entry.id,
+                        _quality: (payload.quality as usize).to_string(),
+                        server_path: entry.server_path.clone(),
+                        _title: entry.title.clone(),
+                        _youtube_id: payload.youtube_id.clone(),
+                    },
+                })
+            }
+            _ => ResponseCheckDatabase::NoExist(CheckDatabaseFail {
+                _success: false,
+                error: String::from("No entry found"),
+            }),
+        }
+    };
+
+    state.json(Step::CheckDatabase, &res).await
+}
+
+async fn get_video_data(
+    State(state): State<Arc<MockState>>,
+    Json(payload): Json<PayloadGetVideoData>,
+) -> Response {
+    let res = match YouTubeURL::new(payload.url) {
+        Ok(youtube_url) if !state.fails(Step::GetVideoData) => {
+            ResponseGetVideoData::Success(GetVideoDataSuccess {
+                _success: true,
+                title: format!("Mock Artist - Mock Track {}", youtube_url.id),
+            })
+        }
+        _ => ResponseGetVideoData::Fail(GetVideoDataFail {
+            _success: false,
+            error: String::from("Video unavailable"),
+        }),
+    };
+
+    state.json(Step::GetVideoData, &res).await
+}
+
+async fn download_video(
+    State(state): State<Arc<MockState>>,
+    Json(payload): Json<PayloadDownloadVideo>,
+) -> Response {
+    let res = match YouTubeURL::new(payload.url) {
+        Ok(youtube_url) if !state.fails(Step::DownloadVideo) => {
+            let mut download_link = state.base_url.join("download.php").unwrap();
+            download_link
+                .query_pairs_mut()
+                .append_pair("id", &youtube_url.id);
+
+            ResponseDownloadVideo::Success(DownloadVideoSuccess {
+                download_link: download_link.to_string(),
+                _success: true,
+            })
+        }
+        _ => ResponseDownloadVideo::Fail(DownloadVideoFail {
+            error: String::from("Conversion failed"),
+            error_type: 1,
+            _success: false,
+        }),
+    };
+
+    state.json(Step::DownloadVideo, &res).await
+}
+
+async fn insert_to_database(
+    State(state): State<Arc<MockState>>,
+    Json(payload): Json<PayloadInsertToDatabase>,
+) -> Response {
+    let res = if state.fails(Step::InsertToDatabase) {
+        ResponseInsertToDatabase::Fail(InsertToDatabaseFail {
+            _success: false,
+            error: String::from("Database error"),
+        })
+    } else {
+        let mut database = state.database.lock().unwrap();
+        let id = database.len() as i64 + 1;
+
+        database.insert(
+            (payload.youtube_id, payload.quality),
+            Entry {
+                id,
+                server_path: payload.server_path,
+                title: payload.title,
+            },
+        );
+
+        ResponseInsertToDatabase::Success(InsertToDatabaseSuccess {
+            _success: true,
+            message: String::from("Inserted"),
+        })
+    };
+
+    state.json(Step::InsertToDatabase, &res).await
+}
+
+/// Parses a single `Range: bytes=<start>-[<end>]` header value into an inclusive range within a
+/// body of `len` bytes
+fn parse_range(value: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let start: usize = start.trim().parse().ok()?;
+    let end = match end.trim() {
+        "" => len.saturating_sub(1),
+        end => end.parse::<usize>().ok()?.min(len.saturating_sub(1)),
+    };
+
+    Some((start, end))
+}
+
+async fn download(
+    State(state): State<Arc<MockState>>,
+    Query(query): Query<DownloadQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if state.fails(Step::Download) {
+        let body = format!("File not found: {}", query.id);
+
+        return state
+            .respond(
+                Step::Download,
+                StatusCode::NOT_FOUND,
+                "text/plain",
+                body.into(),
+            )
+            .await;
+    }
+
+    let len = state.mp3.len();
+
+    // like any CDN, a stale `If-Range` gets the whole (changed) file instead of the range
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| {
+            headers
+                .get(header::IF_RANGE)
+                .is_none_or(|v| v.as_bytes() == MP3_ETAG.as_bytes())
+        })
+        .map(|v| parse_range(v, len));
+
+    let (status, body, content_range) = match range {
+        None => (StatusCode::OK, state.mp3.clone(), None),
+        Some(Some((start, end))) if start <= end => (
+            StatusCode::PARTIAL_CONTENT,
+            state.mp3.slice(start..=end),
+            Some(format!("bytes {}-{}/{}", start, end, len)),
+        ),
+        Some(_) => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            Bytes::new(),
+            Some(format!("bytes */{}", len)),
+        ),
+    };
+
+    let mut res = state
+        .respond(Step::Download, status, "audio/mpeg", body)
+        .await;
+
+    let headers = res.headers_mut();
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::ETAG, HeaderValue::from_static(MP3_ETAG));
+    if let Some(content_range) = content_range {
+        headers.insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&content_range).expect("content range should be ascii"),
+        );
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use infer::audio::is_mp3;
+
+    async fn post(base_url: &Url, endpoint: &str, body: serde_json::Value) -> serde_json::Value {
+        reqwest::Client::new()
+            .post(base_url.join(endpoint).unwrap())
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_silent_mp3() {
+        let mp3 = silent_mp3(MP3_FRAMES);
+        assert!(is_mp3(&mp3));
+        assert_eq!(mp3.len(), MP3_FRAMES * 417);
+    }
+
+    #[tokio::test]
+    async fn test_insert_then_exist() {
+        let base_url = spawn(MockConfig::default()).await;
+        let check = serde_json::json!({
+            "formatValue": 1, "quality": "Kbps128", "youtube_id": "yPvoKz6tyJs"
+        });
+
+        let res = post(&base_url, "check_database.php", check.clone()).await;
+        assert_eq!(res["success"], false);
+
+        let res = post(
+            &base_url,
+            "insert_to_database.php",
+            serde_json::json!({
+                "formatValue": 1, "quality": "Kbps128", "server_path": "http://cdn/x",
+                "title": "t", "youtube_id": "yPvoKz6tyJs"
+            }),
+        )
+        .await;
+        assert_eq!(res["success"], true);
+
+        let res = post(&base_url, "check_database.php", check).await;
+        assert_eq!(res["success"], true);
+        assert_eq!(res["data"]["server_path"], "http://cdn/x");
+        assert_eq!(res["data"]["quality"], "4");
+    }
+
+    #[tokio::test]
+    async fn test_fail_switches() {
+        let base_url = spawn(MockConfig {
+            fail: vec![Step::DownloadVideo, Step::Download],
+            ..Default::default()
+        })
+        .await;
+
+        let res = post(
+            &base_url,
+            "download_video.php",
+            serde_json::json!({
+                "formatValue": 1, "quality": "Kbps96", "title": "t",
+                "url": "https://www.youtube.com/watch?v=yPvoKz6tyJs"
+            }),
+        )
+        .await;
+        assert_eq!(res["success"], false);
+        assert_eq!(res["errorType"], 1);
+
+        let res = reqwest::get(base_url.join("download.php?id=yPvoKz6tyJs").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_truncate() {
+        let base_url = spawn(MockConfig {
+            truncate: vec![Step::Download],
+            ..Default::default()
+        })
+        .await;
+
+        let res = reqwest::get(base_url.join("download.php?id=yPvoKz6tyJs").unwrap())
+            .await
+            .unwrap();
+        assert!(res.bytes().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_range() {
+        let base_url = spawn(MockConfig::default()).await;
+        let url = base_url.join("download.php?id=yPvoKz6tyJs").unwrap();
+        let mp3 = silent_mp3(MP3_FRAMES);
+        let client = reqwest::Client::new();
+
+        let res = client
+            .get(url.clone())
+            .header("Range", "bytes=100-")
+            .header("If-Range", MP3_ETAG)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            res.headers()["content-range"],
+            format!("bytes 100-{}/{}", mp3.len() - 1, mp3.len()).as_str()
+        );
+        assert_eq!(res.bytes().await.unwrap(), mp3[100..]);
+
+        let res = client
+            .get(url.clone())
+            .header("Range", "bytes=100-")
+            .header("If-Range", "\"something-else\"")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.bytes().await.unwrap(), mp3);
+
+        let res = client
+            .get(url)
+            .header("Range", format!("bytes={}-", mp3.len()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+
+    #[tokio::test]
+    async fn test_delay() {
+        let base_url = spawn(MockConfig {
+            delay: Duration::from_millis(200),
+            ..Default::default()
+        })
+        .await;
+
+        let start = std::time::Instant::now();
+        let res = reqwest::get(base_url.join("download.php?id=yPvoKz6tyJs").unwrap())
+            .await
+            .unwrap();
+        assert!(is_mp3(&res.bytes().await.unwrap()));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::id3::id3v2_len;
+
+/// Bit rates (kb/s) of MPEG-1 Layer III frames by bitrate index
+const MPEG1_KBPS: [u32; 15] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+
+/// Bit rates (kb/s) of MPEG-2 and MPEG-2.5 Layer III frames by bitrate index
+const MPEG2_KBPS: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+/// Sample rates (Hz) of MPEG-1 frames by sample rate index; halved for MPEG-2 and quartered for
+/// MPEG-2.5
+const MPEG1_SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+/// What an MPEG Layer III frame header says about its frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FrameHeader {
+    kbps: u32,
+    sample_rate: u32,
+    /// Samples per channel in the frame
+    samples: u32,
+    /// Length of the frame in bytes, header included
+    len: usize,
+}
+
+impl FrameHeader {
+    /// Parses the four bytes at the start of `h`; `None` unless they are a valid Layer III
+    /// header with a known bitrate (free-format frames are not supported)
+    fn parse(h: &[u8]) -> Option<Self> {
+        let [b0, b1, b2, ..] = *h else {
+            return None;
+        };
+        let (version, layer) = ((b1 >> 3) & 0b11, (b1 >> 1) & 0b11);
+        let (index, rate) = ((b2 >> 4) as usize, ((b2 >> 2) & 0b11) as usize);
+        let padding = ((b2 >> 1) & 1) as usize;
+
+        // sync word, a known MPEG version, layer III, and a valid bitrate and sample rate
+        if b0 != 0xFF || b1 & 0xE0 != 0xE0 || version == 0b01 || layer != 0b01 {
+            return None;
+        }
+        if index == 0 || index == 15 || rate == 0b11 {
+            return None;
+        }
+
+        let (kbps, sample_rate, samples) = match version {
+            0b11 => (MPEG1_KBPS[index], MPEG1_SAMPLE_RATES[rate], 1152),
+            0b10 => (MPEG2_KBPS[index], MPEG1_SAMPLE_RATES[rate] / 2, 576),
+            _ => (MPEG2_KBPS[index], MPEG1_SAMPLE_RATES[rate] / 4, 576),
+        };
+        let len = (samples / 8 * kbps * 1000 / sample_rate) as usize + padding;
+
+        Some(FrameHeader {
+            kbps,
+            sample_rate,
+            samples,
+            len,
+        })
+    }
+}
+
+/// Bit rate in kb/s of an MP3 file, as given by the first MPEG Layer III frame header found in
+/// `head` (the first bytes of the file) after any ID3v2 tag. `None` if there is no such header
+/// in `head`, or if it is a free-format frame.
+pub fn mp3_kbps(head: &[u8]) -> Option<u32> {
+    let frames = head.get(id3v2_len(head)..)?;
+
+    frames
+        .windows(4)
+        .find_map(FrameHeader::parse)
+        .map(|header| header.kbps)
+}
+
+/// Length and average bit rate of a whole MP3 file, measured from its frames
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mp3Info {
+    pub frames: u64,
+    pub duration_ms: u64,
+    /// Average bit rate in kb/s (the bit rate of every frame for a constant bit rate file)
+    pub kbps: u32,
+}
+
+/// Walks the MPEG Layer III frames of an MP3 file fed to it chunk by chunk (see `push`),
+/// skipping tags and anything else that is not a frame, to measure its length and bit rate
+#[derive(Debug, Default)]
+pub struct Frames {
+    /// Bytes still to be skipped, such as the rest of an ID3v2 tag
+    skip: usize,
+    /// Bytes fed but not walked yet: less than a frame
+    pending: Vec<u8>,
+    frames: u64,
+    bytes: u64,
+    duration_us: u64,
+}
+
+impl Frames {
+    /// Walks the frames of a file starting with a `skip` bytes long ID3v2 tag (see `id3v2_len`)
+    pub fn new(skip: usize) -> Self {
+        Frames {
+            skip,
+            ..Frames::default()
+        }
+    }
+
+    /// Walks the frames of the next bytes of the file
+    pub fn push(&mut self, chunk: &[u8]) {
+        let skipped = self.skip.min(chunk.len());
+        self.skip -= skipped;
+        self.pending.extend_from_slice(&chunk[skipped..]);
+        self.walk(false);
+    }
+
+    /// What the frames of the whole file tell, once all of it was pushed. `None` if it holds no
+    /// frames.
+    pub fn finish(mut self) -> Option<Mp3Info> {
+        self.walk(true);
+
+        if self.frames == 0 {
+            return None;
+        }
+
+        Some(Mp3Info {
+            frames: self.frames,
+            duration_ms: self.duration_us / 1000,
+            kbps: ((self.bytes * 8 * 1000 + self.duration_us / 2) / self.duration_us.max(1)) as u32,
+        })
+    }
+
+    /// Walks the frames in `pending`, keeping what may be the start of a frame cut short unless
+    /// the file ends there
+    fn walk(&mut self, end: bool) {
+        let mut pos = 0;
+
+        while pos + 4 <= self.pending.len() {
+            let header = match FrameHeader::parse(&self.pending[pos..]) {
+                Some(header) if pos + header.len <= self.pending.len() => header,
+                Some(_) if !end => break,
+                _ => {
+                    pos += 1;
+                    continue;
+                }
+            };
+            let frame = &self.pending[pos..pos + header.len];
+            pos += header.len;
+
+            // the Xing/Info frame LAME puts first describes the file rather than holding audio
+            let head = &frame[..frame.len().min(40)];
+            if self.frames == 0 && (head.windows(4).any(|w| w == b"Xing" || w == b"Info")) {
+                continue;
+            }
+
+            self.frames += 1;
+            self.bytes += header.len as u64;
+            self.duration_us += header.samples as u64 * 1_000_000 / header.sample_rate as u64;
+        }
+
+        self.pending.drain(..pos);
+    }
+}
+
+/// Walks every MPEG Layer III frame of `mp3` (skipping tags and anything else that is not a
+/// frame) to measure its length and bit rate. `None` if `mp3` holds no frames.
+pub fn mp3_info(mp3: &[u8]) -> Option<Mp3Info> {
+    let mut frames = Frames::new(id3v2_len(mp3));
+    frames.push(mp3);
+    frames.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mp3_kbps() {
+        let mp3 = crate::mock::silent_mp3(2);
+        assert_eq!(mp3_kbps(&mp3), Some(128));
+
+        // 320 kb/s, behind an ID3v2 tag holding a stray sync word
+        let mut tagged = vec![b'I', b'D', b'3', 4, 0, 0, 0, 0, 1, 4];
+        tagged.extend_from_slice(&[0xFF; 132]);
+        tagged.extend_from_slice(&[0xFF, 0xFB, 0xE0, 0x00]);
+        assert_eq!(id3v2_len(&tagged), 142);
+        assert_eq!(mp3_kbps(&tagged), Some(320));
+
+        // MPEG-2 at 64 kb/s
+        assert_eq!(mp3_kbps(&[0xFF, 0xF3, 0x80, 0x00]), Some(64));
+
+        assert_eq!(mp3_kbps(b"<html>rate limited</html>"), None);
+        assert_eq!(mp3_kbps(&tagged[..100]), None);
+    }
+
+    #[test]
+    fn test_mp3_info() {
+        // 1152 samples at 44.1 kHz make 26.122 ms a frame
+        let mp3 = crate::mock::silent_mp3(100);
+        let info = mp3_info(&mp3).unwrap();
+        assert_eq!(info.frames, 100);
+        assert_eq!(info.duration_ms, 2612);
+        assert_eq!(info.kbps, 128);
+
+        // tags on both ends, and garbage between frames, are skipped
+        let mut tagged = vec![b'I', b'D', b'3', 4, 0, 0, 0, 0, 0, 2, 0xFF, 0xFB];
+        tagged.extend_from_slice(&crate::mock::silent_mp3(10));
+        tagged.extend_from_slice(b"junk");
+        tagged.extend_from_slice(&crate::mock::silent_mp3(10));
+        tagged.extend_from_slice(b"TAG");
+        tagged.resize(tagged.len() + 125, 0);
+        assert_eq!(mp3_info(&tagged).unwrap().frames, 20);
+
+        // a LAME Info frame is not counted
+        let mut info_frame = crate::mock::silent_mp3(1);
+        info_frame[36..40].copy_from_slice(b"Info");
+        info_frame.extend_from_slice(&crate::mock::silent_mp3(4));
+        assert_eq!(mp3_info(&info_frame).unwrap().frames, 4);
+
+        assert_eq!(mp3_info(b"<html>rate limited</html>"), None);
+
+        // fed in chunks cutting through the tag and frames, the same is found
+        let mut frames = Frames::new(id3v2_len(&tagged));
+        for chunk in tagged.chunks(7) {
+            frames.push(chunk);
+        }
+        assert_eq!(frames.finish(), mp3_info(&tagged));
+    }
+}
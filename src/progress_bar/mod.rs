@@ -0,0 +1,54 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use photon::convert::{Event, Outcome, Progress, Source};
+
+const TEMPLATE: &str = "{prefix:11} [{bar:30}] {bytes:>10}/{total_bytes:10} {wide_msg}";
+
+/// `Progress` drawing one terminal progress bar per conversion on stderr
+pub fn progress_bars() -> Progress {
+    let multi = MultiProgress::new();
+    let bars: Mutex<HashMap<String, ProgressBar>> = Mutex::new(HashMap::new());
+    let style = ProgressStyle::with_template(TEMPLATE)
+        .expect("progress bar template should be valid")
+        .progress_chars("=> ");
+
+    Progress::callback(move |event| {
+        let mut bars = bars.lock().unwrap();
+
+        let id = match &event {
+            Event::Source { id, .. }
+            | Event::StepStarted { id, .. }
+            | Event::StepFinished { id, .. }
+            | Event::Downloaded { id, .. }
+            | Event::Finished { id, .. } => id.clone(),
+        };
+        let bar = bars.entry(id.clone()).or_insert_with(|| {
+            let bar = multi.add(ProgressBar::new(0).with_style(style.clone()));
+            bar.set_prefix(id);
+            bar
+        });
+
+        match event {
+            Event::Source { source, .. } => bar.set_message(match source {
+                Source::Local => "already saved",
+                Source::Database => "converted before",
+                Source::Conversion => "converting",
+            }),
+            Event::StepStarted { step, .. } => bar.set_message(format!("{:?}", step)),
+            Event::StepFinished { .. } => {}
+            Event::Downloaded { bytes, total, .. } => {
+                if let Some(total) = total {
+                    bar.set_length(total);
+                }
+                bar.set_position(bytes);
+            }
+            Event::Finished { result, .. } => bar.finish_with_message(match result {
+                Ok(Outcome::Saved) => String::from("done"),
+                Ok(Outcome::Cached) => String::from("cached"),
+                Err(e) => format!("failed: {}", e),
+            }),
+        }
+    })
+}
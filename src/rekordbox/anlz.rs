@@ -0,0 +1,404 @@
+use std::path::Path;
+
+use crate::analysis::{detect_tempo, Audio, BeatGrid};
+use crate::error::Error;
+use crate::library::{Library, Unmatched};
+use crate::storage::Location;
+
+/// Columns of the waveform preview (`PWAV`) and the tiny one older players show (`PWV2`)
+const PREVIEW_COLUMNS: usize = 400;
+const TINY_PREVIEW_COLUMNS: usize = 100;
+
+/// Columns a second of the scrolling waveform (`PWV3`)
+const DETAIL_RATE: usize = 150;
+
+/// Name of the analysis files of a track, with `.DAT` or `.EXT` after it
+pub const ANLZ_NAME: &str = "ANLZ0000";
+
+/// The analysis files Rekordbox keeps for each track (under `PIONEER/USBANLZ` on a USB stick):
+/// the `.DAT` file every player reads, and the `.EXT` file with what newer players show
+#[derive(Clone, Debug)]
+pub struct AnlzFiles {
+    pub dat: Vec<u8>,
+    pub ext: Vec<u8>,
+}
+
+/// A tagged section of an ANLZ file: its type, the lengths of its header and of all of it, the
+/// rest of its header, then its content (all big-endian)
+fn section(fourcc: &[u8; 4], header: &[u8], content: &[u8]) -> Vec<u8> {
+    let len_header = 12 + header.len();
+    let mut section = Vec::with_capacity(len_header + content.len());
+    section.extend_from_slice(fourcc);
+    section.extend_from_slice(&(len_header as u32).to_be_bytes());
+    section.extend_from_slice(&((len_header + content.len()) as u32).to_be_bytes());
+    section.extend_from_slice(header);
+    section.extend_from_slice(content);
+    section
+}
+
+/// An ANLZ file (`PMAI`) holding `sections`
+fn anlz_file(sections: &[Vec<u8>]) -> Vec<u8> {
+    let content = sections.concat();
+    // what the rest of the file header holds is not known; Rekordbox itself is not consistent
+    section(b"PMAI", &[0; 16], &content)
+}
+
+/// `PPTH`: the path of the audio file, as the player sees it, in UTF-16 with a NUL at the end
+fn path_section(path: &str) -> Vec<u8> {
+    let path: Vec<u8> = path
+        .encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_be_bytes)
+        .collect();
+
+    section(b"PPTH", &(path.len() as u32).to_be_bytes(), &path)
+}
+
+/// `PQTZ`: every beat of `grid` in the first `duration_ms` of the track, as its number in the
+/// bar, tempo in hundredths of a BPM and time in milliseconds
+fn beat_grid_section(grid: &BeatGrid, duration_ms: u64) -> Vec<u8> {
+    let beats = grid.beats(duration_ms);
+    let tempo = (grid.bpm * 100.0).round() as u16;
+
+    let mut header = Vec::with_capacity(12);
+    header.extend_from_slice(&0u32.to_be_bytes());
+    header.extend_from_slice(&0x0008_0000u32.to_be_bytes());
+    header.extend_from_slice(&(beats.len() as u32).to_be_bytes());
+
+    let mut content = Vec::with_capacity(beats.len() * 8);
+    for (time, number) in beats {
+        content.extend_from_slice(&(number as u16).to_be_bytes());
+        content.extend_from_slice(&tempo.to_be_bytes());
+        content.extend_from_slice(&(time.round() as u32).to_be_bytes());
+    }
+
+    section(b"PQTZ", &header, &content)
+}
+
+/// Loudness and brightness (0 to 1) of each of `columns` stretches of `audio`: how loud it is
+/// (RMS, so that a mastered track does not peak everywhere), and how much of it is treble
+fn waveform(audio: &Audio, columns: usize) -> Vec<(f32, f32)> {
+    let samples = &audio.samples;
+    (0..columns)
+        .map(|column| {
+            let stretch = &samples[column * samples.len() / columns..][..samples.len() / columns];
+            if stretch.len() < 2 {
+                return (0.0, 0.0);
+            }
+
+            let energy = stretch.iter().map(|s| s * s).sum::<f32>();
+            // the difference of neighbouring samples is a rough high-pass, twice as loud as
+            // its input at the very top
+            let treble = stretch
+                .windows(2)
+                .map(|w| (w[1] - w[0]) * (w[1] - w[0]))
+                .sum::<f32>();
+
+            let rms = (energy / stretch.len() as f32).sqrt();
+            let brightness = match energy > 0.0 {
+                true => (treble / energy).sqrt() / 2.0,
+                false => 0.0,
+            };
+            ((rms * 2.0).min(1.0), brightness.min(1.0))
+        })
+        .collect()
+}
+
+/// Columns 31 pixels high with 8 shades of white, as `PWAV` and `PWV3` have them
+fn shaded_columns(waveform: &[(f32, f32)]) -> Vec<u8> {
+    waveform
+        .iter()
+        .map(|(height, brightness)| {
+            let height = (height * 31.0).round() as u8;
+            let whiteness = (brightness * 7.0).round() as u8;
+            whiteness << 5 | height
+        })
+        .collect()
+}
+
+/// `PWAV`, `PWV2` or the like: a waveform preview of a fixed number of columns
+fn preview_section(fourcc: &[u8; 4], columns: &[u8]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(8);
+    header.extend_from_slice(&(columns.len() as u32).to_be_bytes());
+    header.extend_from_slice(&0x0010_0000u32.to_be_bytes());
+
+    section(fourcc, &header, columns)
+}
+
+/// `PWV3`: the scrolling waveform, `DETAIL_RATE` columns a second
+fn detail_section(columns: &[u8]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(12);
+    header.extend_from_slice(&1u32.to_be_bytes());
+    header.extend_from_slice(&(columns.len() as u32).to_be_bytes());
+    header.extend_from_slice(&0x0096_0000u32.to_be_bytes());
+
+    section(b"PWV3", &header, columns)
+}
+
+/// The analysis files of `audio`, the track at `path` (as the player sees it, e.g.
+/// `/Contents/Artist/Track.mp3` on a USB stick): its beat grid (without one if `grid` is `None`)
+/// and waveforms
+pub fn anlz_files(path: &str, audio: &Audio, grid: Option<&BeatGrid>) -> AnlzFiles {
+    let duration_ms = audio.duration_ms();
+
+    let mut dat = vec![path_section(path)];
+    if let Some(grid) = grid {
+        dat.push(beat_grid_section(grid, duration_ms));
+    }
+    dat.push(preview_section(
+        b"PWAV",
+        &shaded_columns(&waveform(audio, PREVIEW_COLUMNS)),
+    ));
+    let tiny: Vec<u8> = waveform(audio, TINY_PREVIEW_COLUMNS)
+        .iter()
+        .map(|(height, _)| (height * 15.0).round() as u8)
+        .collect();
+    dat.push(preview_section(b"PWV2", &tiny));
+
+    let detail_columns = (duration_ms as usize * DETAIL_RATE / 1000).max(1);
+    let ext = vec![
+        path_section(path),
+        detail_section(&shaded_columns(&waveform(audio, detail_columns))),
+    ];
+
+    AnlzFiles {
+        dat: anlz_file(&dat),
+        ext: anlz_file(&ext),
+    }
+}
+
+/// What `export_anlz` did
+#[derive(Debug, Default)]
+pub struct AnlzExport {
+    /// YouTube IDs of the tracks whose analysis files were written
+    pub written: Vec<String>,
+    /// Those of them without a beat grid, as no beats were found
+    pub without_grid: Vec<String>,
+    /// Tracks left out, such as remote or missing files
+    pub skipped: Vec<Unmatched>,
+}
+
+/// The path of `path` as a player sees it: from `root` (e.g., where a USB stick is mounted) if
+/// given, or else the whole absolute path
+fn player_path(path: &Path, root: Option<&Path>) -> Result<String, String> {
+    let path = std::path::absolute(path).map_err(|e| e.to_string())?;
+    let path = match root {
+        Some(root) => {
+            let root = std::path::absolute(root).map_err(|e| e.to_string())?;
+            let relative = path
+                .strip_prefix(&root)
+                .map_err(|_| format!("not under {}", root.display()))?;
+            Path::new("/").join(relative)
+        }
+        None => path,
+    };
+
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Decodes every local MP3 file of `library`, detects its beat grid (unless the index already
+/// has it) and writes its analysis files into `<out>/<youtube id>/`, with the path of the file
+/// from `root` in them (see `anlz_files`)
+pub fn export_anlz(library: &Library, out: &Path, root: Option<&Path>) -> AnlzExport {
+    let mut export = AnlzExport::default();
+
+    for track in library.tracks() {
+        let skipped = |reason: String| Unmatched {
+            path: track.path.clone(),
+            reason,
+        };
+        if !matches!(track.path.to_string_lossy().parse(), Ok(Location::Local(_))) {
+            export
+                .skipped
+                .push(skipped(String::from("not a local file")));
+            continue;
+        }
+
+        let player_path = match player_path(&track.path, root) {
+            Ok(player_path) => player_path,
+            Err(reason) => {
+                export.skipped.push(skipped(reason));
+                continue;
+            }
+        };
+        let written = Audio::read(&track.path).and_then(|audio| {
+            let grid = match track.tempo {
+                Some(tempo) => Some(tempo.grid),
+                None => detect_tempo(&audio).map(|tempo| tempo.grid),
+            };
+            let files = anlz_files(&player_path, &audio, grid.as_ref());
+
+            let dir = out.join(&track.id);
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(dir.join(format!("{}.DAT", ANLZ_NAME)), files.dat)?;
+            std::fs::write(dir.join(format!("{}.EXT", ANLZ_NAME)), files.ext)?;
+            Ok::<_, Error>(grid.is_some())
+        });
+
+        match written {
+            Ok(has_grid) => {
+                export.written.push(track.id.clone());
+                if !has_grid {
+                    export.without_grid.push(track.id.clone());
+                }
+            }
+            Err(e) => export.skipped.push(skipped(e.value)),
+        }
+    }
+
+    export
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::tests::kicks;
+    use crate::library::tests::track;
+    use crate::library::INDEX_NAME;
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_be_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    /// The sections of an ANLZ file as type, rest of the header and content, checking that the
+    /// lengths in the file header and in every section header add up
+    fn sections(file: &[u8]) -> Vec<(String, &[u8], &[u8])> {
+        assert_eq!(&file[..4], b"PMAI");
+        assert_eq!(u32_at(file, 4), 28);
+        assert_eq!(u32_at(file, 8) as usize, file.len());
+
+        let mut sections = Vec::new();
+        let mut at = 28;
+        while at < file.len() {
+            let len_header = u32_at(file, at + 4) as usize;
+            let len_tag = u32_at(file, at + 8) as usize;
+            assert!(len_header >= 12 && len_tag >= len_header && at + len_tag <= file.len());
+
+            sections.push((
+                String::from_utf8(file[at..at + 4].to_vec()).unwrap(),
+                &file[at + 12..at + len_header],
+                &file[at + len_header..at + len_tag],
+            ));
+            at += len_tag;
+        }
+        assert_eq!(at, file.len());
+
+        sections
+    }
+
+    fn path_of(header: &[u8], content: &[u8]) -> String {
+        assert_eq!(u32_at(header, 0) as usize, content.len());
+        let units: Vec<u16> = content.chunks(2).map(|c| u16_at(c, 0)).collect();
+        assert_eq!(units.last(), Some(&0));
+        String::from_utf16(&units[..units.len() - 1]).unwrap()
+    }
+
+    #[test]
+    fn test_anlz_files() {
+        let audio = kicks(128.0, 100.0, 1, 10.0);
+        let grid = detect_tempo(&audio).unwrap().grid;
+        let files = anlz_files(
+            "/Contents/Daft Punk – One More Time.mp3",
+            &audio,
+            Some(&grid),
+        );
+
+        let dat = sections(&files.dat);
+        let kinds: Vec<_> = dat.iter().map(|(kind, _, _)| kind.as_str()).collect();
+        assert_eq!(kinds, ["PPTH", "PQTZ", "PWAV", "PWV2"]);
+        assert_eq!(
+            path_of(dat[0].1, dat[0].2),
+            "/Contents/Daft Punk – One More Time.mp3"
+        );
+
+        let (_, header, content) = dat[1];
+        assert_eq!(header.len(), 12);
+        assert_eq!(u32_at(header, 4), 0x0008_0000);
+        let len_beats = u32_at(header, 8) as usize;
+        assert_eq!(content.len(), len_beats * 8);
+        // 10 seconds at 128 BPM from the first kick, the last beat of a bar
+        assert_eq!(len_beats, 22);
+        let beats: Vec<(u16, u16, u32)> = content
+            .chunks(8)
+            .map(|b| (u16_at(b, 0), u16_at(b, 2), u32_at(b, 4)))
+            .collect();
+        for (i, (number, tempo, time)) in beats.iter().enumerate() {
+            assert_eq!(*number as usize, (i + 3) % 4 + 1);
+            assert!(tempo.abs_diff(12800) <= 2, "{} BPM", tempo);
+            let expected = 100.0 + i as f64 * 468.75;
+            assert!(
+                (*time as f64 - expected).abs() <= 10.0,
+                "beat {} at {} ms",
+                i,
+                time
+            );
+        }
+
+        for (index, columns, max) in [(2, PREVIEW_COLUMNS, 31), (3, TINY_PREVIEW_COLUMNS, 15)] {
+            let (_, header, content) = dat[index];
+            assert_eq!(u32_at(header, 0) as usize, columns);
+            assert_eq!(u32_at(header, 4), 0x0010_0000);
+            assert_eq!(content.len(), columns);
+            assert!(content.iter().all(|c| c & 0x1F <= max));
+            assert!(content.iter().any(|c| c & 0x1F > 0));
+        }
+        // the tiny preview has no shades
+        assert!(dat[3].2.iter().all(|c| c >> 4 == 0));
+
+        let ext = sections(&files.ext);
+        let kinds: Vec<_> = ext.iter().map(|(kind, _, _)| kind.as_str()).collect();
+        assert_eq!(kinds, ["PPTH", "PWV3"]);
+        let (_, header, content) = ext[1];
+        assert_eq!(u32_at(header, 0), 1);
+        assert_eq!(u32_at(header, 4) as usize, content.len());
+        assert_eq!(u32_at(header, 8), 0x0096_0000);
+        assert_eq!(content.len(), 10 * DETAIL_RATE);
+        // silent before the first kick, loud on it
+        assert_eq!(content[0], 0);
+        assert!(content[16] & 0x1F > 10);
+    }
+
+    #[test]
+    fn test_export_anlz() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut library = Library::open(dir.path().join(INDEX_NAME)).unwrap();
+
+        let mut silent = track("yPvoKz6tyJs");
+        silent.path = dir.path().join("usb/music/One More Time.mp3");
+        std::fs::create_dir_all(silent.path.parent().unwrap()).unwrap();
+        std::fs::write(&silent.path, crate::mock::silent_mp3(200)).unwrap();
+        library.insert(silent);
+
+        let mut elsewhere = track("dQw4w9WgXcQ");
+        elsewhere.path = dir.path().join("dQw4w9WgXcQ.mp3");
+        std::fs::write(&elsewhere.path, crate::mock::silent_mp3(200)).unwrap();
+        library.insert(elsewhere);
+        let mut remote = track("FGBhQbmPwH8");
+        remote.path = "s3://music/FGBhQbmPwH8.mp3".into();
+        library.insert(remote);
+
+        let out = dir.path().join("usb/PIONEER/USBANLZ");
+        let export = export_anlz(&library, &out, Some(&dir.path().join("usb")));
+        assert_eq!(export.written, ["yPvoKz6tyJs"]);
+        assert_eq!(export.without_grid, ["yPvoKz6tyJs"]);
+        let mut reasons: Vec<_> = export.skipped.iter().map(|s| s.reason.clone()).collect();
+        reasons.sort();
+        assert_eq!(reasons.len(), 2);
+        assert_eq!(reasons[0], "not a local file");
+        assert!(reasons[1].starts_with("not under "));
+
+        let dat = std::fs::read(out.join("yPvoKz6tyJs/ANLZ0000.DAT")).unwrap();
+        let dat = sections(&dat);
+        let kinds: Vec<_> = dat.iter().map(|(kind, _, _)| kind.as_str()).collect();
+        assert_eq!(kinds, ["PPTH", "PWAV", "PWV2"]);
+        assert_eq!(path_of(dat[0].1, dat[0].2), "/music/One More Time.mp3");
+        assert!(dat[1].2.iter().all(|c| *c == 0));
+
+        let ext = std::fs::read(out.join("yPvoKz6tyJs/ANLZ0000.EXT")).unwrap();
+        assert_eq!(sections(&ext).len(), 2);
+    }
+}
@@ -0,0 +1,102 @@
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use crate::error::{Error, ErrorKind};
+use crate::youtube_url::{is_youtube_id, YouTubeURL};
+
+mod anlz;
+mod xml;
+pub use anlz::{anlz_files, export_anlz, AnlzExport, AnlzFiles, ANLZ_NAME};
+pub use xml::{collection_xml, Collection};
+
+/// A crate list: a named list of videos, kept as a file of one YouTube URL (or ID) per line, as
+/// given to `y2-mp3 --input`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrateList {
+    /// Name of the list, the file name without its extension
+    pub name: String,
+    /// YouTube IDs in the list, in order
+    pub ids: Vec<String>,
+}
+
+impl CrateList {
+    /// Reads the crate list at `path`. Blank lines and lines starting with `#` are ignored; any
+    /// other line that is not a YouTube URL or ID is an error.
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let name = path
+            .file_stem()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy()
+            .into_owned();
+
+        let mut ids: Vec<String> = Vec::new();
+        let lines = std::fs::read_to_string(path)?;
+        let lines = lines
+            .lines()
+            .enumerate()
+            .map(|(n, l)| (n + 1, l.trim()))
+            .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+
+        for (n, line) in lines {
+            let id = match is_youtube_id(line) {
+                true => line.to_string(),
+                false => Url::parse(line)
+                    .map_err(|e| e.to_string())
+                    .and_then(|url| YouTubeURL::new(url).map_err(|e| e.value))
+                    .map(|youtube_url| youtube_url.id)
+                    .map_err(|reason| Error {
+                        kind: ErrorKind::InvalidURL,
+                        value: format!("{}:{}: {}", path.display(), n, reason),
+                    })?,
+            };
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+
+        Ok(CrateList { name, ids })
+    }
+}
+
+/// `file://localhost/...` URI of the local file `path` (made absolute from the current
+/// directory), as Rekordbox locates tracks
+fn location_uri(path: &Path) -> Result<String, Error> {
+    let path: PathBuf = std::path::absolute(path)?;
+    let url = Url::from_file_path(&path).map_err(|_| Error {
+        kind: ErrorKind::Error,
+        value: format!("{} is not a local path", path.display()),
+    })?;
+
+    Ok(url.as_str().replacen("file://", "file://localhost", 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crate_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Peak Time.txt");
+        std::fs::write(
+            &path,
+            "# friday\nhttps://www.youtube.com/watch?v=yPvoKz6tyJs\n\ndQw4w9WgXcQ\nyPvoKz6tyJs\n",
+        )
+        .unwrap();
+
+        let list = CrateList::read(&path).unwrap();
+        assert_eq!(list.name, "Peak Time");
+        assert_eq!(list.ids, ["yPvoKz6tyJs", "dQw4w9WgXcQ"]);
+
+        std::fs::write(&path, "yPvoKz6tyJs\nnot a video\n").unwrap();
+        let err = CrateList::read(&path).unwrap_err();
+        assert!(err
+            .value
+            .ends_with("Peak Time.txt:2: relative URL without a base"));
+
+        assert_eq!(
+            location_uri(Path::new("/music/Daft Punk - One More Time.mp3")).unwrap(),
+            "file://localhost/music/Daft%20Punk%20-%20One%20More%20Time.mp3"
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use quick_xml::events::{BytesDecl, Event};
+use quick_xml::Writer;
+use std::collections::HashMap;
+use std::io;
+
+use super::{location_uri, CrateList};
+use crate::error::Error;
+use crate::library::date::format_date;
+use crate::library::{Library, Track, Unmatched};
+use crate::mp3::mp3_info;
+use crate::storage::Location;
+use crate::titles::TitleRules;
+
+/// A Rekordbox collection (the `DJ_PLAYLISTS` XML format) and what went into it
+#[derive(Debug)]
+pub struct Collection {
+    pub xml: String,
+    /// YouTube IDs of the tracks in the collection
+    pub tracks: Vec<String>,
+    /// Tracks of the library left out, such as remote or missing files
+    pub skipped: Vec<Unmatched>,
+    /// Crate list entries not in the collection, as crate list name and YouTube ID
+    pub missing: Vec<(String, String)>,
+}
+
+/// The attributes of the `TRACK` entry of `track`, or why it cannot have one
+fn track_attributes(
+    track: &Track,
+    track_id: usize,
+    rules: &TitleRules,
+) -> Result<Vec<(&'static str, String)>, String> {
+    let path = track.path.to_string_lossy();
+    if !matches!(path.parse(), Ok(Location::Local(_))) {
+        return Err(String::from("not a local file"));
+    }
+    if !track.path.is_file() {
+        return Err(String::from("the file is missing"));
+    }
+    let location = location_uri(&track.path).map_err(|e| e.value)?;
+
+    // tracks saved by earlier versions have not been measured
+    let duration_ms = match track.duration_ms {
+        Some(duration_ms) => duration_ms,
+        None => {
+            let mp3 = std::fs::read(&track.path).map_err(|e| e.to_string())?;
+            mp3_info(&mp3)
+                .ok_or("no MPEG audio frames in the file")?
+                .duration_ms
+        }
+    };
+
+    let mut attributes = vec![("TrackID", track_id.to_string())];
+    match &track.title {
+        Some(title) => {
+            let parsed = rules.parse(Some(&track.id), title);
+            attributes.push(("Name", parsed.full_title()));
+            if let Some(artist) = parsed.artist_credit() {
+                attributes.push(("Artist", artist));
+            }
+            if let Some(remix) = parsed.remix {
+                attributes.push(("Mix", remix));
+            }
+            if let Some(label) = parsed.label {
+                attributes.push(("Label", label));
+            }
+        }
+        None => {
+            let name = track.file_name();
+            attributes.push(("Name", name.trim_end_matches(".mp3").to_string()));
+        }
+    }
+    attributes.extend([
+        ("Kind", String::from("MP3 File")),
+        ("Size", track.size.to_string()),
+        ("TotalTime", (duration_ms / 1000).to_string()),
+        ("DateAdded", format_date(track.downloaded_at)),
+        ("BitRate", track.bitrate.kbps().to_string()),
+        ("Comments", track.url.clone()),
+        ("Location", location),
+    ]);
+
+    Ok(attributes)
+}
+
+/// The Rekordbox collection of the local tracks of `library`, their titles parsed with `rules`,
+/// with a playlist for each of `crates`
+pub fn collection_xml(
+    library: &Library,
+    crates: &[CrateList],
+    rules: &TitleRules,
+) -> Result<Collection, Error> {
+    let mut entries = Vec::new();
+    let mut skipped = Vec::new();
+    for track in library.tracks() {
+        match track_attributes(track, entries.len() + 1, rules) {
+            Ok(attributes) => entries.push((track.id.clone(), attributes)),
+            Err(reason) => skipped.push(Unmatched {
+                path: track.path.clone(),
+                reason,
+            }),
+        }
+    }
+
+    let track_ids: HashMap<&str, String> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, (id, _))| (id.as_str(), (i + 1).to_string()))
+        .collect();
+    let mut missing = Vec::new();
+    let playlists: Vec<(&str, Vec<&String>)> = crates
+        .iter()
+        .map(|list| {
+            let keys = list
+                .ids
+                .iter()
+                .filter_map(|id| match track_ids.get(id.as_str()) {
+                    Some(key) => Some(key),
+                    None => {
+                        missing.push((list.name.clone(), id.clone()));
+                        None
+                    }
+                })
+                .collect();
+            (list.name.as_str(), keys)
+        })
+        .collect();
+
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer
+        .create_element("DJ_PLAYLISTS")
+        .with_attribute(("Version", "1.0.0"))
+        .write_inner_content(|w| {
+            w.create_element("PRODUCT")
+                .with_attributes([
+                    ("Name", "photon"),
+                    ("Version", env!("CARGO_PKG_VERSION")),
+                    ("Company", ""),
+                ])
+                .write_empty()?;
+
+            w.create_element("COLLECTION")
+                .with_attribute(("Entries", entries.len().to_string().as_str()))
+                .write_inner_content(|w| {
+                    for (_, attributes) in &entries {
+                        w.create_element("TRACK")
+                            .with_attributes(attributes.iter().map(|(k, v)| (*k, v.as_str())))
+                            .write_empty()?;
+                    }
+                    Ok::<_, io::Error>(())
+                })?;
+
+            w.create_element("PLAYLISTS").write_inner_content(|w| {
+                w.create_element("NODE")
+                    .with_attributes([
+                        ("Type", "0"),
+                        ("Name", "ROOT"),
+                        ("Count", playlists.len().to_string().as_str()),
+                    ])
+                    .write_inner_content(|w| {
+                        for (name, keys) in &playlists {
+                            // a playlist (type 1) of tracks referred to by TrackID (key type 0)
+                            w.create_element("NODE")
+                                .with_attributes([
+                                    ("Name", *name),
+                                    ("Type", "1"),
+                                    ("KeyType", "0"),
+                                    ("Entries", keys.len().to_string().as_str()),
+                                ])
+                                .write_inner_content(|w| {
+                                    for key in keys {
+                                        w.create_element("TRACK")
+                                            .with_attribute(("Key", key.as_str()))
+                                            .write_empty()?;
+                                    }
+                                    Ok::<_, io::Error>(())
+                                })?;
+                        }
+                        Ok::<_, io::Error>(())
+                    })?;
+                Ok::<_, io::Error>(())
+            })?;
+
+            Ok::<_, io::Error>(())
+        })?;
+
+    let mut xml = String::from_utf8(writer.into_inner()).expect("the XML should be UTF-8");
+    xml.push('\n');
+
+    Ok(Collection {
+        xml,
+        tracks: entries.into_iter().map(|(id, _)| id).collect(),
+        skipped,
+        missing,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitrate::BitRate;
+    use crate::library::tests::track;
+    use crate::library::INDEX_NAME;
+    use quick_xml::events::Event;
+    use quick_xml::{Reader, XmlVersion};
+
+    /// Names and attributes of the elements of `xml`, in order
+    fn elements(xml: &str) -> Vec<(String, HashMap<String, String>)> {
+        let mut reader = Reader::from_str(xml);
+        let mut elements = Vec::new();
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(e) | Event::Empty(e) => {
+                    let attributes = e
+                        .attributes()
+                        .map(|a| {
+                            let a = a.unwrap();
+                            let value = a.normalized_value(XmlVersion::Explicit1_0).unwrap();
+                            (a.key.as_ref().to_string(), value.into_owned())
+                        })
+                        .collect();
+                    elements.push((e.name().as_ref().to_string(), attributes));
+                }
+                Event::Eof => return elements,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_collection_xml() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut library = Library::open(dir.path().join(INDEX_NAME)).unwrap();
+
+        let mut tagged = track("yPvoKz6tyJs");
+        tagged.title = Some(String::from(
+            "Daft Punk & Friends - One More Time (Radio Edit) [Virgin Records]",
+        ));
+        tagged.path = dir.path().join("One More Time.mp3");
+        tagged.bitrate = BitRate::Kbps320;
+        tagged.duration_ms = Some(320_500);
+        std::fs::write(&tagged.path, b"").unwrap();
+        library.insert(tagged.clone());
+
+        let mut untitled = track("dQw4w9WgXcQ");
+        untitled.title = None;
+        untitled.path = dir.path().join("dQw4w9WgXcQ.mp3");
+        std::fs::write(&untitled.path, crate::mock::silent_mp3(100)).unwrap();
+        library.insert(untitled);
+
+        let mut remote = track("FGBhQbmPwH8");
+        remote.path = "s3://music/FGBhQbmPwH8.mp3".into();
+        library.insert(remote);
+        let mut missing = track("9bZkp7q5f0w");
+        missing.path = dir.path().join("gone.mp3");
+        library.insert(missing);
+
+        let crates = [CrateList {
+            name: String::from("Peak <Time>"),
+            ids: vec![
+                String::from("yPvoKz6tyJs"),
+                String::from("FGBhQbmPwH8"),
+                String::from("dQw4w9WgXcQ"),
+            ],
+        }];
+        let collection = collection_xml(&library, &crates, &TitleRules::default()).unwrap();
+
+        assert_eq!(collection.tracks, ["dQw4w9WgXcQ", "yPvoKz6tyJs"]);
+        assert_eq!(
+            collection
+                .skipped
+                .iter()
+                .map(|s| s.reason.as_str())
+                .collect::<Vec<_>>(),
+            ["the file is missing", "not a local file"]
+        );
+        assert_eq!(
+            collection.missing,
+            [(String::from("Peak <Time>"), String::from("FGBhQbmPwH8"))]
+        );
+
+        let elements = elements(&collection.xml);
+        let names: Vec<_> = elements.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "DJ_PLAYLISTS",
+                "PRODUCT",
+                "COLLECTION",
+                "TRACK",
+                "TRACK",
+                "PLAYLISTS",
+                "NODE",
+                "NODE",
+                "TRACK",
+                "TRACK"
+            ]
+        );
+        assert_eq!(elements[2].1["Entries"], "2");
+
+        let untitled = &elements[3].1;
+        assert_eq!(untitled["Name"], "dQw4w9WgXcQ");
+        assert_eq!(untitled["TotalTime"], "2");
+        assert!(!untitled.contains_key("Artist"));
+
+        let tagged = &elements[4].1;
+        assert_eq!(tagged["TrackID"], "2");
+        assert_eq!(tagged["Name"], "One More Time (Radio Edit)");
+        assert_eq!(tagged["Artist"], "Daft Punk & Friends");
+        assert_eq!(tagged["Mix"], "Radio Edit");
+        assert_eq!(tagged["Label"], "Virgin Records");
+        assert_eq!(tagged["TotalTime"], "320");
+        assert_eq!(tagged["BitRate"], "320");
+        assert_eq!(tagged["Size"], "417");
+        assert_eq!(tagged["DateAdded"], "2023-11-14");
+        assert_eq!(
+            tagged["Comments"],
+            "https://www.youtube.com/watch?v=yPvoKz6tyJs"
+        );
+        assert_eq!(
+            tagged["Location"],
+            format!(
+                "file://localhost{}/One%20More%20Time.mp3",
+                dir.path().display()
+            )
+        );
+
+        let playlist = &elements[7].1;
+        assert_eq!(playlist["Name"], "Peak <Time>");
+        assert_eq!(playlist["Entries"], "2");
+        assert_eq!(elements[8].1["Key"], "2");
+        assert_eq!(elements[9].1["Key"], "1");
+    }
+}
@@ -0,0 +1,324 @@
+use russh::client::{self, Handle};
+use russh::keys::agent::client::AgentClient;
+use russh::keys::{check_known_hosts_path, load_secret_key, PrivateKeyWithHashAlg};
+use russh::keys::{HashAlg, PublicKeyOrCertificate};
+use russh_sftp::client::SftpSession;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::{Error, ErrorKind};
+
+#[cfg(test)]
+pub(crate) mod server;
+
+/// Port SSH servers listen on unless told otherwise
+pub const DEFAULT_PORT: u16 = 22;
+
+/// Keys tried, in order, when neither `--identity` nor an SSH agent is available
+const DEFAULT_IDENTITIES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// A directory on a remote host, written `user@host:/path` (as for `scp`) or
+/// `ssh://user@host:port/path` when the port is not 22. The user defaults to `$USER`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Remote {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Remote {
+    /// Whether `s` names a remote directory rather than a local one: an `ssh://` URL, or a colon
+    /// before any slash as `scp` decides
+    pub fn is_remote(s: &str) -> bool {
+        s.starts_with("ssh://")
+            || s.split_once(':')
+                .is_some_and(|(host, _)| !host.is_empty() && !host.contains('/'))
+    }
+
+    /// Remote path of the file called `name` in this directory
+    pub fn join(&self, name: &str) -> String {
+        format!("{}/{}", self.path.trim_end_matches('/'), name)
+    }
+}
+
+fn invalid_remote(s: &str, reason: &str) -> Error {
+    Error {
+        kind: ErrorKind::InvalidRemote,
+        value: format!("`{}` is not a remote directory: {}", s, reason),
+    }
+}
+
+fn default_user() -> String {
+    std::env::var("USER").unwrap_or_else(|_| String::from("root"))
+}
+
+impl FromStr for Remote {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("ssh://") {
+            let url = url::Url::parse(s).map_err(|e| invalid_remote(s, &e.to_string()))?;
+            let host = url
+                .host_str()
+                .ok_or_else(|| invalid_remote(s, "missing host"))?;
+            let user = match url.username() {
+                "" => default_user(),
+                user => user.to_string(),
+            };
+            let path =
+                urlencoding::decode(url.path()).map_err(|e| invalid_remote(s, &e.to_string()))?;
+
+            return Ok(Remote {
+                user,
+                host: host.to_string(),
+                port: url.port().unwrap_or(DEFAULT_PORT),
+                path: path.into_owned(),
+            });
+        }
+
+        let (login, path) = s
+            .split_once(':')
+            .ok_or_else(|| invalid_remote(s, "expected user@host:/path"))?;
+        let (user, host) = match login.split_once('@') {
+            Some((user, host)) => (user.to_string(), host),
+            None => (default_user(), login),
+        };
+
+        if user.is_empty() || host.is_empty() {
+            return Err(invalid_remote(s, "expected user@host:/path"));
+        }
+
+        // like scp, a relative path starts in the home directory
+        let path = match path {
+            "" => String::from("."),
+            path => path.to_string(),
+        };
+
+        Ok(Remote {
+            user,
+            host: host.to_string(),
+            port: DEFAULT_PORT,
+            path,
+        })
+    }
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            DEFAULT_PORT => write!(f, "{}@{}:{}", self.user, self.host, self.path),
+            port => write!(f, "ssh://{}@{}:{}{}", self.user, self.host, port, self.path),
+        }
+    }
+}
+
+/// How to log in to remote hosts
+#[derive(Clone, Debug, Default)]
+pub struct SshConfig {
+    /// Private key to log in with; without one, the keys of the SSH agent (`SSH_AUTH_SOCK`) are
+    /// tried, then `~/.ssh/id_ed25519`, `~/.ssh/id_ecdsa` and `~/.ssh/id_rsa`
+    pub identity: Option<PathBuf>,
+    /// File listing the trusted host keys instead of `~/.ssh/known_hosts`
+    pub known_hosts: Option<PathBuf>,
+}
+
+fn home_ssh_dir() -> Result<PathBuf, Error> {
+    std::env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".ssh"))
+        .ok_or_else(|| ssh_error("HOME is not set"))
+}
+
+fn ssh_error(value: impl Into<String>) -> Error {
+    Error {
+        kind: ErrorKind::SshError,
+        value: value.into(),
+    }
+}
+
+/// Checks the key of the server against the known hosts, as `ssh` does with
+/// `StrictHostKeyChecking=yes`
+struct Client {
+    host: String,
+    port: u16,
+    known_hosts: PathBuf,
+}
+
+impl client::Handler for Client {
+    type Error = Error;
+
+    async fn check_server_key(&mut self, key: &PublicKeyOrCertificate) -> Result<bool, Error> {
+        let PublicKeyOrCertificate::PublicKey { key, .. } = key else {
+            return Err(ssh_error("host certificates are not supported"));
+        };
+
+        match check_known_hosts_path(&self.host, self.port, key, &self.known_hosts) {
+            Ok(true) => Ok(true),
+            Ok(false) => Err(ssh_error(format!(
+                "{} is not a known host; add its key to {} (e.g., with `ssh-keyscan`)",
+                self.host,
+                self.known_hosts.display()
+            ))),
+            Err(e) => Err(ssh_error(format!(
+                "could not check the key of {}: {}",
+                self.host, e
+            ))),
+        }
+    }
+}
+
+/// An SFTP session with a remote host
+pub struct Sftp {
+    // keeps the connection open for as long as the session is used
+    _ssh: Handle<Client>,
+    pub sftp: SftpSession,
+    pub remote: Remote,
+}
+
+impl Sftp {
+    /// Logs in to the host of `remote` and starts an SFTP session there
+    pub async fn connect(remote: &Remote, config: &SshConfig) -> Result<Self, Error> {
+        let known_hosts = match &config.known_hosts {
+            Some(path) => path.clone(),
+            None => home_ssh_dir()?.join("known_hosts"),
+        };
+        let handler = Client {
+            host: remote.host.clone(),
+            port: remote.port,
+            known_hosts,
+        };
+        let ssh_config = client::Config {
+            inactivity_timeout: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+
+        let mut ssh = client::connect(
+            Arc::new(ssh_config),
+            (remote.host.as_str(), remote.port),
+            handler,
+        )
+        .await?;
+
+        if !authenticate(&mut ssh, &remote.user, config).await? {
+            return Err(ssh_error(format!(
+                "could not log in to {} as {}",
+                remote.host, remote.user
+            )));
+        }
+
+        let channel = ssh.channel_open_session().await?;
+        channel.request_subsystem(true, "sftp").await?;
+        let sftp = SftpSession::new(channel.into_stream()).await?;
+
+        Ok(Sftp {
+            _ssh: ssh,
+            sftp,
+            remote: remote.clone(),
+        })
+    }
+}
+
+/// Logs in with the configured key, or else with the keys of the SSH agent and then the default
+/// key files. Returns whether one of them was accepted.
+async fn authenticate(
+    ssh: &mut Handle<Client>,
+    user: &str,
+    config: &SshConfig,
+) -> Result<bool, Error> {
+    let rsa_hash = ssh.best_supported_rsa_hash().await?.flatten();
+
+    if let Some(identity) = &config.identity {
+        return authenticate_key(ssh, user, identity, rsa_hash).await;
+    }
+
+    if let Ok(mut agent) = AgentClient::connect_env().await {
+        let identities = agent.request_identities().await.unwrap_or_default();
+
+        for identity in identities {
+            let key = identity.public_key().into_owned();
+            let res = ssh
+                .authenticate_publickey_with(user, key, rsa_hash, &mut agent)
+                .await
+                .map_err(|e| ssh_error(format!("ssh agent: {}", e)))?;
+
+            if res.success() {
+                return Ok(true);
+            }
+        }
+    }
+
+    let dir = home_ssh_dir()?;
+    for name in DEFAULT_IDENTITIES {
+        let path = dir.join(name);
+        if path.exists() && authenticate_key(ssh, user, &path, rsa_hash).await? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+async fn authenticate_key(
+    ssh: &mut Handle<Client>,
+    user: &str,
+    path: &Path,
+    rsa_hash: Option<HashAlg>,
+) -> Result<bool, Error> {
+    // keys protected by a passphrase are meant to be used through the agent
+    let key = load_secret_key(path, None)
+        .map_err(|e| ssh_error(format!("could not load {}: {}", path.display(), e)))?;
+    let key = PrivateKeyWithHashAlg::new(Arc::new(key), rsa_hash);
+
+    Ok(ssh.authenticate_publickey(user, key).await?.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(user: &str, host: &str, port: u16, path: &str) -> Remote {
+        Remote {
+            user: user.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_remote() {
+        let cases = [
+            ("dj@nas:/music", remote("dj", "nas", 22, "/music")),
+            ("dj@nas:music", remote("dj", "nas", 22, "music")),
+            ("dj@nas:", remote("dj", "nas", 22, ".")),
+            (
+                "ssh://dj@nas.local:2222/music/my%20library",
+                remote("dj", "nas.local", 2222, "/music/my library"),
+            ),
+        ];
+
+        for (s, expected) in cases {
+            assert!(Remote::is_remote(s), "{}", s);
+            assert_eq!(s.parse::<Remote>().unwrap(), expected, "{}", s);
+        }
+
+        for s in ["mp3", "./a:b", "/music", ""] {
+            assert!(!Remote::is_remote(s), "{}", s);
+        }
+
+        assert!("@nas:/music".parse::<Remote>().is_err());
+        assert!("ssh:///music".parse::<Remote>().is_err());
+
+        assert_eq!(
+            remote("dj", "nas", 2222, "/music").to_string(),
+            "ssh://dj@nas:2222/music"
+        );
+        assert_eq!(
+            remote("dj", "nas", 22, "/music").join("a.mp3"),
+            "/music/a.mp3"
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use russh::keys::ssh_key::private::{Ed25519Keypair, PrivateKey};
+use russh::keys::ssh_key::LineEnding;
+use russh::server::{Auth, Msg, Session};
+use russh::{Channel, ChannelId};
+use russh_sftp::protocol::{
+    Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode,
+};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::SshConfig;
+
+/// An SSH server on 127.0.0.1 that only speaks SFTP, serving a local directory as `/` to whoever
+/// logs in with the key in `config`; stands in for the remote host in tests
+pub(crate) struct TestServer {
+    pub port: u16,
+    /// Logs in to this server and trusts its key
+    pub config: SshConfig,
+    _keys: tempfile::TempDir,
+}
+
+impl TestServer {
+    /// `user@127.0.0.1` with the given remote path, on the port of this server
+    pub fn remote(&self, path: &str) -> super::Remote {
+        super::Remote {
+            user: String::from("dj"),
+            host: String::from("127.0.0.1"),
+            port: self.port,
+            path: path.to_string(),
+        }
+    }
+}
+
+fn key(seed: u8) -> PrivateKey {
+    PrivateKey::from(Ed25519Keypair::from_seed(&[seed; 32]))
+}
+
+/// Serves `root`; with `corrupt_writes`, every byte written over SFTP is flipped, as a faulty disk
+/// or transfer would do
+pub(crate) async fn spawn(root: &Path, corrupt_writes: bool) -> TestServer {
+    let (host_key, client_key) = (key(1), key(2));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let keys = tempfile::tempdir().unwrap();
+    let identity = keys.path().join("id_ed25519");
+    client_key
+        .write_openssh_file(&identity, LineEnding::LF)
+        .unwrap();
+    let known_hosts = keys.path().join("known_hosts");
+    std::fs::write(
+        &known_hosts,
+        format!(
+            "[127.0.0.1]:{} {}\n",
+            port,
+            host_key.public_key().to_openssh().unwrap()
+        ),
+    )
+    .unwrap();
+
+    let config = Arc::new(russh::server::Config {
+        keys: vec![host_key],
+        ..Default::default()
+    });
+    let (public_key, root) = (client_key.public_key().clone(), root.to_path_buf());
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let config = config.clone();
+            let handler = SshHandler {
+                client_key: public_key.clone(),
+                root: root.clone(),
+                corrupt_writes,
+                channels: HashMap::new(),
+            };
+            tokio::spawn(async move {
+                if let Ok(session) = russh::server::run_stream(config, socket, handler).await {
+                    let _ = session.await;
+                }
+            });
+        }
+    });
+
+    TestServer {
+        port,
+        config: SshConfig {
+            identity: Some(identity),
+            known_hosts: Some(known_hosts),
+        },
+        _keys: keys,
+    }
+}
+
+struct SshHandler {
+    client_key: russh::keys::PublicKey,
+    root: PathBuf,
+    corrupt_writes: bool,
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+impl russh::server::Handler for SshHandler {
+    type Error = russh::Error;
+
+    async fn auth_publickey(
+        &mut self,
+        _user: &str,
+        key: &russh::keys::PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        if key.key_data() == self.client_key.key_data() {
+            Ok(Auth::Accept)
+        } else {
+            Ok(Auth::reject())
+        }
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        reply: russh::server::ChannelOpenHandle,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        reply.accept().await;
+        Ok(())
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        id: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        match self.channels.remove(&id) {
+            Some(channel) if name == "sftp" => {
+                session.channel_success(id)?;
+                let sftp = SftpHandler {
+                    root: self.root.clone(),
+                    corrupt_writes: self.corrupt_writes,
+                    files: HashMap::new(),
+                    dirs: HashMap::new(),
+                    next_handle: 0,
+                };
+                russh_sftp::server::run(channel.into_stream(), sftp).await;
+            }
+            _ => session.channel_failure(id)?,
+        }
+
+        Ok(())
+    }
+}
+
+struct SftpHandler {
+    root: PathBuf,
+    corrupt_writes: bool,
+    files: HashMap<String, std::fs::File>,
+    /// Entries of open directories that are yet to be read
+    dirs: HashMap<String, Option<Vec<File>>>,
+    next_handle: u64,
+}
+
+fn status_code(e: std::io::Error) -> StatusCode {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => StatusCode::NoSuchFile,
+        std::io::ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+        _ => StatusCode::Failure,
+    }
+}
+
+fn ok(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: String::from("Ok"),
+        language_tag: String::from("en-US"),
+    }
+}
+
+impl SftpHandler {
+    fn local(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    fn handle(&mut self) -> String {
+        self.next_handle += 1;
+        self.next_handle.to_string()
+    }
+
+    fn attrs(&self, id: u32, path: &str) -> Result<Attrs, StatusCode> {
+        let metadata = std::fs::metadata(self.local(path)).map_err(status_code)?;
+
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&metadata),
+        })
+    }
+}
+
+impl russh_sftp::server::Handler for SftpHandler {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        _attrs: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        let file = std::fs::OpenOptions::from(pflags)
+            .open(self.local(&filename))
+            .map_err(status_code)?;
+        let handle = self.handle();
+        self.files.insert(handle.clone(), file);
+
+        Ok(Handle { id, handle })
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        self.files.remove(&handle);
+        self.dirs.remove(&handle);
+        Ok(ok(id))
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<Data, Self::Error> {
+        let file = self.files.get_mut(&handle).ok_or(StatusCode::Failure)?;
+        file.seek(SeekFrom::Start(offset)).map_err(status_code)?;
+
+        let mut data = vec![0; len as usize];
+        let n = file.read(&mut data).map_err(status_code)?;
+        if n == 0 {
+            return Err(StatusCode::Eof);
+        }
+        data.truncate(n);
+
+        Ok(Data { id, data })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        mut data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        if self.corrupt_writes {
+            data.iter_mut().for_each(|b| *b = !*b);
+        }
+
+        let file = self.files.get_mut(&handle).ok_or(StatusCode::Failure)?;
+        file.seek(SeekFrom::Start(offset)).map_err(status_code)?;
+        file.write_all(&data).map_err(status_code)?;
+
+        Ok(ok(id))
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        self.attrs(id, &path)
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        self.attrs(id, &path)
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let file = self.files.get(&handle).ok_or(StatusCode::Failure)?;
+        let metadata = file.metadata().map_err(status_code)?;
+
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&metadata),
+        })
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        std::fs::remove_file(self.local(&filename)).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn rename(
+        &mut self,
+        id: u32,
+        oldpath: String,
+        newpath: String,
+    ) -> Result<Status, Self::Error> {
+        // like OpenSSH, never replace an existing file
+        if self.local(&newpath).exists() {
+            return Err(StatusCode::Failure);
+        }
+
+        std::fs::rename(self.local(&oldpath), self.local(&newpath)).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        std::fs::create_dir(self.local(&path)).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        let files = std::fs::read_dir(self.local(&path))
+            .map_err(status_code)?
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                let name = entry.file_name().into_string().ok()?;
+                Some(File::new(name, FileAttributes::from(&metadata)))
+            })
+            .collect();
+
+        let handle = self.handle();
+        self.dirs.insert(handle.clone(), Some(files));
+
+        Ok(Handle { id, handle })
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        match self.dirs.get_mut(&handle).and_then(Option::take) {
+            Some(files) => Ok(Name { id, files }),
+            None => Err(StatusCode::Eof),
+        }
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let path = match path.as_str() {
+            "" | "." => String::from("/"),
+            path => format!("/{}", path.trim_start_matches('/')),
+        };
+
+        Ok(Name {
+            id,
+            files: vec![File::dummy(path)],
+        })
+    }
+}
@@ -0,0 +1,185 @@
+use futures_util::stream::{self, StreamExt};
+use quick_xml::events::Event;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use super::ByteStream;
+use crate::error::{Error, ErrorKind};
+
+/// The first error the body of a request yielded, if any; the HTTP client itself would only
+/// report that the request failed
+pub(super) type KeptError = Arc<Mutex<Option<Error>>>;
+
+/// Turns `body` into the body of a request, keeping its first error aside
+pub(super) fn request_body(body: ByteStream) -> (reqwest::Body, KeptError) {
+    let kept = KeptError::default();
+    let slot = kept.clone();
+
+    let body = body.map(move |chunk| {
+        chunk.map_err(|e| {
+            let value = e.value.clone();
+            slot.lock().unwrap().get_or_insert(e);
+            std::io::Error::other(value)
+        })
+    });
+
+    (reqwest::Body::wrap_stream(body), kept)
+}
+
+/// Sends `request`, failing with the error its body yielded if that is why it failed
+pub(super) async fn send(
+    request: reqwest::RequestBuilder,
+    kept: Option<&KeptError>,
+) -> Result<reqwest::Response, Error> {
+    request.send().await.map_err(|e| {
+        kept.and_then(|kept| kept.lock().unwrap().take())
+            .unwrap_or_else(|| Error::from(e))
+    })
+}
+
+/// Fails unless `res` is a success, with what the server said about it
+pub(super) async fn check(res: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = res.status();
+    let Some(kind) = ErrorKind::from_status(status.as_u16()) else {
+        return Ok(res);
+    };
+
+    let url = res.url().clone();
+    let body = res.text().await.unwrap_or_default();
+    let mut value = format!("{} answered {}", url, status);
+    if !body.is_empty() && body.len() <= 1024 {
+        value = format!("{}: {}", value, body.trim());
+    }
+
+    Err(Error { kind, value })
+}
+
+/// The `Content-Length` of `res`, which is also right for a response to `HEAD` (unlike
+/// `Response::content_length`)
+pub(super) fn content_length(res: &reqwest::Response) -> Option<u64> {
+    res.headers()
+        .get("Content-Length")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// The body of `res`, chunk by chunk
+pub(super) fn response_stream(res: reqwest::Response) -> ByteStream {
+    stream::unfold(Some(res), |res| async move {
+        let mut res = res?;
+
+        match res.chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk.to_vec()), Some(res))),
+            Ok(None) => None,
+            Err(e) => Some((Err(Error::from(e)), None)),
+        }
+    })
+    .boxed()
+}
+
+/// Reads every `record` element of `xml` (by local name, ignoring namespaces), collecting the text
+/// of the descendants named in `fields`. An empty field element (e.g., `<d:collection/>`) is
+/// collected as an empty string.
+pub(super) fn xml_records(
+    xml: &str,
+    record: &str,
+    fields: &[&str],
+) -> Result<Vec<Vec<Option<String>>>, Error> {
+    let malformed = |e: &dyn fmt::Display| Error {
+        kind: ErrorKind::MalformedResponse,
+        value: format!("bad xml: {}", e),
+    };
+
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut records = Vec::new();
+    let mut current: Option<Vec<Option<String>>> = None;
+    let mut field: Option<usize> = None;
+
+    loop {
+        let event = reader.read_event().map_err(|e| malformed(&e))?;
+
+        match event {
+            Event::Eof => break,
+            Event::Start(e) if e.local_name().as_ref() == record => {
+                current = Some(vec![None; fields.len()]);
+            }
+            Event::Start(e) => {
+                field = fields.iter().position(|f| *f == e.local_name().as_ref());
+                if let (Some(i), Some(values)) = (field, &mut current) {
+                    values[i].get_or_insert_with(String::new);
+                }
+            }
+            Event::Empty(e) => {
+                let i = fields.iter().position(|f| *f == e.local_name().as_ref());
+                if let (Some(i), Some(values)) = (i, &mut current) {
+                    values[i].get_or_insert_with(String::new);
+                }
+            }
+            Event::Text(text) => {
+                if let (Some(i), Some(values)) = (field, &mut current) {
+                    values[i].get_or_insert_with(String::new).push_str(&text);
+                }
+            }
+            Event::GeneralRef(r) => {
+                let c = match r.resolve_char_ref().map_err(|e| malformed(&e))? {
+                    Some(c) => c,
+                    None => match &*r {
+                        "amp" => '&',
+                        "lt" => '<',
+                        "gt" => '>',
+                        "quot" => '"',
+                        "apos" => '\'',
+                        other => return Err(malformed(&format!("unknown entity &{};", other))),
+                    },
+                };
+                if let (Some(i), Some(values)) = (field, &mut current) {
+                    values[i].get_or_insert_with(String::new).push(c);
+                }
+            }
+            Event::End(e) => {
+                if e.local_name().as_ref() == record {
+                    records.extend(current.take());
+                }
+                field = None;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xml_records() {
+        let xml = r#"<?xml version="1.0"?>
+            <d:multistatus xmlns:d="DAV:">
+              <d:response><d:href>/music/</d:href>
+                <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>
+                </d:propstat></d:response>
+              <d:response><d:href>/music/a&amp;b.mp3</d:href>
+                <d:propstat><d:prop><d:getcontentlength>42</d:getcontentlength>
+                <d:resourcetype/></d:prop></d:propstat></d:response>
+            </d:multistatus>"#;
+
+        let records =
+            xml_records(xml, "response", &["href", "getcontentlength", "collection"]).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                vec![Some(String::from("/music/")), None, Some(String::new())],
+                vec![
+                    Some(String::from("/music/a&b.mp3")),
+                    Some(String::from("42")),
+                    None
+                ],
+            ]
+        );
+    }
+}
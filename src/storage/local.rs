@@ -0,0 +1,124 @@
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+
+use super::{read_stream, write_stream, ByteStream, Stat, Storage};
+use crate::error::Error;
+
+/// A local directory
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: PathBuf) -> Self {
+        LocalStorage { dir }
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    fn location(&self, name: &str) -> String {
+        self.dir.join(name).display().to_string()
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        Some(&self.dir)
+    }
+
+    async fn stat(&self, name: &str) -> Result<Option<Stat>, Error> {
+        match tokio::fs::metadata(self.dir.join(name)).await {
+            Ok(m) if m.is_file() => Ok(Some(Stat {
+                name: name.to_string(),
+                size: m.len(),
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get(&self, name: &str) -> Result<ByteStream, Error> {
+        Ok(read_stream(
+            tokio::fs::File::open(self.dir.join(name)).await?,
+        ))
+    }
+
+    async fn put(&self, name: &str, body: ByteStream, _: Option<u64>) -> Result<u64, Error> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        // hidden, and not `<name>.part`, which is where downloads of `<id>.mp3` are resumed from
+        let path = self.dir.join(name);
+        let part = self.dir.join(format!(".{}.upload.tmp", name));
+
+        let mut file = tokio::fs::File::create(&part).await?;
+        let written = match write_stream(body, &mut file).await {
+            Ok(written) => file.sync_all().await.map(|()| written).map_err(Error::from),
+            Err(e) => Err(e),
+        };
+
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&part).await;
+        }
+        let written = written?;
+
+        tokio::fs::rename(&part, &path).await?;
+
+        Ok(written)
+    }
+
+    async fn list(&self) -> Result<Vec<Stat>, Error> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut files = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if let (true, Some(name)) = (metadata.is_file(), entry.file_name().to_str()) {
+                files.push(Stat {
+                    name: name.to_string(),
+                    size: metadata.len(),
+                });
+            }
+        }
+
+        Ok(files)
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), Error> {
+        Ok(tokio::fs::remove_file(self.dir.join(name)).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::exercise;
+    use futures_util::stream::{self, StreamExt};
+
+    #[tokio::test]
+    async fn test_local_storage() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // the directory is created by the first `put`
+        exercise(&LocalStorage::new(dir.path().join("music"))).await;
+    }
+
+    #[tokio::test]
+    async fn test_put_leaves_part_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.mp3.part"), b"partial").unwrap();
+
+        let storage = LocalStorage::new(dir.path().to_path_buf());
+        let body = stream::iter([Ok(b"whole".to_vec())]).boxed();
+        assert_eq!(storage.put("a.mp3", body, Some(5)).await.unwrap(), 5);
+
+        assert_eq!(std::fs::read(dir.path().join("a.mp3")).unwrap(), b"whole");
+        assert_eq!(
+            std::fs::read(dir.path().join("a.mp3.part")).unwrap(),
+            b"partial"
+        );
+        assert!(!dir.path().join(".a.mp3.upload.tmp").exists());
+    }
+}
@@ -0,0 +1,140 @@
+use async_trait::async_trait;
+use russh_sftp::client::error::Error as SftpError;
+use russh_sftp::protocol::StatusCode;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::OnceCell;
+
+use super::{read_stream, write_stream, ByteStream, Stat, Storage};
+use crate::error::Error;
+use crate::ssh::{Remote, Sftp, SshConfig};
+
+/// A directory on a remote host, reached over SFTP. The connection is made on first use and then
+/// shared by every operation.
+pub struct SftpStorage {
+    remote: Remote,
+    ssh: SshConfig,
+    sftp: OnceCell<Sftp>,
+}
+
+impl SftpStorage {
+    pub fn new(remote: Remote, ssh: SshConfig) -> Self {
+        SftpStorage {
+            remote,
+            ssh,
+            sftp: OnceCell::new(),
+        }
+    }
+
+    async fn sftp(&self) -> Result<&Sftp, Error> {
+        self.sftp
+            .get_or_try_init(|| Sftp::connect(&self.remote, &self.ssh))
+            .await
+    }
+}
+
+fn no_such_file(e: &SftpError) -> bool {
+    matches!(e, SftpError::Status(status) if status.status_code == StatusCode::NoSuchFile)
+}
+
+#[async_trait]
+impl Storage for SftpStorage {
+    fn location(&self, name: &str) -> String {
+        Remote {
+            path: self.remote.join(name),
+            ..self.remote.clone()
+        }
+        .to_string()
+    }
+
+    async fn stat(&self, name: &str) -> Result<Option<Stat>, Error> {
+        let sftp = self.sftp().await?;
+
+        match sftp.sftp.metadata(self.remote.join(name)).await {
+            Ok(m) if !m.file_type().is_dir() => Ok(Some(Stat {
+                name: name.to_string(),
+                size: m.len(),
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if no_such_file(&e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get(&self, name: &str) -> Result<ByteStream, Error> {
+        let sftp = self.sftp().await?;
+
+        Ok(read_stream(sftp.sftp.open(self.remote.join(name)).await?))
+    }
+
+    async fn put(&self, name: &str, body: ByteStream, _: Option<u64>) -> Result<u64, Error> {
+        let sftp = self.sftp().await?;
+        let (path, part) = (
+            self.remote.join(name),
+            self.remote.join(&format!("{}.part", name)),
+        );
+
+        let mut file = sftp.sftp.create(part.as_str()).await?;
+        let written = write_stream(body, &mut file).await;
+
+        // closes the remote file whether or not the body was complete
+        let closed = file.shutdown().await;
+
+        let written = written.and_then(|written| Ok(closed.map(|()| written)?));
+        if written.is_err() {
+            let _ = sftp.sftp.remove_file(part.as_str()).await;
+        }
+        let written = written?;
+
+        // SFTP servers commonly refuse to rename over an existing file
+        if sftp.sftp.try_exists(path.as_str()).await? {
+            sftp.sftp.remove_file(path.as_str()).await?;
+        }
+        sftp.sftp.rename(part, path).await?;
+
+        Ok(written)
+    }
+
+    async fn list(&self) -> Result<Vec<Stat>, Error> {
+        let sftp = self.sftp().await?;
+
+        let entries = match sftp.sftp.read_dir(self.remote.path.as_str()).await {
+            Ok(entries) => entries,
+            Err(e) if no_such_file(&e) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(entries
+            .filter(|entry| !entry.file_type().is_dir())
+            .map(|entry| Stat {
+                name: entry.file_name(),
+                size: entry.metadata().len(),
+            })
+            .collect())
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), Error> {
+        let sftp = self.sftp().await?;
+
+        Ok(sftp.sftp.remove_file(self.remote.join(name)).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::server::spawn;
+    use crate::storage::tests::exercise;
+
+    #[tokio::test]
+    async fn test_sftp_storage() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("music")).unwrap();
+        let server = spawn(root.path(), false).await;
+
+        exercise(&SftpStorage::new(
+            server.remote("/music"),
+            server.config.clone(),
+        ))
+        .await;
+    }
+}
@@ -0,0 +1,547 @@
+use futures_util::StreamExt;
+use std::fmt;
+
+use crate::error::Error;
+use crate::id3::id3v2_len;
+use crate::migrate::{copy, free_name, hash, library};
+use crate::mp3::mp3_kbps;
+use crate::ssh::SshConfig;
+use crate::storage::{Location, Storage};
+
+/// Most bytes read from the start of a file to find its bit rate
+const MAX_HEAD_LEN: usize = 1 << 20;
+
+/// What `sync` does besides copying to `to` the files only `from` has
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SyncOptions {
+    /// Also copy to `from` the files only `to` has
+    pub both_ways: bool,
+    /// Delete from `to` the files `from` does not have, so that it ends up the same as `from`
+    pub mirror: bool,
+    /// Only work out what would be done
+    pub dry_run: bool,
+}
+
+/// Which of the two libraries
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    From,
+    To,
+}
+
+/// One change to make to a library
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Copy the MP3 file of `id`, called `name`, to the `to` side from the other one, as `to_name`
+    /// (`name`, unless the `to` side has another file of that name; see `free_name`)
+    Copy {
+        id: String,
+        name: String,
+        to_name: String,
+        to: Side,
+        size: u64,
+    },
+    /// Delete the MP3 file of `id`, called `name`, from the `to` side (mirror mode)
+    Delete { id: String, name: String, size: u64 },
+}
+
+/// What one side holds for an ID both libraries have
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    pub size: u64,
+    /// SHA-256 of the file; only worked out when both sides have the same size
+    pub sha256: Option<String>,
+    /// Bit rate in kb/s, if the file starts with a recognizable MP3 frame
+    pub kbps: Option<u32>,
+}
+
+/// The same ID holds different files on the two sides; neither is touched
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub id: String,
+    pub from: Version,
+    pub to: Version,
+}
+
+/// What `sync` found and what it does (or, with `dry_run`, would do) about it
+#[derive(Default)]
+pub struct SyncReport {
+    /// Where the files are, for messages
+    pub from: String,
+    pub to: String,
+    pub actions: Vec<Action>,
+    pub conflicts: Vec<Conflict>,
+    /// Number of IDs both libraries hold the same file for
+    pub in_sync: usize,
+    /// Actions that were tried and failed
+    pub failed: Vec<(Action, Error)>,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kbps {
+            Some(kbps) => write!(f, "{} kb/s, ", kbps)?,
+            None => write!(f, "unknown bitrate, ")?,
+        }
+        write!(f, "{} bytes", self.size)?;
+        if let Some(sha256) = &self.sha256 {
+            write!(f, ", sha256 {}", sha256)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Bit rate of the file called `name`, reading no more of it than needed
+async fn kbps(storage: &dyn Storage, name: &str) -> Result<Option<u32>, Error> {
+    let mut body = storage.get(name).await?;
+    let mut head = Vec::new();
+
+    while let Some(chunk) = body.next().await {
+        head.extend(chunk?);
+
+        // the first frame header is right after the tag, if not a few bytes later
+        if head.len() >= id3v2_len(&head) + 4 {
+            if let Some(kbps) = mp3_kbps(&head) {
+                return Ok(Some(kbps));
+            }
+        }
+        if head.len() >= MAX_HEAD_LEN {
+            break;
+        }
+    }
+
+    Ok(mp3_kbps(&head))
+}
+
+impl SyncReport {
+    fn location(&self, side: Side, name: &str) -> String {
+        let dir = match side {
+            Side::From => &self.from,
+            Side::To => &self.to,
+        };
+        format!("{}/{}", dir.trim_end_matches('/'), name)
+    }
+
+    /// One line describing `action`
+    pub fn describe(&self, action: &Action) -> String {
+        match action {
+            Action::Copy {
+                name,
+                to_name,
+                to,
+                size,
+                ..
+            } => {
+                let from = match to {
+                    Side::From => Side::To,
+                    Side::To => Side::From,
+                };
+                format!(
+                    "copy {} to {} ({} bytes)",
+                    self.location(from, name),
+                    self.location(*to, to_name),
+                    size
+                )
+            }
+            Action::Delete { name, size, .. } => {
+                format!("delete {} ({} bytes)", self.location(Side::To, name), size)
+            }
+        }
+    }
+}
+
+/// Compares the libraries at `from` and `to` by YouTube ID (from the names of their MP3 files, or
+/// else their tags), then copies the MP3 files only `from` has into `to` under the same name (or
+/// see `free_name`: no file is replaced), checking every copy as `migrate` does. IDs both sides hold different files for (by size, then
+/// SHA-256) are reported as conflicts and left alone. See `SyncOptions` for the rest.
+///
+/// A failed copy or deletion does not stop the others; it is reported in `SyncReport::failed`.
+pub async fn sync(
+    from: &Location,
+    to: &Location,
+    ssh: &SshConfig,
+    options: SyncOptions,
+) -> Result<SyncReport, Error> {
+    if from == to {
+        return Err(Error::from(format!(
+            "{} is both the source and the destination",
+            from
+        )));
+    }
+
+    let (src, dst) = (from.open(ssh)?, to.open(ssh)?);
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    let mut report = SyncReport {
+        from: from.to_string(),
+        to: to.to_string(),
+        ..Default::default()
+    };
+
+    let (ours, theirs) = (library(src).await?, library(dst).await?);
+
+    for (id, stat) in &ours {
+        let Some(other) = theirs.get(id) else {
+            report.actions.push(Action::Copy {
+                id: id.clone(),
+                name: stat.name.clone(),
+                to_name: free_name(dst, &stat.name, id).await?,
+                to: Side::To,
+                size: stat.size,
+            });
+            continue;
+        };
+
+        let (mut from_version, mut to_version) = (
+            Version {
+                size: stat.size,
+                sha256: None,
+                kbps: None,
+            },
+            Version {
+                size: other.size,
+                sha256: None,
+                kbps: None,
+            },
+        );
+
+        if stat.size == other.size {
+            let (_, ours) = hash(src, &stat.name).await?;
+            let (_, theirs) = hash(dst, &other.name).await?;
+            if ours == theirs {
+                report.in_sync += 1;
+                continue;
+            }
+
+            from_version.sha256 = Some(ours);
+            to_version.sha256 = Some(theirs);
+        }
+
+        from_version.kbps = kbps(src, &stat.name).await?;
+        to_version.kbps = kbps(dst, &other.name).await?;
+        report.conflicts.push(Conflict {
+            id: id.clone(),
+            from: from_version,
+            to: to_version,
+        });
+    }
+
+    for (id, stat) in theirs.iter().filter(|(id, _)| !ours.contains_key(*id)) {
+        let (id, name, size) = (id.clone(), stat.name.clone(), stat.size);
+
+        if options.both_ways {
+            report.actions.push(Action::Copy {
+                to_name: free_name(src, &name, &id).await?,
+                id,
+                name,
+                to: Side::From,
+                size,
+            });
+        } else if options.mirror {
+            report.actions.push(Action::Delete { id, name, size });
+        }
+    }
+
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    for action in report.actions.clone() {
+        eprintln!("info: {}", report.describe(&action));
+
+        let done = match &action {
+            Action::Copy {
+                name,
+                to_name,
+                to: Side::To,
+                ..
+            } => copy(src, dst, name, to_name).await.map(drop),
+            Action::Copy {
+                name,
+                to_name,
+                to: Side::From,
+                ..
+            } => copy(dst, src, name, to_name).await.map(drop),
+            Action::Delete { name, .. } => dst.delete(name).await,
+        };
+
+        if let Err(e) = done {
+            report.failed.push((action, e));
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::id3::{replace_tag, Frame, Tag};
+    use crate::mock::silent_mp3;
+    use std::path::Path;
+
+    const A: &str = "yPvoKz6tyJs";
+    const B: &str = "dQw4w9WgXcQ";
+    const C: &str = "9bZkp7q19f0";
+    const D: &str = "kJQP7kiw5Fk";
+
+    fn mp3_name(id: &str) -> String {
+        format!("{}.mp3", id)
+    }
+
+    /// `mp3` tagged with YouTube ID `id`, as photon tags the files it names after a template
+    pub(crate) fn tagged(id: &str, mp3: &[u8]) -> Vec<u8> {
+        let mut tag = Tag::default();
+        tag.set(Frame::UserText {
+            description: String::from("YOUTUBE_ID"),
+            value: id.to_string(),
+        });
+        replace_tag(mp3, &tag.to_bytes(Default::default()))
+    }
+
+    /// `frames` silent 320 kb/s frames
+    fn mp3_320(frames: usize) -> Vec<u8> {
+        // 144 * 320000 / 44100 bytes per frame, no padding
+        let mut mp3 = Vec::new();
+        for _ in 0..frames {
+            mp3.extend_from_slice(&[0xFF, 0xFB, 0xE0, 0x00]);
+            mp3.resize(mp3.len() + 1044 - 4, 0);
+        }
+        mp3
+    }
+
+    fn write(dir: &Path, id: &str, mp3: &[u8]) {
+        std::fs::write(dir.join(mp3_name(id)), mp3).unwrap();
+    }
+
+    fn ids(dir: &Path) -> Vec<String> {
+        let mut ids: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    fn names(ids: &[&str]) -> Vec<String> {
+        let mut names: Vec<String> = ids.iter().map(|id| mp3_name(id)).collect();
+        names.sort();
+        names
+    }
+
+    /// `from` holds A, B, C and a stray file, `to` holds the same B, another C and D
+    fn libraries() -> (tempfile::TempDir, tempfile::TempDir, Location, Location) {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+
+        write(from.path(), A, &silent_mp3(3));
+        write(from.path(), B, &silent_mp3(4));
+        write(from.path(), C, &silent_mp3(5));
+        std::fs::write(from.path().join("notes.txt"), "not a song").unwrap();
+        write(to.path(), B, &silent_mp3(4));
+        write(to.path(), C, &mp3_320(2));
+        write(to.path(), D, &silent_mp3(6));
+
+        let locations = (
+            Location::Local(from.path().to_path_buf()),
+            Location::Local(to.path().to_path_buf()),
+        );
+        (from, to, locations.0, locations.1)
+    }
+
+    #[tokio::test]
+    async fn test_sync_one_way() {
+        let (from, to, src, dst) = libraries();
+        let ssh = SshConfig::default();
+
+        let dry_run = SyncOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = sync(&src, &dst, &ssh, dry_run).await.unwrap();
+        assert_eq!(
+            report.actions,
+            vec![Action::Copy {
+                id: A.to_string(),
+                name: mp3_name(A),
+                to_name: mp3_name(A),
+                to: Side::To,
+                size: silent_mp3(3).len() as u64
+            }]
+        );
+        assert_eq!(report.in_sync, 1);
+        assert_eq!(
+            report.conflicts,
+            vec![Conflict {
+                id: C.to_string(),
+                from: Version {
+                    size: silent_mp3(5).len() as u64,
+                    sha256: None,
+                    kbps: Some(128)
+                },
+                to: Version {
+                    size: mp3_320(2).len() as u64,
+                    sha256: None,
+                    kbps: Some(320)
+                },
+            }]
+        );
+        assert_eq!(ids(to.path()).len(), 3);
+
+        let report = sync(&src, &dst, &ssh, SyncOptions::default())
+            .await
+            .unwrap();
+        assert!(report.failed.is_empty());
+        assert_eq!(
+            std::fs::read(to.path().join(mp3_name(A))).unwrap(),
+            silent_mp3(3)
+        );
+        // neither side of a conflict is overwritten, and extras stay without `mirror`
+        assert_eq!(
+            std::fs::read(to.path().join(mp3_name(C))).unwrap(),
+            mp3_320(2)
+        );
+        assert!(to.path().join(mp3_name(D)).exists());
+        assert_eq!(ids(from.path()).len(), 4);
+
+        let report = sync(&src, &dst, &ssh, SyncOptions::default())
+            .await
+            .unwrap();
+        assert!(report.actions.is_empty());
+        assert_eq!(report.in_sync, 2);
+    }
+
+    #[tokio::test]
+    async fn test_sync_both_ways_and_mirror() {
+        let ssh = SshConfig::default();
+
+        let (from, to, src, dst) = libraries();
+        let both_ways = SyncOptions {
+            both_ways: true,
+            ..Default::default()
+        };
+        let report = sync(&src, &dst, &ssh, both_ways).await.unwrap();
+        assert_eq!(report.actions.len(), 2);
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(ids(to.path()), names(&[A, B, C, D]));
+        assert_eq!(
+            std::fs::read(from.path().join(mp3_name(D))).unwrap(),
+            silent_mp3(6)
+        );
+
+        let (from, to, src, dst) = libraries();
+        let mirror = SyncOptions {
+            mirror: true,
+            ..Default::default()
+        };
+        let report = sync(&src, &dst, &ssh, mirror).await.unwrap();
+        assert_eq!(
+            report.describe(&report.actions[1]),
+            format!(
+                "delete {}/{}.mp3 ({} bytes)",
+                to.path().display(),
+                D,
+                silent_mp3(6).len()
+            )
+        );
+        assert_eq!(ids(to.path()), names(&[A, B, C]));
+        assert_eq!(ids(from.path()).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_sync_templated_names() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (src, dst) = (
+            Location::Local(from.path().to_path_buf()),
+            Location::Local(to.path().to_path_buf()),
+        );
+        let ssh = SshConfig::default();
+
+        let one_more_time = tagged(A, &silent_mp3(3));
+        std::fs::write(
+            from.path().join("Daft Punk - One More Time.mp3"),
+            &one_more_time,
+        )
+        .unwrap();
+        std::fs::write(
+            from.path().join(format!("Mixtape [{}].mp3", B)),
+            silent_mp3(4),
+        )
+        .unwrap();
+        std::fs::write(from.path().join("Untagged.mp3"), silent_mp3(5)).unwrap();
+        // the same video under another name is the same file
+        write(to.path(), B, &silent_mp3(4));
+
+        let report = sync(&src, &dst, &ssh, SyncOptions::default())
+            .await
+            .unwrap();
+        assert!(report.failed.is_empty());
+        assert_eq!(
+            report.actions,
+            vec![Action::Copy {
+                id: A.to_string(),
+                name: String::from("Daft Punk - One More Time.mp3"),
+                to_name: String::from("Daft Punk - One More Time.mp3"),
+                to: Side::To,
+                size: one_more_time.len() as u64
+            }]
+        );
+        assert_eq!(report.in_sync, 1);
+        assert_eq!(
+            std::fs::read(to.path().join("Daft Punk - One More Time.mp3")).unwrap(),
+            one_more_time
+        );
+
+        let report = sync(&src, &dst, &ssh, SyncOptions::default())
+            .await
+            .unwrap();
+        assert!(report.actions.is_empty());
+        assert_eq!(report.in_sync, 2);
+    }
+
+    #[tokio::test]
+    async fn test_sync_name_of_another_video() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (src, dst) = (
+            Location::Local(from.path().to_path_buf()),
+            Location::Local(to.path().to_path_buf()),
+        );
+        let ssh = SshConfig::default();
+
+        let (ours, theirs) = (tagged(A, &silent_mp3(3)), tagged(B, &silent_mp3(4)));
+        std::fs::write(from.path().join("Intro.mp3"), &ours).unwrap();
+        std::fs::write(to.path().join("Intro.mp3"), &theirs).unwrap();
+
+        let both_ways = SyncOptions {
+            both_ways: true,
+            ..Default::default()
+        };
+        let report = sync(&src, &dst, &ssh, both_ways).await.unwrap();
+        assert!(report.failed.is_empty());
+        assert_eq!(
+            report.describe(&report.actions[0]),
+            format!(
+                "copy {}/Intro.mp3 to {}/Intro [{}].mp3 ({} bytes)",
+                from.path().display(),
+                to.path().display(),
+                A,
+                ours.len()
+            )
+        );
+
+        // each side keeps its own file and gets the other one under a name of its own
+        assert_eq!(std::fs::read(to.path().join("Intro.mp3")).unwrap(), theirs);
+        assert_eq!(
+            std::fs::read(to.path().join(format!("Intro [{}].mp3", A))).unwrap(),
+            ours
+        );
+        assert_eq!(std::fs::read(from.path().join("Intro.mp3")).unwrap(), ours);
+        assert_eq!(
+            std::fs::read(from.path().join(format!("Intro [{}].mp3", B))).unwrap(),
+            theirs
+        );
+
+        let report = sync(&src, &dst, &ssh, both_ways).await.unwrap();
+        assert!(report.actions.is_empty());
+        assert_eq!(report.in_sync, 2);
+    }
+}
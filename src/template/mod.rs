@@ -0,0 +1,256 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::bitrate::BitRate;
+use crate::error::{Error, ErrorKind};
+use crate::titles::ParsedTitle;
+
+/// The template photon names MP3 files with unless told otherwise
+pub const DEFAULT_TEMPLATE: &str = "{id}.mp3";
+
+/// Longest file name (in bytes) most filesystems accept
+const MAX_NAME_LEN: usize = 255;
+
+/// Names Windows (and so FAT and exFAT drives used by it) reserves for devices
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// A value a template can use
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Id,
+    Title,
+    Artist,
+    Kbps,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+/// What an MP3 file is named after
+#[derive(Clone, Debug)]
+pub struct Metadata<'a> {
+    pub id: &'a str,
+    /// What the title of the video tells, unless it is not known yet
+    pub title: Option<&'a ParsedTitle>,
+    pub bitrate: BitRate,
+}
+
+/// A file name template such as `{artist} - {title} [{id}] {kbps}k.mp3`, where `{id}` is the
+/// YouTube ID, `{artist}` (with featured artists) and `{title}` (with the remix) come from the
+/// title of the video (see `TitleRules`), and `{kbps}` is the bitrate asked for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE
+            .parse()
+            .expect("DEFAULT_TEMPLATE should parse")
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |why: String| Error {
+            kind: ErrorKind::Error,
+            value: format!("`{}` is not a file name template: {}", s, why),
+        };
+
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                return Err(invalid(String::from("`}` without `{`")));
+            }
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| invalid(String::from("`{` without `}`")))?;
+            let field = match &rest[start + 1..start + end] {
+                "id" => Field::Id,
+                "title" => Field::Title,
+                "artist" => Field::Artist,
+                "kbps" => Field::Kbps,
+                other => return Err(invalid(format!("unknown field `{{{}}}`", other))),
+            };
+            parts.push(Part::Field(field));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+
+        if !s.to_lowercase().ends_with(".mp3") {
+            return Err(invalid(String::from("it must end with `.mp3`")));
+        }
+
+        Ok(Template {
+            source: s.to_string(),
+            parts,
+        })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// `name` made into a file name every common filesystem (ext4, APFS, NTFS, FAT32, exFAT) accepts:
+/// characters they forbid become `_`, runs of whitespace become one space, trailing dots and
+/// spaces go, reserved device names get a `_` in front, and long names are cut to 255 bytes while
+/// keeping the extension
+pub fn sanitize(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            c if c.is_whitespace() => {
+                if !out.ends_with(' ') {
+                    out.push(' ');
+                }
+            }
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => out.push('_'),
+            c if c.is_control() => out.push('_'),
+            c => out.push(c),
+        }
+    }
+
+    let (stem, extension) = match out.rfind('.') {
+        Some(dot) => out.split_at(dot),
+        None => (out.as_str(), ""),
+    };
+    let mut stem = stem.trim().trim_end_matches('.').trim_end().to_string();
+    if stem.is_empty() {
+        stem.push('_');
+    }
+    if RESERVED_NAMES.contains(&stem.to_uppercase().as_str()) {
+        stem.insert(0, '_');
+    }
+
+    while stem.len() + extension.len() > MAX_NAME_LEN {
+        stem.pop();
+    }
+
+    format!("{}{}", stem.trim_end(), extension)
+}
+
+/// `name` with ` [<id>]` put before its extension, to tell it from a file of another video
+pub fn disambiguate(name: &str, youtube_id: &str) -> String {
+    let (stem, extension) = name.split_at(name.rfind('.').unwrap_or(name.len()));
+
+    sanitize(&format!("{} [{}]{}", stem, youtube_id, extension))
+}
+
+impl Template {
+    /// Whether names from this template hold the YouTube ID, so that two videos cannot get the
+    /// same one
+    pub fn has_id(&self) -> bool {
+        self.parts.contains(&Part::Field(Field::Id))
+    }
+
+    /// The (sanitized) name of the MP3 file described by `metadata`, or `None` if the template
+    /// uses the title and it is not known yet
+    pub fn render(&self, metadata: &Metadata) -> Option<String> {
+        let needs_title = self
+            .parts
+            .iter()
+            .any(|part| matches!(part, Part::Field(Field::Title) | Part::Field(Field::Artist)));
+        if needs_title && metadata.title.is_none() {
+            return None;
+        }
+
+        let artist = metadata.title.and_then(ParsedTitle::artist_credit);
+        let title = metadata.title.map(ParsedTitle::full_title);
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => name.push_str(text),
+                Part::Field(Field::Id) => name.push_str(metadata.id),
+                Part::Field(Field::Title) => name.push_str(title.as_deref().unwrap_or("")),
+                Part::Field(Field::Artist) => {
+                    name.push_str(artist.as_deref().unwrap_or("Unknown Artist"))
+                }
+                Part::Field(Field::Kbps) => name.push_str(&metadata.bitrate.kbps().to_string()),
+            }
+        }
+
+        Some(sanitize(&name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::titles::TitleRules;
+
+    #[test]
+    fn test_template() {
+        let template: Template = "{artist} - {title} [{id}] {kbps}k.mp3".parse().unwrap();
+        let rules = TitleRules::default();
+        let parsed = rules.parse(
+            None,
+            "Daft Punk ft. Romanthony - One More Time (Radio Edit) [HD]",
+        );
+        let mut metadata = Metadata {
+            id: "yPvoKz6tyJs",
+            title: Some(&parsed),
+            bitrate: BitRate::Kbps320,
+        };
+        assert!(template.has_id());
+        assert_eq!(
+            template.render(&metadata).as_deref(),
+            Some("Daft Punk feat. Romanthony - One More Time (Radio Edit) [yPvoKz6tyJs] 320k.mp3")
+        );
+
+        let parsed = rules.parse(None, "AC/DC: Live?");
+        metadata.title = Some(&parsed);
+        assert_eq!(
+            template.render(&metadata).as_deref(),
+            Some("Unknown Artist - AC_DC_ Live_ [yPvoKz6tyJs] 320k.mp3")
+        );
+
+        metadata.title = None;
+        assert_eq!(template.render(&metadata), None);
+        assert_eq!(
+            Template::default().render(&metadata).as_deref(),
+            Some("yPvoKz6tyJs.mp3")
+        );
+
+        for bad in ["{id}", "{id}.mp3}", "{id.mp3", "{album}.mp3", "title.wav"] {
+            assert!(bad.parse::<Template>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("a\tb  c\n.mp3"), "a b c.mp3");
+        assert_eq!(sanitize("a\u{7}b.mp3"), "a_b.mp3");
+        assert_eq!(sanitize("Track... .mp3"), "Track.mp3");
+        assert_eq!(sanitize("con.mp3"), "_con.mp3");
+        assert_eq!(sanitize(".mp3"), "_.mp3");
+
+        let long = sanitize(&format!("{}.mp3", "é".repeat(200)));
+        assert_eq!(long.len(), 254);
+        assert!(long.ends_with("é.mp3"));
+
+        assert_eq!(
+            disambiguate("One More Time.mp3", "yPvoKz6tyJs"),
+            "One More Time [yPvoKz6tyJs].mp3"
+        );
+    }
+}
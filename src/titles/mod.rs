@@ -0,0 +1,367 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+use crate::error::{Error, ErrorKind};
+use crate::youtube_url::is_youtube_id;
+
+/// The rules photon uses unless given a rules file (see the comments in it for the format)
+pub const DEFAULT_RULES: &str = include_str!("rules.txt");
+
+/// Separators between artist and track, as in `Artist - Track`
+const SEPARATORS: [&str; 4] = [" - ", " – ", " — ", " -- "];
+
+/// Words that make a bracketed part of a title the name of a remix, edit or version
+const VERSION_WORDS: &str = "remix|mix|edit|version|rework|bootleg|dub|vip|remaster|remastered|\
+                             flip|cover|instrumental|acapella|live|extended|remake";
+
+/// A bracketed part of a title, as in `(Extended Mix)` or `[Label Release]`
+static BRACKETED: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\s*[(\[]([^()\[\]]*)[)\]]").unwrap());
+
+/// A bracketed part naming featured artists
+static FEATURING: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)^(?:feat\.?|ft\.?|featuring)\s+(.+)$").unwrap());
+
+/// Featured artists after the artist or the title, without brackets
+static INLINE_FEATURING: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\s+(?:feat\.?|ft\.?|featuring)\s+").unwrap());
+
+/// A name of a remix, edit or version
+static VERSION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(&format!(r"(?i)\b(?:{})\b", VERSION_WORDS)).unwrap());
+
+/// A bracketed part naming the label
+static LABEL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^(.+?)(?:\s+release|(\s+(?:records|recordings|music)))$").unwrap()
+});
+
+/// What a YouTube video title tells about the track, such as `Artist feat. Guest - Track
+/// (Extended Mix) [Label Release]`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ParsedTitle {
+    pub artist: Option<String>,
+    /// Featured artists (`feat.`, `ft.` or `featuring`)
+    #[serde(default)]
+    pub featuring: Vec<String>,
+    /// The track, without its remix, featured artists or label
+    pub title: String,
+    /// Name of the remix, edit or version, such as `Extended Mix`
+    #[serde(default)]
+    pub remix: Option<String>,
+    /// Label that released the track, from a tag like `[Monstercat Release]`
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+impl ParsedTitle {
+    /// The artist with the featured ones, as in `Artist feat. Guest, Other`
+    pub fn artist_credit(&self) -> Option<String> {
+        let artist = self.artist.as_ref()?;
+
+        Some(match self.featuring.is_empty() {
+            true => artist.clone(),
+            false => format!("{} feat. {}", artist, self.featuring.join(", ")),
+        })
+    }
+
+    /// The track with its remix, as in `Track (Extended Mix)`
+    pub fn full_title(&self) -> String {
+        match &self.remix {
+            Some(remix) => format!("{} ({})", self.title, remix),
+            None => self.title.clone(),
+        }
+    }
+}
+
+/// A rule cleaning video titles: every match of `pattern` is replaced by `replacement`
+#[derive(Clone, Debug)]
+struct Rule {
+    pattern: Regex,
+    replacement: String,
+}
+
+/// A field of one track set by its YouTube ID
+#[derive(Clone, Debug)]
+struct Override {
+    youtube_id: String,
+    field: Field,
+    value: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Artist,
+    Title,
+    Featuring,
+    Remix,
+    Label,
+}
+
+/// How video titles are parsed: rules that clean them (e.g., of `[Official Video]`), and fields
+/// of single tracks set by YouTube ID, read from a rules file like `DEFAULT_RULES`
+#[derive(Clone, Debug)]
+pub struct TitleRules {
+    rules: Vec<Rule>,
+    overrides: Vec<Override>,
+}
+
+impl Default for TitleRules {
+    fn default() -> Self {
+        DEFAULT_RULES.parse().expect("DEFAULT_RULES should parse")
+    }
+}
+
+impl FromStr for TitleRules {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut title_rules = TitleRules {
+            rules: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        for (i, line) in s.lines().enumerate() {
+            let invalid = |why: String| Error {
+                kind: ErrorKind::Error,
+                value: format!("line {} of the title rules: {}", i + 1, why),
+            };
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some((pattern, replacement)) = line.split_once(" =>") {
+                let pattern = Regex::new(pattern.trim_end()).map_err(|e| invalid(e.to_string()))?;
+                title_rules.rules.push(Rule {
+                    pattern,
+                    replacement: replacement
+                        .strip_prefix(' ')
+                        .unwrap_or(replacement)
+                        .to_string(),
+                });
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(String::from("expected `<regex> => <replacement>`")))?;
+            let (youtube_id, field) = key.trim().split_once(' ').ok_or_else(|| {
+                invalid(String::from("expected `<youtube id> <field> = <value>`"))
+            })?;
+            if !is_youtube_id(youtube_id) {
+                return Err(invalid(format!("`{}` is not a YouTube ID", youtube_id)));
+            }
+            let field = match field.trim() {
+                "artist" => Field::Artist,
+                "title" => Field::Title,
+                "featuring" => Field::Featuring,
+                "remix" => Field::Remix,
+                "label" => Field::Label,
+                other => return Err(invalid(format!("unknown field `{}`", other))),
+            };
+
+            title_rules.overrides.push(Override {
+                youtube_id: youtube_id.to_string(),
+                field,
+                value: value.trim().to_string(),
+            });
+        }
+
+        Ok(title_rules)
+    }
+}
+
+/// `s` with runs of whitespace made one space, and without separators left dangling at the ends
+fn squeeze(s: &str) -> String {
+    s.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_matches(|c: char| c.is_whitespace() || "-–—|:~".contains(c))
+        .to_string()
+}
+
+/// Featured artists listed as `A, B & C`
+fn split_artists(s: &str) -> Vec<String> {
+    s.split(", ")
+        .flat_map(|part| part.split(" & "))
+        .map(squeeze)
+        .filter(|artist| !artist.is_empty())
+        .collect()
+}
+
+impl TitleRules {
+    /// Reads the rules file at `path`
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let rules = std::fs::read_to_string(path)?;
+
+        rules.parse().map_err(|e: Error| Error {
+            kind: e.kind,
+            value: format!("{}: {}", path.display(), e.value),
+        })
+    }
+
+    /// Cleans `video_title` with the rules, then splits it into artist, title, featured artists,
+    /// remix and label. Fields set for `youtube_id` in the rules file win over the title.
+    pub fn parse(&self, youtube_id: Option<&str>, video_title: &str) -> ParsedTitle {
+        let mut cleaned = video_title.to_string();
+        for rule in &self.rules {
+            cleaned = rule
+                .pattern
+                .replace_all(&cleaned, rule.replacement.as_str())
+                .into_owned();
+        }
+
+        let mut parsed = ParsedTitle::default();
+
+        // bracketed parts naming featured artists, the remix or the label are taken out; others
+        // stay in the title
+        let cleaned = BRACKETED.replace_all(&cleaned, |caps: &regex::Captures| {
+            let inner = squeeze(&caps[1]);
+            if let Some(guests) = FEATURING.captures(&inner) {
+                parsed.featuring.extend(split_artists(&guests[1]));
+            } else if parsed.remix.is_none() && VERSION.is_match(&inner) {
+                parsed.remix = Some(inner);
+            } else if let Some(name) = LABEL.captures(&inner).filter(|_| parsed.label.is_none()) {
+                // `Monstercat Release` names Monstercat, `Spinnin' Records` names itself
+                parsed.label = Some(match name.get(2) {
+                    Some(_) => inner.clone(),
+                    None => name[1].to_string(),
+                });
+            } else {
+                return caps[0].to_string();
+            }
+            String::new()
+        });
+        let cleaned = squeeze(&cleaned);
+
+        let split = SEPARATORS
+            .iter()
+            .filter_map(|sep| cleaned.find(sep).map(|at| (at, sep.len())))
+            .min();
+        let (artist, mut title) = match split {
+            Some((at, len)) => (Some(squeeze(&cleaned[..at])), squeeze(&cleaned[at + len..])),
+            None => (None, cleaned.clone()),
+        };
+
+        // unbracketed featured artists, after the artist or the title
+        parsed.artist = artist.map(|artist| match INLINE_FEATURING.find(&artist) {
+            Some(m) => {
+                parsed.featuring.extend(split_artists(&artist[m.end()..]));
+                squeeze(&artist[..m.start()])
+            }
+            None => artist,
+        });
+        if let Some(m) = INLINE_FEATURING.find(&title) {
+            parsed.featuring.extend(split_artists(&title[m.end()..]));
+            title = squeeze(&title[..m.start()]);
+        }
+
+        // a remix after a second separator, as in `Artist - Track - Extended Mix`
+        if parsed.remix.is_none() {
+            if let Some((track, remix)) = title.rsplit_once(" - ") {
+                if VERSION.is_match(remix) {
+                    parsed.remix = Some(squeeze(remix));
+                    title = squeeze(track);
+                }
+            }
+        }
+
+        parsed.title = title.trim_matches('"').to_string();
+        parsed.artist = parsed.artist.filter(|artist| !artist.is_empty());
+        if parsed.title.is_empty() {
+            parsed.title = squeeze(video_title);
+        }
+
+        for o in self
+            .overrides
+            .iter()
+            .filter(|o| Some(o.youtube_id.as_str()) == youtube_id)
+        {
+            let value = (!o.value.is_empty()).then(|| o.value.clone());
+            match o.field {
+                Field::Artist => parsed.artist = value,
+                Field::Title => parsed.title = o.value.clone(),
+                Field::Featuring => parsed.featuring = split_artists(&o.value),
+                Field::Remix => parsed.remix = value,
+                Field::Label => parsed.label = value,
+            }
+        }
+
+        parsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A video title and what it should parse into
+    #[derive(Deserialize)]
+    struct Fixture {
+        video_title: String,
+        #[serde(flatten)]
+        parsed: ParsedTitle,
+    }
+
+    #[test]
+    fn test_parse() {
+        let fixtures = std::fs::read_to_string("tests/titles.json").unwrap();
+        let fixtures: Vec<Fixture> = serde_json::from_str(&fixtures).unwrap();
+        let rules = TitleRules::default();
+
+        for fixture in fixtures {
+            assert_eq!(
+                rules.parse(None, &fixture.video_title),
+                fixture.parsed,
+                "{}",
+                fixture.video_title
+            );
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules: TitleRules = [
+            "# comment",
+            "",
+            r"(?i)\[premiere\]\s* =>",
+            r"^(\S+) x (\S+) - => $1 & $2 -",
+            "yPvoKz6tyJs remix = Radio Edit",
+            "yPvoKz6tyJs featuring = A, B",
+            "yPvoKz6tyJs label =",
+        ]
+        .join("\n")
+        .parse()
+        .unwrap();
+
+        let parsed = rules.parse(
+            Some("yPvoKz6tyJs"),
+            "[Premiere] Alpha x Beta - Song [Label Release]",
+        );
+        assert_eq!(parsed.artist.as_deref(), Some("Alpha & Beta"));
+        assert_eq!(
+            parsed.artist_credit().as_deref(),
+            Some("Alpha & Beta feat. A, B")
+        );
+        assert_eq!(parsed.full_title(), "Song (Radio Edit)");
+        assert_eq!(parsed.label, None);
+
+        // overrides only apply to their track
+        let parsed = rules.parse(Some("dQw4w9WgXcQ"), "Alpha - Song [Label Release]");
+        assert_eq!(parsed.label.as_deref(), Some("Label"));
+        assert_eq!(parsed.remix, None);
+
+        for bad in [
+            "(unclosed =>",
+            "yPvoKz6tyJs album = X",
+            "dQw4w9 artist = X",
+            "nonsense",
+        ] {
+            assert!(bad.parse::<TitleRules>().is_err(), "{}", bad);
+        }
+    }
+}
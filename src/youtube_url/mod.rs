@@ -7,6 +7,13 @@ const PATTERN_EMBED: &str = r"^\/embed";
 const PATTERN_SHORT: &str = r"^\/shorts";
 const PATTERN_REGULAR: &str = r"^\/watch";
 
+/// Whether `s` looks like a YouTube video ID: 11 of the characters `validate` accepts in one
+pub fn is_youtube_id(s: &str) -> bool {
+    s.len() == 11
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
 #[derive(Clone, Debug)]
 pub enum YouTubeURLKind {
     Short,
@@ -100,8 +107,10 @@ impl YouTubeURL {
                 youtube_id = String::from("invalid");
             }
             _ => {
-                let id_pattern =
-                    Regex::new(r"(?:(?:shorts|embed)\/(\S+)\/?)|(?:watch\?v=(\S+))").unwrap();
+                let id_pattern = Regex::new(
+                    r"(?:(?:shorts|embed)\/([a-zA-Z0-9_-]{11}))|(?:watch\?v=([a-zA-Z0-9_-]{11}))",
+                )
+                .unwrap();
 
                 for (_, [id]) in id_pattern.captures_iter(url.as_str()).map(|c| c.extract()) {
                     youtube_id = String::from(id);
@@ -135,6 +144,16 @@ mod tests {
                 YouTubeURLKind::Short,
                 "3rLN_-VNcfs",
             ),
+            (
+                "https://www.youtube.com/watch?v=yPvoKz6tyJs&t=42",
+                YouTubeURLKind::Regular,
+                "yPvoKz6tyJs",
+            ),
+            (
+                "https://www.youtube.com/shorts/3rLN_-VNcfs?feature=share",
+                YouTubeURLKind::Short,
+                "3rLN_-VNcfs",
+            ),
             (
                 "https://www.youtube.com/invalid/invalid",
                 YouTubeURLKind::Invalid,